@@ -12,6 +12,7 @@ use rotor_http::header::ContentLength;
 use rotor_http::uri::RequestUri;
 use rotor_stream::{Deadline, Accept, Stream};
 use rotor_http::server::{RecvMode, Server, Head, Response, Parser};
+use rotor_http::server::NoUpgrade;
 use rotor_http::server::{Context as HttpContext};
 use rotor::mio::tcp::TcpListener;
 use time::Duration;
@@ -58,6 +59,7 @@ fn send_string(res: &mut Response, data: &[u8]) {
 
 impl Server for HelloWorld {
     type Context = Context;
+    type Upgrade = NoUpgrade<Context>;
     fn headers_received(_head: &Head, _scope: &mut Scope<Context>)
         -> Result<(Self, RecvMode, Deadline), StatusCode>
     {
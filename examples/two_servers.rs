@@ -9,6 +9,7 @@ use rotor_http::status::StatusCode::{self};
 use rotor_http::header::ContentLength;
 use rotor_stream::{Deadline, Accept, Stream};
 use rotor_http::server::{RecvMode, Server, Head, Response, Parser};
+use rotor_http::server::NoUpgrade;
 use rotor::mio::tcp::{TcpListener, TcpStream};
 use time::Duration;
 
@@ -51,6 +52,7 @@ fn send_string(res: &mut Response, data: &[u8]) {
 
 impl Server for Incr {
     type Context = Context;
+    type Upgrade = NoUpgrade<Context>;
     fn headers_received(_head: &Head, _scope: &mut Scope<Context>)
         -> Result<(Self, RecvMode, Deadline), StatusCode>
     {
@@ -99,6 +101,7 @@ impl Server for Incr {
 
 impl Server for Get {
     type Context = Context;
+    type Upgrade = NoUpgrade<Context>;
     fn headers_received(_head: &Head, _scope: &mut Scope<Context>)
         -> Result<(Self, RecvMode, Deadline), StatusCode>
     {
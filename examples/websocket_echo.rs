@@ -0,0 +1,135 @@
+extern crate rotor;
+extern crate rotor_stream;
+extern crate rotor_http;
+extern crate time;
+
+
+use rotor::Scope;
+use rotor_http::status::StatusCode;
+use rotor_http::header::ContentLength;
+use rotor_stream::{Deadline, Accept, Stream};
+use rotor_http::server::{RecvMode, Server, Head, Response, Parser};
+use rotor_http::server::{Context as HttpContext};
+use rotor_http::websocket::{self, WebSocket, WebSocketHandler};
+use rotor_http::websocket::{Message, Sender};
+use rotor::mio::tcp::TcpListener;
+use time::Duration;
+
+
+struct Context;
+
+impl rotor_http::server::Context for Context {}
+
+enum Handshake {
+    Upgrade,
+    Plain,
+}
+
+struct Echo;
+
+impl Server for Handshake {
+    type Context = Context;
+    type Upgrade = WebSocket<Echo>;
+    fn headers_received(_head: &Head, _scope: &mut Scope<Context>)
+        -> Result<(Self, RecvMode, Deadline), StatusCode>
+    {
+        Ok((Handshake::Plain, RecvMode::Buffered(1024),
+            Deadline::now() + Duration::seconds(10)))
+    }
+    fn request_start(self, head: Head, res: &mut Response,
+        scope: &mut Scope<Context>)
+        -> Option<Self>
+    {
+        match websocket::accept(&head, res) {
+            Ok(()) => {
+                res.done_headers().unwrap();
+                res.done();
+                Some(Handshake::Upgrade)
+            }
+            Err(StatusCode::UpgradeRequired) => {
+                scope.emit_error_page(StatusCode::UpgradeRequired, res);
+                None
+            }
+            Err(_) => Some(Handshake::Plain),
+        }
+    }
+    fn request_received(self, _data: &[u8], res: &mut Response,
+        _scope: &mut Scope<Context>)
+        -> Option<Self>
+    {
+        match self {
+            Handshake::Upgrade => Some(self),
+            Handshake::Plain => {
+                let data = b"Connect with websocket to get echo\n";
+                res.status(StatusCode::Ok);
+                res.add_header(ContentLength(data.len() as u64)).unwrap();
+                res.done_headers().unwrap();
+                res.write_body(data);
+                res.done();
+                None
+            }
+        }
+    }
+    fn request_chunk(self, _chunk: &[u8], _response: &mut Response,
+        _scope: &mut Scope<Context>)
+        -> Option<Self>
+    {
+        unreachable!();
+    }
+    fn request_end(self, _response: &mut Response, _scope: &mut Scope<Context>)
+        -> Option<Self>
+    {
+        unreachable!();
+    }
+    fn timeout(self, _response: &mut Response, _scope: &mut Scope<Context>)
+        -> Option<(Self, Deadline)>
+    {
+        unimplemented!();
+    }
+    fn wakeup(self, _response: &mut Response, _scope: &mut Scope<Context>)
+        -> Option<Self>
+    {
+        unimplemented!();
+    }
+    fn upgrade(self, _scope: &mut Scope<Context>)
+        -> Option<WebSocket<Echo>>
+    {
+        Some(WebSocket::new(Echo, Deadline::now() + Duration::seconds(60)))
+    }
+}
+
+impl WebSocketHandler for Echo {
+    type Context = Context;
+    fn message(self, message: Message, sender: &mut Sender,
+        _scope: &mut Scope<Context>)
+        -> Option<Self>
+    {
+        match message {
+            Message::Text(text) => sender.text(text),
+            Message::Binary(data) => sender.binary(data),
+        }
+        Some(self)
+    }
+    fn timeout(self, _sender: &mut Sender, _scope: &mut Scope<Context>)
+        -> Option<(Self, Deadline)>
+    {
+        // Close idle connections
+        None
+    }
+    fn wakeup(self, _sender: &mut Sender, _scope: &mut Scope<Context>)
+        -> Option<Self>
+    {
+        unreachable!();
+    }
+}
+
+fn main() {
+    let mut event_loop = rotor::EventLoop::new().unwrap();
+    let mut handler = rotor::Handler::new(Context, &mut event_loop);
+    let lst = TcpListener::bind(&"127.0.0.1:3000".parse().unwrap()).unwrap();
+    let ok = handler.add_machine_with(&mut event_loop, |scope| {
+        Accept::<Stream<Parser<Handshake, _>>, _>::new(lst, scope)
+    }).is_ok();
+    assert!(ok);
+    event_loop.run(&mut handler).unwrap();
+}
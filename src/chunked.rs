@@ -0,0 +1,359 @@
+//! Chunked transfer encoding (RFC 7230 section 4.1)
+//!
+//! The encoder writes directly into the output buffer. The decoder is driven
+//! by the protocol parser: it tracks the position in the chunked body with
+//! `State` and parses individual lines.
+use std::io::Write;
+use std::str::from_utf8;
+use std::ascii::AsciiExt;
+
+use rotor_stream::Buf;
+use hyper::header::Headers;
+
+
+quick_error! {
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum ChunkError {
+        InvalidSize {
+            description("Chunk size is not a valid hexadecimal number")
+        }
+        SizeOverflow {
+            description("Chunk size is too large")
+        }
+        InvalidExtension {
+            description("Chunk extension is malformed")
+        }
+        InvalidTrailer {
+            description("Trailer field is malformed")
+        }
+        NoDataEnd {
+            description("Chunk data is not followed by CRLF")
+        }
+    }
+}
+
+/// Position of the decoder in the chunked body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Waiting for the chunk-size line
+    Head,
+    /// Reading chunk data (bytes left)
+    Data(u64),
+    /// Waiting for CRLF after chunk data
+    DataEnd,
+    /// Reading trailer fields after the last chunk, until an empty line
+    Trailers,
+}
+
+/// Chunk extensions: list of names and optional values
+pub type Extensions = Vec<(String, Option<String>)>;
+
+/// Parsed chunk-size line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkHead<'a> {
+    pub size: u64,
+    /// Raw extensions (everything after the first semicolon)
+    pub extensions: &'a [u8],
+}
+
+fn is_tchar(c: u8) -> bool {
+    match c {
+        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' => true,
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.'
+        | b'^' | b'_' | b'`' | b'|' | b'~' => true,
+        _ => false,
+    }
+}
+
+fn is_ws(c: u8) -> bool {
+    c == b' ' || c == b'\t'
+}
+
+/// Parses the chunk-size line (without CRLF)
+///
+/// Whitespace before the semicolon is tolerated as some implementations
+/// send it (RFC 7230 calls it BWS in the errata).
+pub fn parse_head(line: &[u8]) -> Result<ChunkHead, ChunkError> {
+    use self::ChunkError::*;
+    let digits = line.iter().take_while(|&&c| match c {
+        b'0'...b'9' | b'a'...b'f' | b'A'...b'F' => true,
+        _ => false,
+    }).count();
+    if digits == 0 {
+        return Err(InvalidSize);
+    }
+    let mut size = 0u64;
+    for &c in &line[..digits] {
+        if size >> 60 != 0 {
+            return Err(SizeOverflow);
+        }
+        size = (size << 4) | match c {
+            b'0'...b'9' => c - b'0',
+            b'a'...b'f' => c - b'a' + 10,
+            _ => c - b'A' + 10,
+        } as u64;
+    }
+    let rest = &line[digits..];
+    let ws = rest.iter().take_while(|&&c| is_ws(c)).count();
+    let rest = &rest[ws..];
+    if rest.len() == 0 {
+        return Ok(ChunkHead { size: size, extensions: b"" });
+    }
+    if rest[0] != b';' {
+        return Err(InvalidSize);
+    }
+    let extensions = &rest[1..];
+    // Validate extensions early, so that they can't be used to smuggle
+    // anything through the proxy
+    try!(parse_extensions(extensions));
+    Ok(ChunkHead { size: size, extensions: extensions })
+}
+
+/// Parses chunk extensions into a list of name and optional value
+///
+/// The value may be either a token or a quoted string, the latter is
+/// unescaped.
+pub fn parse_extensions(data: &[u8]) -> Result<Extensions, ChunkError> {
+    use self::ChunkError::*;
+    let mut result = Vec::new();
+    let mut pos = 0;
+    let skip_ws = |pos: &mut usize| {
+        while *pos < data.len() && is_ws(data[*pos]) {
+            *pos += 1;
+        }
+    };
+    loop {
+        skip_ws(&mut pos);
+        let start = pos;
+        while pos < data.len() && is_tchar(data[pos]) {
+            pos += 1;
+        }
+        if start == pos {
+            return Err(InvalidExtension);
+        }
+        let name = from_utf8(&data[start..pos]).unwrap().to_string();
+        skip_ws(&mut pos);
+        let value = if pos < data.len() && data[pos] == b'=' {
+            pos += 1;
+            skip_ws(&mut pos);
+            if pos < data.len() && data[pos] == b'"' {
+                pos += 1;
+                let mut value = Vec::new();
+                loop {
+                    if pos >= data.len() {
+                        return Err(InvalidExtension);
+                    }
+                    match data[pos] {
+                        b'"' => break,
+                        b'\\' if pos + 1 < data.len() => {
+                            value.push(data[pos+1]);
+                            pos += 2;
+                        }
+                        b'\\' | b'\r' | b'\n' => return Err(InvalidExtension),
+                        c => {
+                            value.push(c);
+                            pos += 1;
+                        }
+                    }
+                }
+                pos += 1;
+                Some(try!(String::from_utf8(value)
+                    .map_err(|_| InvalidExtension)))
+            } else {
+                let start = pos;
+                while pos < data.len() && is_tchar(data[pos]) {
+                    pos += 1;
+                }
+                if start == pos {
+                    return Err(InvalidExtension);
+                }
+                Some(from_utf8(&data[start..pos]).unwrap().to_string())
+            }
+        } else {
+            None
+        };
+        result.push((name, value));
+        skip_ws(&mut pos);
+        if pos == data.len() {
+            return Ok(result);
+        }
+        if data[pos] != b';' {
+            return Err(InvalidExtension);
+        }
+        pos += 1;
+    }
+}
+
+/// Parses single trailer field line (without CRLF) into name and value
+///
+/// Obsolete line folding is not supported
+pub fn parse_trailer(line: &[u8]) -> Result<(&str, &[u8]), ChunkError> {
+    use self::ChunkError::*;
+    let colon = try!(line.iter().position(|&c| c == b':')
+        .ok_or(InvalidTrailer));
+    let name = &line[..colon];
+    if name.len() == 0 || !name.iter().all(|&c| is_tchar(c)) {
+        return Err(InvalidTrailer);
+    }
+    let mut value = &line[colon+1..];
+    while value.len() > 0 && is_ws(value[0]) {
+        value = &value[1..];
+    }
+    while value.len() > 0 && is_ws(value[value.len()-1]) {
+        value = &value[..value.len()-1];
+    }
+    if value.iter().any(|&c| c == b'\r' || c == b'\n' || c == 0) {
+        return Err(InvalidTrailer);
+    }
+    Ok((from_utf8(name).unwrap(), value))
+}
+
+/// Adds trailer field line (without CRLF) to the headers
+///
+/// Fields which determine message framing are not allowed in trailers, as
+/// well as more than `max_num` fields in total
+pub fn add_trailer(trailers: &mut Headers, line: &[u8], max_num: usize)
+    -> Result<(), ()>
+{
+    let (name, value) = try!(parse_trailer(line).map_err(|_| ()));
+    if trailers.len() >= max_num ||
+       name.eq_ignore_ascii_case("Content-Length") ||
+       name.eq_ignore_ascii_case("Transfer-Encoding")
+    {
+        return Err(());
+    }
+    let mut values = trailers.get_raw(name).map(|x| x.to_vec())
+        .unwrap_or(Vec::new());
+    values.push(value.to_vec());
+    trailers.set_raw(name.to_string(), values);
+    Ok(())
+}
+
+/// Checks that chunk data is followed by CRLF
+pub fn check_data_end(data: &[u8]) -> Result<(), ChunkError> {
+    if &data[..2] == b"\r\n" {
+        Ok(())
+    } else {
+        Err(ChunkError::NoDataEnd)
+    }
+}
+
+/// Writes a chunk into the buffer
+///
+/// Zero-length data is skipped, because empty chunk would terminate
+/// the body.
+pub fn write_chunk(buf: &mut Buf, data: &[u8]) {
+    if data.len() == 0 {
+        return;
+    }
+    write!(buf, "{:x}\r\n", data.len()).unwrap();
+    buf.write(data).unwrap();
+    buf.write(b"\r\n").unwrap();
+}
+
+/// Writes the last chunk and the end of (empty) trailer section
+pub fn write_last_chunk(buf: &mut Buf) {
+    buf.write(b"0\r\n\r\n").unwrap();
+}
+
+/// Returns false for fields that must not be sent in trailers
+///
+/// These are fields needed for message framing, routing or processing of
+/// the payload (RFC 7230 section 4.1.2)
+pub fn is_allowed_trailer(name: &str) -> bool {
+    const FORBIDDEN: &'static [&'static str] = &[
+        "Content-Length", "Transfer-Encoding", "Trailer", "Host",
+        "Content-Encoding", "Content-Type", "Content-Range",
+        "Cache-Control", "Expect", "Max-Forwards", "Pragma", "Range", "TE",
+        "Authorization", "Set-Cookie", "Proxy-Authenticate",
+        "WWW-Authenticate",
+    ];
+    !FORBIDDEN.iter().any(|x| x.eq_ignore_ascii_case(name))
+}
+
+/// Writes the last chunk followed by trailer fields
+pub fn write_trailers(buf: &mut Buf, trailers: &Headers) {
+    write!(buf, "0\r\n{}\r\n", trailers).unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use rotor_stream::Buf;
+    use super::{parse_head, parse_extensions, parse_trailer, ChunkHead};
+    use super::{write_chunk, write_last_chunk, check_data_end};
+    use super::{write_trailers, is_allowed_trailer};
+    use hyper::header::Headers;
+    use super::ChunkError::*;
+
+    #[test]
+    fn encode() {
+        let mut buf = Buf::new();
+        write_chunk(&mut buf, b"hello");
+        write_chunk(&mut buf, b"");
+        write_chunk(&mut buf, &[b'x'; 26]);
+        write_last_chunk(&mut buf);
+        let mut expected = b"5\r\nhello\r\n1a\r\n".to_vec();
+        expected.extend([b'x'; 26].iter().cloned());
+        expected.extend(b"\r\n0\r\n\r\n".iter().cloned());
+        assert_eq!(&buf[..], &expected[..]);
+    }
+
+    #[test]
+    fn encode_trailers() {
+        let mut buf = Buf::new();
+        let mut trailers = Headers::new();
+        trailers.set_raw("X-Checksum", vec![b"abc".to_vec()]);
+        write_trailers(&mut buf, &trailers);
+        assert_eq!(&buf[..], &b"0\r\nX-Checksum: abc\r\n\r\n"[..]);
+        assert!(is_allowed_trailer("x-checksum"));
+        assert!(!is_allowed_trailer("content-length"));
+    }
+
+    #[test]
+    fn head() {
+        assert_eq!(parse_head(b"0"),
+            Ok(ChunkHead { size: 0, extensions: b"" }));
+        assert_eq!(parse_head(b"1aF").unwrap().size, 0x1af);
+        assert_eq!(parse_head(b"10 ").unwrap().size, 16);
+        assert_eq!(parse_head(b"5;name=value"),
+            Ok(ChunkHead { size: 5, extensions: b"name=value" }));
+        assert_eq!(parse_head(b"ffffffffffffffff").unwrap().size,
+                   0xffffffffffffffff);
+        assert_eq!(parse_head(b"10000000000000000"), Err(SizeOverflow));
+        assert_eq!(parse_head(b""), Err(InvalidSize));
+        assert_eq!(parse_head(b"-5"), Err(InvalidSize));
+        assert_eq!(parse_head(b"0x5"), Err(InvalidSize));
+        assert_eq!(parse_head(b"5 5"), Err(InvalidSize));
+        assert_eq!(parse_head(b"5;"), Err(InvalidExtension));
+    }
+
+    #[test]
+    fn extensions() {
+        assert_eq!(parse_extensions(b"a").unwrap(),
+            vec![("a".to_string(), None)]);
+        assert_eq!(parse_extensions(b"a=1; b = \"x\\\"y\";c").unwrap(),
+            vec![("a".to_string(), Some("1".to_string())),
+                 ("b".to_string(), Some("x\"y".to_string())),
+                 ("c".to_string(), None)]);
+        assert!(parse_extensions(b"a=\"unterminated").is_err());
+        assert!(parse_extensions(b"a=").is_err());
+        assert!(parse_extensions(b"a b").is_err());
+    }
+
+    #[test]
+    fn trailer() {
+        assert_eq!(parse_trailer(b"Content-MD5: abc ").unwrap(),
+                   ("Content-MD5", &b"abc"[..]));
+        assert_eq!(parse_trailer(b"X-Empty:").unwrap(),
+                   ("X-Empty", &b""[..]));
+        assert!(parse_trailer(b"no colon").is_err());
+        assert!(parse_trailer(b"Bad Name: x").is_err());
+        assert!(parse_trailer(b": x").is_err());
+    }
+
+    #[test]
+    fn data_end() {
+        assert!(check_data_end(b"\r\n").is_ok());
+        assert!(check_data_end(b"0\r").is_err());
+    }
+}
@@ -0,0 +1,480 @@
+//! Establishing connections with Happy Eyeballs (RFC 8305)
+//!
+//! The `Connect` state machine resolves the host name, and then races
+//! connection attempts to all addresses of the host. Attempts are started
+//! one by one with `CONNECTION_ATTEMPT_DELAY` between them (or right after
+//! the previous attempt fails), alternating address families. The first
+//! connection established is used to create the wrapped state machine,
+//! others are closed. If no connection is established within
+//! `Context::connect_timeout()` all attempts are abandoned.
+use std::io;
+use std::any::Any;
+use std::cmp::max;
+use std::mem::replace;
+use std::error::Error;
+use std::collections::VecDeque;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use ip::IpAddr;
+use time::{Duration, SteadyTime};
+use rotor::{Machine, Response, Scope, Timeout};
+use rotor::mio::{EventSet, PollOpt};
+use rotor::mio::tcp::TcpStream;
+
+use super::dns::{ResolverContext, ResolveError};
+use super::{Context, PoolContext, Destination, Requester, ResponseError};
+
+
+/// Delay before starting the next connection attempt, in milliseconds
+pub const CONNECTION_ATTEMPT_DELAY: i64 = 250;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ConnectError {
+        Resolve(err: ResolveError) {
+            description("Can't resolve host name")
+            display("Can't resolve host name: {}", err)
+        }
+        // Connection to each address has failed
+        Connect(errors: Vec<(SocketAddr, io::Error)>) {
+            description("Can't connect to any address of the host")
+            display("Can't connect to any of {} addresses", errors.len())
+        }
+        // Connection is established, but the state machine which uses it
+        // can't be created
+        Create(err: Box<Error>) {
+            description("Can't create the state machine for the connection")
+            display("Can't create the state machine for the connection: {}",
+                    err)
+        }
+        // Proxy replied to `CONNECT` with non-successful status
+        Tunnel(status: u16) {
+            description("Proxy refused to establish the tunnel")
+            display("Proxy refused to establish the tunnel: {}", status)
+        }
+        // Connection to the proxy is closed, or its reply is invalid
+        TunnelBroken(err: Option<io::Error>) {
+            description("Proxy connection is broken while establishing \
+                the tunnel")
+        }
+    }
+}
+
+/// What the connection is established for
+///
+/// This is the seed of the `Connect` state machine, and it's passed to the
+/// wrapped state machine when connection is established. The seed is
+/// cloned before that, to report the error if the wrapped state machine
+/// can't be created.
+pub trait Target<C>: Sized {
+    /// Host name (or IP address) and port to connect to
+    fn address(&self) -> (&str, u16);
+    /// Connection can't be established
+    fn connect_failed(self, error: ConnectError, scope: &mut Scope<C>);
+}
+
+/// The order and timing of connection attempts
+///
+/// It's independent of the actual sockets, to be easy to test.
+pub struct Race {
+    addresses: VecDeque<SocketAddr>,
+    running: usize,
+    /// When the next attempt may start (`None` means right now)
+    next: Option<SteadyTime>,
+    delay: Duration,
+    errors: Vec<(SocketAddr, io::Error)>,
+}
+
+pub struct Racing<S> {
+    race: Race,
+    sockets: Vec<(SocketAddr, TcpStream)>,
+    timeout: Option<Timeout>,
+    /// When all attempts are abandoned
+    deadline: SteadyTime,
+    seed: S,
+}
+
+/// The state machine which establishes connection for `M`
+pub enum Connect<M, S> {
+    Resolving(S),
+    Racing(Racing<S>),
+    Connected(M),
+}
+
+fn socket_addr(ip: IpAddr, port: u16) -> SocketAddr {
+    match ip {
+        IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
+        IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)),
+    }
+}
+
+impl Race {
+    /// Orders addresses for connecting
+    ///
+    /// The family of the first address is preferred, then families are
+    /// alternated (RFC 8305 section 4).
+    pub fn new(addresses: &[IpAddr], port: u16, delay: Duration) -> Race {
+        let first_v6 = match addresses.first() {
+            Some(&IpAddr::V6(_)) => true,
+            _ => false,
+        };
+        let (mut preferred, mut other) = (VecDeque::new(), VecDeque::new());
+        for &ip in addresses {
+            let v6 = match ip {
+                IpAddr::V6(_) => true,
+                IpAddr::V4(_) => false,
+            };
+            let addr = socket_addr(ip, port);
+            if v6 == first_v6 {
+                preferred.push_back(addr);
+            } else {
+                other.push_back(addr);
+            }
+        }
+        let mut ordered = VecDeque::with_capacity(addresses.len());
+        loop {
+            match (preferred.pop_front(), other.pop_front()) {
+                (None, None) => break,
+                (a, b) => {
+                    ordered.extend(a);
+                    ordered.extend(b);
+                }
+            }
+        }
+        Race {
+            addresses: ordered,
+            running: 0,
+            next: None,
+            delay: delay,
+            errors: Vec::new(),
+        }
+    }
+    /// Returns the address to connect to now, if there is one
+    pub fn poll(&mut self, now: SteadyTime) -> Option<SocketAddr> {
+        if self.addresses.len() == 0 {
+            return None;
+        }
+        match self.next {
+            Some(next) if self.running > 0 && now < next => None,
+            _ => {
+                self.running += 1;
+                self.next = Some(now + self.delay);
+                self.addresses.pop_front()
+            }
+        }
+    }
+    /// Connection attempt has failed, next one may start immediately
+    pub fn failed(&mut self, addr: SocketAddr, error: io::Error) {
+        self.running -= 1;
+        self.next = None;
+        self.errors.push((addr, error));
+    }
+    /// Abandons addresses which are not tried yet
+    pub fn give_up(&mut self) {
+        while let Some(addr) = self.addresses.pop_front() {
+            self.errors.push((addr, timed_out()));
+        }
+    }
+    /// Time when the next attempt should start
+    pub fn deadline(&self) -> Option<SteadyTime> {
+        if self.addresses.len() > 0 { self.next } else { None }
+    }
+    /// Returns true if all attempts have failed
+    pub fn is_lost(&self) -> bool {
+        self.addresses.len() == 0 && self.running == 0
+    }
+    pub fn into_errors(self) -> Vec<(SocketAddr, io::Error)> {
+        self.errors
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "Connection timed out")
+}
+
+fn start<C, M, S>(seed: S, addresses: &[IpAddr], scope: &mut Scope<C>)
+    -> Response<Connect<M, S>, S>
+    where C: Context, S: Target<C>
+{
+    let race = {
+        let (_, port) = seed.address();
+        let delay = Duration::milliseconds(CONNECTION_ATTEMPT_DELAY);
+        Race::new(addresses, port, delay)
+    };
+    race_on(Racing {
+        race: race,
+        sockets: Vec::new(),
+        timeout: None,
+        deadline: SteadyTime::now() + scope.connect_timeout(),
+        seed: seed,
+    }, scope)
+}
+
+/// Starts connection attempts which are due, and reschedules the timer
+fn race_on<C, M, S>(mut r: Racing<S>, scope: &mut Scope<C>)
+    -> Response<Connect<M, S>, S>
+    where S: Target<C>
+{
+    let now = SteadyTime::now();
+    if now >= r.deadline {
+        // Otherwise unreachable addresses which drop packets are waited
+        // for until the operating system gives up (minutes)
+        for (addr, sock) in replace(&mut r.sockets, Vec::new()) {
+            scope.deregister(&sock).ok();
+            r.race.failed(addr, timed_out());
+        }
+        r.race.give_up();
+    }
+    while let Some(addr) = r.race.poll(now) {
+        let res = TcpStream::connect(&addr).and_then(|sock| {
+            try!(scope.register(&sock, EventSet::writable(),
+                                PollOpt::level()));
+            Ok(sock)
+        });
+        match res {
+            Ok(sock) => r.sockets.push((addr, sock)),
+            Err(e) => r.race.failed(addr, e),
+        }
+    }
+    if let Some(timeout) = r.timeout.take() {
+        scope.clear_timeout(timeout);
+    }
+    if r.race.is_lost() {
+        let errors = r.race.into_errors();
+        r.seed.connect_failed(ConnectError::Connect(errors), scope);
+        return Response::done();
+    }
+    let deadline = match r.race.deadline() {
+        Some(next) if next < r.deadline => next,
+        _ => r.deadline,
+    };
+    let ms = max((deadline - now).num_milliseconds(), 0);
+    r.timeout = scope.timeout_ms(ms as u64).ok();
+    Response::ok(Connect::Racing(r))
+}
+
+impl<M, S> Connect<M, S>
+    where M: Machine<Seed=(TcpStream, S)>,
+          M::Context: ResolverContext + Context,
+          S: Target<M::Context> + Clone + Any,
+{
+    fn resolve(seed: S, scope: &mut Scope<M::Context>)
+        -> Response<Self, S>
+    {
+        let notifier = scope.notifier();
+        let result = {
+            let (host, _) = seed.address();
+            scope.resolver().resolve(host, notifier)
+        };
+        match result {
+            Some(Ok(addresses)) => start(seed, &addresses, scope),
+            Some(Err(e)) => {
+                seed.connect_failed(ConnectError::Resolve(e), scope);
+                Response::done()
+            }
+            None => Response::ok(Connect::Resolving(seed)),
+        }
+    }
+    /// Checks which connection attempts are finished
+    fn check(mut r: Racing<S>, scope: &mut Scope<M::Context>)
+        -> Response<Self, S>
+    {
+        let mut i = 0;
+        while i < r.sockets.len() {
+            let result = {
+                let sock = &r.sockets[i].1;
+                match sock.take_socket_error() {
+                    Err(e) => Some(Err(e)),
+                    Ok(()) if sock.peer_addr().is_ok() => Some(Ok(())),
+                    Ok(()) => None,
+                }
+            };
+            match result {
+                Some(Err(e)) => {
+                    let (addr, sock) = r.sockets.remove(i);
+                    scope.deregister(&sock).ok();
+                    r.race.failed(addr, e);
+                }
+                Some(Ok(())) => {
+                    let (_, sock) = r.sockets.remove(i);
+                    // Others are closed when dropped
+                    for (_, other) in replace(&mut r.sockets, Vec::new()) {
+                        scope.deregister(&other).ok();
+                    }
+                    if let Some(timeout) = r.timeout.take() {
+                        scope.clear_timeout(timeout);
+                    }
+                    // Wrapped state machine registers socket itself
+                    scope.deregister(&sock).ok();
+                    // The seed is consumed by `create`, but the failure
+                    // must be reported to it
+                    let target = r.seed.clone();
+                    return match M::create((sock, r.seed), scope) {
+                        Ok(m) => Response::ok(Connect::Connected(m)),
+                        Err(e) => {
+                            target.connect_failed(ConnectError::Create(e),
+                                                  scope);
+                            Response::done()
+                        }
+                    };
+                }
+                None => i += 1,
+            }
+        }
+        race_on(r, scope)
+    }
+}
+
+impl<M, S> Machine for Connect<M, S>
+    where M: Machine<Seed=(TcpStream, S)>,
+          M::Context: ResolverContext + Context,
+          S: Target<M::Context> + Clone + Any,
+{
+    type Context = M::Context;
+    type Seed = S;
+    fn create(seed: S, scope: &mut Scope<M::Context>)
+        -> Result<Self, Box<Error>>
+    {
+        // Resolving is started on wakeup, to be able to fail right away
+        try!(scope.notifier().wakeup());
+        Ok(Connect::Resolving(seed))
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<M::Context>)
+        -> Response<Self, S>
+    {
+        match self {
+            Connect::Resolving(seed) => Response::ok(Connect::Resolving(seed)),
+            Connect::Racing(r) => Connect::check(r, scope),
+            Connect::Connected(m) => {
+                m.ready(events, scope)
+                    .map(Connect::Connected, |(_, seed)| seed)
+            }
+        }
+    }
+    fn spawned(self, scope: &mut Scope<M::Context>) -> Response<Self, S> {
+        match self {
+            Connect::Connected(m) => {
+                m.spawned(scope).map(Connect::Connected, |(_, seed)| seed)
+            }
+            me => Response::ok(me),
+        }
+    }
+    fn timeout(self, scope: &mut Scope<M::Context>) -> Response<Self, S> {
+        match self {
+            Connect::Resolving(seed) => Response::ok(Connect::Resolving(seed)),
+            Connect::Racing(mut r) => {
+                r.timeout = None;
+                race_on(r, scope)
+            }
+            Connect::Connected(m) => {
+                m.timeout(scope).map(Connect::Connected, |(_, seed)| seed)
+            }
+        }
+    }
+    fn wakeup(self, scope: &mut Scope<M::Context>) -> Response<Self, S> {
+        match self {
+            Connect::Resolving(seed) => Connect::resolve(seed, scope),
+            Connect::Racing(r) => Response::ok(Connect::Racing(r)),
+            Connect::Connected(m) => {
+                m.wakeup(scope).map(Connect::Connected, |(_, seed)| seed)
+            }
+        }
+    }
+}
+
+impl<C: PoolContext> Target<C> for Destination {
+    fn address(&self) -> (&str, u16) {
+        (&self.host[..], self.port)
+    }
+    fn connect_failed(self, error: ConnectError, scope: &mut Scope<C>) {
+        let failed = scope.pool().connect_failed(&self);
+        let error = ResponseError::Connect(error);
+        for req in failed {
+            req.bad_response(&error, scope);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+    use ip::IpAddr;
+    use time::{Duration, SteadyTime};
+    use super::Race;
+
+    fn v4(x: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, x))
+    }
+
+    fn v6(x: u16) -> IpAddr {
+        IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, x))
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn error() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionRefused, "refused")
+    }
+
+    #[test]
+    fn interleave() {
+        let mut race = Race::new(&[v6(1), v6(2), v6(3), v4(1), v4(2)], 80,
+                                 Duration::zero());
+        let now = SteadyTime::now();
+        let mut order = Vec::new();
+        while let Some(a) = race.poll(now) {
+            order.push(a);
+        }
+        assert_eq!(order, vec![
+            addr("[2001:db8::1]:80"), addr("127.0.0.1:80"),
+            addr("[2001:db8::2]:80"), addr("127.0.0.2:80"),
+            addr("[2001:db8::3]:80"),
+        ]);
+        let mut race = Race::new(&[v4(1), v6(1)], 80, Duration::zero());
+        assert_eq!(race.poll(now), Some(addr("127.0.0.1:80")));
+    }
+
+    #[test]
+    fn staggered() {
+        let delay = Duration::milliseconds(250);
+        let mut race = Race::new(&[v6(1), v4(1), v6(2)], 443, delay);
+        let now = SteadyTime::now();
+        let first = race.poll(now).unwrap();
+        assert_eq!(race.poll(now), None);
+        assert_eq!(race.deadline(), Some(now + delay));
+        let second = race.poll(now + delay).unwrap();
+        assert_eq!(second, addr("127.0.0.1:443"));
+        // Failure starts the next attempt immediately
+        race.failed(first, error());
+        let third = race.poll(now + delay).unwrap();
+        assert_eq!(race.deadline(), None);
+        assert!(!race.is_lost());
+        race.failed(second, error());
+        race.failed(third, error());
+        assert!(race.is_lost());
+        let errors = race.into_errors();
+        assert_eq!(errors.iter().map(|&(a, _)| a).collect::<Vec<_>>(),
+                   vec![first, second, third]);
+    }
+
+    #[test]
+    fn give_up() {
+        let delay = Duration::milliseconds(250);
+        let mut race = Race::new(&[v6(1), v4(1)], 80, delay);
+        let now = SteadyTime::now();
+        let first = race.poll(now).unwrap();
+        race.give_up();
+        assert_eq!(race.poll(now + delay), None);
+        assert_eq!(race.deadline(), None);
+        assert!(!race.is_lost());
+        race.failed(first, error());
+        assert!(race.is_lost());
+        let errors = race.into_errors();
+        assert_eq!(errors[0].0, addr("127.0.0.1:80"));
+        assert_eq!(errors[0].1.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(errors[1].0, first);
+    }
+}
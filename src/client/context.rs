@@ -1,22 +1,35 @@
-use std::marker::PhantomData;
-use rotor::{Peer2Socket, Peer1Socket, Scope, create_pair};
-use super::{pool, RequestCreator, PipeInit, PoolFsmInit, Response};
+use time::Duration;
 
-/// A pool of HTTP connections
-pub struct Pool<R: RequestCreator>(Peer2Socket<pool::Pool<R>>);
+use server::{MAX_HEADERS_NUM, MAX_HEADERS_SIZE, MAX_CHUNK_HEAD};
 
-pub trait Context<R: RequestCreator> {
-    fn get_pool<'x>(&'x mut self) -> &'x mut Pool<R>;
-}
 
-impl<R: RequestCreator> Pool<R> {
-    /// Creates a pool
+pub trait Context {
+    fn byte_timeout(&self) -> Duration {
+        Duration::seconds(10)
+    }
+    /// Time to wait for `100 Continue` before sending the request body
+    ///
+    /// Only used for requests with `Expect: 100-continue` header.
+    fn continue_timeout(&self) -> Duration {
+        Duration::seconds(1)
+    }
+    /// Time to establish connection to any of the addresses of the host
     ///
-    /// The method returns a Pool and some kind of token, that may be used
-    /// to initialize the state machine of the connection.
-    pub fn create() -> (PoolFsmInit<R>, Pool<R>)
-    {
-        let (tok1, tok2) = create_pair(pool::Pool::new());
-        (PoolFsmInit(tok1), Pool(tok2))
+    /// Counted after the name is resolved. Attempts which are still in
+    /// progress after that fail with `TimedOut` error.
+    fn connect_timeout(&self) -> Duration {
+        Duration::seconds(10)
+    }
+    /// Maximum size of response headers (including status line)
+    fn max_headers_size(&self) -> usize {
+        MAX_HEADERS_SIZE
+    }
+    /// Maximum number of response headers (and trailers)
+    fn max_headers_num(&self) -> usize {
+        MAX_HEADERS_NUM
+    }
+    /// Maximum length of chunk-size line (including chunk extensions)
+    fn max_chunk_head(&self) -> usize {
+        MAX_CHUNK_HEAD
     }
 }
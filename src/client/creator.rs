@@ -1,5 +0,0 @@
-use super::Request;
-
-pub trait RequestCreator {
-    fn create(&mut self, &mut Request);
-}
@@ -0,0 +1,177 @@
+use std::io::Read;
+use std::fs::File;
+use std::cmp::min;
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::net::{SocketAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+use ip::IpAddr;
+use time::Duration;
+
+
+/// Maximum number of nameservers used (like `MAXNS` of glibc)
+pub const MAX_NAMESERVERS: usize = 3;
+
+/// Resolver configuration
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Recursive resolvers queried in order
+    pub nameservers: Vec<SocketAddr>,
+    /// Time to wait for the reply from a single nameserver
+    pub timeout: Duration,
+    /// Number of times all nameservers are queried before giving up
+    pub attempts: u32,
+    /// Static addresses (from `/etc/hosts`), keyed by lowercase name
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+pub fn parse_ip(value: &str) -> Option<IpAddr> {
+    if let Ok(ip) = value.parse::<Ipv4Addr>() {
+        Some(IpAddr::V4(ip))
+    } else if let Ok(ip) = value.parse::<Ipv6Addr>() {
+        Some(IpAddr::V6(ip))
+    } else {
+        None
+    }
+}
+
+fn read_file(path: &str) -> Option<String> {
+    let mut buf = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut buf)).ok()
+        .map(|_| buf)
+}
+
+impl Config {
+    /// Empty config with default timeouts
+    pub fn new() -> Config {
+        Config {
+            nameservers: Vec::new(),
+            timeout: Duration::seconds(5),
+            attempts: 2,
+            hosts: HashMap::new(),
+        }
+    }
+    /// Reads `/etc/resolv.conf` and `/etc/hosts`
+    ///
+    /// Missing files are ignored. Like in glibc, the nameserver on
+    /// localhost is used if there are no nameservers configured.
+    pub fn system() -> Config {
+        let mut cfg = Config::new();
+        if let Some(data) = read_file("/etc/resolv.conf") {
+            cfg.add_resolv_conf(&data);
+        }
+        if let Some(data) = read_file("/etc/hosts") {
+            cfg.add_hosts(&data);
+        }
+        if cfg.nameservers.len() == 0 {
+            cfg.nameservers.push(
+                SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1),
+                                                 53)));
+        }
+        cfg
+    }
+    /// Adds nameservers and options from the `resolv.conf(5)` file
+    ///
+    /// Only `nameserver` lines and `timeout` and `attempts` options are
+    /// supported, search domains are not.
+    pub fn add_resolv_conf(&mut self, data: &str) {
+        for line in data.lines() {
+            let line = line.splitn(2, |c| c == '#' || c == ';')
+                .next().unwrap();
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => {
+                    let addr = match words.next().and_then(parse_ip) {
+                        Some(IpAddr::V4(ip)) => {
+                            SocketAddr::V4(SocketAddrV4::new(ip, 53))
+                        }
+                        Some(IpAddr::V6(ip)) => {
+                            SocketAddr::V6(SocketAddrV6::new(ip, 53, 0, 0))
+                        }
+                        None => continue,
+                    };
+                    if self.nameservers.len() < MAX_NAMESERVERS {
+                        self.nameservers.push(addr);
+                    }
+                }
+                Some("options") => {
+                    for opt in words {
+                        let mut pair = opt.splitn(2, ':');
+                        let name = pair.next().unwrap();
+                        let value = match pair.next().map(|x| x.parse()) {
+                            Some(Ok(x)) => x,
+                            _ => continue,
+                        };
+                        match name {
+                            "timeout" => {
+                                self.timeout = Duration::seconds(
+                                    min(value, 30) as i64);
+                            }
+                            "attempts" => {
+                                self.attempts = min(value, 5);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    /// Adds static addresses from the `hosts(5)` file
+    pub fn add_hosts(&mut self, data: &str) {
+        for line in data.lines() {
+            let line = line.splitn(2, '#').next().unwrap();
+            let mut words = line.split_whitespace();
+            let ip = match words.next().and_then(parse_ip) {
+                Some(ip) => ip,
+                None => continue,
+            };
+            for name in words {
+                let name = name.to_ascii_lowercase();
+                let list = self.hosts.entry(name).or_insert_with(Vec::new);
+                if !list.contains(&ip) {
+                    list.push(ip);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use ip::IpAddr;
+    use time::Duration;
+    use super::Config;
+
+    #[test]
+    fn resolv_conf() {
+        let mut cfg = Config::new();
+        cfg.add_resolv_conf("# comment\n\
+            nameserver 10.0.0.1\n\
+            nameserver ::1 ; other comment\n\
+            nameserver fe80::1%eth0\n\
+            search example.com\n\
+            options ndots:2 timeout:3 attempts:10\n");
+        assert_eq!(cfg.nameservers, vec![
+            "10.0.0.1:53".parse::<SocketAddr>().unwrap(),
+            "[::1]:53".parse::<SocketAddr>().unwrap(),
+        ]);
+        assert_eq!(cfg.timeout, Duration::seconds(3));
+        assert_eq!(cfg.attempts, 5);
+    }
+
+    #[test]
+    fn hosts() {
+        let mut cfg = Config::new();
+        cfg.add_hosts("127.0.0.1 localhost Local.Domain # comment\n\
+                       ::1 localhost\n\
+                       # 10.0.0.1 commented\n\
+                       junk line\n");
+        let v4 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        assert_eq!(cfg.hosts["local.domain"], vec![v4]);
+        assert_eq!(cfg.hosts["localhost"].len(), 2);
+        assert_eq!(cfg.hosts.len(), 2);
+    }
+}
@@ -0,0 +1,554 @@
+//! Non-blocking DNS resolver
+//!
+//! The `Resolver` is kept in the context of the event loop (see
+//! `ResolverContext`), and the `Socket` state machine sends queries and
+//! receives replies over UDP. Any state machine may resolve names with
+//! `Resolver::resolve()`, which either returns the result immediately (for
+//! IP addresses, names from `/etc/hosts`, and cached results) or wakes the
+//! state machine up when the result is ready.
+//!
+//! Both `A` and `AAAA` records are queried. Results are cached for the
+//! smallest TTL of the records (negative ones are cached according to
+//! RFC 2308). Expired results are evicted whenever a lookup is finished.
+//! Truncated replies are used as is, we don't retry over TCP.
+use std::cmp::{min, max};
+use std::mem::replace;
+use std::ascii::AsciiExt;
+use std::net::SocketAddr;
+use std::marker::PhantomData;
+use std::collections::HashMap;
+use std::error::Error;
+
+use ip::IpAddr;
+use time::{Duration, SteadyTime, precise_time_ns};
+use rotor::{Machine, Response, Scope, Notifier, Timeout};
+use rotor::mio::{EventSet, PollOpt};
+use rotor::mio::udp::UdpSocket;
+
+use super::Wake;
+use self::config::parse_ip;
+
+mod wire;
+mod config;
+
+pub use self::config::Config;
+
+
+/// TTL of negative answer when server doesn't provide one
+pub const NEGATIVE_TTL: u32 = 30;
+/// Maximum size of the reply (we don't support EDNS)
+const MAX_REPLY: usize = 512;
+
+quick_error! {
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum ResolveError {
+        InvalidName {
+            description("Name can't be resolved")
+        }
+        NotFound {
+            description("Host not found")
+        }
+        ServerFailure {
+            description("Nameserver failed to resolve the name")
+        }
+        Timeout {
+            description("Nameservers have not replied in time")
+        }
+    }
+}
+
+pub type Addresses = Result<Vec<IpAddr>, ResolveError>;
+
+/// The context of the event loop which has the resolver
+pub trait ResolverContext {
+    fn resolver(&mut self) -> &mut Resolver;
+}
+
+struct Query {
+    name: String,
+    qtype: u16,
+    attempt: usize,
+    server: SocketAddr,
+    deadline: SteadyTime,
+}
+
+/// Result for a single record type, with TTL
+type Answer = (Addresses, u32);
+
+struct Lookup<N> {
+    waiters: Vec<N>,
+    v4: Option<Answer>,
+    v6: Option<Answer>,
+}
+
+struct Entry {
+    addresses: Addresses,
+    expires: SteadyTime,
+}
+
+pub struct Resolver<N=Notifier> {
+    config: Config,
+    cache: HashMap<String, Entry>,
+    lookups: HashMap<String, Lookup<N>>,
+    queries: HashMap<u16, Query>,
+    outgoing: Vec<(SocketAddr, Vec<u8>)>,
+    socket: Option<N>,
+    /// State of the generator of query identifiers
+    seed: u32,
+}
+
+/// The state machine which owns UDP socket of the `Resolver`
+///
+/// Should be added to the loop once, the seed is the unit.
+pub struct Socket<C: ResolverContext> {
+    /// Sockets for IPv4 and IPv6 nameservers, each one is bound only if
+    /// there is a nameserver of the address family
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+    timeout: Option<Timeout>,
+    phantom: PhantomData<*const C>,
+}
+
+/// Merges results of `A` and `AAAA` queries, IPv6 addresses go first
+fn merge(v4: Answer, v6: Answer) -> Answer {
+    match (v4, v6) {
+        ((Ok(a4), t4), (Ok(mut a6), t6)) => {
+            a6.extend(a4);
+            (Ok(a6), min(t4, t6))
+        }
+        ((Ok(a), ttl), _) | (_, (Ok(a), ttl)) => (Ok(a), ttl),
+        ((Err(ResolveError::NotFound), t4),
+         (Err(ResolveError::NotFound), t6))
+        => (Err(ResolveError::NotFound), min(t4, t6)),
+        // Failures are not cached
+        ((Err(ResolveError::NotFound), _), (Err(e), _)) => (Err(e), 0),
+        ((Err(e), _), _) => (Err(e), 0),
+    }
+}
+
+impl<N: Wake> Resolver<N> {
+    pub fn new(config: Config) -> Resolver<N> {
+        Resolver {
+            config: config,
+            cache: HashMap::new(),
+            lookups: HashMap::new(),
+            queries: HashMap::new(),
+            outgoing: Vec::new(),
+            socket: None,
+            seed: (precise_time_ns() as u32) | 1,
+        }
+    }
+    /// Sets the state machine which sends queries
+    pub fn set_socket(&mut self, waker: N) {
+        self.socket = Some(waker);
+    }
+    /// Resolves the name
+    ///
+    /// Returns `None` if the name is being resolved, then the `waker` is
+    /// woken up when the result is ready, and `resolve()` must be called
+    /// again to get it.
+    pub fn resolve(&mut self, name: &str, waker: N) -> Option<Addresses> {
+        self.resolve_at(name, waker, SteadyTime::now())
+    }
+    fn resolve_at(&mut self, name: &str, waker: N, now: SteadyTime)
+        -> Option<Addresses>
+    {
+        if let Some(ip) = parse_ip(name) {
+            return Some(Ok(vec![ip]));
+        }
+        let name = name.trim_right_matches('.').to_ascii_lowercase();
+        if let Some(list) = self.config.hosts.get(&name) {
+            return Some(Ok(list.clone()));
+        }
+        if let Some(entry) = self.cache.get(&name) {
+            if entry.expires > now {
+                return Some(entry.addresses.clone());
+            }
+        }
+        self.cache.remove(&name);
+        if let Some(lookup) = self.lookups.get_mut(&name) {
+            lookup.waiters.push(waker);
+            return None;
+        }
+        if !wire::valid_name(&name) {
+            return Some(Err(ResolveError::InvalidName));
+        }
+        if self.config.nameservers.len() == 0 {
+            return Some(Err(ResolveError::NotFound));
+        }
+        self.lookups.insert(name.clone(), Lookup {
+            waiters: vec![waker],
+            v4: None,
+            v6: None,
+        });
+        self.send(name.clone(), wire::A, 0, now);
+        self.send(name, wire::AAAA, 0, now);
+        self.socket.as_ref().map(|s| s.wake());
+        None
+    }
+    /// Returns queries which should be sent
+    pub fn take_queries(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        replace(&mut self.outgoing, Vec::new())
+    }
+    /// Returns the time when the next query times out
+    pub fn next_deadline(&self) -> Option<SteadyTime> {
+        self.queries.values().map(|q| q.deadline).min()
+    }
+    /// Processes reply received from the `source` address
+    ///
+    /// Replies which don't match any outstanding query are ignored.
+    pub fn reply(&mut self, data: &[u8], source: SocketAddr) {
+        self.reply_at(data, source, SteadyTime::now())
+    }
+    fn reply_at(&mut self, data: &[u8], source: SocketAddr, now: SteadyTime)
+    {
+        let reply = match wire::parse(data) {
+            Ok(reply) => reply,
+            Err(()) => return,
+        };
+        match self.queries.get(&reply.id) {
+            Some(q) if q.server == source && q.name == reply.name &&
+                       q.qtype == reply.qtype => {}
+            _ => return,
+        }
+        let query = self.queries.remove(&reply.id).unwrap();
+        if reply.addresses.len() > 0 {
+            let ttl = reply.addresses.iter().map(|&(_, t)| t).min().unwrap();
+            let list = reply.addresses.into_iter().map(|(a, _)| a).collect();
+            self.answered(query.name, query.qtype, (Ok(list), ttl), now);
+        } else if reply.rcode == wire::NXDOMAIN ||
+            reply.rcode == wire::NOERROR && !reply.truncated
+        {
+            let ttl = reply.negative_ttl.unwrap_or(NEGATIVE_TTL);
+            self.answered(query.name, query.qtype,
+                (Err(ResolveError::NotFound), ttl), now);
+        } else {
+            self.retry(query, ResolveError::ServerFailure, now);
+        }
+    }
+    /// Retries queries which are timed out
+    pub fn timeout(&mut self) {
+        self.timeout_at(SteadyTime::now())
+    }
+    fn timeout_at(&mut self, now: SteadyTime) {
+        let expired = self.queries.iter()
+            .filter(|&(_, q)| q.deadline <= now)
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            let query = self.queries.remove(&id).unwrap();
+            self.retry(query, ResolveError::Timeout, now);
+        }
+        self.evict(now);
+    }
+    /// Removes expired entries, so the cache doesn't grow indefinitely
+    fn evict(&mut self, now: SteadyTime) {
+        let expired = self.cache.iter()
+            .filter(|&(_, e)| e.expires <= now)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in expired {
+            self.cache.remove(&name);
+        }
+    }
+    fn next_id(&mut self) -> u16 {
+        loop {
+            // xorshift32, ids must not be easy to guess
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 17;
+            self.seed ^= self.seed << 5;
+            let id = self.seed as u16;
+            if !self.queries.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+    fn send(&mut self, name: String, qtype: u16, attempt: usize,
+        now: SteadyTime)
+    {
+        let id = self.next_id();
+        let ns = &self.config.nameservers;
+        let server = ns[attempt % ns.len()];
+        self.outgoing.push((server, wire::query(id, &name, qtype)));
+        self.queries.insert(id, Query {
+            name: name,
+            qtype: qtype,
+            attempt: attempt,
+            server: server,
+            deadline: now + self.config.timeout,
+        });
+    }
+    /// Sends query to the next nameserver, if attempts are not exhausted
+    fn retry(&mut self, query: Query, error: ResolveError, now: SteadyTime)
+    {
+        let attempts = self.config.attempts as usize *
+                       self.config.nameservers.len();
+        if query.attempt + 1 < attempts {
+            self.send(query.name, query.qtype, query.attempt + 1, now);
+        } else {
+            self.answered(query.name, query.qtype, (Err(error), 0), now);
+        }
+    }
+    fn answered(&mut self, name: String, qtype: u16, answer: Answer,
+        now: SteadyTime)
+    {
+        let done = match self.lookups.get_mut(&name) {
+            Some(lookup) => {
+                if qtype == wire::A {
+                    lookup.v4 = Some(answer);
+                } else {
+                    lookup.v6 = Some(answer);
+                }
+                lookup.v4.is_some() && lookup.v6.is_some()
+            }
+            None => false,
+        };
+        if !done {
+            return;
+        }
+        let lookup = self.lookups.remove(&name).unwrap();
+        let (addresses, ttl) = merge(lookup.v4.unwrap(), lookup.v6.unwrap());
+        // Waiters must be able to fetch the result even if TTL is zero
+        let ttl = max(ttl, 1);
+        self.evict(now);
+        self.cache.insert(name, Entry {
+            addresses: addresses,
+            expires: now + Duration::seconds(ttl as i64),
+        });
+        for waiter in lookup.waiters {
+            waiter.wake();
+        }
+    }
+}
+
+/// Binds the socket to the wildcard `addr`, and registers it in the loop
+fn bind<C>(addr: &str, scope: &mut Scope<C>) -> Result<UdpSocket, Box<Error>>
+{
+    let sock = try!(UdpSocket::bound(&addr.parse().unwrap()));
+    try!(scope.register(&sock, EventSet::readable(), PollOpt::level()));
+    Ok(sock)
+}
+
+impl<C: ResolverContext> Socket<C> {
+    fn flush(mut self, scope: &mut Scope<C>) -> Response<Self, ()> {
+        for (addr, data) in scope.resolver().take_queries() {
+            let sock = match addr {
+                SocketAddr::V4(_) => self.v4.as_ref(),
+                SocketAddr::V6(_) => self.v6.as_ref(),
+            };
+            // On error the query is retransmitted after timeout
+            sock.map(|sock| sock.send_to(&data, &addr).ok());
+        }
+        if let Some(timeout) = self.timeout.take() {
+            scope.clear_timeout(timeout);
+        }
+        if let Some(deadline) = scope.resolver().next_deadline() {
+            let ms = max((deadline - SteadyTime::now()).num_milliseconds(),
+                         0);
+            self.timeout = scope.timeout_ms(ms as u64).ok();
+        }
+        Response::ok(self)
+    }
+}
+
+impl<C: ResolverContext> Machine for Socket<C> {
+    type Context = C;
+    type Seed = ();
+    fn create(_seed: (), scope: &mut Scope<C>)
+        -> Result<Self, Box<Error>>
+    {
+        let (has_v4, has_v6) = {
+            let ns = &scope.resolver().config.nameservers;
+            (ns.iter().any(|a| matches!(*a, SocketAddr::V4(_))),
+             ns.iter().any(|a| matches!(*a, SocketAddr::V6(_))))
+        };
+        let v4 = if has_v4 { Some(try!(bind("0.0.0.0:0", scope))) }
+                 else { None };
+        let v6 = if has_v6 { Some(try!(bind("[::]:0", scope))) }
+                 else { None };
+        let notifier = scope.notifier();
+        scope.resolver().set_socket(notifier);
+        Ok(Socket {
+            v4: v4,
+            v6: v6,
+            timeout: None,
+            phantom: PhantomData,
+        })
+    }
+    fn ready(self, _events: EventSet, scope: &mut Scope<C>)
+        -> Response<Self, ()>
+    {
+        let mut buf = [0u8; MAX_REPLY];
+        for sock in self.v4.iter().chain(self.v6.iter()) {
+            loop {
+                match sock.recv_from(&mut buf) {
+                    Ok(Some((num, addr))) => {
+                        scope.resolver().reply(&buf[..num], addr);
+                    }
+                    Ok(None) => break,
+                    // Errors like ICMP unreachable, query will time out
+                    Err(_) => break,
+                }
+            }
+        }
+        self.flush(scope)
+    }
+    fn spawned(self, _scope: &mut Scope<C>) -> Response<Self, ()> {
+        unreachable!();
+    }
+    fn timeout(mut self, scope: &mut Scope<C>) -> Response<Self, ()> {
+        self.timeout = None;
+        scope.resolver().timeout();
+        self.flush(scope)
+    }
+    fn wakeup(self, scope: &mut Scope<C>) -> Response<Self, ()> {
+        self.flush(scope)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{UdpSocket, Ipv4Addr, Ipv6Addr};
+    use ip::IpAddr;
+    use time::{Duration, SteadyTime};
+    use test_util::{Waker, waker};
+    use super::{Resolver, Config, ResolveError};
+    use super::wire::{answer, NXDOMAIN};
+
+    fn v4() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))
+    }
+
+    fn v6() -> IpAddr {
+        IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))
+    }
+
+    /// The stand-in DNS server replying with `rcode` and fixed addresses
+    struct Server {
+        server: UdpSocket,
+        client: UdpSocket,
+    }
+
+    impl Server {
+        fn new() -> Server {
+            Server {
+                server: UdpSocket::bind("127.0.0.1:0").unwrap(),
+                client: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            }
+        }
+        fn resolver(&self) -> Resolver<Waker> {
+            let mut cfg = Config::new();
+            cfg.nameservers.push(self.server.local_addr().unwrap());
+            cfg.add_hosts("10.0.0.1 static.example.com\n");
+            Resolver::new(cfg)
+        }
+        fn serve(&self, resolver: &mut Resolver<Waker>, rcode: u8, ttl: u32)
+        {
+            let queries = resolver.take_queries();
+            for &(addr, ref data) in &queries {
+                self.client.send_to(data, addr).unwrap();
+            }
+            let addresses = if rcode == 0 {
+                vec![(v4(), ttl), (v6(), ttl + 10)]
+            } else {
+                vec![]
+            };
+            let mut buf = [0u8; 512];
+            for _ in 0..queries.len() {
+                let (n, addr) = self.server.recv_from(&mut buf).unwrap();
+                let reply = answer(&buf[..n], rcode, &addresses);
+                self.server.send_to(&reply, addr).unwrap();
+            }
+            for _ in 0..queries.len() {
+                let (n, addr) = self.client.recv_from(&mut buf).unwrap();
+                resolver.reply(&buf[..n], addr);
+            }
+        }
+    }
+
+    #[test]
+    fn immediate() {
+        let mut resolver = Server::new().resolver();
+        assert_eq!(resolver.resolve("127.0.0.2", waker()),
+                   Some(Ok(vec![v4()])));
+        assert_eq!(resolver.resolve("Static.Example.COM.", waker()),
+                   Some(Ok(vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))])));
+        assert_eq!(resolver.resolve("bad..name", waker()),
+                   Some(Err(ResolveError::InvalidName)));
+        assert_eq!(resolver.take_queries().len(), 0);
+    }
+
+    #[test]
+    fn loopback_server() {
+        let server = Server::new();
+        let mut resolver = server.resolver();
+        let socket = waker();
+        resolver.set_socket(socket.clone());
+        let w1 = waker();
+        let w2 = waker();
+        assert_eq!(resolver.resolve("example.com", w1.clone()), None);
+        assert_eq!(resolver.resolve("EXAMPLE.com", w2.clone()), None);
+        assert_eq!(socket.0.get(), 1);
+        server.serve(&mut resolver, 0, 300);
+        assert_eq!((w1.0.get(), w2.0.get()), (1, 1));
+        assert_eq!(resolver.resolve("example.com", waker()),
+                   Some(Ok(vec![v6(), v4()])));
+        assert!(resolver.next_deadline().is_none());
+
+        assert_eq!(resolver.resolve("missing.example.com", w1.clone()),
+                   None);
+        server.serve(&mut resolver, NXDOMAIN, 0);
+        assert_eq!(resolver.resolve("missing.example.com", waker()),
+                   Some(Err(ResolveError::NotFound)));
+    }
+
+    #[test]
+    fn ttl() {
+        let server = Server::new();
+        let mut resolver = server.resolver();
+        let now = SteadyTime::now();
+        assert_eq!(resolver.resolve_at("example.com", waker(), now), None);
+        server.serve(&mut resolver, 0, 60);
+        let later = now + Duration::seconds(59);
+        assert!(resolver.resolve_at("example.com", waker(), later)
+                .is_some());
+        let later = now + Duration::seconds(61);
+        assert_eq!(resolver.resolve_at("example.com", waker(), later), None);
+        assert_eq!(resolver.take_queries().len(), 2);
+    }
+
+    #[test]
+    fn evict() {
+        let server = Server::new();
+        let mut resolver = server.resolver();
+        let now = SteadyTime::now();
+        assert_eq!(resolver.resolve_at("example.com", waker(), now), None);
+        server.serve(&mut resolver, 0, 60);
+        assert_eq!(resolver.resolve_at("example.org", waker(), now), None);
+        server.serve(&mut resolver, 0, 600);
+        assert_eq!(resolver.cache.len(), 2);
+        resolver.timeout_at(now + Duration::seconds(61));
+        assert_eq!(resolver.cache.len(), 1);
+        assert!(resolver.cache.contains_key("example.org"));
+    }
+
+    #[test]
+    fn timeout() {
+        let server = Server::new();
+        let mut resolver = server.resolver();
+        let w = waker();
+        let now = SteadyTime::now();
+        assert_eq!(resolver.resolve_at("example.com", w.clone(), now), None);
+        assert_eq!(resolver.take_queries().len(), 2);
+        let now = now + Duration::seconds(5);
+        resolver.timeout_at(now);
+        // Retransmitted, as there are two attempts by default
+        assert_eq!(resolver.take_queries().len(), 2);
+        assert_eq!(w.0.get(), 0);
+        resolver.timeout_at(now + Duration::seconds(5));
+        assert_eq!(resolver.take_queries().len(), 0);
+        assert_eq!(w.0.get(), 1);
+        assert_eq!(resolver.resolve_at("example.com", waker(), now),
+                   Some(Err(ResolveError::Timeout)));
+    }
+}
@@ -0,0 +1,281 @@
+//! Encoding queries and decoding replies (RFC 1035)
+use std::ascii::AsciiExt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use ip::IpAddr;
+
+
+pub const A: u16 = 1;
+pub const SOA: u16 = 6;
+pub const AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// Limit of compression pointers followed for a single name
+const MAX_JUMPS: usize = 32;
+
+pub const NOERROR: u8 = 0;
+pub const NXDOMAIN: u8 = 3;
+
+/// The decoded reply
+#[derive(Debug)]
+pub struct Reply {
+    pub id: u16,
+    pub rcode: u8,
+    pub truncated: bool,
+    /// The name from the question section (lowercase, without trailing dot)
+    pub name: String,
+    pub qtype: u16,
+    /// Addresses of the type asked, with TTLs
+    pub addresses: Vec<(IpAddr, u32)>,
+    /// TTL of the negative answer, from SOA record in authority section
+    pub negative_ttl: Option<u32>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+/// Returns true if name can be queried
+///
+/// Name must be without trailing dot.
+pub fn valid_name(name: &str) -> bool {
+    name.len() > 0 && name.len() <= 253 &&
+        name.split('.').all(|x| x.len() > 0 && x.len() <= 63)
+}
+
+/// Encodes query with recursion desired
+pub fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    assert!(valid_name(name));
+    let mut buf = Vec::with_capacity(name.len() + 18);
+    buf.extend(&[(id >> 8) as u8, id as u8,
+                 0x01, 0x00,  // RD
+                 0, 1,  // QDCOUNT
+                 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        buf.push(label.len() as u8);
+        buf.extend(label.as_bytes());
+    }
+    buf.extend(&[0,
+                 (qtype >> 8) as u8, qtype as u8,
+                 (CLASS_IN >> 8) as u8, CLASS_IN as u8]);
+    buf
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, ()> {
+        if self.pos >= self.data.len() {
+            return Err(());
+        }
+        self.pos += 1;
+        Ok(self.data[self.pos-1])
+    }
+    fn u16(&mut self) -> Result<u16, ()> {
+        Ok(((try!(self.u8()) as u16) << 8) | try!(self.u8()) as u16)
+    }
+    fn u32(&mut self) -> Result<u32, ()> {
+        Ok(((try!(self.u16()) as u32) << 16) | try!(self.u16()) as u32)
+    }
+    fn bytes(&mut self, num: usize) -> Result<&'a [u8], ()> {
+        if self.pos + num > self.data.len() {
+            return Err(());
+        }
+        self.pos += num;
+        Ok(&self.data[self.pos-num..self.pos])
+    }
+    fn name(&mut self) -> Result<String, ()> {
+        let mut name = String::new();
+        let mut pos = self.pos;
+        let mut jumps = 0;
+        loop {
+            if pos >= self.data.len() {
+                return Err(());
+            }
+            let len = self.data[pos] as usize;
+            if len & 0xC0 == 0xC0 {
+                if pos + 1 >= self.data.len() || jumps >= MAX_JUMPS {
+                    return Err(());
+                }
+                if jumps == 0 {
+                    self.pos = pos + 2;
+                }
+                jumps += 1;
+                pos = ((len & 0x3F) << 8) | self.data[pos+1] as usize;
+                continue;
+            } else if len & 0xC0 != 0 {
+                return Err(());
+            }
+            pos += 1;
+            if len == 0 {
+                break;
+            }
+            if pos + len > self.data.len() {
+                return Err(());
+            }
+            if name.len() > 0 {
+                name.push('.');
+            }
+            for &c in &self.data[pos..pos+len] {
+                name.push((c as char).to_ascii_lowercase());
+            }
+            pos += len;
+        }
+        if jumps == 0 {
+            self.pos = pos;
+        }
+        Ok(name)
+    }
+}
+
+/// Decodes the reply
+///
+/// Only address records of the type asked are returned. Records of CNAME
+/// chain are not checked, as we only ask the recursive resolver.
+pub fn parse(data: &[u8]) -> Result<Reply, ()> {
+    let mut rd = Reader { data: data, pos: 0 };
+    let id = try!(rd.u16());
+    let flags = try!(rd.u16());
+    if flags & 0x8000 == 0 {  // QR
+        return Err(());
+    }
+    let qdcount = try!(rd.u16());
+    let ancount = try!(rd.u16());
+    let nscount = try!(rd.u16());
+    try!(rd.u16());  // ARCOUNT
+    if qdcount != 1 {
+        return Err(());
+    }
+    let name = try!(rd.name());
+    let qtype = try!(rd.u16());
+    if try!(rd.u16()) != CLASS_IN {
+        return Err(());
+    }
+    let mut reply = Reply {
+        id: id,
+        rcode: (flags & 0x0F) as u8,
+        truncated: flags & 0x0200 != 0,
+        name: name,
+        qtype: qtype,
+        addresses: Vec::new(),
+        negative_ttl: None,
+    };
+    for i in 0..(ancount as usize + nscount as usize) {
+        try!(rd.name());
+        let rtype = try!(rd.u16());
+        let class = try!(rd.u16());
+        let ttl = try!(rd.u32());
+        let rdlen = try!(rd.u16()) as usize;
+        let rdata = try!(rd.bytes(rdlen));
+        if class != CLASS_IN {
+            continue;
+        }
+        if i < ancount as usize {
+            match (rtype, qtype, rdlen) {
+                (A, A, 4) => {
+                    let ip = Ipv4Addr::new(rdata[0], rdata[1],
+                                           rdata[2], rdata[3]);
+                    reply.addresses.push((IpAddr::V4(ip), ttl));
+                }
+                (AAAA, AAAA, 16) => {
+                    let mut seg = [0u16; 8];
+                    for (j, s) in seg.iter_mut().enumerate() {
+                        *s = ((rdata[j*2] as u16) << 8) |
+                             rdata[j*2+1] as u16;
+                    }
+                    let ip = Ipv6Addr::new(seg[0], seg[1], seg[2], seg[3],
+                                           seg[4], seg[5], seg[6], seg[7]);
+                    reply.addresses.push((IpAddr::V6(ip), ttl));
+                }
+                _ => {}
+            }
+        } else if rtype == SOA && rdlen >= 4 {
+            // The MINIMUM field is the last one (RFC 2308 section 5)
+            let m = &rdata[rdlen-4..];
+            let minimum = ((m[0] as u32) << 24) | ((m[1] as u32) << 16) |
+                          ((m[2] as u32) << 8) | m[3] as u32;
+            reply.negative_ttl = Some(::std::cmp::min(ttl, minimum));
+        }
+    }
+    Ok(reply)
+}
+
+/// Builds reply to the query, like the DNS server does
+#[cfg(test)]
+pub fn answer(query: &[u8], rcode: u8, addresses: &[(IpAddr, u32)])
+    -> Vec<u8>
+{
+    let qtype = ((query[query.len()-4] as u16) << 8) |
+                query[query.len()-3] as u16;
+    let mut buf = query.to_vec();
+    buf[2] = 0x81;  // QR, RD
+    buf[3] = 0x80 | rcode;  // RA
+    let mut count = 0;
+    for &(ref ip, ttl) in addresses {
+        let rdata = match (ip, qtype) {
+            (&IpAddr::V4(ref ip), A) => ip.octets().to_vec(),
+            (&IpAddr::V6(ref ip), AAAA) => {
+                ip.segments().iter()
+                    .flat_map(|s| vec![(s >> 8) as u8, *s as u8])
+                    .collect()
+            }
+            _ => continue,
+        };
+        count += 1;
+        buf.extend(&[0xC0, 12,  // pointer to the question name
+                     (qtype >> 8) as u8, qtype as u8, 0, 1,
+                     (ttl >> 24) as u8, (ttl >> 16) as u8,
+                     (ttl >> 8) as u8, ttl as u8,
+                     0, rdata.len() as u8]);
+        buf.extend(&rdata[..]);
+    }
+    buf[7] = count;
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter::repeat;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use ip::IpAddr;
+    use super::{query, parse, answer, valid_name, A, AAAA, NXDOMAIN};
+
+    #[test]
+    fn names() {
+        assert!(valid_name("example.com"));
+        assert!(!valid_name("example..com"));
+        assert!(!valid_name(""));
+        let long = repeat('x').take(64).collect::<String>();
+        assert!(!valid_name(&long));
+    }
+
+    #[test]
+    fn roundtrip() {
+        let q = query(0x1234, "Example.com", A);
+        assert_eq!(&q[..4], &[0x12, 0x34, 0x01, 0x00]);
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        let v6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let reply = parse(&answer(&q, 0, &[(ip, 300), (v6, 10)])).unwrap();
+        assert_eq!(reply.id, 0x1234);
+        assert_eq!(reply.name, "example.com");
+        assert_eq!(reply.qtype, A);
+        assert_eq!(reply.addresses, vec![(ip, 300)]);
+        let q = query(1, "example.com", AAAA);
+        let reply = parse(&answer(&q, 0, &[(ip, 300), (v6, 10)])).unwrap();
+        assert_eq!(reply.addresses, vec![(v6, 10)]);
+        let reply = parse(&answer(&q, NXDOMAIN, &[])).unwrap();
+        assert_eq!(reply.rcode, NXDOMAIN);
+        assert_eq!(reply.addresses, vec![]);
+        // Queries are not replies
+        assert!(parse(&q).is_err());
+        assert!(parse(&q[..10]).is_err());
+    }
+
+    #[test]
+    fn pointer_loop() {
+        let mut q = query(1, "example.com", A);
+        q[2] = 0x81;
+        q[12] = 0xC0;
+        q[13] = 12;
+        assert!(parse(&q).is_err());
+    }
+}
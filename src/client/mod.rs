@@ -4,21 +4,52 @@
 //! provide HTTP/2.0 and TLS implementation with exactly the same protocol.
 //! But it's yet unproven if it is possible.
 //!
-//! Also DNS resolving is not implemented yet.
+//! The connection is driven by the `Parser` protocol (i.e. use
+//! `rotor_stream::Stream<Parser<..>>`). When connection is idle it asks
+//! the `Client` what to do next, and each request is handled by its own
+//! `Requester`, much like `server::Server` handles requests.
+//!
+//! Connections may be reused for subsequent requests to the same
+//! destination by keeping the `Pool` in the context and driving them with
+//! the `Connection` client.
+//!
+//! Host names are resolved by the non-blocking resolver in the `dns`
+//! module, which runs in the same event loop. The `Connect` state machine
+//! resolves the name and races connections to all of the addresses (see
+//! RFC 8305), then the first one established is handed to the wrapped
+//! state machine (e.g. `Stream<Parser<Connection<..>, TcpStream>>`).
+//!
+//! Redirects are followed by wrapping the requester into `Redirects`, and
+//! idempotent requests are retried on broken connections with `Retries`.
+//!
+//! Requests may be sent through the forward HTTP `Proxy` set in the `Pool`:
+//! plain `http` requests are forwarded by it and `https` ones are sent over
+//! the `CONNECT` tunnel opened by the `Tunnel` state machine.
 
 mod context;
+pub mod dns;
 mod pool;
+mod connect;
+mod redirect;
+mod retry;
+mod proxy;
 mod request;
-mod creator;
 mod response;
+mod protocol;
+mod parser;
 
-pub use self::context::{Context, Pool};
-pub use self::request::{Request};
-pub use self::creator::{RequestCreator};
-pub use self::pool::{PipeInit, PoolFsm};
-pub use self::response::{Response};
-
-use rotor::Peer1Socket;
-
-/// Token useed to initialize Pool state machine
-pub struct PoolFsmInit<R: RequestCreator>(Peer1Socket<pool::Pool<R>>);
+pub use self::context::Context;
+pub use self::request::Request;
+pub use self::response::{Head, ResponseError};
+pub use self::protocol::{Client, Requester, Task, RecvMode};
+pub use self::parser::Parser;
+pub use self::pool::{Pool, PoolContext, Connection, Destination, Scheme};
+pub use self::pool::{Action, Wake};
+pub use self::connect::{Connect, Target, ConnectError, Race};
+pub use self::connect::CONNECTION_ATTEMPT_DELAY;
+pub use self::redirect::{Redirects, Follow, Hop, next_hop, request_target};
+pub use self::redirect::{MAX_REDIRECTS, MAX_REDIRECT_BODY};
+pub use self::retry::{Retries, can_retry, MAX_RETRIES, RETRY_BACKOFF};
+pub use self::proxy::{Proxy, Route, Tunnel, Tunneled, ProxyAuthorization};
+pub use self::proxy::{absolute_target, connect_request, tunnel_reply};
+pub use self::proxy::MAX_TUNNEL_REPLY;
@@ -0,0 +1,1319 @@
+use std::cmp::min;
+use std::mem::replace;
+use std::marker::PhantomData;
+
+use rotor::Scope;
+use rotor_stream::{Protocol, StreamSocket, Deadline, Expectation as E};
+use rotor_stream::{Request as Next, Transport, Exception, Buf, MAX_BUF_SIZE};
+use hyper::header::Headers;
+
+use message::MessageState;
+use chunked::{self, State as ChunkState, add_trailer};
+use super::context::Context;
+use super::protocol::{Client, Requester, Task, RecvMode};
+use super::request::{Request, state, is_head, expects_continue};
+use super::request::{body_watermark, forward};
+use super::response::{Head, BodyKind, ResponseError};
+
+
+/// The request which is in progress on the connection
+struct Exchange<R: Requester> {
+    requester: R,
+    request: MessageState,
+    is_head: bool,
+    deadline: Deadline,
+    /// Some bytes of the response (i.e. interim response) are received
+    received: bool,
+    /// Request body is not sent until `100 Continue` is received or until
+    /// this deadline
+    expect_continue: Option<Deadline>,
+    /// Watermark of the progressive request body
+    watermark: Option<usize>,
+    /// Progressive request body is being sent, so the requester is asked
+    /// for more each time the output buffer is flushed down to the
+    /// watermark
+    flushing: bool,
+}
+
+enum BodyProgress {
+    /// Buffered fixed-size response (bytes left)
+    BufferFixed(usize),
+    /// Buffered response till end of input (byte limit)
+    BufferEOF(usize),
+    /// Buffered response with chunked encoding
+    /// (limit, bytes buffered, chunk state)
+    BufferChunked(usize, usize, ChunkState),
+    /// Progressive fixed-size response (size hint, bytes left)
+    ProgressiveFixed(usize, u64),
+    /// Progressive till end of input (size hint)
+    ProgressiveEOF(usize),
+    /// Progressive with chunked encoding
+    /// (hint, offset, chunk state)
+    ProgressiveChunked(usize, usize, ChunkState),
+}
+
+struct ReadBody<R: Requester> {
+    exchange: Exchange<R>,
+    progress: BodyProgress,
+    /// Connection is closed after the response
+    close: bool,
+    /// Trailer fields of chunked response, received so far
+    trailers: Headers,
+}
+
+/// Result of processing the part of the response body
+enum Step<R: Requester> {
+    /// More data is expected
+    More(R, BodyProgress),
+    /// Response is received completely and is passed to the requester
+    Done,
+    /// Requester has given up or response is invalid, connection can't be
+    /// used anymore
+    Abort,
+}
+
+/// HTTP/1.x client protocol
+///
+/// Sends requests produced by the `Client` one by one over the connection.
+pub struct Parser<C: Client, S: StreamSocket>(ParserImpl<C>,
+                                              PhantomData<*const S>);
+
+enum ParserImpl<C: Client> {
+    /// Waiting until connection is established
+    Connecting(C),
+    /// No request in progress, waiting for wakeup (or the deadline)
+    Idle(C, Deadline),
+    /// Waiting for response headers
+    ReadHeaders(C, Exchange<C::Requester>),
+    ReadingBody(C, ReadBody<C::Requester>),
+}
+
+fn start_body(mode: RecvMode, body: BodyKind) -> BodyProgress {
+    use super::response::BodyKind::*;
+    use super::protocol::RecvMode::*;
+    use self::BodyProgress::*;
+
+    match (mode, body) {
+        (Buffered(_), Fixed(y)) => BufferFixed(y as usize),
+        (Buffered(x), Chunked) => BufferChunked(x, 0, ChunkState::Head),
+        (Buffered(x), Eof) => BufferEOF(x),
+        (Progressive(x), Fixed(y)) => ProgressiveFixed(x, y),
+        (Progressive(x), Chunked) => {
+            ProgressiveChunked(x, 0, ChunkState::Head)
+        }
+        (Progressive(x), Eof) => ProgressiveEOF(x),
+    }
+}
+
+/// Returns true if progressive request body is still being sent
+fn is_flushing(watermark: Option<usize>, request: &MessageState) -> bool {
+    use message::MessageState::{FixedSizeBody, ChunkedBody};
+    watermark.is_some() &&
+        matches!(*request, FixedSizeBody { .. } | ChunkedBody { .. })
+}
+
+fn chunk_size(line: &[u8]) -> Result<u64, ResponseError> {
+    // Chunk extensions are validated, but ignored
+    chunked::parse_head(line).map(|head| head.size)
+        .map_err(|_| ResponseError::InvalidBody)
+}
+
+fn fail<R: Requester>(requester: R, err: ResponseError,
+    scope: &mut Scope<R::Context>)
+    -> Step<R>
+{
+    requester.bad_response(&err, scope);
+    Step::Abort
+}
+
+fn call_trailers<R: Requester>(requester: R, trailers: Headers,
+    request: &mut Request, scope: &mut Scope<R::Context>)
+    -> Option<R>
+{
+    if trailers.len() > 0 {
+        requester.response_trailers(trailers, request, scope)
+    } else {
+        Some(requester)
+    }
+}
+
+/// Passes the body bytes received so far to the requester
+fn read_body<R: Requester>(requester: R, progress: BodyProgress,
+    trailers: &mut Headers, inp: &mut Buf, end: usize,
+    request: &mut Request, scope: &mut Scope<R::Context>)
+    -> Step<R>
+{
+    use self::BodyProgress::*;
+    use self::Step::*;
+    use chunked::State::{Head, Data, DataEnd, Trailers};
+    use super::response::ResponseError::{InvalidBody, ResponseTooLarge};
+
+    match progress {
+        BufferFixed(x) => {
+            requester.response_received(&inp[..x], request, scope);
+            inp.consume(x);
+            Done
+        }
+        // The limit is reached before the end of stream
+        BufferEOF(_) => fail(requester, ResponseTooLarge, scope),
+        BufferChunked(limit, off, Head) => {
+            let size = match chunk_size(&inp[off..end]) {
+                Ok(size) => size,
+                Err(e) => return fail(requester, e, scope),
+            };
+            if off as u64 + size > limit as u64 {
+                return fail(requester, ResponseTooLarge, scope);
+            }
+            inp.remove_range(off..end+2);
+            let next = if size == 0 { Trailers } else { Data(size) };
+            More(requester, BufferChunked(limit, off, next))
+        }
+        BufferChunked(limit, off, Data(bytes)) => {
+            More(requester, BufferChunked(limit, off + bytes as usize,
+                                          DataEnd))
+        }
+        BufferChunked(limit, off, DataEnd) => {
+            if chunked::check_data_end(&inp[off..]).is_err() {
+                return fail(requester, InvalidBody, scope);
+            }
+            inp.remove_range(off..off+2);
+            More(requester, BufferChunked(limit, off, Head))
+        }
+        BufferChunked(limit, off, Trailers) => {
+            if end > off {
+                let max = scope.max_headers_num();
+                if add_trailer(trailers, &inp[off..end], max).is_err() {
+                    return fail(requester, InvalidBody, scope);
+                }
+                inp.remove_range(off..end+2);
+                return More(requester, BufferChunked(limit, off, Trailers));
+            }
+            let trailers = replace(trailers, Headers::new());
+            let r = call_trailers(requester, trailers, request, scope);
+            r.map(|r| r.response_received(&inp[..off], request, scope));
+            inp.consume(off+2);
+            Done
+        }
+        ProgressiveFixed(hint, mut left) => {
+            let real_bytes = min(inp.len() as u64, left) as usize;
+            let mut r = Some(requester);
+            if real_bytes > 0 {
+                r = r.and_then(|r| r.response_chunk(&inp[..real_bytes],
+                                                    request, scope));
+            }
+            inp.consume(real_bytes);
+            left -= real_bytes as u64;
+            match r {
+                Some(r) if left == 0 => {
+                    r.response_end(request, scope);
+                    Done
+                }
+                Some(r) => More(r, ProgressiveFixed(hint, left)),
+                None => Abort,
+            }
+        }
+        ProgressiveEOF(hint) => {
+            let ln = inp.len();
+            let r = requester.response_chunk(&inp[..ln], request, scope);
+            inp.consume(ln);
+            match r {
+                Some(r) => More(r, ProgressiveEOF(hint)),
+                None => Abort,
+            }
+        }
+        ProgressiveChunked(hint, off, Head) => {
+            let size = match chunk_size(&inp[off..end]) {
+                Ok(size) => size,
+                Err(e) => return fail(requester, e, scope),
+            };
+            inp.remove_range(off..end+2);
+            let next = if size == 0 { Trailers } else { Data(size) };
+            More(requester, ProgressiveChunked(hint, off, next))
+        }
+        ProgressiveChunked(hint, off, Data(mut left)) => {
+            let ln = min(off as u64 + left, inp.len() as u64) as usize;
+            left -= (ln - off) as u64;
+            let next = if left == 0 { DataEnd } else { Data(left) };
+            if ln < hint {
+                return More(requester, ProgressiveChunked(hint, ln, next));
+            }
+            let r = requester.response_chunk(&inp[..ln], request, scope);
+            inp.consume(ln);
+            match r {
+                Some(r) => More(r, ProgressiveChunked(hint, 0, next)),
+                None => Abort,
+            }
+        }
+        ProgressiveChunked(hint, off, DataEnd) => {
+            if chunked::check_data_end(&inp[off..]).is_err() {
+                return fail(requester, InvalidBody, scope);
+            }
+            inp.remove_range(off..off+2);
+            More(requester, ProgressiveChunked(hint, off, Head))
+        }
+        ProgressiveChunked(hint, off, Trailers) => {
+            if end > off {
+                let max = scope.max_headers_num();
+                if add_trailer(trailers, &inp[off..end], max).is_err() {
+                    return fail(requester, InvalidBody, scope);
+                }
+                inp.remove_range(off..end+2);
+                return More(requester,
+                            ProgressiveChunked(hint, off, Trailers));
+            }
+            let mut r = Some(requester);
+            if off > 0 {
+                r = r.and_then(
+                    |r| r.response_chunk(&inp[..off], request, scope));
+            }
+            inp.consume(off+2);
+            let trailers = replace(trailers, Headers::new());
+            let r = r.and_then(
+                |r| call_trailers(r, trailers, request, scope));
+            match r {
+                Some(r) => {
+                    r.response_end(request, scope);
+                    Done
+                }
+                None => Abort,
+            }
+        }
+    }
+}
+
+/// Closes the connection
+fn closed<C, S>(client: C, scope: &mut Scope<C::Context>)
+    -> Next<Parser<C, S>>
+    where C: Client, S: StreamSocket
+{
+    client.connection_closed(scope);
+    None
+}
+
+/// Executes the task returned by the client for the idle connection
+fn idle<C, S>(task: Task<C>, transport: &mut Transport<S>,
+    scope: &mut Scope<C::Context>)
+    -> Next<Parser<C, S>>
+    where C: Client, S: StreamSocket
+{
+    match task {
+        Task::Request(client, requester) => {
+            start_request(client, requester, transport, scope)
+        }
+        Task::Sleep(client, deadline) => {
+            ParserImpl::Idle(client, deadline).request(scope)
+        }
+        Task::Close => None,
+    }
+}
+
+fn start_request<C, S>(client: C, requester: C::Requester,
+    transport: &mut Transport<S>, scope: &mut Scope<C::Context>)
+    -> Next<Parser<C, S>>
+    where C: Client, S: StreamSocket
+{
+    let (requester, started, head, expect, watermark, request) = {
+        let mut req = Request::new(transport.output());
+        forward(&mut req, client.forward_proxy().cloned());
+        let r = requester.prepare_request(&mut req, scope);
+        let started = req.is_started();
+        let head = is_head(&req);
+        let expect = expects_continue(&req);
+        let watermark = body_watermark(&req);
+        (r, started, head, expect, watermark, state(req))
+    };
+    match requester {
+        Some(r) => {
+            let cont = if expect {
+                Some(Deadline::now() + scope.continue_timeout())
+            } else {
+                None
+            };
+            ParserImpl::ReadHeaders(client, Exchange {
+                requester: r,
+                is_head: head,
+                deadline: Deadline::now() + scope.byte_timeout(),
+                received: false,
+                expect_continue: cont,
+                watermark: watermark,
+                flushing: is_flushing(watermark, &request),
+                request: request,
+            }).request(scope)
+        }
+        // Partially written request can't be cancelled
+        None if started => closed(client, scope),
+        None => idle(client.connection_idle(scope), transport, scope),
+    }
+}
+
+/// Server is ready to receive the request body (or continue timeout is
+/// expired)
+fn send_body<C, S>(client: C, mut exchange: Exchange<C::Requester>,
+    transport: &mut Transport<S>, scope: &mut Scope<C::Context>)
+    -> Next<Parser<C, S>>
+    where C: Client, S: StreamSocket
+{
+    exchange.expect_continue = None;
+    let dline = exchange.deadline;
+    let res = exchange.call(transport.output(),
+        |r, req| r.continue_request(req, scope).map(|r| (r, dline)));
+    match res {
+        Some(ex) => ParserImpl::ReadHeaders(client, ex).request(scope),
+        None => closed(client, scope),
+    }
+}
+
+/// Response is received, connection is reused if that's possible
+fn finish<C, S>(client: C, request: MessageState, close: bool,
+    transport: &mut Transport<S>, scope: &mut Scope<C::Context>)
+    -> Next<Parser<C, S>>
+    where C: Client, S: StreamSocket
+{
+    // The rest of the request can't be sent after the response, so
+    // connection is closed if request is incomplete
+    if close || !matches!(request, MessageState::Done { close: false }) {
+        return closed(client, scope);
+    }
+    idle(client.connection_idle(scope), transport, scope)
+}
+
+fn parse_headers<C, S>(client: C, mut exchange: Exchange<C::Requester>,
+    transport: &mut Transport<S>, end: usize,
+    scope: &mut Scope<C::Context>)
+    -> Next<Parser<C, S>>
+    where C: Client, S: StreamSocket
+{
+    use super::response::ResponseError::ResponseTooLarge;
+
+    let parsed = Head::parse(&transport.input()[..end+4],
+                             scope.max_headers_num());
+    transport.input().consume(end+4);
+    let head = match parsed {
+        Ok(head) => head,
+        Err(e) => {
+            exchange.requester.bad_response(&e, scope);
+            return closed(client, scope);
+        }
+    };
+    if head.is_interim() {
+        exchange.received = true;
+        if head.status.to_u16() == 100 && exchange.expect_continue.is_some()
+        {
+            return send_body(client, exchange, transport, scope);
+        }
+        return ParserImpl::ReadHeaders(client, exchange).request(scope);
+    }
+    let body = match head.body_kind(exchange.is_head) {
+        Ok(body) => body,
+        Err(e) => {
+            exchange.requester.bad_response(&e, scope);
+            return closed(client, scope);
+        }
+    };
+    // Switching protocols is not supported, so the connection is useless
+    // after `101` response
+    let close = head.wants_close() || body == BodyKind::Eof ||
+                head.status.to_u16() == 101;
+    let Exchange { requester, request, is_head, watermark, .. } = exchange;
+    let (res, request) = {
+        let mut req = request.with(transport.output());
+        let res = requester.headers_received(head, &mut req, scope);
+        (res, state(req))
+    };
+    let (r, mode, dline) = match res {
+        Some((_, RecvMode::Buffered(x), _)) if x >= MAX_BUF_SIZE
+        => panic!("Can't buffer {} bytes, max {}", x, MAX_BUF_SIZE),
+        Some((r, RecvMode::Buffered(x), _))
+        if matches!(body, BodyKind::Fixed(y) if y >= x as u64)
+        => {
+            r.bad_response(&ResponseTooLarge, scope);
+            return closed(client, scope);
+        }
+        Some(tuple) => tuple,
+        None => return closed(client, scope),
+    };
+    let rb = ReadBody {
+        exchange: Exchange {
+            requester: r,
+            is_head: is_head,
+            deadline: dline,
+            received: true,
+            expect_continue: None,
+            watermark: watermark,
+            flushing: is_flushing(watermark, &request),
+            request: request,
+        },
+        progress: start_body(mode, body),
+        close: close,
+        trailers: Headers::new(),
+    };
+    if body == BodyKind::Fixed(0) {
+        // No need to wait for anything
+        read_response(client, rb, transport, 0, scope)
+    } else {
+        ParserImpl::ReadingBody(client, rb).request(scope)
+    }
+}
+
+fn read_response<C, S>(client: C, mut rb: ReadBody<C::Requester>,
+    transport: &mut Transport<S>, end: usize,
+    scope: &mut Scope<C::Context>)
+    -> Next<Parser<C, S>>
+    where C: Client, S: StreamSocket
+{
+    let (step, request) = {
+        let (inp, out) = transport.buffers();
+        let mut req = rb.exchange.request.with(out);
+        let step = read_body(rb.exchange.requester, rb.progress,
+                             &mut rb.trailers, inp, end, &mut req, scope);
+        (step, state(req))
+    };
+    match step {
+        Step::More(r, progress) => {
+            ParserImpl::ReadingBody(client, ReadBody {
+                exchange: Exchange {
+                    requester: r,
+                    is_head: rb.exchange.is_head,
+                    deadline: rb.exchange.deadline,
+                    received: true,
+                    expect_continue: None,
+                    watermark: rb.exchange.watermark,
+                    flushing: is_flushing(rb.exchange.watermark, &request),
+                    request: request,
+                },
+                progress: progress,
+                close: rb.close,
+                trailers: rb.trailers,
+            }).request(scope)
+        }
+        Step::Done => finish(client, request, rb.close, transport, scope),
+        Step::Abort => closed(client, scope),
+    }
+}
+
+/// Delivers the rest of the response, which is delimited by the end of
+/// stream
+fn read_till_eof<R: Requester>(rb: ReadBody<R>, inp: &mut Buf,
+    request: &mut Request, scope: &mut Scope<R::Context>)
+{
+    use self::BodyProgress::*;
+    match rb.progress {
+        BufferEOF(_) => {
+            rb.exchange.requester.response_received(&inp[..], request, scope);
+        }
+        ProgressiveEOF(_) => {
+            let mut r = Some(rb.exchange.requester);
+            if inp.len() > 0 {
+                r = r.and_then(
+                    |r| r.response_chunk(&inp[..], request, scope));
+            }
+            r.map(|r| r.response_end(request, scope));
+        }
+        _ => unreachable!(),
+    }
+    let ln = inp.len();
+    inp.consume(ln);
+}
+
+/// Output buffer is flushed down to the watermark, so the requester may
+/// write the next chunk of the progressive request body
+fn body_flushed<R: Requester>(ex: Exchange<R>, out: &mut Buf,
+    scope: &mut Scope<R::Context>)
+    -> Option<Exchange<R>>
+{
+    let dline = ex.deadline;
+    let before = out.len();
+    let res = ex.call(out,
+        |r, req| r.body_flushed(req, scope).map(|r| (r, dline)));
+    res.map(|mut ex| {
+        if out.len() == before {
+            // Nothing is written, so wait for the data written in wakeup
+            ex.flushing = false;
+        }
+        ex
+    })
+}
+
+impl<R: Requester> Exchange<R> {
+    /// Calls the requester with the request object
+    ///
+    /// Returns `None` if requester has given up
+    fn call<F>(self, out: &mut Buf, f: F) -> Option<Exchange<R>>
+        where F: FnOnce(R, &mut Request) -> Option<(R, Deadline)>
+    {
+        let Exchange { requester, request, is_head, received,
+                       expect_continue, watermark, .. } = self;
+        let (res, request) = {
+            let mut req = request.with(out);
+            let res = f(requester, &mut req);
+            (res, state(req))
+        };
+        let flushing = is_flushing(watermark, &request);
+        res.map(|(r, dline)| Exchange {
+            requester: r,
+            request: request,
+            is_head: is_head,
+            deadline: dline,
+            received: received,
+            expect_continue: expect_continue,
+            watermark: watermark,
+            flushing: flushing,
+        })
+    }
+}
+
+fn response_error(exc: Exception, reading_body: bool) -> ResponseError {
+    use rotor_stream::Exception::*;
+    match exc {
+        // Chunk size line or trailer field is too long
+        LimitReached if reading_body => ResponseError::InvalidBody,
+        LimitReached => ResponseError::HeadersTooLarge,
+        EndOfStream => ResponseError::ConnectionClosed,
+        ReadError(e) | WriteError(e) => ResponseError::Io(e),
+    }
+}
+
+/// Error when connection is broken before any response bytes are received
+fn no_response(exc: Exception) -> ResponseError {
+    use rotor_stream::Exception::*;
+    match exc {
+        LimitReached => ResponseError::HeadersTooLarge,
+        EndOfStream => ResponseError::NoResponse(None),
+        ReadError(e) | WriteError(e) => ResponseError::NoResponse(Some(e)),
+    }
+}
+
+impl<C: Client> ParserImpl<C> {
+    fn request<S: StreamSocket>(self, scope: &mut Scope<C::Context>)
+        -> Next<Parser<C, S>>
+    {
+        use self::ParserImpl::*;
+        use self::BodyProgress::*;
+        let (exp, dline) = match self {
+            Connecting(_) => {
+                (E::Flush(0), Deadline::now() + scope.byte_timeout())
+            }
+            // Nothing is expected from the server, but we want to know if
+            // it closes the connection
+            Idle(_, dline) => (E::Bytes(1), dline),
+            ReadHeaders(_, ref ex) | ReadingBody(_, ReadBody {
+                exchange: ref ex, .. })
+            if ex.flushing && ex.expect_continue.is_none()
+            => {
+                // Response is not read until request body is flushed
+                (E::Flush(ex.watermark.unwrap()), ex.deadline)
+            }
+            ReadHeaders(_, ref ex) => {
+                let dline = match ex.expect_continue {
+                    Some(cont) if cont < ex.deadline => cont,
+                    _ => ex.deadline,
+                };
+                (E::Delimiter(0, b"\r\n\r\n", scope.max_headers_size()),
+                 dline)
+            }
+            ReadingBody(_, ref rb) => {
+                let exp = match *&rb.progress {
+                    BufferFixed(x) => E::Bytes(x),
+                    // One byte more to find out that limit is exceeded
+                    BufferEOF(x) => E::Bytes(x + 1),
+                    BufferChunked(_, off, ChunkState::Head)
+                    | ProgressiveChunked(_, off, ChunkState::Head)
+                    => E::Delimiter(off, b"\r\n",
+                                    off + scope.max_chunk_head()),
+                    BufferChunked(_, off, ChunkState::Data(y))
+                    => E::Bytes(off + y as usize),
+                    BufferChunked(_, off, ChunkState::DataEnd)
+                    | ProgressiveChunked(_, off, ChunkState::DataEnd)
+                    => E::Bytes(off + 2),
+                    BufferChunked(_, off, ChunkState::Trailers)
+                    | ProgressiveChunked(_, off, ChunkState::Trailers)
+                    => E::Delimiter(off, b"\r\n",
+                                    off + scope.max_headers_size()),
+                    ProgressiveFixed(hint, left)
+                    => E::Bytes(min(hint as u64, left) as usize),
+                    ProgressiveEOF(hint) => E::Bytes(hint),
+                    ProgressiveChunked(hint, off, ChunkState::Data(left))
+                    => E::Bytes(min(hint as u64, off as u64 + left) as usize)
+                };
+                (exp, rb.exchange.deadline)
+            }
+        };
+        Some((Parser(self, PhantomData), exp, dline))
+    }
+    fn bytes_read<S: StreamSocket>(self, transport: &mut Transport<S>,
+        end: usize, scope: &mut Scope<C::Context>)
+        -> Next<Parser<C, S>>
+    {
+        use self::ParserImpl::*;
+        match self {
+            // Server must not send anything before the request
+            Connecting(client) | Idle(client, _) => closed(client, scope),
+            ReadHeaders(client, ex) => {
+                parse_headers(client, ex, transport, end, scope)
+            }
+            ReadingBody(client, rb) => {
+                read_response(client, rb, transport, end, scope)
+            }
+        }
+    }
+    fn bytes_flushed<S: StreamSocket>(self, transport: &mut Transport<S>,
+        scope: &mut Scope<C::Context>)
+        -> Next<Parser<C, S>>
+    {
+        use self::ParserImpl::*;
+        match self {
+            Connecting(client) => {
+                idle(client.connection_idle(scope), transport, scope)
+            }
+            ReadHeaders(client, ex) => {
+                if !ex.flushing || ex.expect_continue.is_some() {
+                    return ReadHeaders(client, ex).request(scope);
+                }
+                match body_flushed(ex, transport.output(), scope) {
+                    Some(ex) => ReadHeaders(client, ex).request(scope),
+                    None => closed(client, scope),
+                }
+            }
+            ReadingBody(client, mut rb) => {
+                if !rb.exchange.flushing {
+                    return ReadingBody(client, rb).request(scope);
+                }
+                match body_flushed(rb.exchange, transport.output(), scope) {
+                    Some(ex) => {
+                        rb.exchange = ex;
+                        ReadingBody(client, rb).request(scope)
+                    }
+                    None => closed(client, scope),
+                }
+            }
+            me => me.request(scope),
+        }
+    }
+    fn exception<S: StreamSocket>(self, transport: &mut Transport<S>,
+        exc: Exception, scope: &mut Scope<C::Context>)
+        -> Next<Parser<C, S>>
+    {
+        use self::ParserImpl::*;
+        use self::BodyProgress::*;
+        match self {
+            Connecting(client) | Idle(client, _) => closed(client, scope),
+            ReadHeaders(client, ex) => {
+                let err = if !ex.received && transport.input().len() == 0 {
+                    no_response(exc)
+                } else {
+                    response_error(exc, false)
+                };
+                ex.requester.bad_response(&err, scope);
+                closed(client, scope)
+            }
+            ReadingBody(client, mut rb) => {
+                let eof = matches!(exc, Exception::EndOfStream) &&
+                    matches!(rb.progress, BufferEOF(_) | ProgressiveEOF(_));
+                if eof {
+                    // Response is complete, but connection is closed anyway
+                    let (inp, out) = transport.buffers();
+                    let request = replace(&mut rb.exchange.request,
+                        MessageState::Done { close: true });
+                    let mut req = request.with(out);
+                    read_till_eof(rb, inp, &mut req, scope);
+                } else {
+                    rb.exchange.requester.bad_response(
+                        &response_error(exc, true), scope);
+                }
+                closed(client, scope)
+            }
+        }
+    }
+    fn timeout<S: StreamSocket>(self, transport: &mut Transport<S>,
+        scope: &mut Scope<C::Context>)
+        -> Next<Parser<C, S>>
+    {
+        use self::ParserImpl::*;
+        match self {
+            // Can't connect in time
+            Connecting(client) => closed(client, scope),
+            Idle(client, _) => idle(client.timeout(scope), transport, scope),
+            ReadHeaders(client, ex) => {
+                if let Some(cont) = ex.expect_continue {
+                    if cont <= Deadline::now() {
+                        // No reply from the server, so send the body anyway
+                        return send_body(client, ex, transport, scope);
+                    }
+                }
+                let res = ex.call(transport.output(),
+                    |r, req| r.timeout(req, scope));
+                match res {
+                    Some(ex) => ReadHeaders(client, ex).request(scope),
+                    None => closed(client, scope),
+                }
+            }
+            ReadingBody(client, mut rb) => {
+                let res = rb.exchange.call(transport.output(),
+                    |r, req| r.timeout(req, scope));
+                match res {
+                    Some(ex) => {
+                        rb.exchange = ex;
+                        ReadingBody(client, rb).request(scope)
+                    }
+                    None => closed(client, scope),
+                }
+            }
+        }
+    }
+    fn wakeup<S: StreamSocket>(self, transport: &mut Transport<S>,
+        scope: &mut Scope<C::Context>)
+        -> Next<Parser<C, S>>
+    {
+        use self::ParserImpl::*;
+        match self {
+            me @ Connecting(_) => me.request(scope),
+            Idle(client, _) => idle(client.wakeup(scope), transport, scope),
+            ReadHeaders(client, ex) => {
+                let dline = ex.deadline;
+                let res = ex.call(transport.output(),
+                    |r, req| r.wakeup(req, scope).map(|r| (r, dline)));
+                match res {
+                    Some(ex) => ReadHeaders(client, ex).request(scope),
+                    None => closed(client, scope),
+                }
+            }
+            ReadingBody(client, mut rb) => {
+                let dline = rb.exchange.deadline;
+                let res = rb.exchange.call(transport.output(),
+                    |r, req| r.wakeup(req, scope).map(|r| (r, dline)));
+                match res {
+                    Some(ex) => {
+                        rb.exchange = ex;
+                        ReadingBody(client, rb).request(scope)
+                    }
+                    None => closed(client, scope),
+                }
+            }
+        }
+    }
+}
+
+impl<C: Client, S: StreamSocket> Protocol for Parser<C, S> {
+    type Context = C::Context;
+    type Socket = S;
+    type Seed = C::Seed;
+    fn create(seed: C::Seed, _sock: &mut S, scope: &mut Scope<C::Context>)
+        -> Next<Self>
+    {
+        ParserImpl::Connecting(C::create(seed, scope)).request(scope)
+    }
+    fn bytes_read(self, transport: &mut Transport<S>, end: usize,
+        scope: &mut Scope<C::Context>)
+        -> Next<Self>
+    {
+        self.0.bytes_read(transport, end, scope)
+    }
+    fn bytes_flushed(self, transport: &mut Transport<S>,
+        scope: &mut Scope<C::Context>)
+        -> Next<Self>
+    {
+        self.0.bytes_flushed(transport, scope)
+    }
+    fn exception(self, transport: &mut Transport<S>, exc: Exception,
+        scope: &mut Scope<C::Context>)
+        -> Next<Self>
+    {
+        self.0.exception(transport, exc, scope)
+    }
+    fn timeout(self, transport: &mut Transport<S>,
+        scope: &mut Scope<C::Context>)
+        -> Next<Self>
+    {
+        self.0.timeout(transport, scope)
+    }
+    fn wakeup(self, transport: &mut Transport<S>,
+        scope: &mut Scope<C::Context>)
+        -> Next<Self>
+    {
+        self.0.wakeup(transport, scope)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::net::TcpListener as StdListener;
+
+    use rotor::{self, Scope};
+    use rotor::mio::tcp::TcpStream;
+    use rotor_stream::{Stream, Deadline, MAX_BUF_SIZE};
+    use hyper::method::Method;
+    use hyper::version::HttpVersion as Version;
+    use hyper::header::{Headers, ContentLength, Expect};
+    use hyper::header::{TransferEncoding, Encoding};
+    use time::Duration;
+
+    use client::{self, Client, Requester, Task, RecvMode, Request, Head};
+    use client::ResponseError;
+    use test_util::{read_some, find};
+    use super::Parser;
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    struct Context {
+        log: Log,
+        continue_timeout: Duration,
+    }
+
+    impl client::Context for Context {
+        fn continue_timeout(&self) -> Duration {
+            self.continue_timeout
+        }
+    }
+
+    /// Chunk of the progressive request body
+    const CHUNK: &'static [u8] = &[b'x'; 1000];
+    /// Number of chunks in the progressive request body
+    const CHUNKS: usize = 10;
+
+    /// Framing of the progressive request body
+    #[derive(Clone, Copy)]
+    enum Upload {
+        Fixed,
+        Chunked,
+    }
+
+    #[derive(Clone)]
+    struct Plan {
+        method: Method,
+        mode: RecvMode,
+        /// Request body which is sent after `Expect: 100-continue`
+        expect: Option<&'static [u8]>,
+        /// Progressive request body, which is sent chunk by chunk
+        upload: Option<Upload>,
+    }
+
+    fn get(mode: RecvMode) -> Plan {
+        Plan { method: Method::Get, mode: mode, expect: None, upload: None }
+    }
+
+    /// Sends planned requests one by one, then sleeps
+    struct Queue(Vec<Plan>);
+
+    /// Logs everything received, progressive body is logged at the end
+    ///
+    /// The last field is the number of request body chunks sent.
+    struct Fetch(Plan, Vec<u8>, usize);
+
+    impl Client for Queue {
+        type Context = Context;
+        type Requester = Fetch;
+        type Seed = Vec<Plan>;
+        fn create(seed: Vec<Plan>, _scope: &mut Scope<Context>) -> Queue {
+            Queue(seed)
+        }
+        fn connection_idle(mut self, scope: &mut Scope<Context>)
+            -> Task<Queue>
+        {
+            if self.0.len() == 0 {
+                scope.log.borrow_mut().push("idle".to_string());
+                let dline = Deadline::now() + Duration::seconds(10);
+                return Task::Sleep(self, dline);
+            }
+            let plan = self.0.remove(0);
+            Task::Request(self, Fetch(plan, Vec::new(), 0))
+        }
+        fn timeout(self, _scope: &mut Scope<Context>) -> Task<Queue> {
+            unreachable!();
+        }
+        fn wakeup(self, _scope: &mut Scope<Context>) -> Task<Queue> {
+            unreachable!();
+        }
+        fn connection_closed(self, scope: &mut Scope<Context>) {
+            scope.log.borrow_mut().push("closed".to_string());
+        }
+    }
+
+    impl Requester for Fetch {
+        type Context = Context;
+        fn prepare_request(self, req: &mut Request,
+            _scope: &mut Scope<Context>)
+            -> Option<Fetch>
+        {
+            req.start(self.0.method.clone(), "/", Version::Http11);
+            if let Some(body) = self.0.expect {
+                req.add_header(ContentLength(body.len() as u64)).unwrap();
+                req.add_header(Expect::Continue).unwrap();
+                req.done_headers().unwrap();
+            } else if let Some(upload) = self.0.upload {
+                match upload {
+                    Upload::Fixed => {
+                        let len = CHUNK.len() * CHUNKS;
+                        req.add_header(ContentLength(len as u64)).unwrap();
+                    }
+                    Upload::Chunked => {
+                        req.add_header(TransferEncoding(
+                            vec![Encoding::Chunked])).unwrap();
+                    }
+                }
+                // The first chunk is written when headers are flushed
+                req.progressive_body(100);
+                req.done_headers().unwrap();
+            } else {
+                req.done_headers().unwrap();
+                req.done();
+            }
+            Some(self)
+        }
+        fn body_flushed(mut self, req: &mut Request,
+            scope: &mut Scope<Context>)
+            -> Option<Fetch>
+        {
+            req.write_body(CHUNK);
+            self.2 += 1;
+            if self.2 == CHUNKS {
+                req.done();
+                scope.log.borrow_mut().push("uploaded".to_string());
+            }
+            Some(self)
+        }
+        fn continue_request(self, req: &mut Request,
+            scope: &mut Scope<Context>)
+            -> Option<Fetch>
+        {
+            scope.log.borrow_mut().push("continue".to_string());
+            req.write_body(self.0.expect.unwrap());
+            req.done();
+            Some(self)
+        }
+        fn headers_received(self, head: Head, _req: &mut Request,
+            scope: &mut Scope<Context>)
+            -> Option<(Fetch, RecvMode, Deadline)>
+        {
+            let mut log = scope.log.borrow_mut();
+            log.push(format!("headers {} {}",
+                             head.status.to_u16(), head.reason));
+            if let Some(val) = head.headers.get_raw("X-Test") {
+                log.push(format!("x-test {}",
+                                 String::from_utf8_lossy(&val[0])));
+            }
+            let mode = self.0.mode;
+            Some((self, mode, Deadline::now() + Duration::seconds(10)))
+        }
+        fn response_received(self, data: &[u8], _req: &mut Request,
+            scope: &mut Scope<Context>)
+        {
+            scope.log.borrow_mut().push(format!("received {}",
+                String::from_utf8_lossy(data)));
+        }
+        fn response_chunk(mut self, chunk: &[u8], _req: &mut Request,
+            _scope: &mut Scope<Context>)
+            -> Option<Fetch>
+        {
+            self.1.extend(chunk.iter().cloned());
+            Some(self)
+        }
+        fn response_end(self, _req: &mut Request,
+            scope: &mut Scope<Context>)
+        {
+            scope.log.borrow_mut().push(format!("end {}",
+                String::from_utf8_lossy(&self.1)));
+        }
+        fn response_trailers(self, trailers: Headers, _req: &mut Request,
+            scope: &mut Scope<Context>)
+            -> Option<Fetch>
+        {
+            let val = trailers.get_raw("X-Sum").unwrap();
+            scope.log.borrow_mut().push(format!("trailer {}",
+                String::from_utf8_lossy(&val[0])));
+            Some(self)
+        }
+        fn bad_response(self, err: &ResponseError,
+            scope: &mut Scope<Context>)
+        {
+            scope.log.borrow_mut().push(format!("error {:?}", err));
+        }
+        fn timeout(self, _req: &mut Request, scope: &mut Scope<Context>)
+            -> Option<(Fetch, Deadline)>
+        {
+            scope.log.borrow_mut().push("timeout".to_string());
+            None
+        }
+        fn wakeup(self, _req: &mut Request, _scope: &mut Scope<Context>)
+            -> Option<Fetch>
+        {
+            unreachable!();
+        }
+    }
+
+    /// Connects the client to the stand-in server, which is the `peer`
+    macro_rules! connect {
+        ($event_loop:ident, $handler:ident, $peer:ident, $log:ident,
+         $continue_ms:expr, $plans:expr) => {
+            let $log = Rc::new(RefCell::new(Vec::<String>::new()));
+            let mut $event_loop = rotor::EventLoop::new().unwrap();
+            let mut $handler = rotor::Handler::new(Context {
+                log: $log.clone(),
+                continue_timeout: Duration::milliseconds($continue_ms),
+            }, &mut $event_loop);
+            let lst = StdListener::bind("127.0.0.1:0").unwrap();
+            let sock = TcpStream::connect(&lst.local_addr().unwrap())
+                .unwrap();
+            let plans = $plans;
+            assert!($handler.add_machine_with(&mut $event_loop, |scope| {
+                Stream::<Parser<Queue, _>>::new(sock, plans, scope)
+            }).is_ok());
+            let (mut $peer, _) = lst.accept().unwrap();
+        }
+    }
+
+    /// Waits for the request head and replies with the `response`
+    ///
+    /// Returns everything received for the request so far
+    macro_rules! exchange {
+        ($event_loop:ident, $handler:ident, $peer:ident, $response:expr)
+        => {{
+            let mut data = Vec::new();
+            run_until!($event_loop, $handler, {
+                read_some(&mut $peer, &mut data);
+                find(&data, b"\r\n\r\n").is_some()
+            });
+            $peer.write_all($response).unwrap();
+            data
+        }}
+    }
+
+    fn check(log: &Log, expected: &[&str]) {
+        let log = log.borrow();
+        let real = log.iter().map(|x| &x[..]).collect::<Vec<_>>();
+        assert_eq!(&real[..], expected);
+    }
+
+    #[test]
+    fn buffered() {
+        connect!(event_loop, handler, peer, log, 1000,
+                 vec![get(RecvMode::Buffered(1024))]);
+        let request = exchange!(event_loop, handler, peer,
+            b"HTTP/1.1 200 OK\r\nX-Test: yes\r\n\
+              Content-Length: 5\r\n\r\nhello");
+        assert_eq!(&request[..], b"GET / HTTP/1.1\r\n\r\n");
+        run_until!(event_loop, handler, log.borrow().len() == 4);
+        check(&log, &["headers 200 OK", "x-test yes", "received hello",
+                      "idle"]);
+    }
+
+    #[test]
+    fn progressive() {
+        connect!(event_loop, handler, peer, log, 1000,
+                 vec![get(RecvMode::Progressive(1))]);
+        exchange!(event_loop, handler, peer,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello");
+        run_until!(event_loop, handler, log.borrow().len() == 1);
+        for _ in 0..10 {
+            event_loop.run_once(&mut handler, Some(10)).unwrap();
+        }
+        peer.write_all(b" world").unwrap();
+        run_until!(event_loop, handler, log.borrow().len() == 3);
+        check(&log, &["headers 200 OK", "end hello world", "idle"]);
+    }
+
+    #[test]
+    fn chunked_with_trailers() {
+        const RESPONSE: &'static [u8] = b"HTTP/1.1 200 OK\r\n\
+            Transfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nX-Sum: 11\r\n\r\n";
+        connect!(event_loop, handler, peer, log, 1000,
+                 vec![get(RecvMode::Buffered(1024)),
+                      get(RecvMode::Progressive(4))]);
+        exchange!(event_loop, handler, peer, RESPONSE);
+        exchange!(event_loop, handler, peer, RESPONSE);
+        run_until!(event_loop, handler, log.borrow().len() == 7);
+        check(&log, &["headers 200 OK", "trailer 11", "received hello world",
+                      "headers 200 OK", "trailer 11", "end hello world",
+                      "idle"]);
+    }
+
+    #[test]
+    fn keep_alive() {
+        connect!(event_loop, handler, peer, log, 1000,
+                 vec![get(RecvMode::Buffered(1024)),
+                      get(RecvMode::Buffered(1024))]);
+        exchange!(event_loop, handler, peer,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na");
+        // Second request is sent over the same connection
+        exchange!(event_loop, handler, peer,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb");
+        run_until!(event_loop, handler, log.borrow().len() == 5);
+        check(&log, &["headers 200 OK", "received a",
+                      "headers 200 OK", "received b", "idle"]);
+    }
+
+    #[test]
+    fn close() {
+        connect!(event_loop, handler, peer, log, 1000,
+                 vec![get(RecvMode::Buffered(1024)),
+                      get(RecvMode::Buffered(1024))]);
+        exchange!(event_loop, handler, peer,
+            b"HTTP/1.1 200 OK\r\nConnection: close\r\n\
+              Content-Length: 1\r\n\r\na");
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, !read_some(&mut peer, &mut data));
+        // Second request is never sent
+        assert_eq!(data.len(), 0);
+        check(&log, &["headers 200 OK", "received a", "closed"]);
+    }
+
+    #[test]
+    fn no_body() {
+        connect!(event_loop, handler, peer, log, 1000, vec![
+            Plan { method: Method::Head, ..get(RecvMode::Buffered(1024)) },
+            get(RecvMode::Buffered(1024)),
+            get(RecvMode::Progressive(1)),
+        ]);
+        let request = exchange!(event_loop, handler, peer,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n");
+        assert_eq!(&request[..], b"HEAD / HTTP/1.1\r\n\r\n");
+        exchange!(event_loop, handler, peer,
+            b"HTTP/1.1 204 No Content\r\n\r\n");
+        exchange!(event_loop, handler, peer,
+            b"HTTP/1.1 304 Not Modified\r\nContent-Length: 5\r\n\r\n");
+        run_until!(event_loop, handler, log.borrow().len() == 7);
+        check(&log, &["headers 200 OK", "received ",
+                      "headers 204 No Content", "received ",
+                      "headers 304 Not Modified", "end ", "idle"]);
+    }
+
+    #[test]
+    fn eof_body() {
+        for &(mode, result) in &[(RecvMode::Buffered(1024), "received"),
+                                 (RecvMode::Progressive(1), "end")]
+        {
+            connect!(event_loop, handler, peer, log, 1000, vec![get(mode)]);
+            exchange!(event_loop, handler, peer,
+                b"HTTP/1.1 200 OK\r\n\r\nhello");
+            drop(peer);
+            run_until!(event_loop, handler, log.borrow().len() == 3);
+            let body = format!("{} hello", result);
+            check(&log, &["headers 200 OK", &body[..], "closed"]);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected="Can't buffer")]
+    fn buffer_too_large() {
+        connect!(event_loop, handler, peer, log, 1000,
+                 vec![get(RecvMode::Buffered(MAX_BUF_SIZE))]);
+        exchange!(event_loop, handler, peer,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+        run_until!(event_loop, handler, log.borrow().len() == 2);
+    }
+
+    fn post() -> Plan {
+        Plan { method: Method::Post, mode: RecvMode::Buffered(1024),
+               expect: Some(b"body"), upload: None }
+    }
+
+    #[test]
+    fn continue_received() {
+        connect!(event_loop, handler, peer, log, 1000, vec![post()]);
+        let mut data = exchange!(event_loop, handler, peer,
+            b"HTTP/1.1 100 Continue\r\n\r\n");
+        assert!(find(&data, b"Expect: 100-continue\r\n").is_some());
+        assert_eq!(find(&data, b"\r\n\r\n"), Some(data.len()));
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            find(&data, b"\r\n\r\nbody").is_some()
+        });
+        peer.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        run_until!(event_loop, handler, log.borrow().len() == 4);
+        check(&log, &["continue", "headers 200 OK", "received ", "idle"]);
+    }
+
+    #[test]
+    fn continue_timeout() {
+        connect!(event_loop, handler, peer, log, 50, vec![post()]);
+        // Server doesn't reply, so the body is sent after the timeout
+        let mut data = exchange!(event_loop, handler, peer, b"");
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            find(&data, b"\r\n\r\nbody").is_some()
+        });
+        check(&log, &["continue"]);
+        peer.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        run_until!(event_loop, handler, log.borrow().len() == 4);
+        check(&log, &["continue", "headers 200 OK", "received ", "idle"]);
+    }
+
+    #[test]
+    fn final_status_before_continue() {
+        connect!(event_loop, handler, peer, log, 1000, vec![post()]);
+        let mut data = exchange!(event_loop, handler, peer,
+            b"HTTP/1.1 417 Expectation Failed\r\n\
+              Content-Length: 0\r\n\r\n");
+        run_until!(event_loop, handler, !read_some(&mut peer, &mut data));
+        // Body is never sent, so connection can't be reused
+        assert_eq!(find(&data, b"\r\n\r\n"), Some(data.len()));
+        check(&log, &["headers 417 Expectation Failed", "received ",
+                      "closed"]);
+    }
+
+    #[test]
+    fn no_response() {
+        for &(reply, error) in &[
+            (&b""[..], "error NoResponse(None)"),
+            // Interim response is a sign that request is being processed
+            (&b"HTTP/1.1 100 Continue\r\n\r\n"[..],
+             "error ConnectionClosed"),
+            (&b"HTTP/1.1 200 OK\r\n"[..], "error ConnectionClosed"),
+        ] {
+            connect!(event_loop, handler, peer, log, 1000,
+                     vec![get(RecvMode::Buffered(1024))]);
+            exchange!(event_loop, handler, peer, reply);
+            drop(peer);
+            run_until!(event_loop, handler, log.borrow().len() == 2);
+            check(&log, &[error, "closed"]);
+        }
+    }
+    /// Receives progressive request body, until `end` is found
+    fn upload(framing: Upload, end: &[u8]) -> Vec<u8> {
+        connect!(event_loop, handler, peer, log, 1000, vec![
+            Plan { method: Method::Post, upload: Some(framing),
+                   ..get(RecvMode::Buffered(1024)) },
+        ]);
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            find(&data, end).is_some()
+        });
+        check(&log, &["uploaded"]);
+        peer.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        run_until!(event_loop, handler, log.borrow().len() == 4);
+        check(&log, &["uploaded", "headers 200 OK", "received ", "idle"]);
+        data
+    }
+
+    #[test]
+    fn progressive_upload() {
+        let mut body = Vec::new();
+        for _ in 0..CHUNKS {
+            body.extend(CHUNK.iter().cloned());
+        }
+        let data = upload(Upload::Fixed, &body);
+        let head = find(&data, b"\r\n\r\n").unwrap();
+        assert!(find(&data[..head], b"Content-Length: 10000\r\n")
+                .is_some());
+        assert_eq!(&data[head..], &body[..]);
+    }
+
+    #[test]
+    fn progressive_chunked_upload() {
+        let data = upload(Upload::Chunked, b"\r\n0\r\n\r\n");
+        let head = find(&data, b"\r\n\r\n").unwrap();
+        assert!(find(&data[..head], b"Transfer-Encoding: chunked\r\n")
+                .is_some());
+        let chunks = data[head..].windows(5)
+            .filter(|x| *x == b"3e8\r\n").count();
+        assert_eq!(chunks, CHUNKS);
+        assert_eq!(data.len() - head, CHUNKS * (5 + CHUNK.len() + 2) + 5);
+    }
+}
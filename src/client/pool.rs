@@ -1,41 +1,746 @@
+//! Keep-alive connection pool
+//!
+//! The `Pool` is kept in the context of the event loop (see `PoolContext`)
+//! and connections are driven by the `Connection` client. Requests are
+//! passed to idle connections to the same destination, and are queued
+//! when connection limits are reached.
+//!
+//! The pool can't establish connections by itself, as it doesn't know how
+//! to create sockets. When new connection is needed `Pool::request()`
+//! returns `Action::Connect`. Connections needed later (i.e. when slot is
+//! freed for the queued request) are accumulated in the pool, and the
+//! connector set by `Pool::set_connector()` is woken up to fetch them with
+//! `Pool::take_connects()`. The connector is also responsible for sending
+//! requests delayed with `Pool::request_at()`, by calling `Pool::timeout()`
+//! at `Pool::deadline()`.
+//!
+//! When the `Proxy` is set with `Pool::set_proxy()`, plain `http` requests
+//! are queued for the proxy destination, and the `Connection` to it sends
+//! them with absolute-form target (see `Request::start_url()`).
+use std::mem::replace;
 use std::marker::PhantomData;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 
-use ip::IpAddr;
-use rotor::{Port, Scope, create_pair};
-use rotor::{Monitor, Peer2Socket, Peer1Monitor, Peer1Socket};
+use hyper::Url;
+use rotor::{Scope, Notifier};
+use rotor_stream::Deadline;
+use time::{Duration, SteadyTime};
 
-use super::{RequestCreator, Context, PoolFsmInit};
+use super::{Context, Client, Requester, Task, ResponseError};
+use super::{Proxy, Route};
 
 
-/// Token that is used to initialize single http connection
-pub struct PipeInit<R>(IpAddr, Peer2Socket<R>);
+/// Default limit of connections to the single destination
+pub const MAX_CONNECTIONS_PER_HOST: usize = 8;
+/// Default limit of connections in the pool
+pub const MAX_CONNECTIONS: usize = 100;
+/// Default time idle connection is kept open
+pub const IDLE_TIMEOUT_SECONDS: i64 = 60;
 
-pub struct Chunk<R: RequestCreator> {
-    idle: Vec<Peer2Socket<Peer2Socket<R>>>,
-    busy: Vec<Peer2Socket<Peer2Socket<R>>>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scheme {
+    Http,
+    Https,
 }
 
-pub struct Pool<R: RequestCreator> {
-    connections: HashMap<IpAddr, Chunk<R>>,
+/// The destination of the requests
+///
+/// Connections are only reused for requests to the same destination.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Destination {
+    pub scheme: Scheme,
+    pub host: String,
+    pub port: u16,
 }
 
-pub struct PoolFsm<R, C>(Peer1Monitor<Pool<R>>, PhantomData<*const C>)
-    where R: RequestCreator, C: Context<R>;
+/// Something that can wake up the state machine
+///
+/// Implemented for `rotor::Notifier`, other implementations are useful for
+/// tests.
+pub trait Wake {
+    fn wake(&self);
+}
 
-impl<R: RequestCreator> Pool<R> {
-    pub fn new() -> Pool<R> {
-        Pool {
-            connections: HashMap::new(),
+impl Wake for Notifier {
+    fn wake(&self) {
+        // State machine is already closed if this fails, the pool is
+        // notified about that anyway
+        self.wakeup().ok();
+    }
+}
+
+/// What happened to the request passed to `Pool::request()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Request is passed to the idle connection
+    Reused,
+    /// Request is queued and the new connection to the destination must be
+    /// established (with the `Connection` client and destination as a seed)
+    Connect,
+    /// Limits are reached, request is queued until some connection is free
+    Queued,
+}
+
+struct IdleConnection<N> {
+    id: usize,
+    waker: N,
+}
+
+struct Host<R, N> {
+    /// Idle connections, the most recently used is the last one
+    idle: Vec<IdleConnection<N>>,
+    /// All connections, including idle and not yet established ones
+    connections: usize,
+    /// Connections which are not established yet
+    connecting: usize,
+    queue: VecDeque<R>,
+    /// Retried requests, which are only sent over new connections (as idle
+    /// ones may be stale)
+    retries: VecDeque<R>,
+}
+
+pub struct Pool<R, N=Notifier> {
+    hosts: HashMap<Destination, Host<R, N>>,
+    /// Requests passed to idle connections, which are not woken up yet
+    assigned: HashMap<usize, R>,
+    /// Idle connections which are asked to close to free the slot
+    evicted: HashSet<usize>,
+    /// Connections that must be established
+    connects: Vec<Destination>,
+    connector: Option<N>,
+    proxy: Option<Proxy>,
+    /// Requests which are sent later (i.e. retried after backoff)
+    delayed: Vec<(SteadyTime, Destination, R)>,
+    next_id: usize,
+    total: usize,
+    max_per_host: usize,
+    max_total: usize,
+    idle_timeout: Duration,
+}
+
+/// The context of the event loop which has connection pool
+pub trait PoolContext: Context + Sized {
+    type Requester: Requester<Context=Self>;
+    fn pool(&mut self) -> &mut Pool<Self::Requester>;
+}
+
+/// The client which serves requests queued in the `Pool`
+///
+/// Create connection with destination as a seed.
+pub struct Connection<C: PoolContext> {
+    destination: Destination,
+    id: usize,
+    /// Connection has been idle at least once (so it's established)
+    established: bool,
+    /// When the idle connection is closed
+    deadline: Deadline,
+    /// Set when the destination is the forward proxy
+    proxy: Option<Proxy>,
+    phantom: PhantomData<*const C>,
+}
+
+impl Destination {
+    /// Destination of the `http` or `https` URL
+    pub fn from_url(url: &Url) -> Option<Destination> {
+        let scheme = match &url.scheme[..] {
+            "http" => Scheme::Http,
+            "https" => Scheme::Https,
+            _ => return None,
+        };
+        match (url.serialize_host(), url.port_or_default()) {
+            (Some(host), Some(port)) => Some(Destination {
+                scheme: scheme,
+                // IPv6 addresses are in brackets
+                host: host.trim_matches(|c| c == '[' || c == ']')
+                          .to_string(),
+                port: port,
+            }),
+            _ => None,
         }
     }
 }
 
+impl<R, N> Host<R, N> {
+    fn new() -> Host<R, N> {
+        Host {
+            idle: Vec::new(),
+            connections: 0,
+            connecting: 0,
+            queue: VecDeque::new(),
+            retries: VecDeque::new(),
+        }
+    }
+    /// Number of requests waiting for a connection
+    fn waiting(&self) -> usize {
+        self.queue.len() + self.retries.len()
+    }
+}
 
-impl<R: RequestCreator> PoolFsmInit<R>
-{
-    fn create<C: Context<R>>(self, scope: &mut Scope<C>) -> PoolFsm<R, C>
+impl<R, N: Wake> Pool<R, N> {
+    pub fn new() -> Pool<R, N> {
+        Pool {
+            hosts: HashMap::new(),
+            assigned: HashMap::new(),
+            evicted: HashSet::new(),
+            connects: Vec::new(),
+            connector: None,
+            proxy: None,
+            delayed: Vec::new(),
+            next_id: 0,
+            total: 0,
+            max_per_host: MAX_CONNECTIONS_PER_HOST,
+            max_total: MAX_CONNECTIONS,
+            idle_timeout: Duration::seconds(IDLE_TIMEOUT_SECONDS),
+        }
+    }
+    /// Sets limits of connections per destination and in total
+    pub fn set_limits(&mut self, per_host: usize, total: usize) {
+        assert!(per_host > 0 && total > 0);
+        self.max_per_host = per_host;
+        self.max_total = total;
+    }
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = timeout;
+    }
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+    /// Sends plain `http` requests through the forward proxy
+    ///
+    /// Requests to the `https` destinations are still queued by the
+    /// destination, and the connector must open tunnels for them through
+    /// the proxy (see `Tunnel`).
+    pub fn set_proxy(&mut self, proxy: Proxy) {
+        self.proxy = Some(proxy);
+    }
+    pub fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+    /// Returns the destination by which requests to the `origin` are
+    /// queued
+    ///
+    /// That's the proxy, if the requests are forwarded by it. Destinations
+    /// passed to `request()`, `request_at()` and `connect_later()` are
+    /// converted with this method, so the origin may be passed there.
+    pub fn destination(&self, origin: &Destination) -> Destination {
+        match self.proxy {
+            Some(ref proxy) if proxy.route(origin) == Route::Forward => {
+                proxy.destination()
+            }
+            _ => origin.clone(),
+        }
+    }
+    /// Sets the state machine which establishes connections requested
+    /// after `Pool::request()` has returned
+    pub fn set_connector(&mut self, waker: N) {
+        self.connector = Some(waker);
+    }
+    /// Returns destinations for which connections must be established
+    pub fn take_connects(&mut self) -> Vec<Destination> {
+        replace(&mut self.connects, Vec::new())
+    }
+    /// Asks the connector to establish connection reserved by `request()`
+    ///
+    /// This is useful when `Action::Connect` is returned in the state
+    /// machine which can't establish the connection itself.
+    pub fn connect_later(&mut self, destination: Destination) {
+        let destination = self.destination(&destination);
+        self.connects.push(destination);
+        self.connector.as_ref().map(|c| c.wake());
+    }
+    /// Sends the request at the `time`
+    ///
+    /// The request is only sent over a new connection, as idle ones may be
+    /// stale (this is used to retry requests). The connector must call
+    /// `timeout()` at the `deadline()`.
+    pub fn request_at(&mut self, time: SteadyTime, destination: Destination,
+        requester: R)
     {
-        PoolFsm(self.0.initiate(scope), PhantomData)
+        let destination = self.destination(&destination);
+        self.delayed.push((time, destination, requester));
+        self.connector.as_ref().map(|c| c.wake());
+    }
+    /// Returns the time when the next delayed request must be sent
+    pub fn deadline(&self) -> Option<SteadyTime> {
+        self.delayed.iter().map(|&(time, _, _)| time).min()
+    }
+    /// Sends delayed requests which are due
+    pub fn timeout(&mut self, now: SteadyTime) {
+        let mut i = 0;
+        while i < self.delayed.len() {
+            if self.delayed[i].0 > now {
+                i += 1;
+                continue;
+            }
+            let (_, dest, req) = self.delayed.swap_remove(i);
+            if self.enqueue(dest.clone(), req, true) == Action::Connect {
+                self.connect_later(dest);
+            }
+        }
+    }
+    /// Number of open connections (including ones being established)
+    pub fn connections(&self) -> usize {
+        self.total
+    }
+    /// Sends the request using idle connection, or queues it
+    pub fn request(&mut self, destination: Destination, requester: R)
+        -> Action
+    {
+        let destination = self.destination(&destination);
+        {
+            let host = self.hosts.entry(destination.clone())
+                .or_insert_with(Host::new);
+            if let Some(conn) = host.idle.pop() {
+                self.assigned.insert(conn.id, requester);
+                conn.waker.wake();
+                return Action::Reused;
+            }
+        }
+        self.enqueue(destination, requester, false)
+    }
+    /// Queues the request, `retry` means it waits for a new connection
+    fn enqueue(&mut self, destination: Destination, requester: R,
+        retry: bool)
+        -> Action
+    {
+        {
+            let host = self.hosts.entry(destination.clone())
+                .or_insert_with(Host::new);
+            if retry {
+                host.retries.push_back(requester);
+            } else {
+                host.queue.push_back(requester);
+            }
+        }
+        if self.reserve(&destination) {
+            Action::Connect
+        } else {
+            self.schedule();
+            Action::Queued
+        }
+    }
+    /// Allocates identifier for the new connection
+    pub fn connection_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+    /// Connection is ready for the next request
+    ///
+    /// Returns the queued request if there is one, otherwise connection is
+    /// idle until `waker` is woken up. The `established` is false when
+    /// connection has just connected, only such connections get retried
+    /// requests. If retried requests can't get a new connection because
+    /// of the limits, the connection is evicted to free the slot.
+    pub fn connection_idle(&mut self, destination: &Destination, id: usize,
+        established: bool, waker: N)
+        -> Option<R>
+    {
+        {
+            let host = self.hosts.entry(destination.clone())
+                .or_insert_with(Host::new);
+            if !established {
+                host.connecting -= 1;
+                if let Some(req) = host.retries.pop_front() {
+                    return Some(req);
+                }
+            }
+            if let Some(req) = host.queue.pop_front() {
+                return Some(req);
+            }
+            if host.retries.len() > host.connecting {
+                self.evicted.insert(id);
+                waker.wake();
+            } else {
+                host.idle.push(IdleConnection { id: id, waker: waker });
+            }
+        }
+        // Requests to other destinations may wait for this connection
+        self.schedule();
+        None
+    }
+    /// Returns the request passed to the idle connection
+    pub fn take_assigned(&mut self, id: usize) -> Option<R> {
+        self.assigned.remove(&id)
+    }
+    /// Returns true (once) if idle connection must be closed to free the
+    /// slot for other destinations
+    pub fn evicted(&mut self, id: usize) -> bool {
+        self.evicted.remove(&id)
+    }
+    /// Connection is closed
+    ///
+    /// Returns requests which can't be sent because the destination is
+    /// unreachable (connection has failed before being established and
+    /// there are no other connections to the destination).
+    pub fn connection_closed(&mut self, destination: &Destination,
+        id: usize, established: bool)
+        -> Vec<R>
+    {
+        let mut failed = Vec::new();
+        self.evicted.remove(&id);
+        let orphan = self.assigned.remove(&id);
+        let unused = match self.hosts.get_mut(destination) {
+            Some(host) => {
+                host.idle.retain(|c| c.id != id);
+                host.connections -= 1;
+                self.total -= 1;
+                if !established {
+                    host.connecting -= 1;
+                }
+                if let Some(req) = orphan {
+                    // Connection has been closed before it was woken up
+                    host.queue.push_front(req);
+                }
+                if !established && host.connections == 0 {
+                    while let Some(req) = host.retries.pop_front() {
+                        failed.push(req);
+                    }
+                    while let Some(req) = host.queue.pop_front() {
+                        failed.push(req);
+                    }
+                }
+                host.connections == 0 && host.waiting() == 0
+            }
+            None => false,
+        };
+        if unused {
+            self.hosts.remove(destination);
+        }
+        self.schedule();
+        failed
+    }
+    /// Connection can't be established, so `Connection` is not created
+    ///
+    /// Returns requests which can't be sent, like `connection_closed()`.
+    pub fn connect_failed(&mut self, destination: &Destination) -> Vec<R> {
+        // Identifiers are allocated starting from one, so nothing matches
+        self.connection_closed(destination, 0, false)
+    }
+    /// Reserves the slot for the new connection if the queued request
+    /// needs one
+    fn reserve(&mut self, destination: &Destination) -> bool {
+        let host = match self.hosts.get_mut(destination) {
+            Some(host) => host,
+            None => return false,
+        };
+        if host.waiting() > host.connecting &&
+            host.connections < self.max_per_host &&
+            self.total < self.max_total
+        {
+            host.connections += 1;
+            host.connecting += 1;
+            self.total += 1;
+            true
+        } else {
+            false
+        }
+    }
+    /// Closes the oldest idle connection
+    fn evict(&mut self) -> bool {
+        for host in self.hosts.values_mut() {
+            if host.idle.len() > 0 {
+                let conn = host.idle.remove(0);
+                self.evicted.insert(conn.id);
+                conn.waker.wake();
+                return true;
+            }
+        }
+        false
+    }
+    /// Requests connections for queued requests, if limits allow
+    ///
+    /// When the global limit is reached, idle connection is closed to free
+    /// the slot.
+    fn schedule(&mut self) {
+        let max_per_host = self.max_per_host;
+        let waiting = self.hosts.iter()
+            .filter(|&(_, h)| h.waiting() > h.connecting &&
+                              h.connections < max_per_host)
+            .map(|(d, _)| d.clone())
+            .collect::<Vec<_>>();
+        let mut scheduled = false;
+        for dest in waiting {
+            while self.reserve(&dest) {
+                self.connects.push(dest.clone());
+                scheduled = true;
+            }
+            if self.total >= self.max_total {
+                // One at a time, the next one is evicted when this one
+                // is closed
+                if self.evicted.len() == 0 {
+                    self.evict();
+                }
+                break;
+            }
+        }
+        if scheduled {
+            self.connector.as_ref().map(|c| c.wake());
+        }
+    }
+}
+
+impl<C: PoolContext> Client for Connection<C> {
+    type Context = C;
+    type Requester = C::Requester;
+    type Seed = Destination;
+    fn create(seed: Destination, scope: &mut Scope<C>) -> Self {
+        let proxy = match scope.pool().proxy {
+            Some(ref proxy) if proxy.destination() == seed => {
+                Some(proxy.clone())
+            }
+            _ => None,
+        };
+        Connection {
+            destination: seed,
+            id: scope.pool().connection_id(),
+            established: false,
+            deadline: Deadline::now(),
+            proxy: proxy,
+            phantom: PhantomData,
+        }
+    }
+    fn connection_idle(mut self, scope: &mut Scope<C>) -> Task<Self> {
+        let notifier = scope.notifier();
+        let established = replace(&mut self.established, true);
+        let req = scope.pool().connection_idle(&self.destination, self.id,
+                                               established, notifier);
+        match req {
+            Some(req) => Task::Request(self, req),
+            None => {
+                self.deadline = Deadline::now() + scope.pool().idle_timeout();
+                Task::Sleep(self, self.deadline)
+            }
+        }
+    }
+    fn timeout(self, scope: &mut Scope<C>) -> Task<Self> {
+        if let Some(req) = scope.pool().take_assigned(self.id) {
+            return Task::Request(self, req);
+        }
+        // Idle for too long
+        scope.pool().connection_closed(&self.destination, self.id, true);
+        Task::Close
+    }
+    fn wakeup(self, scope: &mut Scope<C>) -> Task<Self> {
+        if let Some(req) = scope.pool().take_assigned(self.id) {
+            return Task::Request(self, req);
+        }
+        if scope.pool().evicted(self.id) {
+            scope.pool().connection_closed(&self.destination, self.id, true);
+            return Task::Close;
+        }
+        let deadline = self.deadline;
+        Task::Sleep(self, deadline)
+    }
+    fn connection_closed(self, scope: &mut Scope<C>) {
+        let failed = scope.pool().connection_closed(&self.destination,
+                                                    self.id,
+                                                    self.established);
+        for req in failed {
+            req.bad_response(&ResponseError::ConnectionFailed, scope);
+        }
+    }
+    fn forward_proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use time::{Duration, SteadyTime};
+    use test_util::{Waker, waker};
+    use super::{Pool, Action, Destination, Scheme};
+    use super::super::Proxy;
+
+    fn dest(host: &str) -> Destination {
+        Destination {
+            scheme: Scheme::Http,
+            host: host.to_string(),
+            port: 80,
+        }
+    }
+
+    #[test]
+    fn reuse() {
+        let mut pool = Pool::<u32, Waker>::new();
+        let a = dest("a");
+        assert_eq!(pool.request(a.clone(), 1), Action::Connect);
+        let id = pool.connection_id();
+        let w = waker();
+        assert_eq!(pool.connection_idle(&a, id, false, w.clone()), Some(1));
+        assert_eq!(pool.connection_idle(&a, id, true, w.clone()), None);
+        assert_eq!(pool.request(a.clone(), 2), Action::Reused);
+        assert_eq!(w.0.get(), 1);
+        assert_eq!(pool.take_assigned(id), Some(2));
+        assert_eq!(pool.connections(), 1);
+        // Other scheme is other destination
+        let mut b = a.clone();
+        b.scheme = Scheme::Https;
+        assert_eq!(pool.connection_idle(&a, id, true, w.clone()), None);
+        assert_eq!(pool.request(b, 3), Action::Connect);
+    }
+
+    #[test]
+    fn per_host_limit() {
+        let mut pool = Pool::<u32, Waker>::new();
+        pool.set_limits(1, 10);
+        let a = dest("a");
+        assert_eq!(pool.request(a.clone(), 1), Action::Connect);
+        assert_eq!(pool.request(a.clone(), 2), Action::Queued);
+        assert_eq!(pool.request(dest("b"), 3), Action::Connect);
+        let id = pool.connection_id();
+        assert_eq!(pool.connection_idle(&a, id, false, waker()), Some(1));
+        assert_eq!(pool.connection_idle(&a, id, true, waker()), Some(2));
+        assert_eq!(pool.connection_idle(&a, id, true, waker()), None);
+    }
+
+    #[test]
+    fn global_limit() {
+        let mut pool = Pool::<u32, Waker>::new();
+        let connector = waker();
+        pool.set_connector(connector.clone());
+        pool.set_limits(2, 1);
+        let a = dest("a");
+        let b = dest("b");
+        assert_eq!(pool.request(a.clone(), 1), Action::Connect);
+        let id = pool.connection_id();
+        let w = waker();
+        assert_eq!(pool.connection_idle(&a, id, false, w.clone()), Some(1));
+        assert_eq!(pool.request(b.clone(), 2), Action::Queued);
+        assert_eq!(pool.take_connects(), vec![]);
+        // Idle connection is evicted to free the slot
+        assert_eq!(pool.connection_idle(&a, id, true, w.clone()), None);
+        assert_eq!(w.0.get(), 1);
+        assert!(pool.evicted(id));
+        assert_eq!(pool.connection_closed(&a, id, true), vec![]);
+        assert_eq!(pool.take_connects(), vec![b.clone()]);
+        assert_eq!(connector.0.get(), 1);
+        let id = pool.connection_id();
+        assert_eq!(pool.connection_idle(&b, id, false, waker()), Some(2));
+    }
+
+    #[test]
+    fn connect_failed() {
+        let mut pool = Pool::<u32, Waker>::new();
+        pool.set_limits(1, 1);
+        let a = dest("a");
+        assert_eq!(pool.request(a.clone(), 1), Action::Connect);
+        assert_eq!(pool.request(a.clone(), 2), Action::Queued);
+        let id = pool.connection_id();
+        assert_eq!(pool.connection_closed(&a, id, false), vec![1, 2]);
+        assert_eq!(pool.connections(), 0);
+        assert_eq!(pool.request(a.clone(), 3), Action::Connect);
+        assert_eq!(pool.connect_failed(&a), vec![3]);
+        assert_eq!(pool.connections(), 0);
+    }
+
+    #[test]
+    fn delayed() {
+        let mut pool = Pool::<u32, Waker>::new();
+        let connector = waker();
+        pool.set_connector(connector.clone());
+        let a = dest("a");
+        assert_eq!(pool.request(a.clone(), 0), Action::Connect);
+        let idle = waker();
+        let id = pool.connection_id();
+        assert_eq!(pool.connection_idle(&a, id, false, idle.clone()),
+                   Some(0));
+        assert_eq!(pool.connection_idle(&a, id, true, idle.clone()), None);
+        let now = SteadyTime::now();
+        let later = now + Duration::milliseconds(100);
+        pool.request_at(later, a.clone(), 1);
+        assert_eq!(pool.deadline(), Some(later));
+        pool.timeout(now);
+        assert_eq!(pool.take_connects(), vec![]);
+        pool.timeout(later);
+        assert_eq!(pool.deadline(), None);
+        // Idle connection is not used
+        assert_eq!(pool.take_connects(), vec![a.clone()]);
+        assert_eq!(connector.0.get(), 2);
+        assert_eq!(idle.0.get(), 0);
+        let id = pool.connection_id();
+        assert_eq!(pool.connection_idle(&a, id, false, waker()), Some(1));
+    }
+
+    #[test]
+    fn retry_waits_for_new_connection() {
+        let mut pool = Pool::<u32, Waker>::new();
+        pool.set_limits(2, 10);
+        let a = dest("a");
+        assert_eq!(pool.request(a.clone(), 1), Action::Connect);
+        let first = pool.connection_id();
+        assert_eq!(pool.connection_idle(&a, first, false, waker()),
+                   Some(1));
+        let now = SteadyTime::now();
+        pool.request_at(now, a.clone(), 2);
+        pool.timeout(now);
+        assert_eq!(pool.take_connects(), vec![a.clone()]);
+        // Keep-alive connection doesn't take the retried request
+        assert_eq!(pool.connection_idle(&a, first, true, waker()), None);
+        let id = pool.connection_id();
+        assert_eq!(pool.connection_idle(&a, id, false, waker()), Some(2));
+    }
+
+    #[test]
+    fn retry_evicts() {
+        let mut pool = Pool::<u32, Waker>::new();
+        pool.set_limits(1, 10);
+        let a = dest("a");
+        assert_eq!(pool.request(a.clone(), 1), Action::Connect);
+        let id = pool.connection_id();
+        assert_eq!(pool.connection_idle(&a, id, false, waker()), Some(1));
+        let now = SteadyTime::now();
+        pool.request_at(now, a.clone(), 2);
+        pool.timeout(now);
+        assert_eq!(pool.take_connects(), vec![]);
+        // The only connection is closed to make room for the new one
+        let w = waker();
+        assert_eq!(pool.connection_idle(&a, id, true, w.clone()), None);
+        assert_eq!(w.0.get(), 1);
+        assert!(pool.evicted(id));
+        assert_eq!(pool.request(a.clone(), 3), Action::Queued);
+        assert_eq!(pool.connection_closed(&a, id, true), vec![]);
+        assert_eq!(pool.take_connects(), vec![a.clone()]);
+        let id = pool.connection_id();
+        assert_eq!(pool.connection_idle(&a, id, false, waker()), Some(2));
+        assert_eq!(pool.connection_idle(&a, id, true, waker()), Some(3));
+    }
+
+    #[test]
+    fn proxy() {
+        let mut pool = Pool::<u32, Waker>::new();
+        pool.set_proxy(Proxy::new("proxy", 3128).no_proxy("c"));
+        let proxy = pool.proxy().unwrap().destination();
+        // Plain http requests share connections to the proxy
+        assert_eq!(pool.request(dest("a"), 1), Action::Connect);
+        let id = pool.connection_id();
+        assert_eq!(pool.connection_idle(&proxy, id, false, waker()),
+                   Some(1));
+        assert_eq!(pool.connection_idle(&proxy, id, true, waker()), None);
+        assert_eq!(pool.request(dest("b"), 2), Action::Reused);
+        assert_eq!(pool.take_assigned(id), Some(2));
+        // Others are queued by the origin
+        let mut https = dest("a");
+        https.scheme = Scheme::Https;
+        assert_eq!(pool.destination(&https), https);
+        assert_eq!(pool.destination(&dest("c")), dest("c"));
+        assert_eq!(pool.destination(&proxy), proxy);
+        pool.connect_later(dest("b"));
+        assert_eq!(pool.take_connects(), vec![proxy]);
+    }
+
+    #[test]
+    fn closed_by_server() {
+        let mut pool = Pool::<u32, Waker>::new();
+        let a = dest("a");
+        assert_eq!(pool.request(a.clone(), 1), Action::Connect);
+        let id = pool.connection_id();
+        assert_eq!(pool.connection_idle(&a, id, false, waker()), Some(1));
+        assert_eq!(pool.connection_idle(&a, id, true, waker()), None);
+        // Request is assigned, but connection is closed before wakeup
+        assert_eq!(pool.request(a.clone(), 2), Action::Reused);
+        assert_eq!(pool.connection_closed(&a, id, true), vec![]);
+        assert_eq!(pool.take_connects(), vec![a.clone()]);
+        let id = pool.connection_id();
+        assert_eq!(pool.connection_idle(&a, id, false, waker()), Some(2));
     }
 }
@@ -0,0 +1,156 @@
+use hyper::header::Headers;
+use rotor::Scope;
+use rotor_stream::Deadline;
+
+use super::context::Context;
+use super::{Request, Head, ResponseError, Proxy};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvMode {
+    /// Download whole response body into the memory.
+    ///
+    /// The argument is maximum size of the response. Works for any kind of
+    /// response body: fixed-size, chunked, or read until the end of the
+    /// connection. Responses larger than the limit are rejected with
+    /// `ResponseError::ResponseTooLarge`.
+    ///
+    /// Note the buffer size is asserted on if it's bigger than max buffer size
+    Buffered(usize),
+    /// Fetch data chunk-by-chunk
+    ///
+    /// The parameter denotes minimum number of bytes that may be passed
+    /// to the handler (except the last chunk), in the same way as for
+    /// `server::RecvMode::Progressive`.
+    Progressive(usize),
+}
+
+/// What to do with the idle connection
+pub enum Task<C: Client> {
+    /// Send a request, which is handled by the `Requester`
+    Request(C, C::Requester),
+    /// Wait for `wakeup()` or until the deadline (then `timeout()` is called)
+    Sleep(C, Deadline),
+    /// Close the connection
+    Close,
+}
+
+/// A handler of the client connection
+///
+/// Is created for each connection and decides which requests are sent
+/// over it. Requests are sent one by one, the next one is requested when
+/// the whole response to the previous one is received.
+pub trait Client: Sized {
+    type Context: Context;
+    type Requester: Requester<Context=Self::Context>;
+    /// The value passed to `Stream::new()` to create the connection
+    type Seed;
+    fn create(seed: Self::Seed, scope: &mut Scope<Self::Context>) -> Self;
+    /// Connection is established, or previous response is fully received
+    /// and connection may be reused
+    fn connection_idle(self, scope: &mut Scope<Self::Context>) -> Task<Self>;
+    /// Deadline of the `Task::Sleep` is reached
+    fn timeout(self, scope: &mut Scope<Self::Context>) -> Task<Self>;
+    /// Wakeup while connection is idle
+    fn wakeup(self, scope: &mut Scope<Self::Context>) -> Task<Self>;
+    /// Connection is closed and can't be used anymore
+    ///
+    /// This happens when peer closes the connection, on errors, or when
+    /// response doesn't allow to keep connection alive. It's not called
+    /// when `Task::Close` is returned. If there was a request in progress,
+    /// its `Requester` has already got `bad_response()`.
+    fn connection_closed(self, _scope: &mut Scope<Self::Context>) {}
+    /// Returns the proxy, if the connection is to the forward proxy
+    ///
+    /// Then `Request::start_url()` writes absolute-form request target and
+    /// `Proxy-Authorization` header.
+    fn forward_proxy(&self) -> Option<&Proxy> {
+        None
+    }
+}
+
+/// A handler of the single request
+///
+/// This is the client-side counterpart of `server::Server`.
+pub trait Requester: Sized {
+    type Context: Context;
+    /// Write the request
+    ///
+    /// The request line must be written right in this handler. The rest
+    /// (headers and body) may be written in `wakeup()` later. Returning
+    /// `None` before anything is written cancels the request, otherwise
+    /// the connection is closed.
+    fn prepare_request(self, request: &mut Request,
+        scope: &mut Scope<Self::Context>)
+        -> Option<Self>;
+    /// Write the request body after `Expect: 100-continue` header
+    ///
+    /// Called when `100 Continue` is received, or when the server hasn't
+    /// replied within `Context::continue_timeout()`. When the final
+    /// response is received instead, this method is not called, so the body
+    /// is never sent and connection is closed after the response.
+    fn continue_request(self, _request: &mut Request,
+        _scope: &mut Scope<Self::Context>)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+    /// Output buffer is flushed while sending progressive request body
+    ///
+    /// Write the next chunk of the body here (or `done()` when the body is
+    /// complete). If nothing is written, the method isn't called again
+    /// until data is written in `wakeup()`. See
+    /// `Request::progressive_body()`.
+    fn body_flushed(self, _request: &mut Request,
+        _scope: &mut Scope<Self::Context>)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+    /// Encountered when response headers are received
+    ///
+    /// Returns self, mode and timeout for reading whole response. Interim
+    /// (`1xx`) responses are skipped.
+    ///
+    /// The `request` is passed as you may still be sending the request body
+    /// when response arrives (i.e. server rejects the request early). If
+    /// request is not complete when the response is received, connection
+    /// is closed.
+    fn headers_received(self, head: Head, request: &mut Request,
+        scope: &mut Scope<Self::Context>)
+        -> Option<(Self, RecvMode, Deadline)>;
+    /// Called when full response is received in buffered mode
+    fn response_received(self, data: &[u8], request: &mut Request,
+        scope: &mut Scope<Self::Context>);
+    /// Received chunk of data in progressive mode
+    fn response_chunk(self, chunk: &[u8], request: &mut Request,
+        scope: &mut Scope<Self::Context>)
+        -> Option<Self>;
+    /// End of response body, only for Progressive responses
+    fn response_end(self, request: &mut Request,
+        scope: &mut Scope<Self::Context>);
+    /// Trailer fields of the chunked response are received
+    ///
+    /// Called only if there is at least one trailer field, right before
+    /// `response_received()` or `response_end()`.
+    fn response_trailers(self, _trailers: Headers, _request: &mut Request,
+        _scope: &mut Scope<Self::Context>)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+    /// Response can't be received
+    ///
+    /// Connection is closed after this handler.
+    fn bad_response(self, error: &ResponseError,
+        scope: &mut Scope<Self::Context>);
+    /// Timeout while waiting for response or reading response body
+    ///
+    /// Return the new deadline to wait more, otherwise connection is
+    /// closed.
+    fn timeout(self, request: &mut Request,
+        scope: &mut Scope<Self::Context>)
+        -> Option<(Self, Deadline)>;
+    fn wakeup(self, request: &mut Request, scope: &mut Scope<Self::Context>)
+        -> Option<Self>;
+}
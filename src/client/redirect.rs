@@ -0,0 +1,352 @@
+//! Following redirects
+//!
+//! The `Redirects` requester wraps the user's one and follows `301`, `302`,
+//! `303`, `307` and `308` responses. Redirected request is sent through the
+//! `Pool`, so it works for any destination.
+//!
+//! Method is changed to `GET` for `303` responses (except for `HEAD`), and
+//! for `POST` requests redirected with `301` or `302` like browsers do.
+//! The `Authorization` header is only sent to the origin it was set for.
+use std::str::from_utf8;
+
+use hyper::Url;
+use hyper::method::Method;
+use hyper::version::HttpVersion as Version;
+use hyper::header::{Headers, Host, Authorization};
+use rotor::Scope;
+use rotor_stream::Deadline;
+
+use super::{Request, Head, RecvMode, ResponseError, Requester, Context};
+use super::{PoolContext, Destination, Scheme, Action};
+
+
+/// Default limit of redirects followed
+pub const MAX_REDIRECTS: usize = 10;
+/// Maximum size of the body of redirect response (it's discarded)
+pub const MAX_REDIRECT_BODY: usize = 65536;
+
+/// The request which is repeated for each redirect
+#[derive(Debug, Clone)]
+pub struct Hop {
+    pub method: Method,
+    pub url: Url,
+    /// Value of the `Authorization` header
+    ///
+    /// Credentials must be put here rather than written by requester, to
+    /// be stripped on cross-origin redirects.
+    pub authorization: Option<String>,
+}
+
+/// The requester which may be redirected
+///
+/// Note when used within `Redirects`, the request line, `Host` and
+/// `Authorization` headers are already written when `prepare_request()` is
+/// called. It's called again for each redirect.
+pub trait Follow: Requester {
+    /// Called before following the redirect
+    ///
+    /// Return false to veto the redirect, then the response is passed to
+    /// `headers_received()` as usual.
+    fn follow(&mut self, _head: &Head, _next: &Hop,
+        _scope: &mut Scope<Self::Context>)
+        -> bool
+    {
+        true
+    }
+    /// The request is redirected, the `hop` will be sent next
+    ///
+    /// This is a place to forget the request body, if method is changed.
+    fn redirected(&mut self, _hop: &Hop, _scope: &mut Scope<Self::Context>)
+    {}
+}
+
+struct State {
+    hop: Hop,
+    max_hops: usize,
+    chain: Vec<Url>,
+    /// Where we are redirected, while redirect response is read
+    next: Option<Hop>,
+}
+
+/// Requester which follows redirects
+pub struct Redirects<R> {
+    inner: R,
+    state: State,
+}
+
+/// Returns the request line target (path and query) of the URL
+pub fn request_target(url: &Url) -> String {
+    let mut target = url.serialize_path().unwrap_or_else(|| "/".to_string());
+    if let Some(ref query) = url.query {
+        target.push('?');
+        target.push_str(query);
+    }
+    target
+}
+
+/// Returns the next request if response is a redirect which can be followed
+pub fn next_hop(hop: &Hop, head: &Head) -> Option<Hop> {
+    let method = match head.status.to_u16() {
+        301 | 302 if hop.method == Method::Post => Method::Get,
+        303 if hop.method != Method::Head => Method::Get,
+        301 | 302 | 303 | 307 | 308 => hop.method.clone(),
+        _ => return None,
+    };
+    let location = match head.headers.get_raw("Location") {
+        Some(values) if values.len() == 1 => {
+            match from_utf8(&values[0]) {
+                Ok(value) => value.trim(),
+                Err(_) => return None,
+            }
+        }
+        _ => return None,
+    };
+    let url = match hop.url.join(location) {
+        Ok(url) => url,
+        Err(_) => return None,
+    };
+    let origin = match Destination::from_url(&url) {
+        Some(dest) => dest,
+        None => return None,
+    };
+    let same_origin = Destination::from_url(&hop.url) == Some(origin);
+    Some(Hop {
+        method: method,
+        url: url,
+        authorization: if same_origin { hop.authorization.clone() }
+                       else { None },
+    })
+}
+
+impl<R> Redirects<R> {
+    pub fn new(inner: R, hop: Hop) -> Redirects<R> {
+        Redirects {
+            inner: inner,
+            state: State {
+                chain: vec![hop.url.clone()],
+                hop: hop,
+                max_hops: MAX_REDIRECTS,
+                next: None,
+            },
+        }
+    }
+    /// Sets maximum number of redirects followed
+    ///
+    /// The response is rejected with `ResponseError::TooManyRedirects` when
+    /// there are more.
+    pub fn max_hops(mut self, num: usize) -> Redirects<R> {
+        self.state.max_hops = num;
+        self
+    }
+    /// Returns the request which is sent now
+    pub fn hop(&self) -> &Hop {
+        &self.state.hop
+    }
+    /// Returns the destination of the current request
+    pub fn destination(&self) -> Destination {
+        Destination::from_url(&self.state.hop.url)
+            .expect("URL of the request must be http or https")
+    }
+}
+
+impl<R, C> Redirects<R>
+    where R: Follow<Context=C>,
+          C: PoolContext,
+          C::Requester: From<Redirects<R>>,
+{
+    /// Sends the request to the next location
+    fn follow_next(self, scope: &mut Scope<C>) {
+        let Redirects { mut inner, mut state } = self;
+        let hop = state.next.take().unwrap();
+        inner.redirected(&hop, scope);
+        state.chain.push(hop.url.clone());
+        state.hop = hop;
+        let req = Redirects { inner: inner, state: state };
+        let dest = req.destination();
+        match scope.pool().request(dest.clone(), req.into()) {
+            Action::Connect => scope.pool().connect_later(dest),
+            Action::Reused | Action::Queued => {}
+        }
+    }
+}
+
+impl<R, C> Requester for Redirects<R>
+    where R: Follow<Context=C>,
+          C: PoolContext,
+          C::Requester: From<Redirects<R>>,
+{
+    type Context = C;
+    fn prepare_request(self, request: &mut Request, scope: &mut Scope<C>)
+        -> Option<Self>
+    {
+        let dest = self.destination();
+        let Redirects { inner, state } = self;
+        request.start_url(state.hop.method.clone(), &state.hop.url,
+                          Version::Http11);
+        let port = match (dest.port, dest.scheme) {
+            (80, Scheme::Http) | (443, Scheme::Https) => None,
+            (port, _) => Some(port),
+        };
+        request.add_header(Host {
+            hostname: state.hop.url.serialize_host().unwrap(),
+            port: port,
+        }).unwrap();
+        if let Some(ref value) = state.hop.authorization {
+            request.add_header(Authorization(value.clone())).unwrap();
+        }
+        inner.prepare_request(request, scope)
+            .map(|inner| Redirects { inner: inner, state: state })
+    }
+    fn continue_request(self, request: &mut Request, scope: &mut Scope<C>)
+        -> Option<Self>
+    {
+        let Redirects { inner, state } = self;
+        inner.continue_request(request, scope)
+            .map(|inner| Redirects { inner: inner, state: state })
+    }
+    fn body_flushed(self, request: &mut Request, scope: &mut Scope<C>)
+        -> Option<Self>
+    {
+        let Redirects { inner, state } = self;
+        inner.body_flushed(request, scope)
+            .map(|inner| Redirects { inner: inner, state: state })
+    }
+    fn headers_received(mut self, mut head: Head, request: &mut Request,
+        scope: &mut Scope<C>)
+        -> Option<(Self, RecvMode, Deadline)>
+    {
+        let next = next_hop(&self.state.hop, &head);
+        if let Some(next) = next {
+            if self.inner.follow(&head, &next, scope) {
+                // Chain contains the original URL too
+                if self.state.chain.len() > self.state.max_hops {
+                    self.inner.bad_response(
+                        &ResponseError::TooManyRedirects, scope);
+                    return None;
+                }
+                self.state.next = Some(next);
+                let deadline = Deadline::now() + scope.byte_timeout();
+                return Some((self, RecvMode::Buffered(MAX_REDIRECT_BODY),
+                             deadline));
+            }
+        }
+        head.chain = self.state.chain.clone();
+        let Redirects { inner, state } = self;
+        inner.headers_received(head, request, scope)
+            .map(|(inner, mode, dline)| {
+                (Redirects { inner: inner, state: state }, mode, dline)
+            })
+    }
+    fn response_received(self, data: &[u8], request: &mut Request,
+        scope: &mut Scope<C>)
+    {
+        if self.state.next.is_some() {
+            self.follow_next(scope);
+        } else {
+            self.inner.response_received(data, request, scope);
+        }
+    }
+    fn response_chunk(self, chunk: &[u8], request: &mut Request,
+        scope: &mut Scope<C>)
+        -> Option<Self>
+    {
+        let Redirects { inner, state } = self;
+        inner.response_chunk(chunk, request, scope)
+            .map(|inner| Redirects { inner: inner, state: state })
+    }
+    fn response_end(self, request: &mut Request, scope: &mut Scope<C>) {
+        self.inner.response_end(request, scope)
+    }
+    fn response_trailers(self, trailers: Headers, request: &mut Request,
+        scope: &mut Scope<C>)
+        -> Option<Self>
+    {
+        if self.state.next.is_some() {
+            return Some(self);
+        }
+        let Redirects { inner, state } = self;
+        inner.response_trailers(trailers, request, scope)
+            .map(|inner| Redirects { inner: inner, state: state })
+    }
+    fn bad_response(self, error: &ResponseError, scope: &mut Scope<C>) {
+        self.inner.bad_response(error, scope)
+    }
+    fn timeout(self, request: &mut Request, scope: &mut Scope<C>)
+        -> Option<(Self, Deadline)>
+    {
+        let Redirects { inner, state } = self;
+        inner.timeout(request, scope)
+            .map(|(inner, dline)| (Redirects { inner: inner, state: state },
+                                   dline))
+    }
+    fn wakeup(self, request: &mut Request, scope: &mut Scope<C>)
+        -> Option<Self>
+    {
+        let Redirects { inner, state } = self;
+        inner.wakeup(request, scope)
+            .map(|inner| Redirects { inner: inner, state: state })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::Url;
+    use hyper::method::Method;
+    use super::super::Head;
+    use super::{Hop, next_hop, request_target};
+
+    fn hop(method: Method, url: &str) -> Hop {
+        Hop {
+            method: method,
+            url: Url::parse(url).unwrap(),
+            authorization: Some("Basic dXNlcjpwYXNz".to_string()),
+        }
+    }
+
+    fn redirect(code: u16, location: &str) -> Head {
+        let data = format!("HTTP/1.1 {} Redirect\r\nLocation: {}\r\n\r\n",
+                           code, location);
+        Head::parse(data.as_bytes(), 16).unwrap()
+    }
+
+    #[test]
+    fn methods() {
+        let post = hop(Method::Post, "http://example.com/a/b");
+        let next = next_hop(&post, &redirect(303, "c?x=1")).unwrap();
+        assert_eq!(next.method, Method::Get);
+        assert_eq!(next.url.serialize(), "http://example.com/a/c?x=1");
+        assert_eq!(request_target(&next.url), "/a/c?x=1");
+        let next = next_hop(&post, &redirect(302, "/c")).unwrap();
+        assert_eq!(next.method, Method::Get);
+        let next = next_hop(&post, &redirect(307, "/c")).unwrap();
+        assert_eq!(next.method, Method::Post);
+        let next = next_hop(&post, &redirect(308, "/c")).unwrap();
+        assert_eq!(next.method, Method::Post);
+        let head = hop(Method::Head, "http://example.com/");
+        let next = next_hop(&head, &redirect(303, "/c")).unwrap();
+        assert_eq!(next.method, Method::Head);
+        let put = hop(Method::Put, "http://example.com/");
+        let next = next_hop(&put, &redirect(301, "/c")).unwrap();
+        assert_eq!(next.method, Method::Put);
+        assert!(next_hop(&put, &redirect(300, "/c")).is_none());
+        assert!(next_hop(&put, &redirect(304, "/c")).is_none());
+        assert!(next_hop(&put, &redirect(302, "ftp://example.com/"))
+                .is_none());
+    }
+
+    #[test]
+    fn authorization() {
+        let get = hop(Method::Get, "http://example.com/a");
+        let next = next_hop(&get, &redirect(302, "/b")).unwrap();
+        assert!(next.authorization.is_some());
+        let next = next_hop(&get, &redirect(302, "http://example.com:80/b"))
+            .unwrap();
+        assert!(next.authorization.is_some());
+        let next = next_hop(&get, &redirect(302, "https://example.com/b"))
+            .unwrap();
+        assert!(next.authorization.is_none());
+        let next = next_hop(&get, &redirect(302, "http://other.com/b"))
+            .unwrap();
+        assert!(next.authorization.is_none());
+    }
+}
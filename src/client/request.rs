@@ -1,16 +1,28 @@
+use std::any::Any;
+
 use rotor_stream::Buf;
-use hyper::header::{Header, HeaderFormat};
+use hyper::Url;
+use hyper::header::{Header, HeaderFormat, Expect};
 use hyper::method::Method;
 use hyper::version::HttpVersion as Version;
 
 use message::{MessageState, Message, HeaderError};
+use super::{Proxy, request_target, absolute_target};
 
 
-pub struct Request<'a>(Message<'a>);
+/// The request message
+///
+/// The second field is true when request method is `HEAD`, so the
+/// response has no body regardless of it's headers. The third one is true
+/// when `Expect: 100-continue` header is written. The fourth one is the
+/// watermark of the progressive request body. The last one is the proxy
+/// which forwards the request (see `Client::forward_proxy()`).
+pub struct Request<'a>(Message<'a>, bool, bool, Option<usize>,
+                       Option<Proxy>);
 
 impl<'a> From<Message<'a>> for Request<'a> {
     fn from(msg: Message) -> Request {
-        Request(msg)
+        Request(msg, false, false, None, None)
     }
 }
 
@@ -31,8 +43,30 @@ impl<'a> Request<'a> {
     /// When request line is already written. It's expected that your request
     /// handler state machine will never call the method twice.
     pub fn start(&mut self, method: Method, uri: &str, version: Version) {
+        self.1 = method == Method::Head;
         self.0.request_line(method, uri, version)
     }
+    /// Write request line for the URL
+    ///
+    /// The request target is the path and query of the URL. But when the
+    /// request is forwarded by the proxy, it's the absolute URL, and the
+    /// `Proxy-Authorization` header is written too.
+    ///
+    /// # Panics
+    ///
+    /// When request line is already written, like `start()`.
+    pub fn start_url(&mut self, method: Method, url: &Url, version: Version)
+    {
+        let target = match self.4 {
+            Some(_) => absolute_target(url),
+            None => request_target(url),
+        };
+        self.start(method, &target, version);
+        let auth = self.4.as_ref().and_then(|proxy| proxy.authorization());
+        if let Some(auth) = auth {
+            self.0.add_header(auth).unwrap();
+        }
+    }
     /// Add header to response
     ///
     /// Header is written into the output buffer immediately. And is sent
@@ -47,6 +81,10 @@ impl<'a> Request<'a> {
     /// application handler it's okay to unwrap the result and to get
     /// a meaningful panic (that is basically an assertion).
     ///
+    /// When `Expect: 100-continue` header is added, request body should
+    /// be written in `Requester::continue_request()` rather than right
+    /// after the headers.
+    ///
     /// # Panics
     ///
     /// * Panics when add_header is called in the wrong state.
@@ -55,6 +93,9 @@ impl<'a> Request<'a> {
     pub fn add_header<H: Header+HeaderFormat>(&mut self, header: H)
         -> Result<(), HeaderError>
     {
+        if Any::downcast_ref::<Expect>(&header).is_some() {
+            self.2 = true;
+        }
         self.0.add_header(header)
     }
     /// Returns true if at least `status()` method has been called
@@ -103,6 +144,21 @@ impl<'a> Request<'a> {
     pub fn write_body(&mut self, data: &[u8]) {
         self.0.write_body(data)
     }
+    /// Send the request body progressively
+    ///
+    /// By default the whole body is written into the output buffer at
+    /// once. In progressive mode `Requester::body_flushed()` is called
+    /// each time the output buffer is flushed down to the `watermark`
+    /// bytes, so the next chunk can be written without keeping the whole
+    /// body in memory. Works both for fixed-size (`Content-Length`) and
+    /// chunked body.
+    ///
+    /// Must be called in `Requester::prepare_request()`. It's fine to
+    /// write no body there, the first chunk may be written when the
+    /// headers are flushed.
+    pub fn progressive_body(&mut self, watermark: usize) {
+        self.3 = Some(watermark);
+    }
     /// Returns true if `done()` method is already called and everything
     /// was okay.
     pub fn is_complete(&self) -> bool {
@@ -124,10 +180,31 @@ impl<'a> Request<'a> {
     /// headers (i.e. get Head object needed for `Message::new`)
     pub fn simple<'x>(out_buf: &'x mut Buf, is_head: bool) -> Request<'x>
     {
-        Request(Message::simple(out_buf, is_head))
+        Request(Message::simple(out_buf, is_head), is_head, false, None,
+                None)
     }
 }
 
-pub fn state(resp: Request) -> MessageState {
-    resp.0.state()
+pub fn state(req: Request) -> MessageState {
+    req.0.state()
+}
+
+/// Returns true if request line of `HEAD` request is written
+pub fn is_head(req: &Request) -> bool {
+    req.1
+}
+
+/// Returns true if `Expect: 100-continue` header is written
+pub fn expects_continue(req: &Request) -> bool {
+    req.2
+}
+
+/// Returns the watermark if request body is sent progressively
+pub fn body_watermark(req: &Request) -> Option<usize> {
+    req.3
+}
+
+/// Sets the proxy which forwards the request
+pub fn forward(req: &mut Request, proxy: Option<Proxy>) {
+    req.4 = proxy;
 }
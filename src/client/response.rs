@@ -1,3 +1,197 @@
-pub struct Response {
-    x: u32
+use std::io;
+
+use hyper::Url;
+use hyper::version::HttpVersion as Version;
+use hyper::status::StatusCode;
+use hyper::header::{Headers, ContentLength, TransferEncoding, Encoding};
+use httparse;
+
+use headers::has_token;
+use server::MAX_HEADERS_NUM;
+use super::ConnectError;
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ResponseError {
+        InvalidResponse {
+            description("Status line or headers of the response are invalid")
+        }
+        HeadersTooLarge {
+            description("Response headers are too large")
+        }
+        InvalidBody {
+            description("Response body has invalid framing")
+        }
+        ResponseTooLarge {
+            description("Response body is larger than the buffer limit")
+        }
+        TooManyRedirects {
+            description("Maximum number of redirects is reached")
+        }
+        ConnectionClosed {
+            description("Connection closed before response is complete")
+        }
+        // Connection is broken before any bytes of response are received,
+        // so it's likely that request is not processed by the server
+        NoResponse(err: Option<io::Error>) {
+            description("Connection closed before response is received")
+        }
+        ConnectionFailed {
+            description("Connection closed while being established")
+        }
+        Connect(err: ConnectError) {
+            description("Can't establish connection")
+            display("Can't establish connection: {}", err)
+        }
+        Io(err: io::Error) {
+            description("I/O error")
+            display("I/O error: {}", err)
+        }
+    }
+}
+
+/// Response headers
+#[derive(Debug)]
+pub struct Head {
+    pub version: Version,
+    pub status: StatusCode,
+    pub reason: String,
+    pub headers: Headers,
+    /// URLs requested, when redirects are followed with `Redirects`
+    ///
+    /// The last one is the URL of this response. Empty if redirects are
+    /// not followed.
+    pub chain: Vec<Url>,
+}
+
+/// How the length of the response body is determined
+/// (RFC 7230 section 3.3.3)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BodyKind {
+    Fixed(u64),
+    Chunked,
+    Eof,
+}
+
+impl Head {
+    /// Parses response headers
+    ///
+    /// No more than `max_headers` are allowed.
+    pub fn parse(data: &[u8], max_headers: usize)
+        -> Result<Head, ResponseError>
+    {
+        if max_headers <= MAX_HEADERS_NUM {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS_NUM];
+            Head::parse_with(data, &mut headers[..max_headers])
+        } else {
+            let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+            Head::parse_with(data, &mut headers[..])
+        }
+    }
+    fn parse_with(data: &[u8], headers: &mut [httparse::Header])
+        -> Result<Head, ResponseError>
+    {
+        use self::ResponseError::*;
+        let mut raw = httparse::Response::new(headers);
+        match raw.parse(data) {
+            Ok(httparse::Status::Complete(x)) => {
+                assert!(x == data.len());
+                Ok(Head {
+                    version: if raw.version.unwrap() == 1 { Version::Http11 }
+                             else { Version::Http10 },
+                    status: StatusCode::from_u16(raw.code.unwrap()),
+                    reason: raw.reason.unwrap().to_string(),
+                    headers: try!(Headers::from_raw(raw.headers)
+                        .map_err(|_| InvalidResponse)),
+                    chain: Vec::new(),
+                })
+            }
+            Ok(_) => unreachable!(),
+            Err(httparse::Error::TooManyHeaders) => Err(HeadersTooLarge),
+            Err(_) => Err(InvalidResponse),
+        }
+    }
+    /// Returns true if the response is interim (`1xx` except `101`)
+    pub fn is_interim(&self) -> bool {
+        let code = self.status.to_u16();
+        code >= 100 && code < 200 && code != 101
+    }
+    /// Returns true if server doesn't keep connection alive
+    ///
+    /// That is when there is `Connection: close` or when it's HTTP/1.0
+    /// response without `Connection: keep-alive`
+    pub fn wants_close(&self) -> bool {
+        if has_token(&self.headers, "Connection", "close") {
+            return true;
+        }
+        self.version == Version::Http10 &&
+            !has_token(&self.headers, "Connection", "keep-alive")
+    }
+    /// Determines the length of the response body
+    ///
+    /// The `is_head` is true if the response is for the `HEAD` request
+    pub fn body_kind(&self, is_head: bool) -> Result<BodyKind, ResponseError>
+    {
+        use self::BodyKind::*;
+        let code = self.status.to_u16();
+        if is_head || code < 200 || code == 204 || code == 304 {
+            Ok(Fixed(0))
+        } else if let Some(items) = self.headers.get::<TransferEncoding>() {
+            if &items[..] != [Encoding::Chunked] {
+                Err(ResponseError::InvalidBody)
+            } else {
+                Ok(Chunked)
+            }
+        } else if let Some(len) = self.headers.get::<ContentLength>() {
+            Ok(Fixed(len.0))
+        } else if self.headers.get_raw("Content-Length").is_some() {
+            // Either invalid or multiple different values
+            Err(ResponseError::InvalidBody)
+        } else {
+            Ok(Eof)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::status::StatusCode;
+    use hyper::version::HttpVersion;
+    use super::{Head, BodyKind};
+
+    fn parse(data: &[u8]) -> Head {
+        Head::parse(data, 16).unwrap()
+    }
+
+    #[test]
+    fn status_line() {
+        let head = parse(b"HTTP/1.0 404 Not Found\r\n\r\n");
+        assert_eq!(head.version, HttpVersion::Http10);
+        assert_eq!(head.status, StatusCode::NotFound);
+        assert_eq!(head.reason, "Not Found");
+        assert!(head.wants_close());
+        assert!(Head::parse(b"HTTP/1.1 abc\r\n\r\n", 16).is_err());
+    }
+
+    #[test]
+    fn body_kind() {
+        let head = parse(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n");
+        assert_eq!(head.body_kind(false).unwrap(), BodyKind::Fixed(5));
+        assert_eq!(head.body_kind(true).unwrap(), BodyKind::Fixed(0));
+        assert!(!head.wants_close());
+        let head = parse(b"HTTP/1.1 200 OK\r\n\
+                           Transfer-Encoding: chunked\r\n\r\n");
+        assert_eq!(head.body_kind(false).unwrap(), BodyKind::Chunked);
+        let head = parse(b"HTTP/1.1 200 OK\r\n\r\n");
+        assert_eq!(head.body_kind(false).unwrap(), BodyKind::Eof);
+        let head = parse(b"HTTP/1.1 304 Not Modified\r\n\
+                           Content-Length: 5\r\n\r\n");
+        assert_eq!(head.body_kind(false).unwrap(), BodyKind::Fixed(0));
+        let head = parse(b"HTTP/1.1 200 OK\r\n\
+                           Content-Length: 1\r\nContent-Length: 2\r\n\r\n");
+        assert!(head.body_kind(false).is_err());
+        let head = parse(b"HTTP/1.1 100 Continue\r\n\r\n");
+        assert!(head.is_interim());
+    }
 }
@@ -0,0 +1,318 @@
+//! Retrying idempotent requests
+//!
+//! The server may close keep-alive connection right when we send the
+//! request over it. When connection is broken before any bytes of the
+//! response are received (`ResponseError::NoResponse`), the `Retries`
+//! requester sends the request again through the `Pool`, but only for
+//! idempotent methods, as the server may have processed the request.
+//!
+//! To follow redirects too, use `Retries<Redirects<R>>` as the requester of
+//! the pool. Each redirected request is retried with the default settings.
+use std::cmp::min;
+
+use hyper::header::Headers;
+use hyper::method::Method;
+use rotor::Scope;
+use rotor_stream::Deadline;
+use time::{Duration, SteadyTime};
+
+use super::{Request, Head, RecvMode, ResponseError, Requester};
+use super::{PoolContext, Destination, Redirects, Follow};
+
+
+/// Default number of retries of the single request
+pub const MAX_RETRIES: u32 = 2;
+/// Default delay before the first retry, in milliseconds
+///
+/// The delay is doubled for each subsequent retry.
+pub const RETRY_BACKOFF: i64 = 50;
+/// Maximum delay between retries, in milliseconds
+const MAX_BACKOFF: i64 = 10000;
+
+struct State {
+    destination: Destination,
+    method: Method,
+    retries: u32,
+    max_retries: u32,
+    backoff: Duration,
+}
+
+/// Requester which is sent again if connection is broken early
+///
+/// Note the wrapped requester must be able to write the request again
+/// after `prepare_request()` (and possibly `wakeup()`) were called.
+pub struct Retries<R> {
+    inner: R,
+    state: State,
+}
+
+/// Returns true if request can be retried after the `error`
+pub fn can_retry(method: &Method, error: &ResponseError) -> bool {
+    match *error {
+        ResponseError::NoResponse(_) => method.idempotent(),
+        _ => false,
+    }
+}
+
+impl<R> Retries<R> {
+    /// Wraps the requester which sends the `method` request
+    pub fn new(inner: R, destination: Destination, method: Method)
+        -> Retries<R>
+    {
+        Retries {
+            inner: inner,
+            state: State {
+                destination: destination,
+                method: method,
+                retries: 0,
+                max_retries: MAX_RETRIES,
+                backoff: Duration::milliseconds(RETRY_BACKOFF),
+            },
+        }
+    }
+    /// Sets the maximum number of retries
+    pub fn max_retries(mut self, num: u32) -> Retries<R> {
+        self.state.max_retries = num;
+        self
+    }
+    /// Sets the delay before the first retry
+    pub fn backoff(mut self, delay: Duration) -> Retries<R> {
+        self.state.backoff = delay;
+        self
+    }
+    /// Returns the delay before the next retry
+    fn delay(&self) -> Duration {
+        let ms = self.state.backoff.num_milliseconds()
+            .saturating_mul(1 << min(self.state.retries, 16));
+        Duration::milliseconds(min(ms, MAX_BACKOFF))
+    }
+}
+
+impl<R: Follow> From<Redirects<R>> for Retries<Redirects<R>> {
+    fn from(req: Redirects<R>) -> Retries<Redirects<R>> {
+        let destination = req.destination();
+        let method = req.hop().method.clone();
+        Retries::new(req, destination, method)
+    }
+}
+
+impl<R, C> Requester for Retries<R>
+    where R: Requester<Context=C>,
+          C: PoolContext,
+          C::Requester: From<Retries<R>>,
+{
+    type Context = C;
+    fn prepare_request(self, request: &mut Request, scope: &mut Scope<C>)
+        -> Option<Self>
+    {
+        let Retries { inner, state } = self;
+        inner.prepare_request(request, scope)
+            .map(|inner| Retries { inner: inner, state: state })
+    }
+    fn continue_request(self, request: &mut Request, scope: &mut Scope<C>)
+        -> Option<Self>
+    {
+        let Retries { inner, state } = self;
+        inner.continue_request(request, scope)
+            .map(|inner| Retries { inner: inner, state: state })
+    }
+    fn body_flushed(self, request: &mut Request, scope: &mut Scope<C>)
+        -> Option<Self>
+    {
+        let Retries { inner, state } = self;
+        inner.body_flushed(request, scope)
+            .map(|inner| Retries { inner: inner, state: state })
+    }
+    fn headers_received(self, head: Head, request: &mut Request,
+        scope: &mut Scope<C>)
+        -> Option<(Self, RecvMode, Deadline)>
+    {
+        let Retries { inner, state } = self;
+        inner.headers_received(head, request, scope)
+            .map(|(inner, mode, dline)| {
+                (Retries { inner: inner, state: state }, mode, dline)
+            })
+    }
+    fn response_received(self, data: &[u8], request: &mut Request,
+        scope: &mut Scope<C>)
+    {
+        self.inner.response_received(data, request, scope)
+    }
+    fn response_chunk(self, chunk: &[u8], request: &mut Request,
+        scope: &mut Scope<C>)
+        -> Option<Self>
+    {
+        let Retries { inner, state } = self;
+        inner.response_chunk(chunk, request, scope)
+            .map(|inner| Retries { inner: inner, state: state })
+    }
+    fn response_end(self, request: &mut Request, scope: &mut Scope<C>) {
+        self.inner.response_end(request, scope)
+    }
+    fn response_trailers(self, trailers: Headers, request: &mut Request,
+        scope: &mut Scope<C>)
+        -> Option<Self>
+    {
+        let Retries { inner, state } = self;
+        inner.response_trailers(trailers, request, scope)
+            .map(|inner| Retries { inner: inner, state: state })
+    }
+    fn bad_response(mut self, error: &ResponseError, scope: &mut Scope<C>) {
+        if self.state.retries < self.state.max_retries &&
+            can_retry(&self.state.method, error)
+        {
+            let time = SteadyTime::now() + self.delay();
+            let dest = self.state.destination.clone();
+            self.state.retries += 1;
+            scope.pool().request_at(time, dest, self.into());
+        } else {
+            self.inner.bad_response(error, scope)
+        }
+    }
+    fn timeout(self, request: &mut Request, scope: &mut Scope<C>)
+        -> Option<(Self, Deadline)>
+    {
+        let Retries { inner, state } = self;
+        inner.timeout(request, scope)
+            .map(|(inner, dline)| (Retries { inner: inner, state: state },
+                                   dline))
+    }
+    fn wakeup(self, request: &mut Request, scope: &mut Scope<C>)
+        -> Option<Self>
+    {
+        let Retries { inner, state } = self;
+        inner.wakeup(request, scope)
+            .map(|inner| Retries { inner: inner, state: state })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use hyper::Url;
+    use hyper::method::Method;
+    use rotor::Scope;
+    use rotor_stream::Deadline;
+    use time::Duration;
+    use client::{self, Request, Head, RecvMode, ResponseError, Requester};
+    use client::{Pool, PoolContext, Destination, Scheme};
+    use client::{Redirects, Follow, Hop};
+    use super::{Retries, can_retry};
+
+    type Stacked = Retries<Redirects<Fetch>>;
+
+    struct Context(Pool<Stacked>);
+
+    impl client::Context for Context {}
+
+    impl PoolContext for Context {
+        type Requester = Stacked;
+        fn pool(&mut self) -> &mut Pool<Stacked> {
+            &mut self.0
+        }
+    }
+
+    struct Fetch;
+
+    impl Requester for Fetch {
+        type Context = Context;
+        fn prepare_request(self, _req: &mut Request,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            unreachable!();
+        }
+        fn headers_received(self, _head: Head, _req: &mut Request,
+            _scope: &mut Scope<Context>)
+            -> Option<(Self, RecvMode, Deadline)>
+        {
+            unreachable!();
+        }
+        fn response_received(self, _data: &[u8], _req: &mut Request,
+            _scope: &mut Scope<Context>)
+        {
+            unreachable!();
+        }
+        fn response_chunk(self, _chunk: &[u8], _req: &mut Request,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            unreachable!();
+        }
+        fn response_end(self, _req: &mut Request,
+            _scope: &mut Scope<Context>)
+        {
+            unreachable!();
+        }
+        fn bad_response(self, _error: &ResponseError,
+            _scope: &mut Scope<Context>)
+        {
+            unreachable!();
+        }
+        fn timeout(self, _req: &mut Request, _scope: &mut Scope<Context>)
+            -> Option<(Self, Deadline)>
+        {
+            unreachable!();
+        }
+        fn wakeup(self, _req: &mut Request, _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            unreachable!();
+        }
+    }
+
+    impl Follow for Fetch {}
+
+    fn requester<R: Requester<Context=Context>>(_req: &R) {}
+
+    #[test]
+    fn methods() {
+        let reset = ResponseError::NoResponse(Some(io::Error::new(
+            io::ErrorKind::ConnectionReset, "reset")));
+        for m in &[Method::Get, Method::Head, Method::Put, Method::Delete,
+                   Method::Options]
+        {
+            assert!(can_retry(m, &reset));
+            assert!(can_retry(m, &ResponseError::NoResponse(None)));
+            assert!(!can_retry(m, &ResponseError::ConnectionClosed));
+        }
+        assert!(!can_retry(&Method::Post, &reset));
+        assert!(!can_retry(&Method::Patch, &reset));
+    }
+
+    #[test]
+    fn backoff() {
+        let dest = Destination {
+            scheme: Scheme::Http,
+            host: "example.com".to_string(),
+            port: 80,
+        };
+        let mut r = Retries::new((), dest, Method::Get)
+            .backoff(Duration::milliseconds(100));
+        assert_eq!(r.delay(), Duration::milliseconds(100));
+        r.state.retries = 2;
+        assert_eq!(r.delay(), Duration::milliseconds(400));
+        r.state.retries = 20;
+        assert_eq!(r.delay(), Duration::seconds(10));
+    }
+
+    #[test]
+    fn stacked() {
+        let hop = Hop {
+            method: Method::Put,
+            url: Url::parse("https://example.com:8443/a").unwrap(),
+            authorization: None,
+        };
+        // Redirected request is queued into the pool of `Stacked` this way
+        let req = Stacked::from(Redirects::new(Fetch, hop));
+        requester(&req);
+        requester(&req.inner);
+        assert!(req.state.destination == Destination {
+            scheme: Scheme::Https,
+            host: "example.com".to_string(),
+            port: 8443,
+        });
+        assert_eq!(req.state.method, Method::Put);
+        assert_eq!(req.state.retries, 0);
+    }
+}
@@ -0,0 +1,19 @@
+//! Helpers for headers which are not (fully) supported by hyper
+use std::ascii::AsciiExt;
+use std::str::from_utf8;
+
+use hyper::header::Headers;
+
+
+/// Returns true if comma-separated header `name` contains the `token`
+///
+/// Comparison is case-insensitive, as are all the tokens in `Connection`
+/// and `Upgrade` headers.
+pub fn has_token(headers: &Headers, name: &str, token: &str) -> bool {
+    headers.get_raw(name).map(|values| {
+        values.iter()
+        .filter_map(|v| from_utf8(v).ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case(token))
+    }).unwrap_or(false)
+}
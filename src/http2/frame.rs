@@ -0,0 +1,247 @@
+//! HTTP/2 framing layer (RFC 7540 section 4 and 6)
+use std::io::Write;
+
+use rotor_stream::Buf;
+
+
+/// Size of the frame header
+pub const HEADER_SIZE: usize = 9;
+/// Default (and minimum) value of `SETTINGS_MAX_FRAME_SIZE`
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16384;
+/// Default value of `SETTINGS_INITIAL_WINDOW_SIZE`
+pub const DEFAULT_WINDOW_SIZE: u32 = 65535;
+/// Default value of `SETTINGS_HEADER_TABLE_SIZE`
+pub const DEFAULT_TABLE_SIZE: u32 = 4096;
+/// Maximum size of the flow-control window
+pub const MAX_WINDOW_SIZE: i64 = 0x7FFFFFFF;
+
+pub const DATA: u8 = 0x0;
+pub const HEADERS: u8 = 0x1;
+pub const PRIORITY: u8 = 0x2;
+pub const RST_STREAM: u8 = 0x3;
+pub const SETTINGS: u8 = 0x4;
+pub const PUSH_PROMISE: u8 = 0x5;
+pub const PING: u8 = 0x6;
+pub const GOAWAY: u8 = 0x7;
+pub const WINDOW_UPDATE: u8 = 0x8;
+pub const CONTINUATION: u8 = 0x9;
+
+pub const END_STREAM: u8 = 0x1;
+pub const ACK: u8 = 0x1;
+pub const END_HEADERS: u8 = 0x4;
+pub const PADDED: u8 = 0x8;
+pub const PRIORITY_FLAG: u8 = 0x20;
+
+pub const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+pub const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Error codes used in `RST_STREAM` and `GOAWAY` frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    InternalError = 0x2,
+    FlowControlError = 0x3,
+    SettingsTimeout = 0x4,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    Cancel = 0x8,
+    CompressionError = 0x9,
+    ConnectError = 0xa,
+    EnhanceYourCalm = 0xb,
+    InadequateSecurity = 0xc,
+    Http11Required = 0xd,
+}
+
+/// Frame header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Head {
+    pub length: usize,
+    pub kind: u8,
+    pub flags: u8,
+    pub stream_id: u32,
+}
+
+pub fn read_u32(data: &[u8]) -> u32 {
+    (data[0] as u32) << 24 | (data[1] as u32) << 16 |
+    (data[2] as u32) << 8 | data[3] as u32
+}
+
+fn u32_bytes(value: u32) -> [u8; 4] {
+    [(value >> 24) as u8, (value >> 16) as u8,
+     (value >> 8) as u8, value as u8]
+}
+
+impl Head {
+    /// Parses frame header, the data must be at least `HEADER_SIZE` bytes
+    pub fn parse(data: &[u8]) -> Head {
+        Head {
+            length: (data[0] as usize) << 16 | (data[1] as usize) << 8 |
+                    data[2] as usize,
+            kind: data[3],
+            flags: data[4],
+            // Reserved bit is ignored
+            stream_id: read_u32(&data[5..9]) & 0x7FFFFFFF,
+        }
+    }
+    pub fn write(&self, buf: &mut Buf) {
+        let len = self.length;
+        buf.write(&[(len >> 16) as u8, (len >> 8) as u8, len as u8,
+                    self.kind, self.flags]).unwrap();
+        buf.write(&u32_bytes(self.stream_id)).unwrap();
+    }
+}
+
+/// Writes a frame with the payload
+pub fn write(buf: &mut Buf, kind: u8, flags: u8, stream_id: u32,
+    payload: &[u8])
+{
+    Head {
+        length: payload.len(),
+        kind: kind,
+        flags: flags,
+        stream_id: stream_id,
+    }.write(buf);
+    buf.write(payload).unwrap();
+}
+
+/// Removes padding (and priority fields for `HEADERS`) from the payload
+///
+/// Returns `None` if padding is larger than the payload
+pub fn strip(head: &Head, payload: &[u8]) -> Option<(usize, usize)> {
+    let mut start = 0;
+    let mut end = payload.len();
+    if head.flags & PADDED != 0 {
+        if payload.len() < 1 {
+            return None;
+        }
+        let pad = payload[0] as usize;
+        start = 1;
+        if pad > end - start {
+            return None;
+        }
+        end -= pad;
+    }
+    if head.kind == HEADERS && head.flags & PRIORITY_FLAG != 0 {
+        start += 5;
+        if start > end {
+            return None;
+        }
+    }
+    Some((start, end))
+}
+
+pub fn write_settings(buf: &mut Buf, settings: &[(u16, u32)]) {
+    let mut payload = Vec::with_capacity(settings.len() * 6);
+    for &(id, value) in settings {
+        payload.push((id >> 8) as u8);
+        payload.push(id as u8);
+        payload.extend(u32_bytes(value).iter().cloned());
+    }
+    write(buf, SETTINGS, 0, 0, &payload);
+}
+
+/// Parses the payload of `SETTINGS` frame
+///
+/// Returns `None` if payload length is not a multiple of 6
+pub fn parse_settings(payload: &[u8]) -> Option<Vec<(u16, u32)>> {
+    if payload.len() % 6 != 0 {
+        return None;
+    }
+    Some(payload.chunks(6)
+        .map(|x| ((x[0] as u16) << 8 | x[1] as u16, read_u32(&x[2..])))
+        .collect())
+}
+
+pub fn write_rst_stream(buf: &mut Buf, stream_id: u32, code: ErrorCode) {
+    write(buf, RST_STREAM, 0, stream_id, &u32_bytes(code as u32));
+}
+
+pub fn write_window_update(buf: &mut Buf, stream_id: u32, increment: u32) {
+    write(buf, WINDOW_UPDATE, 0, stream_id, &u32_bytes(increment));
+}
+
+pub fn write_goaway(buf: &mut Buf, last_stream_id: u32, code: ErrorCode) {
+    let mut payload = u32_bytes(last_stream_id).to_vec();
+    payload.extend(u32_bytes(code as u32).iter().cloned());
+    write(buf, GOAWAY, 0, 0, &payload);
+}
+
+/// Writes header block splitting it into `CONTINUATION` frames if needed
+pub fn write_headers(buf: &mut Buf, stream_id: u32, block: &[u8],
+    end_stream: bool, max_frame_size: usize)
+{
+    let mut chunks = block.chunks(max_frame_size).peekable();
+    let mut kind = HEADERS;
+    let mut flags = if end_stream { END_STREAM } else { 0 };
+    if block.len() == 0 {
+        write(buf, kind, flags | END_HEADERS, stream_id, b"");
+        return;
+    }
+    while let Some(chunk) = chunks.next() {
+        if chunks.peek().is_none() {
+            flags |= END_HEADERS;
+        }
+        write(buf, kind, flags, stream_id, chunk);
+        kind = CONTINUATION;
+        flags = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rotor_stream::Buf;
+    use super::{Head, strip, write_headers, parse_settings};
+    use super::{HEADERS, DATA, CONTINUATION, PADDED, PRIORITY_FLAG};
+    use super::{END_HEADERS, END_STREAM};
+
+    #[test]
+    fn head() {
+        let mut buf = Buf::new();
+        let head = Head { length: 0x10203, kind: HEADERS, flags: 0x25,
+                          stream_id: 7 };
+        head.write(&mut buf);
+        assert_eq!(&buf[..], &[1, 2, 3, 1, 0x25, 0, 0, 0, 7]);
+        assert_eq!(Head::parse(&buf[..]), head);
+        // Reserved bit
+        assert_eq!(Head::parse(&[0, 0, 0, 0, 0, 0x80, 0, 0, 1]).stream_id, 1);
+    }
+
+    #[test]
+    fn padding() {
+        let head = Head { length: 0, kind: DATA, flags: PADDED,
+                          stream_id: 1 };
+        assert_eq!(strip(&head, b"\x02abcxx"), Some((1, 4)));
+        assert_eq!(strip(&head, b"\x06abc"), None);
+        assert_eq!(strip(&head, b""), None);
+        let head = Head { length: 0, kind: HEADERS,
+                          flags: PADDED|PRIORITY_FLAG, stream_id: 1 };
+        assert_eq!(strip(&head, b"\x01\0\0\0\0\x10abx"), Some((6, 8)));
+        assert_eq!(strip(&head, b"\x01\0\0\0"), None);
+    }
+
+    #[test]
+    fn continuation() {
+        let mut buf = Buf::new();
+        write_headers(&mut buf, 1, b"abcde", true, 2);
+        let first = Head::parse(&buf[..9]);
+        assert_eq!(first.kind, HEADERS);
+        assert_eq!(first.flags, END_STREAM);
+        let last = Head::parse(&buf[2*11..2*11+9]);
+        assert_eq!(last.kind, CONTINUATION);
+        assert_eq!(last.flags, END_HEADERS);
+        assert_eq!(last.length, 1);
+        assert_eq!(buf.len(), 3*9 + 5);
+    }
+
+    #[test]
+    fn settings() {
+        assert_eq!(parse_settings(b"\0\x03\0\0\0\x64"), Some(vec![(3, 100)]));
+        assert_eq!(parse_settings(b"\0\x03\0"), None);
+    }
+}
@@ -0,0 +1,401 @@
+//! Header compression for HTTP/2 (RFC 7541)
+//!
+//! The decoder supports everything in the spec. The encoder never adds
+//! entries to the dynamic table, it only references the static one, so
+//! there is no encoder state to keep in sync with the peer.
+use std::collections::VecDeque;
+
+use super::huffman::{self, HuffmanError};
+
+
+quick_error! {
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum HpackError {
+        Truncated {
+            description("Header block is truncated")
+        }
+        IntegerOverflow {
+            description("Integer in header block is too large")
+        }
+        InvalidIndex(index: usize) {
+            description("Invalid index of the header table")
+            display("Invalid index of the header table: {}", index)
+        }
+        Huffman(err: HuffmanError) {
+            description("Invalid huffman-encoded string")
+            display("Invalid huffman-encoded string: {}", err)
+            from()
+        }
+        InvalidTableSize {
+            description("Dynamic table size update is larger than allowed \
+                or is not at the start of the block")
+        }
+        ListTooLarge {
+            description("Header list is larger than allowed")
+        }
+    }
+}
+
+/// Overhead of every entry for the table size calculation
+const ENTRY_OVERHEAD: usize = 32;
+
+const STATIC_TABLE: [(&'static str, &'static str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Decoded header field: name and value
+pub type Field = (Vec<u8>, Vec<u8>);
+
+/// Decoder of header blocks, one per connection
+pub struct Decoder {
+    /// Newest entries are at the front
+    table: VecDeque<Field>,
+    size: usize,
+    /// Current limit, set by the peer with table size update
+    max_size: usize,
+    /// The limit we've announced in `SETTINGS_HEADER_TABLE_SIZE`
+    settings_size: usize,
+    huffman: huffman::Decoder,
+}
+
+/// Decodes the integer with `prefix` bits in the first byte
+///
+/// Returns the value and the number of bytes consumed
+fn decode_int(data: &[u8], prefix: u8) -> Result<(usize, usize), HpackError>
+{
+    let mask = (1u16 << prefix) as usize - 1;
+    if data.len() == 0 {
+        return Err(HpackError::Truncated);
+    }
+    let mut value = data[0] as usize & mask;
+    if value < mask {
+        return Ok((value, 1));
+    }
+    let mut shift = 0;
+    for (i, &byte) in data[1..].iter().enumerate() {
+        if shift > 28 {
+            return Err(HpackError::IntegerOverflow);
+        }
+        value += ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 2));
+        }
+    }
+    Err(HpackError::Truncated)
+}
+
+/// Encodes the integer with `prefix` bits, `flags` are the high bits of
+/// the first byte
+fn encode_int(value: usize, prefix: u8, flags: u8, buf: &mut Vec<u8>) {
+    let mask = (1u16 << prefix) as usize - 1;
+    if value < mask {
+        buf.push(flags | value as u8);
+        return;
+    }
+    buf.push(flags | mask as u8);
+    let mut rest = value - mask;
+    while rest >= 0x80 {
+        buf.push((rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+    buf.push(rest as u8);
+}
+
+impl Decoder {
+    /// Creates decoder with the table size announced in settings
+    pub fn new(max_size: usize) -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: max_size,
+            settings_size: max_size,
+            huffman: huffman::Decoder::new(),
+        }
+    }
+    fn evict(&mut self, max_size: usize) {
+        while self.size > max_size {
+            let (name, value) = self.table.pop_back()
+                .expect("size accounting is broken");
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+    fn insert(&mut self, field: Field) {
+        let size = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        let max_size = self.max_size;
+        if size > max_size {
+            // Not an error, just clears the table
+            self.evict(0);
+            return;
+        }
+        self.evict(max_size - size);
+        self.size += size;
+        self.table.push_front(field);
+    }
+    fn get(&self, index: usize) -> Result<Field, HpackError> {
+        if index == 0 {
+            return Err(HpackError::InvalidIndex(index));
+        }
+        if index <= STATIC_TABLE.len() {
+            let (name, value) = STATIC_TABLE[index - 1];
+            return Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        }
+        self.table.get(index - STATIC_TABLE.len() - 1).cloned()
+            .ok_or(HpackError::InvalidIndex(index))
+    }
+    fn string(&self, data: &[u8]) -> Result<(Vec<u8>, usize), HpackError> {
+        let (len, off) = try!(decode_int(data, 7));
+        if data.len() < off + len {
+            return Err(HpackError::Truncated);
+        }
+        let raw = &data[off..off+len];
+        let value = if data[0] & 0x80 != 0 {
+            try!(self.huffman.decode(raw))
+        } else {
+            raw.to_vec()
+        };
+        Ok((value, off + len))
+    }
+    /// Name is either indexed (non-zero index) or a literal which follows
+    fn literal(&self, data: &[u8], prefix: u8)
+        -> Result<(Field, usize), HpackError>
+    {
+        let (index, mut off) = try!(decode_int(data, prefix));
+        let name = if index == 0 {
+            let (name, len) = try!(self.string(&data[off..]));
+            off += len;
+            name
+        } else {
+            try!(self.get(index)).0
+        };
+        let (value, len) = try!(self.string(&data[off..]));
+        Ok(((name, value), off + len))
+    }
+    /// Decodes the full header block
+    ///
+    /// The `max_list_size` limits the size of the decoded list, counted
+    /// like `SETTINGS_MAX_HEADER_LIST_SIZE`. When limit is exceeded the
+    /// decoder is still usable for subsequent blocks.
+    pub fn decode(&mut self, block: &[u8], max_list_size: usize)
+        -> Result<Vec<Field>, HpackError>
+    {
+        let mut result = Vec::new();
+        let mut list_size = 0;
+        let mut pos = 0;
+        while pos < block.len() {
+            let data = &block[pos..];
+            let first = data[0];
+            let (field, len) = if first & 0x80 != 0 {
+                // Indexed header field
+                let (index, len) = try!(decode_int(data, 7));
+                (try!(self.get(index)), len)
+            } else if first & 0xC0 == 0x40 {
+                // Literal with incremental indexing
+                let (field, len) = try!(self.literal(data, 6));
+                self.insert(field.clone());
+                (field, len)
+            } else if first & 0xE0 == 0x20 {
+                // Dynamic table size update
+                let (size, len) = try!(decode_int(data, 5));
+                if size > self.settings_size || result.len() > 0 {
+                    return Err(HpackError::InvalidTableSize);
+                }
+                self.max_size = size;
+                self.evict(size);
+                pos += len;
+                continue;
+            } else {
+                // Literal without indexing or never indexed
+                try!(self.literal(data, 4))
+            };
+            list_size += field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+            // The rest of the block is decoded anyway to keep the dynamic
+            // table in sync with the peer
+            if list_size <= max_list_size {
+                result.push(field);
+            }
+            pos += len;
+        }
+        if list_size > max_list_size {
+            return Err(HpackError::ListTooLarge);
+        }
+        Ok(result)
+    }
+}
+
+fn encode_string(data: &[u8], buf: &mut Vec<u8>) {
+    let hlen = huffman::encoded_len(data);
+    if hlen < data.len() {
+        encode_int(hlen, 7, 0x80, buf);
+        huffman::encode(data, buf);
+    } else {
+        encode_int(data.len(), 7, 0, buf);
+        buf.extend(data.iter().cloned());
+    }
+}
+
+/// Encodes the header field appending it to the header block
+///
+/// The name must be lowercase.
+pub fn encode(name: &[u8], value: &[u8], buf: &mut Vec<u8>) {
+    let mut name_index = 0;
+    for (i, &(sname, svalue)) in STATIC_TABLE.iter().enumerate() {
+        if sname.as_bytes() == name {
+            if svalue.as_bytes() == value {
+                encode_int(i + 1, 7, 0x80, buf);
+                return;
+            }
+            if name_index == 0 {
+                name_index = i + 1;
+            }
+        }
+    }
+    // Literal without indexing
+    encode_int(name_index, 4, 0, buf);
+    if name_index == 0 {
+        encode_string(name, buf);
+    }
+    encode_string(value, buf);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Decoder, HpackError, decode_int, encode_int, encode};
+
+    fn fields(list: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        list.iter()
+            .map(|&(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn integers() {
+        // RFC 7541 Appendix C.1
+        let mut buf = Vec::new();
+        encode_int(10, 5, 0, &mut buf);
+        assert_eq!(buf, vec![10]);
+        buf.clear();
+        encode_int(1337, 5, 0, &mut buf);
+        assert_eq!(buf, vec![31, 154, 10]);
+        assert_eq!(decode_int(&[31, 154, 10], 5), Ok((1337, 3)));
+        assert_eq!(decode_int(&[42], 8), Ok((42, 1)));
+        assert_eq!(decode_int(&[31, 154], 5), Err(HpackError::Truncated));
+        assert_eq!(decode_int(&[31, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF], 5),
+                   Err(HpackError::IntegerOverflow));
+    }
+
+    #[test]
+    fn requests_with_huffman() {
+        // RFC 7541 Appendix C.4
+        let mut dec = Decoder::new(4096);
+        assert_eq!(dec.decode(&[
+            0x82, 0x86, 0x84, 0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2,
+            0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff], 16384).unwrap(),
+            fields(&[(":method", "GET"), (":scheme", "http"),
+                     (":path", "/"), (":authority", "www.example.com")]));
+        assert_eq!(dec.size, 57);
+        assert_eq!(dec.decode(&[
+            0x82, 0x86, 0x84, 0xbe, 0x58, 0x86, 0xa8, 0xeb, 0x10, 0x64,
+            0x9c, 0xbf], 16384).unwrap(),
+            fields(&[(":method", "GET"), (":scheme", "http"),
+                     (":path", "/"), (":authority", "www.example.com"),
+                     ("cache-control", "no-cache")]));
+        assert_eq!(dec.size, 110);
+    }
+
+    #[test]
+    fn eviction() {
+        let mut dec = Decoder::new(4096);
+        // Size update to 60 bytes, then "custom-key: custom-header" (55)
+        let mut block = vec![0x3f, 0x1d];
+        block.extend(b"\x40\x0acustom-key\x0dcustom-header".iter().cloned());
+        dec.decode(&block, 16384).unwrap();
+        assert_eq!(dec.size, 55);
+        // Next entry evicts the first one
+        dec.decode(b"\x40\x01a\x01b", 16384).unwrap();
+        assert_eq!(dec.size, 34);
+        assert_eq!(dec.decode(&[0xbf], 16384),
+                   Err(HpackError::InvalidIndex(63)));
+        // Size update in the middle of the block
+        assert_eq!(dec.decode(&[0x82, 0x20], 16384),
+                   Err(HpackError::InvalidTableSize));
+        assert_eq!(dec.decode(&[0x82, 0x82], 40),
+                   Err(HpackError::ListTooLarge));
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut buf = Vec::new();
+        encode(b":status", b"200", &mut buf);
+        assert_eq!(buf, vec![0x88]);
+        encode(b":status", b"302", &mut buf);
+        encode(b"content-type", b"text/html", &mut buf);
+        encode(b"x-custom", b"value", &mut buf);
+        let mut dec = Decoder::new(4096);
+        assert_eq!(dec.decode(&buf, 16384).unwrap(),
+            fields(&[(":status", "200"), (":status", "302"),
+                     ("content-type", "text/html"), ("x-custom", "value")]));
+        // Nothing is added to the dynamic table
+        assert_eq!(dec.size, 0);
+    }
+}
@@ -0,0 +1,10 @@
+//! HTTP/2 framing and header compression
+//!
+//! This is the protocol-level part shared by the server (and possibly
+//! client in the future). Connection state machine is in `server::Http2`.
+pub mod frame;
+pub mod hpack;
+mod huffman;
+
+/// The connection preface sent by client before any frames
+pub const PREFACE: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
@@ -4,12 +4,21 @@ extern crate hyper;
 extern crate httparse;
 extern crate time;
 extern crate rotor_stream;
+extern crate sha1;
+extern crate rustc_serialize;
+#[cfg(feature="tls")] extern crate openssl;
 #[macro_use] extern crate quick_error;
 #[macro_use] extern crate matches;
 
+#[cfg(test)] #[macro_use] mod test_util;
+
 pub mod server;
-//pub mod client;
+pub mod client;
+pub mod websocket;
 mod message;
+mod headers;
+mod chunked;
+mod http2;
 
 pub use hyper::status as status;
 pub use hyper::header as header;
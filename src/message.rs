@@ -5,8 +5,11 @@ use rotor_stream::Buf;
 use hyper::method::Method;
 use hyper::status::StatusCode;
 use hyper::version::HttpVersion as Version;
-use hyper::header::{Header, HeaderFormat, HeaderFormatter};
+use hyper::header::{Header, HeaderFormat, HeaderFormatter, Headers};
 use hyper::header::{ContentLength, TransferEncoding, Encoding};
+use hyper::header::{Connection, ConnectionOption};
+
+use chunked;
 
 
 quick_error! {
@@ -32,29 +35,65 @@ quick_error! {
             description("Neither Content-Length nor TransferEncoding \
                 is present in the headers")
         }
+        TrailersNotAccepted {
+            description("Peer doesn't accept trailers (no `TE: trailers` \
+                in the request)")
+        }
+        ForbiddenTrailer {
+            description("Trailer field is not allowed in trailers \
+                (i.e. it determines message framing)")
+        }
     }
 }
 
 #[derive(Debug)]
 pub enum MessageState {
     /// Nothing has been sent
-    ResponseStart { version: Version, body: Body },
+    ///
+    /// The `trailers` is true when peer accepts trailers (`TE: trailers`)
+    ResponseStart { version: Version, body: Body, trailers: bool,
+                    close: Close },
     RequestStart,
     /// Status line is already in the buffer
-    Headers { body: Body, chunked: bool, request: bool,
-              content_length: Option<u64> },
-    ZeroBodyMessage,  // When response body is Denied
-    IgnoredBody, // When response body is Ignored
-    FixedSizeBody(u64),
-    ChunkedBody,
-    Done,
+    Headers { body: Body, chunked: bool, request: bool, trailers: bool,
+              close: Close, content_length: Option<u64> },
+    // The `close` below is true when connection is closed after the message
+    ZeroBodyMessage { close: bool },  // When response body is Denied
+    UpgradeMessage,  // 101 response, body is denied too
+    IgnoredBody { close: bool }, // When response body is Ignored
+    FixedSizeBody { left: u64, close: bool },
+    ChunkedBody { trailers: bool, close: bool },
+    Done { close: bool },
+    Upgraded,  // Done for 101 response, connection switches protocol
+}
+
+/// Determines whether connection is kept alive after the message
+///
+/// And which `Connection` header must be written to tell that to the peer
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Close {
+    /// Keep-alive is the default for the protocol, nothing is written
+    No,
+    /// HTTP/1.0 keep-alive, `Connection: keep-alive` is written
+    KeepAlive10,
+    /// Connection is closed, `Connection: close` is written
+    Pending,
+    /// Connection is closed, the header is already written by user
+    Yes,
+}
+
+impl Close {
+    fn is_close(self) -> bool {
+        matches!(self, Close::Pending | Close::Yes)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Body {
     Normal,
     Ignored,  // HEAD requests, 304 responses
-    Denied,  // 101, 204 responses (100 too if it is used here)
+    Denied,  // 204 responses (100 too if it is used here)
+    Upgrade,  // 101 response
 }
 
 /// Represents both request message and response message
@@ -88,7 +127,7 @@ impl<'a> Message<'a> {
         use self::Body::*;
         use self::MessageState::*;
         match self.1 {
-            ResponseStart { version, mut body } => {
+            ResponseStart { version, mut body, trailers, close } => {
                 // Note we don't expect code 100 and 102 here, but
                 // we don't assert on that for now. The point is that
                 // responses 100 and 102 are interim. 100 is generated by
@@ -99,13 +138,16 @@ impl<'a> Message<'a> {
                 // TODO(tailhook) should we assert?
                 //
                 write!(self.0, "{} {}\r\n", version, code).unwrap();
-                if matches!(code, SwitchingProtocols|NoContent) {
+                if code == SwitchingProtocols {
+                    body = Upgrade;
+                } else if code == NoContent {
                     body = Denied;
                 } else if body == Normal && code == NotModified {
                     body = Ignored;
                 }
                 self.1 = Headers { body: body, request: false,
-                                   content_length: None, chunked: false };
+                                   content_length: None, chunked: false,
+                                   trailers: trailers, close: close };
             }
             ref state => {
                 panic!("Called status() method on response in a state {:?}",
@@ -131,8 +173,10 @@ impl<'a> Message<'a> {
                 write!(self.0, "{} {} {}\r\n", method, uri, version).unwrap();
                 // It's common to allow request body for GET, is it so
                 // expected for the HEAD too? Other methods?
+                // Servers are required to accept trailers
                 self.1 = Headers { body: Normal, request: true,
-                                   content_length: None, chunked: false };
+                                   content_length: None, chunked: false,
+                                   trailers: true, close: Close::No };
             }
             ref state => {
                 panic!("Called status() method on response in a state {:?}",
@@ -151,6 +195,11 @@ impl<'a> Message<'a> {
     /// double content-length and content-length with the combination of
     /// transfer-encoding.
     ///
+    /// When you add a `Connection` header, the automatic one (`close` or
+    /// `keep-alive`) is not written, so be sure to include the tokens you
+    /// need. If the connection is going to be closed anyway, it's closed
+    /// regardless of the header value.
+    ///
     /// We return Result here to make implementing proxies easier. In the
     /// application handler it's okay to unwrap the result and to get
     /// a meaningful panic (that is basically an assertion).
@@ -166,7 +215,9 @@ impl<'a> Message<'a> {
         use self::MessageState::*;
         use self::HeaderError::*;
         match self.1 {
-            Headers { ref mut content_length, ref mut chunked, .. } => {
+            Headers { ref mut content_length, ref mut chunked,
+                      ref mut close, .. }
+            => {
                 match Any::downcast_ref::<ContentLength>(&header) {
                     Some(&ContentLength(ln)) => {
                         if *chunked {
@@ -194,6 +245,26 @@ impl<'a> Message<'a> {
                     }
                     None => {}
                 }
+                match Any::downcast_ref::<Connection>(&header) {
+                    Some(conn) if conn.contains(&ConnectionOption::Close)
+                    => {
+                        *close = Close::Yes;
+                    }
+                    Some(conn) if *close == Close::KeepAlive10 &&
+                        conn.contains(&ConnectionOption::KeepAlive)
+                    => {
+                        // User wrote the header on it's own
+                        *close = Close::No;
+                    }
+                    Some(_) if *close != Close::No => {
+                        // Only one `Connection` header is allowed, so we
+                        // can't add ours. Connection is closed anyway, as
+                        // we either decided to close it, or it's HTTP/1.0
+                        // and no keep-alive token is written
+                        *close = Close::Yes;
+                    }
+                    _ => {}
+                }
                 write!(self.0, "{}: {}\r\n",
                     H::header_name(),
                     HeaderFormatter(&header)).unwrap();
@@ -231,37 +302,55 @@ impl<'a> Message<'a> {
     pub fn done_headers(&mut self) -> Result<bool, HeaderError> {
         use self::Body::*;
         use self::MessageState::*;
+        if let Headers { close, body, .. } = self.1 {
+            if body != Upgrade {
+                match close {
+                    Close::Pending => {
+                        self.0.write(b"Connection: close\r\n").unwrap();
+                    }
+                    Close::KeepAlive10 => {
+                        self.0.write(b"Connection: keep-alive\r\n").unwrap();
+                    }
+                    Close::No | Close::Yes => {}
+                }
+            }
+        }
         let result = match self.1 {
-            Headers { body: Ignored, .. } => {
-                self.1 = IgnoredBody;
+            Headers { body: Ignored, close, .. } => {
+                self.1 = IgnoredBody { close: close.is_close() };
+                Ok(false)
+            }
+            Headers { body: Denied, close, .. } => {
+                self.1 = ZeroBodyMessage { close: close.is_close() };
                 Ok(false)
             }
-            Headers { body: Denied, .. } => {
-                self.1 = ZeroBodyMessage;
+            Headers { body: Upgrade, .. } => {
+                self.1 = UpgradeMessage;
                 Ok(false)
             }
             Headers { body: Normal, content_length: Some(cl),
-                      chunked: false, request: _ }
+                      chunked: false, request: _, trailers: _, close }
             => {
-                self.1 = FixedSizeBody(cl);
+                self.1 = FixedSizeBody { left: cl, close: close.is_close() };
                 Ok(true)
             }
             Headers { body: Normal, content_length: None, chunked: true,
-                      request: _ }
+                      request: _, trailers, close }
             => {
-                self.1 = ChunkedBody;
+                self.1 = ChunkedBody { trailers: trailers,
+                                       close: close.is_close() };
                 Ok(true)
             }
             Headers { content_length: Some(_), chunked: true, .. }
             => unreachable!(),
             Headers { body: Normal, content_length: None, chunked: false,
-                      request: true }
+                      request: true, trailers: _, close: _ }
             => {
-                self.1 = ZeroBodyMessage;
+                self.1 = ZeroBodyMessage { close: false };
                 Ok(false)
             }
             Headers { body: Normal, content_length: None, chunked: false,
-                      request: false }
+                      request: false, trailers: _, close: _ }
             => Err(HeaderError::CantDetermineBodySize),
             ref state => {
                 panic!("Called done_headers() method on  in a state {:?}",
@@ -276,7 +365,8 @@ impl<'a> Message<'a> {
     /// Works both for fixed-size body and chunked body.
     ///
     /// For the chunked body each chunk is put into the buffer immediately
-    /// prefixed by chunk size.
+    /// prefixed by chunk size. Empty chunks are skipped (as zero-length chunk
+    /// would mean end of body).
     ///
     /// For both modes chunk is put into the buffer, but is only sent when
     /// rotor-stream state machine is reached. So you may put multiple chunks
@@ -293,13 +383,13 @@ impl<'a> Message<'a> {
     pub fn write_body(&mut self, data: &[u8]) {
         use self::MessageState::*;
         match self.1 {
-            ZeroBodyMessage => {
+            ZeroBodyMessage { .. } | UpgradeMessage => {
                 if data.len() != 0 {
                     panic!("Non-zero data length for the response where \
                             the response body is denied (101, 204)");
                 }
             }
-            FixedSizeBody(ref mut x) => {
+            FixedSizeBody { left: ref mut x, .. } => {
                 if data.len() as u64 > *x {
                     panic!("Fixed size response error. \
                         Bytes left {} but got additional {}", x, data.len());
@@ -307,10 +397,7 @@ impl<'a> Message<'a> {
                 self.0.write(data).unwrap();
                 *x -= data.len() as u64;
             }
-            ChunkedBody => {
-                write!(self.0, "{:x}\r\n", data.len()).unwrap();
-                self.0.write(data).unwrap();
-            }
+            ChunkedBody { .. } => chunked::write_chunk(self.0, data),
             ref state => {
                 panic!("Called write_body() method on response \
                     in a state {:?}", state)
@@ -320,7 +407,29 @@ impl<'a> Message<'a> {
     /// Returns true if `done()` method is already called and everything
     /// was okay.
     pub fn is_complete(&self) -> bool {
-        matches!(self.1, MessageState::Done)
+        matches!(self.1, MessageState::Done { .. } | MessageState::Upgraded)
+    }
+    /// Returns true if connection must be closed after the message
+    ///
+    /// This is either requested by peer (`Connection: close` or HTTP/1.0
+    /// without keep-alive) or by the user (`Connection: close` header)
+    pub fn is_close(&self) -> bool {
+        use self::MessageState::*;
+        match self.1 {
+            ResponseStart { close, .. } | Headers { close, .. }
+            => close.is_close(),
+            ZeroBodyMessage { close } | IgnoredBody { close } |
+            FixedSizeBody { close, .. } | ChunkedBody { close, .. } |
+            Done { close }
+            => close,
+            RequestStart | UpgradeMessage | Upgraded => false,
+        }
+    }
+    /// Returns true if `101 Switching Protocols` response is complete
+    ///
+    /// After this point the connection is no more HTTP
+    pub fn is_upgraded(&self) -> bool {
+        matches!(self.1, MessageState::Upgraded)
     }
     /// Writes needed final finalization data into the buffer and asserts
     /// that response is in the appropriate state for that.
@@ -334,14 +443,15 @@ impl<'a> Message<'a> {
     pub fn done(&mut self) {
         use self::MessageState::*;
         match self.1 {
-            ChunkedBody => {
-                self.0.write(b"0\r\n").unwrap();
-                self.1 = Done;
+            ChunkedBody { close, .. } => {
+                chunked::write_last_chunk(self.0);
+                self.1 = Done { close: close };
             }
-            FixedSizeBody(0) => self.1 = Done,
-            ZeroBodyMessage => self.1 = Done,
-            IgnoredBody => self.1 = Done,
-            Done => {}  // multiple invocations are okay
+            FixedSizeBody { left: 0, close } => self.1 = Done { close: close },
+            ZeroBodyMessage { close } => self.1 = Done { close: close },
+            UpgradeMessage => self.1 = Upgraded,
+            IgnoredBody { close } => self.1 = Done { close: close },
+            Done { .. } | Upgraded => {}  // multiple invocations are okay
             ref state => {
                 panic!("Called done() method on response in a state {:?}",
                        state);
@@ -349,6 +459,44 @@ impl<'a> Message<'a> {
         }
     }
 
+    /// Writes the last chunk followed by trailer fields
+    ///
+    /// Trailers are only written if peer accepts them, i.e. request
+    /// contains `TE: trailers`. Otherwise (or when one of the fields is not
+    /// allowed in trailers) error is returned and nothing is written, so
+    /// you can finish message with `done()`. For ignored body (HEAD
+    /// requests) this method is equivalent to `done()`.
+    ///
+    /// # Panics
+    ///
+    /// When message is not chunked or is in the wrong state
+    pub fn done_with_trailers(&mut self, trailers: &Headers)
+        -> Result<(), HeaderError>
+    {
+        use self::MessageState::*;
+        match self.1 {
+            ChunkedBody { trailers: false, .. } => {
+                return Err(HeaderError::TrailersNotAccepted);
+            }
+            ChunkedBody { trailers: true, close } => {
+                let allowed = trailers.iter()
+                    .all(|h| chunked::is_allowed_trailer(h.name()));
+                if !allowed {
+                    return Err(HeaderError::ForbiddenTrailer);
+                }
+                chunked::write_trailers(self.0, trailers);
+                self.1 = Done { close: close };
+            }
+            IgnoredBody { close } => self.1 = Done { close: close },
+            Done { .. } => {}  // multiple invocations are okay
+            ref state => {
+                panic!("Called done_with_trailers() method on response \
+                    in a state {:?}", state);
+            }
+        }
+        Ok(())
+    }
+
     pub fn state(self) -> MessageState {
         self.1
     }
@@ -365,6 +513,9 @@ impl<'a> Message<'a> {
             body: if is_head { Ignored } else { Normal },
             // Always assume HTTP/1.0 when version is unknown
             version: Version::Http10,
+            trailers: false,
+            // Caller decides whether to keep connection alive
+            close: Close::No,
         })
     }
 }
@@ -374,9 +525,10 @@ mod test {
     use rotor_stream::Buf;
     use hyper::method::Method;
     use hyper::status::StatusCode;
-    use hyper::header::ContentLength;
+    use hyper::header::{ContentLength, TransferEncoding, Encoding, Headers};
+    use hyper::header::{Connection, Header};
     use hyper::version::HttpVersion;
-    use super::{Message, MessageState, Body};
+    use super::{Message, MessageState, Body, HeaderError, Close};
 
     #[test]
     fn message_size() {
@@ -394,6 +546,8 @@ mod test {
         fun(MessageState::ResponseStart {
             version: HttpVersion::Http10,
             body: Body::Normal,
+            trailers: false,
+            close: Close::No,
         }.with(&mut buf));
         return buf;
     }
@@ -416,4 +570,165 @@ mod test {
             msg.done();
         })[..], "HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n".as_bytes());
     }
+
+    #[test]
+    fn chunked_request() {
+        assert_eq!(&do_request(|mut msg| {
+            msg.request_line(Method::Post, "/", HttpVersion::Http11);
+            msg.add_header(TransferEncoding(vec![Encoding::Chunked])).unwrap();
+            msg.done_headers().unwrap();
+            msg.write_body(b"hello");
+            msg.write_body(b"");
+            msg.write_body(b" world");
+            msg.done();
+        })[..], concat!("POST / HTTP/1.1\r\n",
+                        "Transfer-Encoding: chunked\r\n\r\n",
+                        "5\r\nhello\r\n",
+                        "6\r\n world\r\n",
+                        "0\r\n\r\n").as_bytes());
+    }
+
+    #[test]
+    fn response_trailers() {
+        let mut trailers = Headers::new();
+        trailers.set_raw("X-Checksum", vec![b"abc".to_vec()]);
+        let mut buf = Buf::new();
+        {
+            let mut msg = MessageState::ResponseStart {
+                version: HttpVersion::Http11,
+                body: Body::Normal,
+                trailers: true,
+                close: Close::No,
+            }.with(&mut buf);
+            msg.response_status(StatusCode::Ok);
+            msg.add_header(TransferEncoding(vec![Encoding::Chunked])).unwrap();
+            msg.done_headers().unwrap();
+            msg.write_body(b"hello");
+            let mut bad = Headers::new();
+            bad.set(ContentLength(5));
+            assert!(matches!(msg.done_with_trailers(&bad),
+                             Err(HeaderError::ForbiddenTrailer)));
+            msg.done_with_trailers(&trailers).unwrap();
+        }
+        assert_eq!(&buf[..], concat!("HTTP/1.1 200 OK\r\n",
+                        "Transfer-Encoding: chunked\r\n\r\n",
+                        "5\r\nhello\r\n",
+                        "0\r\nX-Checksum: abc\r\n\r\n").as_bytes());
+    }
+
+    #[test]
+    fn trailers_not_accepted() {
+        let mut trailers = Headers::new();
+        trailers.set_raw("X-Checksum", vec![b"abc".to_vec()]);
+        let mut buf = Buf::new();
+        {
+            let mut msg = MessageState::ResponseStart {
+                version: HttpVersion::Http11,
+                body: Body::Normal,
+                trailers: false,
+                close: Close::No,
+            }.with(&mut buf);
+            msg.response_status(StatusCode::Ok);
+            msg.add_header(TransferEncoding(vec![Encoding::Chunked])).unwrap();
+            msg.done_headers().unwrap();
+            assert!(matches!(msg.done_with_trailers(&trailers),
+                             Err(HeaderError::TrailersNotAccepted)));
+            msg.done();
+        }
+        assert!(buf[..].ends_with(b"\r\n\r\n0\r\n\r\n"));
+    }
+
+    fn do_response11<F: FnOnce(&mut Message)>(close: Close, fun: F)
+        -> (Buf, bool)
+    {
+        let mut buf = Buf::new();
+        let is_close = {
+            let mut msg = MessageState::ResponseStart {
+                version: HttpVersion::Http11,
+                body: Body::Normal,
+                trailers: false,
+                close: close,
+            }.with(&mut buf);
+            fun(&mut msg);
+            assert!(msg.is_complete());
+            msg.is_close()
+        };
+        (buf, is_close)
+    }
+
+    #[test]
+    fn connection_close() {
+        let (buf, close) = do_response11(Close::Pending, |msg| {
+            msg.response_status(StatusCode::Ok);
+            msg.add_header(ContentLength(0)).unwrap();
+            msg.done_headers().unwrap();
+            msg.done();
+        });
+        assert!(close);
+        assert_eq!(&buf[..], concat!("HTTP/1.1 200 OK\r\n",
+                                     "Content-Length: 0\r\n",
+                                     "Connection: close\r\n\r\n")
+                             .as_bytes());
+    }
+
+    #[test]
+    fn user_connection_close() {
+        let (buf, close) = do_response11(Close::No, |msg| {
+            msg.response_status(StatusCode::Ok);
+            msg.add_header(Connection::close()).unwrap();
+            msg.add_header(ContentLength(0)).unwrap();
+            msg.done_headers().unwrap();
+            msg.done();
+        });
+        assert!(close);
+        assert_eq!(&buf[..], concat!("HTTP/1.1 200 OK\r\n",
+                                     "Connection: close\r\n",
+                                     "Content-Length: 0\r\n\r\n")
+                             .as_bytes());
+    }
+
+    #[test]
+    fn keep_alive_10() {
+        let (buf, close) = do_response11(Close::KeepAlive10, |msg| {
+            msg.response_status(StatusCode::Ok);
+            msg.add_header(ContentLength(0)).unwrap();
+            msg.done_headers().unwrap();
+            msg.done();
+        });
+        assert!(!close);
+        assert!(buf[..].ends_with(b"Connection: keep-alive\r\n\r\n"));
+    }
+
+    #[test]
+    fn user_connection_with_pending_close() {
+        let (buf, close) = do_response11(Close::Pending, |msg| {
+            msg.response_status(StatusCode::Ok);
+            msg.add_header(Connection::keep_alive()).unwrap();
+            msg.add_header(ContentLength(0)).unwrap();
+            msg.done_headers().unwrap();
+            msg.done();
+        });
+        assert!(close);
+        assert_eq!(&buf[..], concat!("HTTP/1.1 200 OK\r\n",
+                                     "Connection: keep-alive\r\n",
+                                     "Content-Length: 0\r\n\r\n")
+                             .as_bytes());
+    }
+
+    #[test]
+    fn user_connection_10() {
+        let (buf, close) = do_response11(Close::KeepAlive10, |msg| {
+            msg.response_status(StatusCode::Ok);
+            let upgrade = Connection::parse_header(&[b"Upgrade".to_vec()]);
+            msg.add_header(upgrade.unwrap()).unwrap();
+            msg.add_header(ContentLength(0)).unwrap();
+            msg.done_headers().unwrap();
+            msg.done();
+        });
+        assert!(close);
+        assert_eq!(&buf[..], concat!("HTTP/1.1 200 OK\r\n",
+                                     "Connection: Upgrade\r\n",
+                                     "Content-Length: 0\r\n\r\n")
+                             .as_bytes());
+    }
 }
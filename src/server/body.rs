@@ -6,6 +6,7 @@ use hyper::header::{Encoding, ConnectionOption};
 use hyper::method::Method::{Get, Head};
 
 use super::request::Head;
+use headers::has_token;
 
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -28,6 +29,12 @@ impl BodyKind {
             } else {
                 Ok((Chunked))
             }
+        } else if has_token(&head.headers, "Connection", "upgrade") &&
+                  head.headers.get_raw("Upgrade").is_some()
+        {
+            // No request body, everything after headers belongs to the
+            // new protocol if handler agrees to switch
+            Ok(Upgrade)
         } else if head.method == Get || head.method == Head {
             Ok(Fixed(0))
         } else if let Some(conn) = head.headers.get::<Connection>() {
@@ -3,7 +3,8 @@ use hyper::header::{ContentLength, ContentType};
 use time::Duration;
 use hyper::mime::{Mime, TopLevel, SubLevel};
 
-use super::Response;
+use super::{Response, ProxyProtocol, Cidr};
+use super::{MAX_HEADERS_NUM, MAX_HEADERS_SIZE, MAX_CHUNK_HEAD};
 
 
 pub trait Context {
@@ -25,4 +26,78 @@ pub trait Context {
     fn byte_timeout(&self) -> Duration {
         Duration::seconds(10)
     }
+    /// Maximum size of request headers (including request line)
+    ///
+    /// Larger requests get `431 Request Header Fields Too Large`. The limit
+    /// also applies to each trailer field of chunked request.
+    fn max_headers_size(&self) -> usize {
+        MAX_HEADERS_SIZE
+    }
+    /// Maximum number of request headers (and trailers)
+    ///
+    /// Up to `MAX_HEADERS_NUM` headers are parsed into the array allocated
+    /// on the stack, larger limits allocate the array on the heap for every
+    /// request.
+    fn max_headers_num(&self) -> usize {
+        MAX_HEADERS_NUM
+    }
+    /// Maximum length of chunk-size line (including chunk extensions)
+    fn max_chunk_head(&self) -> usize {
+        MAX_CHUNK_HEAD
+    }
+    /// Maximum number of requests processed at once on a single connection
+    ///
+    /// When the handler doesn't produce response immediately (i.e. waits for
+    /// `wakeup()`), subsequent requests without a body are read and passed to
+    /// their own handlers. Responses are sent in the order of requests
+    /// anyway.
+    ///
+    /// Default is `1`, i.e. requests are processed one by one, as
+    /// pipelining means handlers of the same connection work concurrently.
+    fn pipeline_depth(&self) -> usize {
+        1
+    }
+    /// Maximum size of responses to requests read ahead (bytes)
+    ///
+    /// These responses are kept in memory until all previous ones are
+    /// sent. When the limit is reached, no more requests are read ahead and
+    /// wakeups of their handlers are postponed until the request becomes
+    /// the current one.
+    fn max_pipeline_buffer(&self) -> usize {
+        65536
+    }
+    /// Whether connections start with the PROXY protocol preamble
+    ///
+    /// Enable it only when all connections come through the load
+    /// balancer which sends the preamble. Addresses from the preamble are
+    /// in `Head::proxy`.
+    fn proxy_protocol(&self) -> ProxyProtocol {
+        ProxyProtocol::Off
+    }
+    /// Addresses of reverse proxies which are trusted to send `Forwarded`
+    /// and `X-Forwarded-For`/`X-Forwarded-Proto` headers
+    ///
+    /// The effective client address is in `Head::client_ip`. By default
+    /// nobody is trusted, so forwarding headers are ignored.
+    fn trusted_proxies(&self) -> &[Cidr] {
+        &[]
+    }
+    /// Maximum number of concurrent streams of HTTP/2 connection
+    ///
+    /// Each stream has its own `Server` state machine, so this is the
+    /// same thing as `pipeline_depth()` for HTTP/1.x. Streams opened over
+    /// the limit are refused.
+    fn http2_max_streams(&self) -> u32 {
+        100
+    }
+    /// Whether HTTP/2 is accepted on plaintext connections
+    ///
+    /// When enabled, `Parser` switches to HTTP/2 when connection starts
+    /// with the HTTP/2 preface (prior knowledge), or when request asks for
+    /// `Upgrade: h2c`. Disabled by default, so a request can't make the
+    /// connection bypass HTTP/1.x-only proxies or filters in front of the
+    /// server. Over TLS HTTP/2 is negotiated with ALPN regardless of this.
+    fn http2_cleartext(&self) -> bool {
+        false
+    }
 }
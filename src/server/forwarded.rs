@@ -0,0 +1,460 @@
+//! Resolution of the effective client address behind reverse proxies
+//!
+//! Both RFC 7239 `Forwarded` and de-facto standard `X-Forwarded-For` and
+//! `X-Forwarded-Proto` headers are supported. Headers are taken into account
+//! only when the peer is in the trusted list, and the chain of proxies is
+//! followed from the right as long as the addresses are trusted.
+use std::ascii::AsciiExt;
+use std::str::{FromStr, from_utf8};
+use std::net::{SocketAddr, Ipv4Addr, Ipv6Addr};
+
+use ip::IpAddr;
+use hyper::header::Headers;
+
+use super::request::Head;
+
+
+quick_error! {
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum CidrError {
+        InvalidAddress {
+            description("Invalid IP address in CIDR")
+        }
+        InvalidPrefix {
+            description("Invalid prefix length in CIDR")
+        }
+    }
+}
+
+/// A range of IP addresses, i.e. `10.0.0.0/8` or `fd00::/8`
+///
+/// Used for the list of trusted proxies in `Context::trusted_proxies()`.
+/// Single address (without prefix length) is also accepted when parsing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    v6: bool,
+    bytes: [u8; 16],
+    prefix: u8,
+}
+
+/// Single hop of the chain of proxies
+#[derive(Debug, PartialEq)]
+struct Hop {
+    /// Address of the client of the proxy (if known)
+    addr: Option<IpAddr>,
+    /// Protocol of the request received by the proxy
+    proto: Option<String>,
+}
+
+fn octets(addr: &IpAddr) -> (bool, [u8; 16]) {
+    let mut bytes = [0u8; 16];
+    match *addr {
+        IpAddr::V4(ref ip) => {
+            for (dst, src) in bytes.iter_mut().zip(ip.octets().iter()) {
+                *dst = *src;
+            }
+            (false, bytes)
+        }
+        IpAddr::V6(ref ip) => {
+            for (i, seg) in ip.segments().iter().enumerate() {
+                bytes[i*2] = (*seg >> 8) as u8;
+                bytes[i*2+1] = *seg as u8;
+            }
+            (true, bytes)
+        }
+    }
+}
+
+/// Converts IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) to plain IPv4
+///
+/// Dual-stack sockets report IPv4 peers this way.
+fn unmapped(addr: &IpAddr) -> IpAddr {
+    match *addr {
+        IpAddr::V6(ref ip) => {
+            let seg = ip.segments();
+            if seg[..6] == [0, 0, 0, 0, 0, 0xffff] {
+                IpAddr::V4(Ipv4Addr::new((seg[6] >> 8) as u8, seg[6] as u8,
+                                         (seg[7] >> 8) as u8, seg[7] as u8))
+            } else {
+                addr.clone()
+            }
+        }
+        IpAddr::V4(_) => addr.clone(),
+    }
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Cidr, CidrError> {
+        let (v6, mut bytes) = octets(&addr);
+        if prefix > if v6 { 128 } else { 32 } {
+            return Err(CidrError::InvalidPrefix);
+        }
+        // Clear host bits, so that comparison is just a mask
+        let prefix_bits = prefix as usize;
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let start = i * 8;
+            if start >= prefix_bits {
+                *byte = 0;
+            } else if start + 8 > prefix_bits {
+                *byte &= !(0xFFu8 >> (prefix_bits - start));
+            }
+        }
+        Ok(Cidr { v6: v6, bytes: bytes, prefix: prefix })
+    }
+    /// Returns true if address belongs to this range
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let (v6, bytes) = octets(&unmapped(addr));
+        if v6 != self.v6 {
+            return false;
+        }
+        let full = (self.prefix / 8) as usize;
+        if bytes[..full] != self.bytes[..full] {
+            return false;
+        }
+        let bits = self.prefix % 8;
+        if bits == 0 {
+            return true;
+        }
+        let mask = !(0xFFu8 >> bits);
+        bytes[full] & mask == self.bytes[full]
+    }
+}
+
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    if let Ok(ip) = Ipv4Addr::from_str(value) {
+        Some(IpAddr::V4(ip))
+    } else if let Ok(ip) = Ipv6Addr::from_str(value) {
+        Some(IpAddr::V6(ip))
+    } else {
+        None
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrError;
+    fn from_str(value: &str) -> Result<Cidr, CidrError> {
+        let mut parts = value.splitn(2, '/');
+        let addr = try!(parts.next().and_then(parse_ip)
+            .ok_or(CidrError::InvalidAddress));
+        let prefix = match parts.next() {
+            Some(x) => try!(x.parse().map_err(|_| CidrError::InvalidPrefix)),
+            None => match addr {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128,
+            },
+        };
+        Cidr::new(addr, prefix)
+    }
+}
+
+fn socket_ip(addr: &SocketAddr) -> IpAddr {
+    match *addr {
+        SocketAddr::V4(ref a) => IpAddr::V4(*a.ip()),
+        SocketAddr::V6(ref a) => unmapped(&IpAddr::V6(*a.ip())),
+    }
+}
+
+/// Splits by separator, skipping separators in quoted strings
+fn split_quoted(value: &str, sep: char) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (idx, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if !quoted && c == sep {
+            result.push(value[start..idx].trim());
+            start = idx + 1;
+        }
+    }
+    result.push(value[start..].trim());
+    result
+}
+
+fn unquote(value: &str) -> String {
+    if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+        return value.to_string();
+    }
+    let mut result = String::with_capacity(value.len());
+    let mut escaped = false;
+    for c in value[1..value.len()-1].chars() {
+        if !escaped && c == '\\' {
+            escaped = true;
+        } else {
+            result.push(c);
+            escaped = false;
+        }
+    }
+    result
+}
+
+/// Parses node identifier, with optional port
+///
+/// Obfuscated identifiers and `unknown` yield `None`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if node.starts_with('[') {
+        // IPv6 is always in brackets in `Forwarded`, may be with port
+        node.find(']').and_then(|end| Ipv6Addr::from_str(&node[1..end]).ok())
+            .map(IpAddr::V6)
+    } else if let Some(ip) = parse_ip(node) {
+        // Bare address, IPv6 is written this way in `X-Forwarded-For`
+        Some(ip)
+    } else {
+        // IPv4 with port
+        node.rsplitn(2, ':').nth(1).and_then(parse_ip)
+    }
+}
+
+/// Values of all comma-separated header fields in a single list
+fn header_list<'x>(headers: &'x Headers, name: &str) -> Option<Vec<&'x str>> {
+    headers.get_raw(name).map(|values| {
+        values.iter()
+        .filter_map(|v| from_utf8(v).ok())
+        .flat_map(|v| split_quoted(v, ',').into_iter())
+        .filter(|v| v.len() > 0)
+        .collect()
+    })
+}
+
+fn forwarded_hops(headers: &Headers) -> Option<Vec<Hop>> {
+    header_list(headers, "Forwarded").map(|elements| {
+        elements.iter().map(|element| {
+            let mut hop = Hop { addr: None, proto: None };
+            for pair in split_quoted(element, ';') {
+                let mut kv = pair.splitn(2, '=');
+                let key = kv.next().unwrap().trim();
+                let value = unquote(kv.next().unwrap_or("").trim());
+                if key.eq_ignore_ascii_case("for") {
+                    hop.addr = parse_node(&value);
+                } else if key.eq_ignore_ascii_case("proto") {
+                    hop.proto = Some(value);
+                }
+            }
+            hop
+        }).collect()
+    })
+}
+
+fn x_forwarded_hops(headers: &Headers) -> Option<Vec<Hop>> {
+    header_list(headers, "X-Forwarded-For").map(|addrs| {
+        let protos = header_list(headers, "X-Forwarded-Proto")
+            .unwrap_or(Vec::new());
+        // Protocol is added by the same proxies as addresses, so they are
+        // matched from the right
+        let skip = addrs.len() as isize - protos.len() as isize;
+        addrs.iter().enumerate().map(|(idx, addr)| {
+            let pidx = idx as isize - skip;
+            Hop {
+                addr: parse_node(addr),
+                proto: if pidx >= 0 {
+                    Some(protos[pidx as usize].to_string())
+                } else {
+                    None
+                },
+            }
+        }).collect()
+    })
+}
+
+fn is_trusted(addr: &IpAddr, trusted: &[Cidr]) -> bool {
+    trusted.iter().any(|cidr| cidr.contains(addr))
+}
+
+/// Fills in `Head::client_ip` and updates `Head::https`
+///
+/// The `Forwarded` header takes precedence over `X-Forwarded-*` when both
+/// are present.
+pub fn resolve(head: &mut Head, trusted: &[Cidr]) {
+    let peer = head.proxy.map(|p| p.source).or(head.peer_addr);
+    head.client_ip = peer.as_ref().map(socket_ip);
+    let mut current = match head.client_ip {
+        Some(ref ip) if is_trusted(ip, trusted) => ip.clone(),
+        _ => return,
+    };
+    let hops = match forwarded_hops(&head.headers)
+                     .or_else(|| x_forwarded_hops(&head.headers))
+    {
+        Some(hops) => hops,
+        None => return,
+    };
+    for hop in hops.into_iter().rev() {
+        if let Some(ref proto) = hop.proto {
+            if proto.eq_ignore_ascii_case("https") {
+                head.https = true;
+            } else if proto.eq_ignore_ascii_case("http") {
+                head.https = false;
+            }
+        }
+        match hop.addr {
+            Some(addr) => current = addr,
+            // Proxy doesn't know (or hides) it's client, so the proxy is
+            // the best approximation of the client we have
+            None => break,
+        }
+        if !is_trusted(&current, trusted) {
+            break;
+        }
+    }
+    head.client_ip = Some(current);
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use ip::IpAddr;
+    use hyper::header::Headers;
+    use server::{Head, MAX_HEADERS_NUM};
+    use super::{Cidr, CidrError, parse_node, split_quoted};
+    use super::{forwarded_hops, x_forwarded_hops, resolve};
+
+    fn ip(value: &str) -> IpAddr {
+        parse_node(value).unwrap()
+    }
+
+    /// Resolves request from the `peer` with `10.0.0.0/8` trusted
+    fn client(peer: &str, headers: &[&str]) -> (Option<IpAddr>, bool) {
+        let mut data = "GET / HTTP/1.1\r\nHost: x\r\n".to_string();
+        for line in headers {
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push_str("\r\n");
+        let mut head = Head::parse(data.as_bytes(), MAX_HEADERS_NUM)
+            .unwrap();
+        head.peer_addr = Some(peer.parse().unwrap());
+        resolve(&mut head, &[Cidr::from_str("10.0.0.0/8").unwrap()]);
+        (head.client_ip, head.https)
+    }
+
+    #[test]
+    fn cidr() {
+        let net = Cidr::from_str("10.1.0.0/16").unwrap();
+        assert!(net.contains(&ip("10.1.2.3")));
+        assert!(!net.contains(&ip("10.2.0.1")));
+        assert!(!net.contains(&ip("::1")));
+        let net = Cidr::from_str("192.168.1.129/25").unwrap();
+        assert!(net.contains(&ip("192.168.1.200")));
+        assert!(!net.contains(&ip("192.168.1.100")));
+        let net = Cidr::from_str("fd00::/8").unwrap();
+        assert!(net.contains(&ip("fdab::1")));
+        assert!(!net.contains(&ip("fe80::1")));
+        assert!(Cidr::from_str("127.0.0.1").unwrap()
+            .contains(&ip("127.0.0.1")));
+        assert!(Cidr::from_str("0.0.0.0/0").unwrap()
+            .contains(&ip("8.8.8.8")));
+        assert_eq!(Cidr::from_str("1.2.3.4/33"),
+                   Err(CidrError::InvalidPrefix));
+        assert_eq!(Cidr::from_str("1.2.3/8"), Err(CidrError::InvalidAddress));
+    }
+
+    #[test]
+    fn mapped_ipv4() {
+        let net = Cidr::from_str("10.1.0.0/16").unwrap();
+        assert!(net.contains(&ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(&ip("::ffff:10.2.0.1")));
+        assert!(!net.contains(&ip("::10.1.2.3")));
+        assert_eq!(client("[::ffff:10.0.0.1]:1234", &[
+            "X-Forwarded-For: 198.51.100.7",
+        ]), (Some(ip("198.51.100.7")), false));
+        assert_eq!(client("[::ffff:203.0.113.5]:1234", &[]),
+                   (Some(ip("203.0.113.5")), false));
+    }
+
+    #[test]
+    fn nodes() {
+        assert_eq!(parse_node("192.0.2.60"), parse_node("192.0.2.60:4711"));
+        assert_eq!(parse_node("[2001:db8:cafe::17]:4711"),
+                   parse_node("2001:db8:cafe::17"));
+        assert!(parse_node("unknown").is_none());
+        assert!(parse_node("_hidden").is_none());
+    }
+
+    #[test]
+    fn forwarded() {
+        assert_eq!(split_quoted(r#"a="x,y", b"#, ','),
+                   vec![r#"a="x,y""#, "b"]);
+        let mut headers = Headers::new();
+        headers.set_raw("Forwarded", vec![
+            b"for=192.0.2.43;proto=https, for=\"[2001:db8:cafe::17]:4711\""
+            .to_vec()]);
+        let hops = forwarded_hops(&headers).unwrap();
+        assert_eq!(hops.len(), 2);
+        assert_eq!(hops[0].addr, Some(ip("192.0.2.43")));
+        assert_eq!(hops[0].proto, Some("https".to_string()));
+        assert_eq!(hops[1].addr, Some(ip("2001:db8:cafe::17")));
+        assert_eq!(hops[1].proto, None);
+    }
+
+    #[test]
+    fn x_forwarded() {
+        let mut headers = Headers::new();
+        headers.set_raw("X-Forwarded-For",
+                        vec![b"1.1.1.1, 10.0.0.1".to_vec()]);
+        headers.set_raw("X-Forwarded-Proto", vec![b"https".to_vec()]);
+        let hops = x_forwarded_hops(&headers).unwrap();
+        assert_eq!(hops[0].proto, None);
+        assert_eq!(hops[1].addr, Some(ip("10.0.0.1")));
+        assert_eq!(hops[1].proto, Some("https".to_string()));
+    }
+
+    #[test]
+    fn untrusted_peer() {
+        assert_eq!(client("203.0.113.5:1234", &[
+            "X-Forwarded-For: 198.51.100.7",
+            "X-Forwarded-Proto: https",
+            "Forwarded: for=198.51.100.7;proto=https",
+        ]), (Some(ip("203.0.113.5")), false));
+    }
+
+    #[test]
+    fn no_headers() {
+        assert_eq!(client("10.0.0.1:1234", &[]),
+                   (Some(ip("10.0.0.1")), false));
+    }
+
+    #[test]
+    fn trusted_chain() {
+        assert_eq!(client("10.0.0.1:1234", &[
+            "X-Forwarded-For: 198.51.100.7, 10.0.0.3",
+            "X-Forwarded-For: 10.0.0.2",
+            "X-Forwarded-Proto: https",
+        ]), (Some(ip("198.51.100.7")), true));
+        assert_eq!(client("10.0.0.1:1234", &[
+            "Forwarded: for=198.51.100.7;proto=https",
+            "Forwarded: for=10.0.0.2;proto=http",
+        ]), (Some(ip("198.51.100.7")), true));
+    }
+
+    #[test]
+    fn spoofed_leftmost() {
+        // Client has sent the header itself, it's first untrusted hop
+        // from the right which is the client
+        assert_eq!(client("10.0.0.1:1234", &[
+            "X-Forwarded-For: 10.0.0.66, 6.6.6.6, 198.51.100.7, 10.0.0.2",
+        ]), (Some(ip("198.51.100.7")), false));
+        assert_eq!(client("10.0.0.1:1234", &[
+            "Forwarded: for=10.0.0.66;proto=https, for=198.51.100.7",
+        ]), (Some(ip("198.51.100.7")), false));
+    }
+
+    #[test]
+    fn unknown_hop() {
+        assert_eq!(client("10.0.0.1:1234", &[
+            "Forwarded: for=198.51.100.7, for=unknown, for=10.0.0.2",
+        ]), (Some(ip("10.0.0.2")), false));
+    }
+
+    #[test]
+    fn both_headers() {
+        assert_eq!(client("10.0.0.1:1234", &[
+            "X-Forwarded-For: 6.6.6.6",
+            "X-Forwarded-Proto: https",
+            "Forwarded: for=\"[2001:db8:cafe::17]:4711\";proto=http",
+        ]), (Some(ip("2001:db8:cafe::17")), false));
+    }
+}
@@ -0,0 +1,1768 @@
+//! HTTP/2 connection (RFC 7540)
+//!
+//! Every stream is served by its own `Server` state machine, exactly like
+//! a request of HTTP/1.x connection. The handler writes the response with
+//! the ordinary `Response` object into the buffer of the stream, and the
+//! buffer is converted into `HEADERS` and `DATA` frames as response is
+//! written. So handlers work unchanged for both protocols, the only
+//! difference they see is `Head::version`.
+//!
+//! Server push and stream priorities are not supported. There is no
+//! protocol switching in HTTP/2, so the stream is reset if the handler
+//! responds with `101 Switching Protocols`.
+use std::cmp::min;
+use std::mem::replace;
+use std::ascii::AsciiExt;
+use std::str::from_utf8;
+use std::marker::PhantomData;
+use std::collections::HashMap;
+
+use rotor::Scope;
+use rotor_stream::{Protocol, StreamSocket, Deadline, Expectation as E};
+use rotor_stream::{Request, Transport, Exception, Buf, MAX_BUF_SIZE};
+use hyper::status::StatusCode::{self, BadRequest, PayloadTooLarge};
+use hyper::status::StatusCode::{RequestTimeout, InternalServerError};
+use hyper::status::StatusCode::{RequestHeaderFieldsTooLarge};
+use hyper::version::HttpVersion as Version;
+use hyper::method::Method;
+use hyper::uri::RequestUri;
+use hyper::header::{Headers, Expect, ContentLength};
+use rustc_serialize::base64::FromBase64;
+
+use http2::{frame, hpack, PREFACE};
+use http2::frame::ErrorCode;
+use message::{MessageState, Body, Close};
+use chunked::{self, State as ChunkState};
+use super::{Response};
+use super::protocol::{Server, RecvMode};
+use super::context::Context;
+use super::request::Head;
+use super::response::state;
+use super::socket::{SocketInfo, fill_head};
+use super::proxy::ProxyAddrs;
+
+
+/// Connection-specific fields, which are not allowed in HTTP/2
+const CONNECTION_HEADERS: &'static [&'static str] = &[
+    "connection", "keep-alive", "proxy-connection", "transfer-encoding",
+    "upgrade",
+];
+
+/// HTTP/2 protocol for connections known to speak HTTP/2
+///
+/// Use it for listeners where HTTP/1.x clients must be rejected. The
+/// `Parser` switches to HTTP/2 itself when `h2` is negotiated with ALPN,
+/// when connection starts with the HTTP/2 preface, or when request asks
+/// for `Upgrade: h2c`.
+pub struct Http2<M: Server, S>(Engine<M>, PhantomData<*const S>);
+
+/// The state machine of HTTP/2 connection
+pub struct Engine<M: Server> {
+    state: State,
+    streams: HashMap<u32, Stream<M>>,
+    decoder: hpack::Decoder,
+    /// Header block which is being received: stream id, whether it ends
+    /// the stream, and fragments received so far
+    continuation: Option<(u32, bool, Vec<u8>)>,
+    /// The highest stream id opened by the client
+    last_stream_id: u32,
+    /// Connection flow-control window for sending
+    send_window: i64,
+    /// Initial window of new streams (`SETTINGS_INITIAL_WINDOW_SIZE`)
+    initial_window: i64,
+    max_frame_size: usize,
+    /// Peer has sent `GOAWAY`, no new streams are accepted
+    goaway: bool,
+    /// When the rest of partially received frame is due
+    frame_deadline: Option<Deadline>,
+    proxy: Option<ProxyAddrs>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for the client connection preface (whether our `SETTINGS`
+    /// are sent already)
+    Preface(bool),
+    /// Reading frames (bytes needed for the next frame)
+    Frames(usize),
+    /// `GOAWAY` is sent, connection is closed when output is flushed
+    Closing,
+}
+
+struct Stream<M> {
+    /// Handler, `None` when it has finished
+    machine: Option<M>,
+    response: MessageState,
+    /// Response as written by the handler (in HTTP/1.1 format)
+    buf: Buf,
+    output: Output,
+    recv: Recv,
+    /// Value of `Content-Length` of the request
+    content_length: Option<u64>,
+    /// Bytes of request body received
+    received: u64,
+    deadline: Deadline,
+}
+
+enum Recv {
+    /// Buffered request (limit, body received so far)
+    Buffered(usize, Buf),
+    /// Every `DATA` frame is passed to the handler
+    Progressive,
+    /// Request is rejected, the rest of the body is ignored
+    Discard,
+    /// End of stream is received
+    Done,
+}
+
+/// Converts the response written by the handler into frames
+struct Output {
+    state: OutState,
+    /// Response body which is not sent yet (without chunked framing)
+    data: Buf,
+    /// Encoded trailer fields
+    trailers: Vec<u8>,
+    /// Stream flow-control window for sending
+    window: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutState {
+    /// Waiting for the end of response headers
+    Headers,
+    /// Body is delimited by `Content-Length` (or there is no body)
+    Fixed,
+    /// Body has chunked encoding
+    Chunked(ChunkState),
+    /// Whole response is converted, but not necessarily sent
+    Done,
+    /// End of stream is sent
+    Sent,
+}
+
+fn is_connection_header(name: &str) -> bool {
+    CONNECTION_HEADERS.iter().any(|&x| x == name)
+}
+
+/// Builds request head from the decoded header block
+///
+/// Returns error for malformed requests (RFC 7540 section 8.1.2)
+fn request_head(fields: Vec<hpack::Field>) -> Result<Head, ()> {
+    let mut method = None;
+    let mut scheme = None;
+    let mut authority = None;
+    let mut path = None;
+    let mut cookies = Vec::new();
+    let mut headers = Headers::new();
+    let mut regular = false;
+    for (name, value) in fields {
+        let name = try!(String::from_utf8(name).map_err(|_| ()));
+        if name.starts_with(":") {
+            // Pseudo-header fields must precede regular ones
+            if regular {
+                return Err(());
+            }
+            let slot = match &name[..] {
+                ":method" => &mut method,
+                ":scheme" => &mut scheme,
+                ":authority" => &mut authority,
+                ":path" => &mut path,
+                _ => return Err(()),
+            };
+            if slot.is_some() {
+                return Err(());
+            }
+            *slot = Some(try!(String::from_utf8(value).map_err(|_| ())));
+            continue;
+        }
+        regular = true;
+        if name.len() == 0 || name.chars().any(|c| c.is_uppercase()) ||
+            is_connection_header(&name) ||
+            (name == "te" && value != b"trailers")
+        {
+            return Err(());
+        }
+        if name == "cookie" {
+            cookies.push(value);
+            continue;
+        }
+        let mut values = headers.get_raw(&name).map(|x| x.to_vec())
+            .unwrap_or(Vec::new());
+        values.push(value);
+        headers.set_raw(name, values);
+    }
+    let method = try!(try!(method.ok_or(())).parse::<Method>()
+        .map_err(|_| ()));
+    let uri = if method == Method::Connect {
+        if scheme.is_some() || path.is_some() {
+            return Err(());
+        }
+        RequestUri::Authority(try!(authority.clone().ok_or(())))
+    } else {
+        let path = try!(path.ok_or(()));
+        if scheme.is_none() || path.len() == 0 {
+            return Err(());
+        }
+        try!(path.parse::<RequestUri>().map_err(|_| ()))
+    };
+    if cookies.len() > 0 {
+        // Cookies may be split into multiple fields for better compression
+        let mut cookie = Vec::new();
+        for (i, value) in cookies.iter().enumerate() {
+            if i > 0 {
+                cookie.extend(b"; ".iter().cloned());
+            }
+            cookie.extend(value.iter().cloned());
+        }
+        headers.set_raw("Cookie", vec![cookie]);
+    }
+    if let Some(authority) = authority {
+        if headers.get_raw("Host").is_none() {
+            headers.set_raw("Host", vec![authority.into_bytes()]);
+        }
+    }
+    Ok(Head {
+        // Filled in by the engine, as it knows the socket
+        peer_addr: None,
+        local_addr: None,
+        proxy: None,
+        client_ip: None,
+        https: false,
+        server_name: None,
+        peer_certificate: None,
+        version: Version::Http20,
+        method: method,
+        uri: uri,
+        headers: headers,
+    })
+}
+
+/// Builds trailer fields from the decoded header block
+///
+/// Fields which determine message framing are not allowed, the same as
+/// for chunked requests of HTTP/1.x
+fn trailer_headers(fields: Vec<hpack::Field>) -> Option<Headers> {
+    let mut headers = Headers::new();
+    for (name, value) in fields {
+        let name = match String::from_utf8(name) {
+            Ok(name) => name,
+            Err(_) => return None,
+        };
+        if name.len() == 0 || name.starts_with(":") ||
+            name.chars().any(|c| c.is_uppercase()) ||
+            name == "content-length" || name == "transfer-encoding"
+        {
+            return None;
+        }
+        let mut values = headers.get_raw(&name).map(|x| x.to_vec())
+            .unwrap_or(Vec::new());
+        values.push(value);
+        headers.set_raw(name, values);
+    }
+    Some(headers)
+}
+
+/// Encodes status line and headers written by `Response` into the block
+///
+/// The `data` is everything up to (but not including) the empty line.
+/// Returns the block and whether the body has chunked encoding.
+fn response_headers(data: &[u8]) -> Result<(Vec<u8>, bool), ()> {
+    let text = try!(from_utf8(data).map_err(|_| ()));
+    let mut lines = text.split("\r\n");
+    let status = {
+        let mut words = lines.next().unwrap().splitn(3, ' ');
+        if !words.next().unwrap().starts_with("HTTP/1.") {
+            return Err(());
+        }
+        try!(words.next().and_then(|x| x.parse::<u16>().ok()).ok_or(()))
+    };
+    if status < 200 {
+        // Informational responses (and protocol switching) are not
+        // supported from the handler
+        return Err(());
+    }
+    let mut block = Vec::new();
+    let mut chunked = false;
+    hpack::encode(b":status", status.to_string().as_bytes(), &mut block);
+    for line in lines.filter(|x| x.len() > 0) {
+        let colon = try!(line.find(':').ok_or(()));
+        let name = line[..colon].trim().to_ascii_lowercase();
+        if name == "transfer-encoding" {
+            chunked = true;
+        }
+        if is_connection_header(&name) {
+            continue;
+        }
+        hpack::encode(name.as_bytes(), line[colon+1..].trim().as_bytes(),
+                      &mut block);
+    }
+    Ok((block, chunked))
+}
+
+/// Decodes `HTTP2-Settings` header (base64url encoded `SETTINGS` payload)
+fn upgrade_settings(value: &[u8]) -> Option<Vec<(u16, u32)>> {
+    value.from_base64().ok()
+        .and_then(|payload| frame::parse_settings(&payload))
+}
+
+fn write_settings<C: Context>(out: &mut Buf, scope: &mut Scope<C>) {
+    frame::write_settings(out, &[
+        (frame::SETTINGS_MAX_CONCURRENT_STREAMS, scope.http2_max_streams()),
+        (frame::SETTINGS_MAX_HEADER_LIST_SIZE,
+         scope.max_headers_size() as u32),
+        (frame::SETTINGS_ENABLE_PUSH, 0),
+    ]);
+}
+
+fn response_start(head: &Head) -> MessageState {
+    MessageState::ResponseStart {
+        // The version is never sent, response is converted into frames
+        version: Version::Http11,
+        body: if head.method == Method::Head { Body::Ignored }
+              else { Body::Normal },
+        // Trailers are always allowed in HTTP/2
+        trailers: true,
+        close: Close::No,
+    }
+}
+
+impl Output {
+    fn new(window: i64) -> Output {
+        Output {
+            state: OutState::Headers,
+            data: Buf::new(),
+            trailers: Vec::new(),
+            window: window,
+        }
+    }
+    /// Converts the response written by the handler so far
+    ///
+    /// Headers are written into `out` immediately, body is kept until
+    /// it's allowed by flow control (see `send()`). The `complete` is true
+    /// when handler has finished the response.
+    fn convert(&mut self, src: &mut Buf, complete: bool, id: u32,
+        out: &mut Buf, max_frame_size: usize)
+        -> Result<(), ()>
+    {
+        if self.state == OutState::Headers {
+            let end = match src[..].windows(4).position(|x| x == b"\r\n\r\n")
+            {
+                Some(end) => end,
+                None => return Ok(()),
+            };
+            let (block, chunked) = try!(response_headers(&src[..end]));
+            src.consume(end+4);
+            if complete && src.len() == 0 {
+                frame::write_headers(out, id, &block, true, max_frame_size);
+                self.state = OutState::Sent;
+                return Ok(());
+            }
+            frame::write_headers(out, id, &block, false, max_frame_size);
+            self.state = if chunked {
+                OutState::Chunked(ChunkState::Head)
+            } else {
+                OutState::Fixed
+            };
+        }
+        match self.state {
+            OutState::Fixed => {
+                self.data.extend(&src[..]);
+                let ln = src.len();
+                src.consume(ln);
+                if complete {
+                    self.state = OutState::Done;
+                }
+            }
+            OutState::Chunked(chunk) => {
+                self.state = try!(self.dechunk(src, chunk));
+                if complete && self.state != OutState::Done {
+                    // Response to HEAD request has no body even if chunked
+                    if src.len() > 0 {
+                        return Err(());
+                    }
+                    self.state = OutState::Done;
+                }
+            }
+            OutState::Headers | OutState::Done | OutState::Sent => {}
+        }
+        Ok(())
+    }
+    fn dechunk(&mut self, src: &mut Buf, mut chunk: ChunkState)
+        -> Result<OutState, ()>
+    {
+        loop {
+            match chunk {
+                ChunkState::Head | ChunkState::Trailers => {
+                    let end = match src[..].windows(2)
+                                            .position(|x| x == b"\r\n")
+                    {
+                        Some(end) => end,
+                        None => return Ok(OutState::Chunked(chunk)),
+                    };
+                    if chunk == ChunkState::Head {
+                        let head = try!(chunked::parse_head(&src[..end])
+                                        .map_err(|_| ()));
+                        chunk = if head.size == 0 { ChunkState::Trailers }
+                                else { ChunkState::Data(head.size) };
+                    } else if end == 0 {
+                        src.consume(2);
+                        return Ok(OutState::Done);
+                    } else {
+                        let (name, value) = try!(
+                            chunked::parse_trailer(&src[..end])
+                            .map_err(|_| ()));
+                        hpack::encode(name.to_ascii_lowercase().as_bytes(),
+                                      value, &mut self.trailers);
+                    }
+                    src.consume(end+2);
+                }
+                ChunkState::Data(left) => {
+                    let ln = min(left, src.len() as u64) as usize;
+                    if ln == 0 {
+                        return Ok(OutState::Chunked(chunk));
+                    }
+                    self.data.extend(&src[..ln]);
+                    src.consume(ln);
+                    chunk = if left == ln as u64 { ChunkState::DataEnd }
+                            else { ChunkState::Data(left - ln as u64) };
+                }
+                ChunkState::DataEnd => {
+                    if src.len() < 2 {
+                        return Ok(OutState::Chunked(chunk));
+                    }
+                    try!(chunked::check_data_end(&src[..]).map_err(|_| ()));
+                    src.consume(2);
+                    chunk = ChunkState::Head;
+                }
+            }
+        }
+    }
+    /// Sends as much of the body as allowed by flow control windows
+    fn send(&mut self, id: u32, out: &mut Buf, conn_window: &mut i64,
+        max_frame_size: usize)
+    {
+        loop {
+            match self.state {
+                OutState::Headers | OutState::Sent => return,
+                _ => {}
+            }
+            let done = self.state == OutState::Done;
+            if self.data.len() == 0 {
+                if done {
+                    if self.trailers.len() > 0 {
+                        frame::write_headers(out, id, &self.trailers, true,
+                                             max_frame_size);
+                    } else {
+                        frame::write(out, frame::DATA, frame::END_STREAM,
+                                     id, b"");
+                    }
+                    self.state = OutState::Sent;
+                }
+                return;
+            }
+            let allowed = min(min(self.window, *conn_window),
+                              max_frame_size as i64);
+            if allowed <= 0 {
+                return;
+            }
+            let ln = min(allowed as usize, self.data.len());
+            let last = done && ln == self.data.len() &&
+                       self.trailers.len() == 0;
+            frame::write(out, frame::DATA,
+                         if last { frame::END_STREAM } else { 0 },
+                         id, &self.data[..ln]);
+            self.data.consume(ln);
+            self.window -= ln as i64;
+            *conn_window -= ln as i64;
+            if last {
+                self.state = OutState::Sent;
+                return;
+            }
+        }
+    }
+}
+
+impl<M: Server> Stream<M> {
+    fn new(machine: M, head: Head, mode: RecvMode, deadline: Deadline,
+        end_stream: bool, window: i64, scope: &mut Scope<M::Context>)
+        -> Stream<M>
+    {
+        let mut stream = Stream {
+            machine: Some(machine),
+            response: response_start(&head),
+            buf: Buf::new(),
+            output: Output::new(window),
+            recv: match mode {
+                RecvMode::Buffered(x) => Recv::Buffered(x, Buf::new()),
+                RecvMode::Progressive(_) => Recv::Progressive,
+            },
+            content_length: head.headers.get::<ContentLength>()
+                            .map(|x| x.0),
+            received: 0,
+            deadline: deadline,
+        };
+        stream.call(scope,
+            |m, resp, scope| m.request_start(head, resp, scope));
+        if end_stream {
+            stream.end_of_request(scope);
+        }
+        stream
+    }
+    /// The stream which only responds with an error page
+    fn error(code: StatusCode, is_head: bool, end_stream: bool, window: i64,
+        scope: &mut Scope<M::Context>)
+        -> Stream<M>
+    {
+        let mut buf = Buf::new();
+        let response = {
+            let mut resp = Response::simple(&mut buf, is_head);
+            scope.emit_error_page(code, &mut resp);
+            state(resp)
+        };
+        Stream {
+            machine: None,
+            response: response,
+            buf: buf,
+            output: Output::new(window),
+            recv: if end_stream { Recv::Done } else { Recv::Discard },
+            content_length: None,
+            received: 0,
+            deadline: Deadline::now(),
+        }
+    }
+    /// Calls the handler with the response of the stream
+    ///
+    /// When handler gives up without starting a response, the
+    /// `500 Internal Server Error` is sent.
+    fn call<F>(&mut self, scope: &mut Scope<M::Context>, f: F)
+        where F: FnOnce(M, &mut Response, &mut Scope<M::Context>)
+                 -> Option<M>
+    {
+        let response = replace(&mut self.response,
+                               MessageState::Done { close: false });
+        let mut resp: Response = response.with(&mut self.buf);
+        if self.machine.is_some() {
+            self.machine = self.machine.take()
+                .and_then(|m| f(m, &mut resp, scope));
+            if self.machine.is_none() && !resp.is_started() {
+                scope.emit_error_page(InternalServerError, &mut resp);
+            }
+        }
+        self.response = state(resp);
+    }
+    /// Request is invalid, handler is notified and gets no more events
+    fn fail(&mut self, code: StatusCode, scope: &mut Scope<M::Context>) {
+        let response = replace(&mut self.response,
+                               MessageState::Done { close: false });
+        let mut resp: Response = response.with(&mut self.buf);
+        if let Some(m) = self.machine.take() {
+            m.bad_request(&mut resp, scope);
+            if !resp.is_started() {
+                scope.emit_error_page(code, &mut resp);
+            }
+        }
+        self.response = state(resp);
+    }
+    fn timeout(&mut self, scope: &mut Scope<M::Context>) {
+        let response = replace(&mut self.response,
+                               MessageState::Done { close: false });
+        let mut resp: Response = response.with(&mut self.buf);
+        let res = self.machine.take()
+            .and_then(|m| m.timeout(&mut resp, scope));
+        match res {
+            Some((m, deadline)) => {
+                self.machine = Some(m);
+                self.deadline = deadline;
+            }
+            None => {
+                if !resp.is_started() {
+                    scope.emit_error_page(RequestTimeout, &mut resp);
+                }
+            }
+        }
+        self.response = state(resp);
+    }
+    fn data(&mut self, data: &[u8], end_stream: bool,
+        scope: &mut Scope<M::Context>)
+        -> Result<(), ErrorCode>
+    {
+        self.received += data.len() as u64;
+        let too_long = self.content_length.map(|x| self.received > x)
+                       .unwrap_or(false);
+        let error = match self.recv {
+            Recv::Done => return Err(ErrorCode::StreamClosed),
+            Recv::Discard => None,
+            _ if too_long => Some(BadRequest),
+            Recv::Buffered(limit, ref buf)
+            if buf.len() + data.len() >= limit
+            => Some(PayloadTooLarge),
+            Recv::Buffered(..) | Recv::Progressive => None,
+        };
+        if let Some(code) = error {
+            self.recv = Recv::Discard;
+            self.fail(code, scope);
+        } else if matches!(self.recv, Recv::Progressive) {
+            self.call(scope,
+                |m, resp, scope| m.request_chunk(data, resp, scope));
+        } else if let Recv::Buffered(_, ref mut buf) = self.recv {
+            buf.extend(data);
+        }
+        if end_stream {
+            self.end_of_request(scope);
+        }
+        Ok(())
+    }
+    fn trailers(&mut self, trailers: Headers, scope: &mut Scope<M::Context>)
+        -> Result<(), ErrorCode>
+    {
+        match self.recv {
+            Recv::Done => return Err(ErrorCode::StreamClosed),
+            Recv::Discard | Recv::Buffered(..) | Recv::Progressive => {}
+        }
+        if trailers.len() > 0 && !matches!(self.recv, Recv::Discard) {
+            self.call(scope, |m, resp, scope| {
+                m.request_trailers(trailers, resp, scope)
+            });
+        }
+        self.end_of_request(scope);
+        Ok(())
+    }
+    fn end_of_request(&mut self, scope: &mut Scope<M::Context>) {
+        let recv = replace(&mut self.recv, Recv::Done);
+        if let Recv::Discard = recv {
+            return;
+        }
+        if self.content_length.map(|x| x != self.received).unwrap_or(false)
+        {
+            self.fail(BadRequest, scope);
+            return;
+        }
+        match recv {
+            Recv::Buffered(_, buf) => {
+                self.call(scope, |m, resp, scope| {
+                    m.request_received(&buf[..], resp, scope)
+                });
+            }
+            Recv::Progressive => {
+                self.call(scope, |m, resp, scope| m.request_end(resp, scope));
+            }
+            Recv::Discard | Recv::Done => {}
+        }
+    }
+    /// Converts and sends the response written so far
+    ///
+    /// Returns true when stream is finished, and error code if stream
+    /// must be reset
+    fn flush(&mut self, id: u32, out: &mut Buf, conn_window: &mut i64,
+        max_frame_size: usize)
+        -> Result<bool, ErrorCode>
+    {
+        let complete = match self.response {
+            MessageState::Done { .. } => true,
+            MessageState::Upgraded => return Err(ErrorCode::InternalError),
+            _ => false,
+        };
+        try!(self.output.convert(&mut self.buf, complete, id, out,
+                                 max_frame_size)
+             .map_err(|()| ErrorCode::InternalError));
+        self.output.send(id, out, conn_window, max_frame_size);
+        if self.output.state == OutState::Sent {
+            return match self.recv {
+                Recv::Done => Ok(true),
+                // The rest of the request is not needed any more
+                _ => Err(ErrorCode::NoError),
+            };
+        }
+        if self.machine.is_none() && !complete {
+            // Handler has given up in the middle of the response
+            return Err(ErrorCode::InternalError);
+        }
+        Ok(false)
+    }
+}
+
+impl<M: Server> Engine<M> {
+    /// Creates a connection which expects the client connection preface
+    ///
+    /// The `proxy` is the address received in the PROXY protocol preamble.
+    pub fn new(proxy: Option<ProxyAddrs>) -> Engine<M> {
+        Engine {
+            state: State::Preface(false),
+            streams: HashMap::new(),
+            decoder: hpack::Decoder::new(frame::DEFAULT_TABLE_SIZE as usize),
+            continuation: None,
+            last_stream_id: 0,
+            send_window: frame::DEFAULT_WINDOW_SIZE as i64,
+            initial_window: frame::DEFAULT_WINDOW_SIZE as i64,
+            max_frame_size: frame::DEFAULT_MAX_FRAME_SIZE as usize,
+            goaway: false,
+            frame_deadline: None,
+            proxy: proxy,
+        }
+    }
+    /// Creates a connection upgraded from HTTP/1.1 by `Upgrade: h2c`
+    ///
+    /// The `settings` is the value of `HTTP2-Settings` header of the
+    /// request. Returns `None` if it's not valid, in which case request
+    /// should be served by HTTP/1.1.
+    pub fn upgrade(proxy: Option<ProxyAddrs>, settings: &[u8])
+        -> Option<Engine<M>>
+    {
+        let settings = match upgrade_settings(settings) {
+            Some(settings) => settings,
+            None => return None,
+        };
+        let mut engine = Engine::new(proxy);
+        if engine.settings(settings).is_err() {
+            return None;
+        }
+        Some(engine)
+    }
+    /// Starts serving the request, which the connection was upgraded with
+    ///
+    /// The `101 Switching Protocols` response must already be in the output
+    /// buffer. The request becomes stream 1, which has no body.
+    pub fn start_upgraded<S>(mut self, head: Head,
+        transport: &mut Transport<S>, scope: &mut Scope<M::Context>)
+        -> Request<Engine<M>>
+        where S: StreamSocket
+    {
+        write_settings(transport.output(), scope);
+        self.state = State::Preface(true);
+        self.last_stream_id = 1;
+        self.start_stream(1, head, true, transport.output(), scope);
+        self.flush_streams(transport, scope)
+    }
+    pub fn request(self, scope: &mut Scope<M::Context>)
+        -> Request<Engine<M>>
+    {
+        let byte_dline = Deadline::now() + scope.byte_timeout();
+        let streams = self.streams.values()
+            .filter(|s| s.machine.is_some())
+            .map(|s| s.deadline)
+            .min();
+        let (exp, dline) = match (self.state, streams) {
+            (State::Preface(_), _) => (E::Bytes(PREFACE.len()), byte_dline),
+            (State::Closing, _) => (E::Flush(0), byte_dline),
+            (State::Frames(x), streams) => {
+                let dline = match (streams, self.frame_deadline) {
+                    (Some(a), Some(b)) => min(a, b),
+                    (Some(a), None) | (None, Some(a)) => a,
+                    // Connection is idle
+                    (None, None) => byte_dline,
+                };
+                (E::Bytes(x), dline)
+            }
+        };
+        Some((self, exp, dline))
+    }
+    /// Sends `GOAWAY` and closes connection when it's flushed
+    fn close<S: StreamSocket>(mut self, transport: &mut Transport<S>,
+        code: ErrorCode, scope: &mut Scope<M::Context>)
+        -> Request<Engine<M>>
+    {
+        frame::write_goaway(transport.output(), self.last_stream_id, code);
+        self.streams.clear();
+        self.state = State::Closing;
+        self.request(scope)
+    }
+    fn reset(&mut self, out: &mut Buf, id: u32, code: ErrorCode) {
+        frame::write_rst_stream(out, id, code);
+        self.streams.remove(&id);
+    }
+    fn frame<S>(&mut self, head: frame::Head, payload: &[u8],
+        transport: &mut Transport<S>, scope: &mut Scope<M::Context>)
+        -> Result<(), ErrorCode>
+        where S: StreamSocket + SocketInfo
+    {
+        use http2::frame::ErrorCode::*;
+        if let Some((id, _, _)) = self.continuation {
+            if head.kind != frame::CONTINUATION || head.stream_id != id {
+                return Err(ProtocolError);
+            }
+        }
+        match head.kind {
+            frame::DATA => self.data(head, payload, transport, scope),
+            frame::HEADERS => {
+                let id = head.stream_id;
+                if id == 0 || id % 2 == 0 {
+                    return Err(ProtocolError);
+                }
+                let (start, end) = try!(frame::strip(&head, payload)
+                                        .ok_or(ProtocolError));
+                self.continuation = Some((id,
+                    head.flags & frame::END_STREAM != 0,
+                    payload[start..end].to_vec()));
+                self.header_fragment(head.flags, transport, scope)
+            }
+            frame::CONTINUATION => {
+                match self.continuation {
+                    Some((_, _, ref mut block)) => {
+                        block.extend(payload.iter().cloned());
+                    }
+                    None => return Err(ProtocolError),
+                }
+                self.header_fragment(head.flags, transport, scope)
+            }
+            frame::SETTINGS => {
+                if head.stream_id != 0 {
+                    return Err(ProtocolError);
+                }
+                if head.flags & frame::ACK != 0 {
+                    return if payload.len() == 0 { Ok(()) }
+                           else { Err(FrameSizeError) };
+                }
+                let settings = try!(frame::parse_settings(payload)
+                                    .ok_or(FrameSizeError));
+                try!(self.settings(settings));
+                frame::write(transport.output(), frame::SETTINGS,
+                             frame::ACK, 0, b"");
+                Ok(())
+            }
+            frame::PING => {
+                if head.stream_id != 0 {
+                    return Err(ProtocolError);
+                }
+                if payload.len() != 8 {
+                    return Err(FrameSizeError);
+                }
+                if head.flags & frame::ACK == 0 {
+                    frame::write(transport.output(), frame::PING,
+                                 frame::ACK, 0, payload);
+                }
+                Ok(())
+            }
+            frame::GOAWAY => {
+                if head.stream_id != 0 {
+                    return Err(ProtocolError);
+                }
+                // Streams in progress are finished, but no new ones are
+                // accepted
+                self.goaway = true;
+                Ok(())
+            }
+            frame::WINDOW_UPDATE => {
+                if payload.len() != 4 {
+                    return Err(FrameSizeError);
+                }
+                let increment = frame::read_u32(payload) & 0x7FFFFFFF;
+                self.window_update(head.stream_id, increment as i64,
+                                   transport.output())
+            }
+            frame::RST_STREAM => {
+                if head.stream_id == 0 {
+                    return Err(ProtocolError);
+                }
+                if payload.len() != 4 {
+                    return Err(FrameSizeError);
+                }
+                if head.stream_id > self.last_stream_id {
+                    // Stream is idle
+                    return Err(ProtocolError);
+                }
+                self.streams.remove(&head.stream_id);
+                Ok(())
+            }
+            frame::PRIORITY => {
+                if head.stream_id == 0 {
+                    return Err(ProtocolError);
+                }
+                // Priorities are not supported
+                Ok(())
+            }
+            // Client can't push
+            frame::PUSH_PROMISE => Err(ProtocolError),
+            // Unknown frame types must be ignored
+            _ => Ok(()),
+        }
+    }
+    fn settings(&mut self, settings: Vec<(u16, u32)>)
+        -> Result<(), ErrorCode>
+    {
+        use http2::frame::ErrorCode::*;
+        for (id, value) in settings {
+            match id {
+                frame::SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(ProtocolError);
+                }
+                frame::SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if value as i64 > frame::MAX_WINDOW_SIZE {
+                        return Err(FlowControlError);
+                    }
+                    let delta = value as i64 - self.initial_window;
+                    self.initial_window = value as i64;
+                    for stream in self.streams.values_mut() {
+                        stream.output.window += delta;
+                        if stream.output.window > frame::MAX_WINDOW_SIZE {
+                            return Err(FlowControlError);
+                        }
+                    }
+                }
+                frame::SETTINGS_MAX_FRAME_SIZE => {
+                    if value < frame::DEFAULT_MAX_FRAME_SIZE ||
+                        value > 0xFFFFFF
+                    {
+                        return Err(ProtocolError);
+                    }
+                    self.max_frame_size = value as usize;
+                }
+                // Irrelevant, as we don't use dynamic table for encoding
+                frame::SETTINGS_HEADER_TABLE_SIZE => {}
+                // The rest only apply to the client
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+    fn window_update(&mut self, id: u32, increment: i64, out: &mut Buf)
+        -> Result<(), ErrorCode>
+    {
+        use http2::frame::ErrorCode::*;
+        if id == 0 {
+            if increment == 0 {
+                return Err(ProtocolError);
+            }
+            self.send_window += increment;
+            if self.send_window > frame::MAX_WINDOW_SIZE {
+                return Err(FlowControlError);
+            }
+            return Ok(());
+        }
+        let last_stream_id = self.last_stream_id;
+        let error = match self.streams.get_mut(&id) {
+            Some(_) if increment == 0 => Some(ProtocolError),
+            Some(stream) => {
+                stream.output.window += increment;
+                if stream.output.window > frame::MAX_WINDOW_SIZE {
+                    Some(FlowControlError)
+                } else {
+                    None
+                }
+            }
+            // Stream is idle
+            None if id > last_stream_id => return Err(ProtocolError),
+            // The stream might be just closed by us
+            None => None,
+        };
+        if let Some(code) = error {
+            self.reset(out, id, code);
+        }
+        Ok(())
+    }
+    fn data<S: StreamSocket>(&mut self, head: frame::Head, payload: &[u8],
+        transport: &mut Transport<S>, scope: &mut Scope<M::Context>)
+        -> Result<(), ErrorCode>
+    {
+        let id = head.stream_id;
+        if id == 0 {
+            return Err(ErrorCode::ProtocolError);
+        }
+        let (start, end) = try!(frame::strip(&head, payload)
+                                .ok_or(ErrorCode::ProtocolError));
+        let end_stream = head.flags & frame::END_STREAM != 0;
+        let last_stream_id = self.last_stream_id;
+        let result = match self.streams.get_mut(&id) {
+            Some(stream) => {
+                let res = stream.data(&payload[start..end], end_stream,
+                                      scope);
+                // Only the handler of progressive request has consumed the
+                // data. Buffered body is limited by the window opened when
+                // stream is started, and rejected body is not needed
+                let consumed = matches!(stream.recv, Recv::Progressive);
+                if res.is_ok() && consumed && payload.len() > 0 {
+                    frame::write_window_update(transport.output(), id,
+                                               payload.len() as u32);
+                }
+                res
+            }
+            None if id > last_stream_id => {
+                return Err(ErrorCode::ProtocolError);
+            }
+            None => Err(ErrorCode::StreamClosed),
+        };
+        if let Err(code) = result {
+            self.reset(transport.output(), id, code);
+        }
+        if payload.len() > 0 {
+            // Connection window is restored as soon as the frame is
+            // processed, because data is either passed to the handler, or
+            // dropped, or kept in the buffer limited by the stream window
+            frame::write_window_update(transport.output(), 0,
+                                       payload.len() as u32);
+        }
+        Ok(())
+    }
+    fn header_fragment<S>(&mut self, flags: u8,
+        transport: &mut Transport<S>, scope: &mut Scope<M::Context>)
+        -> Result<(), ErrorCode>
+        where S: StreamSocket + SocketInfo
+    {
+        let too_large = self.continuation.as_ref()
+            .map(|&(_, _, ref block)| block.len() > scope.max_headers_size())
+            .unwrap_or(false);
+        if too_large {
+            // Can't skip the block without decoding, as the compression
+            // state would be lost
+            return Err(ErrorCode::EnhanceYourCalm);
+        }
+        if flags & frame::END_HEADERS == 0 {
+            return Ok(());
+        }
+        let (id, end_stream, block) = self.continuation.take().unwrap();
+        let fields = match self.decoder.decode(&block,
+                                               scope.max_headers_size())
+        {
+            Ok(fields) => Ok(fields),
+            Err(hpack::HpackError::ListTooLarge) => {
+                Err(RequestHeaderFieldsTooLarge)
+            }
+            Err(_) => return Err(ErrorCode::CompressionError),
+        };
+        if id > self.last_stream_id {
+            self.open_stream(id, end_stream, fields, transport, scope);
+            return Ok(());
+        }
+        let result = match self.streams.get_mut(&id) {
+            Some(stream) => {
+                match fields.ok().and_then(trailer_headers) {
+                    Some(trailers) if end_stream => {
+                        stream.trailers(trailers, scope)
+                    }
+                    _ => Err(ErrorCode::ProtocolError),
+                }
+            }
+            None => Err(ErrorCode::StreamClosed),
+        };
+        if let Err(code) = result {
+            self.reset(transport.output(), id, code);
+        }
+        Ok(())
+    }
+    fn open_stream<S>(&mut self, id: u32, end_stream: bool,
+        fields: Result<Vec<hpack::Field>, StatusCode>,
+        transport: &mut Transport<S>, scope: &mut Scope<M::Context>)
+        where S: StreamSocket + SocketInfo
+    {
+        self.last_stream_id = id;
+        let window = self.initial_window;
+        if self.goaway ||
+            self.streams.len() >= scope.http2_max_streams() as usize
+        {
+            frame::write_rst_stream(transport.output(), id,
+                                    ErrorCode::RefusedStream);
+            return;
+        }
+        let fields = match fields {
+            Ok(fields) => fields,
+            Err(code) => {
+                let stream = Stream::error(code, false, end_stream, window,
+                                           scope);
+                self.streams.insert(id, stream);
+                return;
+            }
+        };
+        let mut head = match request_head(fields) {
+            Ok(head) => head,
+            Err(()) => {
+                frame::write_rst_stream(transport.output(), id,
+                                        ErrorCode::ProtocolError);
+                return;
+            }
+        };
+        fill_head(&mut head, transport.socket(), self.proxy,
+                  scope.trusted_proxies());
+        self.start_stream(id, head, end_stream, transport.output(), scope);
+    }
+    fn start_stream(&mut self, id: u32, head: Head, end_stream: bool,
+        out: &mut Buf, scope: &mut Scope<M::Context>)
+    {
+        let window = self.initial_window;
+        let is_head = head.method == Method::Head;
+        let content_length = head.headers.get::<ContentLength>()
+                             .map(|x| x.0);
+        let stream = match M::headers_received(&head, scope) {
+            Ok((_, RecvMode::Buffered(x), _)) if x >= MAX_BUF_SIZE
+            => panic!("Can't buffer {} bytes, max {}", x, MAX_BUF_SIZE),
+            Ok((_, RecvMode::Buffered(x), _))
+            if content_length.map(|n| n >= x as u64).unwrap_or(false)
+            => {
+                Stream::error(PayloadTooLarge, is_head, end_stream, window,
+                              scope)
+            }
+            Ok((m, mode, dline)) => {
+                if !end_stream &&
+                    head.headers.get::<Expect>() == Some(&Expect::Continue)
+                {
+                    // Handler has already approved request, so just push it
+                    let mut block = Vec::new();
+                    hpack::encode(b":status", b"100", &mut block);
+                    frame::write_headers(out, id, &block, false,
+                                         self.max_frame_size);
+                }
+                match mode {
+                    RecvMode::Buffered(x) if !end_stream &&
+                        x > frame::DEFAULT_WINDOW_SIZE as usize
+                    => {
+                        // Window is not restored until the whole body is
+                        // received, so let the peer send all of it
+                        frame::write_window_update(out, id,
+                            (x - frame::DEFAULT_WINDOW_SIZE as usize) as u32);
+                    }
+                    _ => {}
+                }
+                Stream::new(m, head, mode, dline, end_stream, window, scope)
+            }
+            Err(status) => {
+                Stream::error(status, is_head, end_stream, window, scope)
+            }
+        };
+        self.streams.insert(id, stream);
+    }
+    /// Writes out responses of all streams and forgets finished streams
+    fn flush_streams<S: StreamSocket>(mut self, transport: &mut Transport<S>,
+        scope: &mut Scope<M::Context>)
+        -> Request<Engine<M>>
+    {
+        let max_frame_size = self.max_frame_size;
+        let mut finished = Vec::new();
+        {
+            let out = transport.output();
+            for (&id, stream) in self.streams.iter_mut() {
+                match stream.flush(id, out, &mut self.send_window,
+                                   max_frame_size)
+                {
+                    Ok(false) => {}
+                    Ok(true) => finished.push((id, None)),
+                    Err(code) => finished.push((id, Some(code))),
+                }
+            }
+        }
+        for (id, code) in finished {
+            match code {
+                Some(code) => self.reset(transport.output(), id, code),
+                None => { self.streams.remove(&id); }
+            }
+        }
+        if self.goaway && self.streams.len() == 0 {
+            return self.close(transport, ErrorCode::NoError, scope);
+        }
+        self.request(scope)
+    }
+    pub fn bytes_read<S>(mut self, transport: &mut Transport<S>,
+        _end: usize, scope: &mut Scope<M::Context>)
+        -> Request<Engine<M>>
+        where S: StreamSocket + SocketInfo
+    {
+        let current = self.state;
+        match current {
+            State::Preface(settings_sent) => {
+                let ln = min(transport.input().len(), PREFACE.len());
+                if &transport.input()[..ln] != &PREFACE[..ln] {
+                    // Don't even try to respond, as we don't know if peer
+                    // speaks HTTP/2 at all
+                    return None;
+                }
+                if ln < PREFACE.len() {
+                    return self.request(scope);
+                }
+                transport.input().consume(PREFACE.len());
+                if !settings_sent {
+                    write_settings(transport.output(), scope);
+                }
+                self.state = State::Frames(frame::HEADER_SIZE);
+            }
+            State::Frames(_) => {}
+            // Spurious event, output is still flushing
+            State::Closing => return self.request(scope),
+        }
+        loop {
+            if transport.input().len() < frame::HEADER_SIZE {
+                self.state = State::Frames(frame::HEADER_SIZE);
+                break;
+            }
+            let head = frame::Head::parse(
+                &transport.input()[..frame::HEADER_SIZE]);
+            if head.length > frame::DEFAULT_MAX_FRAME_SIZE as usize {
+                return self.close(transport, ErrorCode::FrameSizeError,
+                                  scope);
+            }
+            let total = frame::HEADER_SIZE + head.length;
+            if transport.input().len() < total {
+                self.state = State::Frames(total);
+                break;
+            }
+            let payload = transport.input()[frame::HEADER_SIZE..total]
+                          .to_vec();
+            transport.input().consume(total);
+            if let Err(code) = self.frame(head, &payload, transport, scope) {
+                return self.close(transport, code, scope);
+            }
+        }
+        // Peer is given byte timeout to send the rest of the frame
+        self.frame_deadline = if transport.input().len() > 0 {
+            Some(Deadline::now() + scope.byte_timeout())
+        } else {
+            None
+        };
+        self.flush_streams(transport, scope)
+    }
+    pub fn bytes_flushed<S: StreamSocket>(self,
+        _transport: &mut Transport<S>, scope: &mut Scope<M::Context>)
+        -> Request<Engine<M>>
+    {
+        if self.state == State::Closing {
+            return None;
+        }
+        self.request(scope)
+    }
+    pub fn exception<S: StreamSocket>(self, _transport: &mut Transport<S>,
+        _exc: Exception, _scope: &mut Scope<M::Context>)
+        -> Request<Engine<M>>
+    {
+        // Neither limits nor end of stream are expected in any state
+        None
+    }
+    pub fn timeout<S: StreamSocket>(mut self, transport: &mut Transport<S>,
+        scope: &mut Scope<M::Context>)
+        -> Request<Engine<M>>
+    {
+        if self.state != State::Closing {
+            let now = Deadline::now();
+            let mut expired = false;
+            for stream in self.streams.values_mut() {
+                if stream.machine.is_some() && stream.deadline <= now {
+                    expired = true;
+                    stream.timeout(scope);
+                }
+            }
+            if expired {
+                return self.flush_streams(transport, scope);
+            }
+        }
+        let current = self.state;
+        match current {
+            State::Frames(_) => {
+                let now = Deadline::now();
+                if self.frame_deadline.is_none() &&
+                    transport.input().len() > 0
+                {
+                    // Part of the frame has arrived, but it isn't enough to
+                    // be processed. Give the peer time to send the rest
+                    self.frame_deadline = Some(now + scope.byte_timeout());
+                }
+                match self.frame_deadline {
+                    // There is no better code for the peer which doesn't
+                    // send the rest of the frame
+                    Some(dline) if dline <= now => {
+                        self.close(transport, ErrorCode::ProtocolError,
+                                   scope)
+                    }
+                    // Stream deadline was moved, wait for the next one
+                    Some(_) => self.request(scope),
+                    None if self.streams.values()
+                                .any(|s| s.machine.is_some())
+                    => self.request(scope),
+                    // Connection is idle
+                    None => self.close(transport, ErrorCode::NoError, scope),
+                }
+            }
+            State::Preface(_) | State::Closing => None,
+        }
+    }
+    pub fn wakeup<S: StreamSocket>(mut self, transport: &mut Transport<S>,
+        scope: &mut Scope<M::Context>)
+        -> Request<Engine<M>>
+    {
+        if self.state == State::Closing {
+            return self.request(scope);
+        }
+        for stream in self.streams.values_mut() {
+            stream.call(scope, |m, resp, scope| m.wakeup(resp, scope));
+        }
+        self.flush_streams(transport, scope)
+    }
+}
+
+fn wrap<M: Server, S>(req: Request<Engine<M>>) -> Request<Http2<M, S>> {
+    req.map(|(engine, exp, dline)| (Http2(engine, PhantomData), exp, dline))
+}
+
+impl<M: Server, S: StreamSocket + SocketInfo> Protocol for Http2<M, S> {
+    type Context = M::Context;
+    type Socket = S;
+    type Seed = ();
+    fn create(_seed: (), _sock: &mut S, scope: &mut Scope<M::Context>)
+        -> Request<Self>
+    {
+        wrap(Engine::new(None).request(scope))
+    }
+    fn bytes_read(self, transport: &mut Transport<S>, end: usize,
+        scope: &mut Scope<M::Context>)
+        -> Request<Self>
+    {
+        wrap(self.0.bytes_read(transport, end, scope))
+    }
+    fn bytes_flushed(self, transport: &mut Transport<S>,
+        scope: &mut Scope<M::Context>)
+        -> Request<Self>
+    {
+        wrap(self.0.bytes_flushed(transport, scope))
+    }
+    fn exception(self, transport: &mut Transport<S>, exc: Exception,
+        scope: &mut Scope<M::Context>)
+        -> Request<Self>
+    {
+        wrap(self.0.exception(transport, exc, scope))
+    }
+    fn timeout(self, transport: &mut Transport<S>,
+        scope: &mut Scope<M::Context>)
+        -> Request<Self>
+    {
+        wrap(self.0.timeout(transport, scope))
+    }
+    fn wakeup(self, transport: &mut Transport<S>,
+        scope: &mut Scope<M::Context>)
+        -> Request<Self>
+    {
+        wrap(self.0.wakeup(transport, scope))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::net::TcpStream as StdStream;
+
+    use rotor::{self, Scope};
+    use rotor::mio::tcp::TcpListener;
+    use rotor_stream::{Accept, Stream, Deadline, Buf};
+    use hyper::method::Method;
+    use hyper::uri::RequestUri;
+    use hyper::version::HttpVersion;
+    use hyper::status::StatusCode;
+    use hyper::header::ContentLength;
+    use time::Duration;
+
+    use http2::{frame, hpack, PREFACE};
+    use http2::frame::ErrorCode;
+    use server::{self, Server, Head, Response, RecvMode, NoUpgrade};
+    use test_util::read_some;
+    use super::{request_head, trailer_headers, upgrade_settings};
+    use super::{Output, OutState, Http2};
+
+    fn fields(list: &[(&str, &str)]) -> Vec<hpack::Field> {
+        list.iter()
+            .map(|&(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    /// Splits the buffer into frames (incomplete frame at the end is
+    /// skipped)
+    fn frames(buf: &[u8]) -> Vec<(frame::Head, Vec<u8>)> {
+        let mut result = Vec::new();
+        let mut data = buf;
+        while data.len() >= frame::HEADER_SIZE {
+            let head = frame::Head::parse(&data[..frame::HEADER_SIZE]);
+            let end = frame::HEADER_SIZE + head.length;
+            if data.len() < end {
+                break;
+            }
+            result.push((head, data[frame::HEADER_SIZE..end].to_vec()));
+            data = &data[end..];
+        }
+        result
+    }
+
+    fn decode(block: &[u8]) -> Vec<(String, String)> {
+        hpack::Decoder::new(4096).decode(block, 65536).unwrap()
+            .into_iter()
+            .map(|(n, v)| (String::from_utf8(n).unwrap(),
+                           String::from_utf8(v).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn head() {
+        let head = request_head(fields(&[
+            (":method", "GET"), (":scheme", "https"),
+            (":authority", "example.com"), (":path", "/index.html"),
+            ("cookie", "a=1"), ("accept", "*/*"), ("cookie", "b=2"),
+        ])).unwrap();
+        assert_eq!(head.method, Method::Get);
+        assert_eq!(head.version, HttpVersion::Http20);
+        assert_eq!(head.uri,
+                   RequestUri::AbsolutePath("/index.html".to_string()));
+        assert_eq!(head.headers.get_raw("Host").unwrap(),
+                   &[b"example.com".to_vec()][..]);
+        assert_eq!(head.headers.get_raw("Cookie").unwrap(),
+                   &[b"a=1; b=2".to_vec()][..]);
+        let head = request_head(fields(&[
+            (":method", "CONNECT"), (":authority", "example.com:443"),
+        ])).unwrap();
+        assert_eq!(head.uri,
+                   RequestUri::Authority("example.com:443".to_string()));
+    }
+
+    #[test]
+    fn malformed_head() {
+        let ok = [(":method", "GET"), (":scheme", "http"), (":path", "/")];
+        assert!(request_head(fields(&ok)).is_ok());
+        // Missing pseudo-header
+        assert!(request_head(fields(&ok[..2])).is_err());
+        // Pseudo-header after regular one
+        assert!(request_head(fields(&[ok[0], ok[1], ("accept", "*/*"),
+                                      ok[2]])).is_err());
+        // Duplicate and unknown pseudo-headers
+        assert!(request_head(fields(&[ok[0], ok[1], ok[2], ok[2]]))
+                .is_err());
+        assert!(request_head(fields(&[ok[0], ok[1], ok[2],
+                                      (":status", "200")])).is_err());
+        // Uppercase and connection-specific fields
+        assert!(request_head(fields(&[ok[0], ok[1], ok[2],
+                                      ("Accept", "*/*")])).is_err());
+        assert!(request_head(fields(&[ok[0], ok[1], ok[2],
+                                      ("connection", "close")])).is_err());
+        assert!(request_head(fields(&[ok[0], ok[1], ok[2],
+                                      ("te", "gzip")])).is_err());
+        assert!(request_head(fields(&[ok[0], ok[1], ok[2],
+                                      ("te", "trailers")])).is_ok());
+    }
+
+    #[test]
+    fn trailers() {
+        let headers = trailer_headers(fields(&[("x-checksum", "abc")]))
+            .unwrap();
+        assert_eq!(headers.get_raw("X-Checksum").unwrap(),
+                   &[b"abc".to_vec()][..]);
+        assert!(trailer_headers(fields(&[(":path", "/")])).is_none());
+        assert!(trailer_headers(fields(&[("content-length", "1")]))
+                .is_none());
+    }
+
+    #[test]
+    fn fixed_response() {
+        let mut src = Buf::new();
+        let mut out = Buf::new();
+        let mut output = Output::new(3);
+        let mut window = 100;
+        src.write(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n").unwrap();
+        output.convert(&mut src, false, 1, &mut out, 16384).unwrap();
+        assert_eq!(out.len(), 0);
+        src.write(b"Connection: close\r\n\r\nhello").unwrap();
+        output.convert(&mut src, true, 1, &mut out, 16384).unwrap();
+        output.send(1, &mut out, &mut window, 16384);
+        let list = frames(&out);
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].0.kind, frame::HEADERS);
+        assert_eq!(list[0].0.flags, frame::END_HEADERS);
+        assert_eq!(decode(&list[0].1), vec![
+            (":status".to_string(), "200".to_string()),
+            ("content-length".to_string(), "5".to_string())]);
+        // Only three bytes fit into the window of the stream
+        assert_eq!(list[1].0.flags, 0);
+        assert_eq!(&list[1].1[..], b"hel");
+        assert_eq!(window, 97);
+        out = Buf::new();
+        output.window += 10;
+        output.send(1, &mut out, &mut window, 16384);
+        let list = frames(&out);
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].0.flags, frame::END_STREAM);
+        assert_eq!(&list[0].1[..], b"lo");
+        assert_eq!(output.state, OutState::Sent);
+    }
+
+    #[test]
+    fn chunked_response() {
+        let mut src = Buf::new();
+        let mut out = Buf::new();
+        let mut output = Output::new(100);
+        let mut window = 100;
+        src.write(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5\r\nhello\r\n6\r\n world\r\n0\r\nX-Sum: 1\r\n\r\n")
+            .unwrap();
+        output.convert(&mut src, true, 3, &mut out, 16384).unwrap();
+        assert_eq!(src.len(), 0);
+        output.send(3, &mut out, &mut window, 16384);
+        let list = frames(&out);
+        assert_eq!(list.len(), 3);
+        assert_eq!(decode(&list[0].1), vec![
+            (":status".to_string(), "200".to_string())]);
+        assert_eq!(&list[1].1[..], b"hello world");
+        assert_eq!(list[1].0.flags, 0);
+        assert_eq!(list[2].0.kind, frame::HEADERS);
+        assert_eq!(list[2].0.flags, frame::END_STREAM|frame::END_HEADERS);
+        assert_eq!(decode(&list[2].1), vec![
+            ("x-sum".to_string(), "1".to_string())]);
+    }
+
+    #[test]
+    fn no_body() {
+        let mut src = Buf::new();
+        let mut out = Buf::new();
+        let mut output = Output::new(100);
+        src.write(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+        output.convert(&mut src, true, 1, &mut out, 16384).unwrap();
+        let list = frames(&out);
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].0.flags, frame::END_STREAM|frame::END_HEADERS);
+        assert_eq!(output.state, OutState::Sent);
+        let mut src = Buf::new();
+        src.write(b"HTTP/1.1 101 Switching Protocols\r\n\r\n").unwrap();
+        assert!(Output::new(100).convert(&mut src, true, 1, &mut out, 16384)
+                .is_err());
+    }
+
+    #[test]
+    fn h2c_settings() {
+        // Padding is omitted in base64url
+        assert_eq!(upgrade_settings(b"AAMAAABkAAQAAP__"),
+                   Some(vec![(3, 100), (4, 65535)]));
+        assert_eq!(upgrade_settings(b""), Some(vec![]));
+        assert_eq!(upgrade_settings(b"AAMA"), None);
+        assert_eq!(upgrade_settings(b"!!"), None);
+    }
+
+    struct Context;
+
+    impl server::Context for Context {
+        fn byte_timeout(&self) -> Duration {
+            Duration::milliseconds(200)
+        }
+    }
+
+    /// Reads request body and responds with `ok`
+    struct Upload;
+
+    impl Upload {
+        fn respond(res: &mut Response) {
+            res.status(StatusCode::Ok);
+            res.add_header(ContentLength(2)).unwrap();
+            res.done_headers().unwrap();
+            res.write_body(b"ok");
+            res.done();
+        }
+    }
+
+    impl Server for Upload {
+        type Context = Context;
+        type Upgrade = NoUpgrade<Context>;
+        fn headers_received(head: &Head, _scope: &mut Scope<Context>)
+            -> Result<(Self, RecvMode, Deadline), StatusCode>
+        {
+            let mode = match head.uri {
+                RequestUri::AbsolutePath(ref p) if p == "/buffered" => {
+                    RecvMode::Buffered(100000)
+                }
+                _ => RecvMode::Progressive(16384),
+            };
+            Ok((Upload, mode, Deadline::now() + Duration::seconds(10)))
+        }
+        fn request_start(self, _head: Head, _res: &mut Response,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            Some(self)
+        }
+        fn request_received(self, _data: &[u8], res: &mut Response,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            Upload::respond(res);
+            None
+        }
+        fn request_chunk(self, _chunk: &[u8], _res: &mut Response,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            Some(self)
+        }
+        fn request_end(self, res: &mut Response,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            Upload::respond(res);
+            None
+        }
+        fn timeout(self, _res: &mut Response, _scope: &mut Scope<Context>)
+            -> Option<(Self, Deadline)>
+        {
+            None
+        }
+        fn wakeup(self, _res: &mut Response, _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            Some(self)
+        }
+    }
+
+    /// Starts the server and makes HTTP/2 connection to it
+    macro_rules! connect {
+        ($event_loop:ident, $handler:ident, $peer:ident) => {
+            let mut $event_loop = rotor::EventLoop::new().unwrap();
+            let mut $handler = rotor::Handler::new(Context,
+                                                   &mut $event_loop);
+            let lst = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())
+                .unwrap();
+            let addr = lst.local_addr().unwrap();
+            assert!($handler.add_machine_with(&mut $event_loop, |scope| {
+                Accept::<Stream<Http2<Upload, _>>, _>::new(lst, scope)
+            }).is_ok());
+            let mut $peer = StdStream::connect(addr).unwrap();
+            let mut buf = Buf::new();
+            buf.write(PREFACE).unwrap();
+            frame::write_settings(&mut buf, &[]);
+            $peer.write_all(&buf[..]).unwrap();
+            let mut data = Vec::new();
+            // Server settings and acknowledgement of ours
+            run_until!($event_loop, $handler, {
+                read_some(&mut $peer, &mut data);
+                frames(&data).len() == 2
+            });
+        }
+    }
+
+    fn send(peer: &mut StdStream, fun: &Fn(&mut Buf)) {
+        let mut buf = Buf::new();
+        fun(&mut buf);
+        peer.write_all(&buf[..]).unwrap();
+    }
+
+    fn request(path: &str, buf: &mut Buf) {
+        let mut block = Vec::new();
+        hpack::encode(b":method", b"POST", &mut block);
+        hpack::encode(b":scheme", b"http", &mut block);
+        hpack::encode(b":path", path.as_bytes(), &mut block);
+        hpack::encode(b":authority", b"localhost", &mut block);
+        frame::write_headers(buf, 1, &block, false, 16384);
+    }
+
+    /// Stream id and increment of every `WINDOW_UPDATE`
+    fn window_updates(data: &[u8]) -> Vec<(u32, u32)> {
+        frames(data).into_iter()
+            .filter(|&(head, _)| head.kind == frame::WINDOW_UPDATE)
+            .map(|(head, payload)| (head.stream_id, frame::read_u32(&payload)))
+            .collect()
+    }
+
+    /// Error code of the `GOAWAY` which is the last frame received
+    fn goaway(data: &[u8]) -> u32 {
+        let frames = frames(data);
+        let &(head, ref payload) = frames.last().unwrap();
+        assert_eq!(head.kind, frame::GOAWAY);
+        frame::read_u32(&payload[4..])
+    }
+
+    fn response_done(data: &[u8]) -> bool {
+        frames(data).iter().any(|&(head, _)| {
+            head.kind == frame::DATA && head.flags & frame::END_STREAM != 0
+        })
+    }
+
+    #[test]
+    fn buffered_body_window() {
+        connect!(event_loop, handler, peer);
+        send(&mut peer, &|buf| {
+            request("/buffered", buf);
+            frame::write(buf, frame::DATA, 0, 1, &[b'x'; 16384]);
+        });
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            window_updates(&data).len() == 2
+        });
+        // Window of the stream is opened for the whole buffer, and is not
+        // restored when data is received
+        assert_eq!(window_updates(&data), vec![
+            (1, 100000 - 65535),
+            (0, 16384),
+        ]);
+        send(&mut peer, &|buf| {
+            for _ in 0..4 {
+                frame::write(buf, frame::DATA, 0, 1, &[b'x'; 16384]);
+            }
+            frame::write(buf, frame::DATA, frame::END_STREAM, 1, b"xyz");
+        });
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            response_done(&data)
+        });
+        assert_eq!(window_updates(&data), vec![
+            (1, 100000 - 65535),
+            (0, 16384), (0, 16384), (0, 16384), (0, 16384), (0, 16384),
+            (0, 3),
+        ]);
+    }
+
+    #[test]
+    fn progressive_body_window() {
+        connect!(event_loop, handler, peer);
+        send(&mut peer, &|buf| {
+            request("/progressive", buf);
+            frame::write(buf, frame::DATA, 0, 1, &[b'x'; 1000]);
+        });
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            window_updates(&data).len() == 2
+        });
+        // Handler has consumed the data
+        assert_eq!(window_updates(&data), vec![(1, 1000), (0, 1000)]);
+        send(&mut peer, &|buf| {
+            frame::write(buf, frame::DATA, frame::END_STREAM, 1, b"xyz");
+        });
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            response_done(&data)
+        });
+        assert_eq!(window_updates(&data), vec![
+            (1, 1000), (0, 1000), (0, 3),
+        ]);
+    }
+
+    #[test]
+    fn idle_stream_window_update() {
+        connect!(event_loop, handler, peer);
+        send(&mut peer, &|buf| frame::write_window_update(buf, 5, 100));
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, !read_some(&mut peer, &mut data));
+        assert_eq!(goaway(&data), ErrorCode::ProtocolError as u32);
+    }
+
+    #[test]
+    fn stalled_frame() {
+        connect!(event_loop, handler, peer);
+        send(&mut peer, &|buf| {
+            // Only part of the header of the PING frame
+            buf.write(&[0, 0, 8, frame::PING]).unwrap();
+        });
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, !read_some(&mut peer, &mut data));
+        assert_eq!(goaway(&data), ErrorCode::ProtocolError as u32);
+    }
+
+    #[test]
+    fn waiting_stream() {
+        connect!(event_loop, handler, peer);
+        send(&mut peer, &|buf| request("/progressive", buf));
+        // Connection is not closed while the handler waits for the body,
+        // even if it's longer than byte timeout
+        let mut data = Vec::new();
+        for _ in 0..50 {
+            event_loop.run_once(&mut handler, Some(10)).unwrap();
+            assert!(read_some(&mut peer, &mut data));
+        }
+        assert!(frames(&data).iter()
+                .all(|&(head, _)| head.kind != frame::GOAWAY));
+        send(&mut peer, &|buf| {
+            frame::write(buf, frame::DATA, frame::END_STREAM, 1, b"");
+        });
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            response_done(&data)
+        });
+        // Then it's idle and closed gracefully
+        run_until!(event_loop, handler, !read_some(&mut peer, &mut data));
+        assert_eq!(goaway(&data), ErrorCode::NoError as u32);
+    }
+}
@@ -1,7 +1,13 @@
 //! HTTP Server implementation
 //!
-//! Currently there is only HTTP/1.x implementation. We want to provide
-//! HTTP/2.0 and HTTPS
+//! Both HTTP/1.x and HTTP/2 are served by the same `Server` handlers.
+//! `Parser` serves HTTP/1.x and switches to HTTP/2 when it's negotiated
+//! with TLS ALPN. If `Context::http2_cleartext()` is enabled, it also
+//! switches when connection starts with the HTTP/2 preface (prior
+//! knowledge), or when the request asks for `Upgrade: h2c`. Use `Http2`
+//! for listeners which accept only HTTP/2.
+//!
+//! HTTPS is supported with the `tls` feature, see `TlsListener`.
 //!
 mod request;
 mod protocol;
@@ -9,6 +15,13 @@ mod context;
 mod parser;
 mod body;
 mod response;
+mod upgrade;
+mod pipeline;
+mod socket;
+mod proxy;
+mod forwarded;
+mod http2;
+#[cfg(feature="tls")] mod tls;
 
 use hyper::method::Method::Head;
 
@@ -17,14 +30,22 @@ pub use self::response::Response;
 pub use self::context::Context;
 pub use self::protocol::{RecvMode, Server};
 pub use self::parser::Parser;
+pub use self::http2::Http2;
+pub use self::upgrade::{Upgrade, NoUpgrade};
+pub use self::socket::{SocketInfo, PeerCertificate};
+pub use self::proxy::{ProxyProtocol, ProxyAddrs};
+pub use self::forwarded::{Cidr, CidrError};
+#[cfg(feature="tls")]
+pub use self::tls::{TlsAcceptor, TlsStream, TlsListener};
+#[cfg(feature="tls")]
+pub use self::tls::{CertResolver, ClientAuth};
 
-// TODO(tailhook) MAX_HEADERS_SIZE can be moved to Context
-// (i.e. made non-constant), but it's more of a problem for MAX_HEADERS_NUM
-// because that would mean we can't allocate array of headers on the stack
-// so performance will degrade. Customizing MAX_HEADERS_SIZE is not very
-// useful on it's own
+// The constants below are defaults for the respective `Context` methods
 
 /// Note httparse requires we preallocate array of this size so be wise
+///
+/// The array of this size is allocated on the stack, if `Context` allows
+/// more headers, the array is allocated on the heap for each request.
 pub const MAX_HEADERS_NUM: usize = 256;
 /// This one is not preallocated, but too large buffer is of limited use
 /// because of previous parameter.
@@ -1,31 +1,48 @@
 use std::cmp::min;
-use std::str::from_utf8;
+use std::mem::{replace, swap};
+use std::ascii::AsciiExt;
 use std::marker::PhantomData;
 
-use rotor_stream::MAX_BUF_SIZE;
+use rotor_stream::{Buf, MAX_BUF_SIZE};
 use rotor::Scope;
 use rotor_stream::{Protocol, StreamSocket, Deadline, Expectation as E};
 use rotor_stream::{Request, Transport, Exception};
 use hyper::status::StatusCode::{PayloadTooLarge, BadRequest, RequestTimeout};
 use hyper::status::StatusCode::{self, RequestHeaderFieldsTooLarge};
 use hyper::method::Method::Head;
-use hyper::header::Expect;
+use hyper::header::{Expect, Headers};
+use hyper::version::HttpVersion as Version;
 
-use super::{MAX_HEADERS_SIZE, MAX_CHUNK_HEAD};
 use super::{Response};
 use super::protocol::{Server, RecvMode};
 use super::context::Context;
 use super::request::Head;
 use super::body::BodyKind;
-use super::response::state;
+use super::response::{state, buffer};
 use message::{MessageState};
+use chunked::{self, State as ChunkState, ChunkError, Extensions};
+use chunked::add_trailer;
+use super::upgrade::Upgrade;
+use super::socket::{SocketInfo, fill_head};
+use super::proxy::{self, ProxyProtocol, ProxyAddrs};
+use super::pipeline::{Pipeline, Queued};
+use super::http2::Engine;
+use http2::PREFACE;
+use headers::has_token;
 
 
+/// Bytes of the HTTP/2 preface which are enough to tell it from HTTP/1.x
+const PREFACE_PREFIX: usize = 4;
+
 struct ReadBody<M: Server> {
     machine: Option<M>,
     deadline: Deadline,
     progress: BodyProgress,
     response: MessageState,
+    /// Trailer fields of chunked request, received so far
+    trailers: Headers,
+    /// Whether subsequent requests may be read before this one is finished
+    pipelining: bool,
 }
 
 pub enum BodyProgress {
@@ -34,30 +51,50 @@ pub enum BodyProgress {
     /// Buffered request till end of input (byte limit)
     BufferEOF(usize),
     /// Buffered request with chunked encoding
-    /// (limit, bytes buffered, bytes left for current chunk)
-    BufferChunked(usize, usize, usize),
+    /// (limit, bytes buffered, chunk state)
+    BufferChunked(usize, usize, ChunkState),
     /// Progressive fixed-size request (size hint, bytes left)
     ProgressiveFixed(usize, u64),
     /// Progressive till end of input (size hint)
     ProgressiveEOF(usize),
     /// Progressive with chunked encoding
-    /// (hint, offset, bytes left for current chunk)
-    ProgressiveChunked(usize, usize, u64),
+    /// (hint, offset, chunk state)
+    ProgressiveChunked(usize, usize, ChunkState),
 }
 
-pub struct Parser<M, S>(ParserImpl<M>, PhantomData<*const S>)
+pub struct Parser<M, S>(ParserImpl<M>, Connection, PhantomData<*const S>)
     where M: Server, S: StreamSocket;
 
+/// The state which is kept for the whole connection
+#[derive(Clone, Copy, Default)]
+struct Connection {
+    /// Addresses received in the PROXY protocol preamble
+    proxy: Option<ProxyAddrs>,
+}
+
 enum ParserImpl<M: Server> {
+    /// Waiting for PROXY protocol preamble (bytes needed)
+    Preamble(usize),
+    /// Waiting for the first request, connection may start with the
+    /// HTTP/2 preface instead
+    Start,
     Idle,
     ReadHeaders,
     ReadingBody(ReadBody<M>),
     /// Close connection after buffer is flushed. In other cases -> Idle
-    Processing(M, MessageState, Deadline),
+    ///
+    /// Requests read ahead of this one are in the pipeline
+    Processing(M, MessageState, Deadline, Pipeline<M>),
     DoneResponse,
+    /// Flushing `101 Switching Protocols` response
+    Upgrading(M::Upgrade),
+    /// Connection is switched to another protocol
+    Upgraded(M::Upgrade),
+    /// Connection speaks HTTP/2
+    Http2(Engine<M>),
 }
 
-impl<M: Server, S: StreamSocket> Parser<M, S> {
+impl<M: Server, S: StreamSocket + SocketInfo> Parser<M, S> {
     fn flush(scope: &mut Scope<M::Context>) -> Request<Parser<M, S>>
     {
         Some((ParserImpl::DoneResponse.wrap(), E::Flush(0),
@@ -82,32 +119,238 @@ impl<M: Server, S: StreamSocket> Parser<M, S> {
         let resp = Response::simple(transport.output(), false);
         Parser::error(scope, resp, code)
     }
-    fn complete<'x, C>(scope: &mut Scope<C>, machine: Option<M>,
-        response: Response<'x>, deadline: Deadline)
+    fn processing(scope: &mut Scope<M::Context>, machine: M,
+        response: MessageState, deadline: Deadline, pipeline: Pipeline<M>)
         -> Request<Parser<M, S>>
-        where C: Context
     {
+        let dline = pipeline.deadline().map_or(deadline,
+                                               |x| min(x, deadline));
+        let exp = if pipeline.can_read(scope.pipeline_depth(),
+                                       scope.max_pipeline_buffer())
+        {
+            E::Delimiter(0, b"\r\n\r\n", scope.max_headers_size())
+        } else {
+            E::Sleep
+        };
+        Some((ParserImpl::Processing(machine, response, deadline, pipeline)
+              .wrap(),
+              exp, dline))
+    }
+    fn complete<'x>(scope: &mut Scope<M::Context>, machine: Option<M>,
+        response: Response<'x>, deadline: Deadline, mut pipeline: Pipeline<M>)
+        -> Request<Parser<M, S>>
+    {
+        if response.is_upgraded() {
+            return match machine.and_then(|m| m.upgrade(scope)) {
+                Some(proto) => ParserImpl::Upgrading(proto).request(scope),
+                // Nothing to do with the connection except closing
+                None => Parser::flush(scope),
+            };
+        }
         match machine {
             Some(m) => {
-                Some((ParserImpl::Processing(m, state(response), deadline)
-                      .wrap(),
-                    E::Sleep, deadline))
+                Parser::processing(scope, m, state(response), deadline,
+                                   pipeline)
             }
             None => {
                 // TODO(tailhook) probably we should do something better than
                 // an assert?
                 assert!(response.is_complete());
-                ParserImpl::Idle.request(scope)
+                if response.is_close() {
+                    return Parser::flush(scope);
+                }
+                match pipeline.pop() {
+                    Some(next) => {
+                        // The next response is the current one now
+                        let out = buffer(response);
+                        out.extend(&next.buf[..]);
+                        let mut resp = next.response.with(out);
+                        let machine = if next.woken {
+                            // Wakeup was postponed while buffers were full
+                            next.machine.and_then(
+                                |m| m.wakeup(&mut resp, scope))
+                        } else {
+                            next.machine
+                        };
+                        if machine.is_none() && !resp.is_complete() {
+                            // Handler has given up without writing full
+                            // response, we can't continue with connection
+                            resp.finish();
+                            return Parser::flush(scope);
+                        }
+                        Parser::complete(scope, machine, resp,
+                                         next.deadline, pipeline)
+                    }
+                    None => ParserImpl::Idle.request(scope),
+                }
             }
         }
     }
 }
 
+fn new_pipeline<M>(pipelining: bool) -> Pipeline<M> {
+    if pipelining {
+        Pipeline::new()
+    } else {
+        Pipeline::blocked()
+    }
+}
+
+/// Reads the next request, while current one is processed
+///
+/// Only requests without a body are read ahead. Otherwise pipeline is
+/// blocked and the request is read in the ordinary way when all previous
+/// ones are finished.
+fn read_ahead<M, S>(transport: &mut Transport<S>, end: usize,
+    scope: &mut Scope<M::Context>, conn: &Connection,
+    pipeline: &mut Pipeline<M>)
+    where M: Server, S: StreamSocket + SocketInfo
+{
+    let max_headers = scope.max_headers_num();
+    let head = match Head::parse(&transport.input()[..end+4], max_headers) {
+        Ok(head) => head,
+        // Error is sent when request becomes the current one
+        Err(_) => {
+            pipeline.block();
+            return;
+        }
+    };
+    if head.wants_close() {
+        // Nothing is read after the request which closes connection
+        pipeline.block();
+    }
+    if BodyKind::parse(&head) != Ok(BodyKind::Fixed(0)) {
+        pipeline.block();
+        return;
+    }
+    let mut buf = Buf::new();
+    swap(transport.output(), &mut buf);
+    let parsed = start_request(transport, end, Ok(head), scope, conn);
+    let (machine, response, deadline) = match parsed {
+        Ok(rb) => {
+            let mut resp = rb.response.with(transport.output());
+            let m = match rb.progress {
+                BodyProgress::BufferFixed(_) => rb.machine.and_then(
+                    |m| m.request_received(&[], &mut resp, scope)),
+                BodyProgress::ProgressiveFixed(..) => rb.machine.and_then(
+                    |m| m.request_end(&mut resp, scope)),
+                _ => unreachable!(),
+            };
+            if m.is_none() && resp.is_close() {
+                pipeline.block();
+            }
+            (m, state(resp), rb.deadline)
+        }
+        Err(can_keep_alive) => {
+            // Error page is already written
+            if !can_keep_alive {
+                pipeline.block();
+            }
+            (None, MessageState::Done { close: !can_keep_alive },
+             Deadline::now())
+        }
+    };
+    swap(transport.output(), &mut buf);
+    pipeline.push(Queued {
+        machine: machine,
+        response: response,
+        buf: buf,
+        deadline: deadline,
+        woken: false,
+    });
+}
+
+/// Calls the handler of the request in pipeline
+///
+/// Response is written into the buffer of that request
+fn queued_event<M, S, F>(request: Queued<M>, transport: &mut Transport<S>,
+    f: F)
+    -> Queued<M>
+    where M: Server, S: StreamSocket,
+          F: FnOnce(M, &mut Response, Deadline) -> Option<(M, Deadline)>
+{
+    let Queued { machine, response, mut buf, deadline, woken } = request;
+    swap(transport.output(), &mut buf);
+    let (machine, response, deadline) = {
+        let mut resp = response.with(transport.output());
+        match machine.and_then(|m| f(m, &mut resp, deadline)) {
+            Some((m, dline)) => (Some(m), state(resp), dline),
+            None => (None, state(resp), deadline),
+        }
+    };
+    swap(transport.output(), &mut buf);
+    Queued {
+        machine: machine,
+        response: response,
+        buf: buf,
+        deadline: deadline,
+        woken: woken,
+    }
+}
+
+/// Puts the connection state into the parser returned by state transition
+fn keep<M, S>(req: Request<Parser<M, S>>, conn: Connection)
+    -> Request<Parser<M, S>>
+    where M: Server, S: StreamSocket
+{
+    req.map(|(Parser(me, _, _), exp, dline)| {
+        (Parser(me, conn, PhantomData), exp, dline)
+    })
+}
+
+fn upgraded<M, S>(req: Request<M::Upgrade>) -> Request<Parser<M, S>>
+    where M: Server, S: StreamSocket
+{
+    req.map(|(proto, exp, dline)| {
+        (ParserImpl::Upgraded(proto).wrap(), exp, dline)
+    })
+}
+
+fn http2<M, S>(req: Request<Engine<M>>) -> Request<Parser<M, S>>
+    where M: Server, S: StreamSocket
+{
+    req.map(|(engine, exp, dline)| {
+        (ParserImpl::Http2(engine).wrap(), exp, dline)
+    })
+}
+
+/// Returns chunk size and extensions (if there are any)
+fn chunk_head(line: &[u8]) -> Result<(u64, Option<Extensions>), ChunkError> {
+    let head = try!(chunked::parse_head(line));
+    if head.extensions.len() > 0 {
+        Ok((head.size, Some(try!(chunked::parse_extensions(head.extensions)))))
+    } else {
+        Ok((head.size, None))
+    }
+}
+
+fn call_extensions<M: Server>(machine: Option<M>, ext: Option<Extensions>,
+    response: &mut Response, scope: &mut Scope<M::Context>)
+    -> Option<M>
+{
+    match ext {
+        Some(ext) => machine.and_then(
+            |m| m.chunk_extensions(&ext, response, scope)),
+        None => machine,
+    }
+}
+
+fn call_trailers<M: Server>(machine: Option<M>, trailers: Headers,
+    response: &mut Response, scope: &mut Scope<M::Context>)
+    -> Option<M>
+{
+    if trailers.len() > 0 {
+        machine.and_then(|m| m.request_trailers(trailers, response, scope))
+    } else {
+        machine
+    }
+}
+
 fn start_headers<C: Context, M: Server, S: StreamSocket>(scope: &mut Scope<C>)
     -> Request<Parser<M, S>>
 {
     Some((ParserImpl::ReadHeaders.wrap(),
-          E::Delimiter(0, b"\r\n\r\n", MAX_HEADERS_SIZE),
+          E::Delimiter(0, b"\r\n\r\n", scope.max_headers_size()),
           Deadline::now() + scope.byte_timeout()))
 }
 
@@ -119,22 +362,102 @@ fn start_body(mode: RecvMode, body: BodyKind) -> BodyProgress {
     match (mode, body) {
         // The size of Fixed(x) is checked in parse_headers
         (Buffered(_), Fixed(y)) => BufferFixed(y as usize),
-        (Buffered(x), Chunked) => BufferChunked(x, 0, 0),
+        (Buffered(x), Chunked) => BufferChunked(x, 0, ChunkState::Head),
         (Buffered(x), Eof) => BufferEOF(x),
         (Progressive(x), Fixed(y)) => ProgressiveFixed(x, y),
-        (Progressive(x), Chunked) => ProgressiveChunked(x, 0, 0),
+        (Progressive(x), Chunked) => {
+            ProgressiveChunked(x, 0, ChunkState::Head)
+        }
         (Progressive(x), Eof) => ProgressiveEOF(x),
-        (_, Upgrade) => unimplemented!(),
+        // Upgrade requests have no body, protocol is switched only after
+        // the response is sent
+        (Buffered(_), Upgrade) => BufferFixed(0),
+        (Progressive(x), Upgrade) => ProgressiveFixed(x, 0),
     }
 }
 
+// Switches connection to HTTP/2 if request asks for that with
+// `Upgrade: h2c` (RFC 7540 section 3.2)
+//
+// Only requests without a body are upgraded, others are served by
+// HTTP/1.1 as usual. The request itself becomes the stream 1.
+fn h2c_upgrade<S, M>(transport: &mut Transport<S>, end: usize,
+    scope: &mut Scope<M::Context>, conn: &Connection)
+    -> Option<Request<Engine<M>>>
+    where M: Server, S: StreamSocket + SocketInfo,
+{
+    // TLS connections negotiate HTTP/2 with ALPN only
+    if transport.socket().is_tls() || !scope.http2_cleartext() {
+        return None;
+    }
+    // Cheap check first, so that most requests are parsed only once
+    let mentioned = transport.input()[..end].windows(3)
+        .any(|x| x.eq_ignore_ascii_case(b"h2c"));
+    if !mentioned {
+        return None;
+    }
+    let parsed = Head::parse(&transport.input()[..end+4],
+                             scope.max_headers_num());
+    let mut head = match parsed {
+        Ok(head) => head,
+        Err(_) => return None,
+    };
+    if !has_token(&head.headers, "Upgrade", "h2c") ||
+       !has_token(&head.headers, "Connection", "upgrade") ||
+       !has_token(&head.headers, "Connection", "HTTP2-Settings")
+    {
+        return None;
+    }
+    match BodyKind::parse(&head) {
+        Ok(BodyKind::Upgrade) | Ok(BodyKind::Fixed(0)) => {}
+        _ => return None,
+    }
+    let engine = match head.headers.get_raw("HTTP2-Settings") {
+        Some(values) if values.len() == 1 => {
+            Engine::upgrade(conn.proxy, &values[0])
+        }
+        _ => None,
+    };
+    let engine = match engine {
+        Some(engine) => engine,
+        None => return None,
+    };
+    transport.input().consume(end+4);
+    transport.output().extend(b"HTTP/1.1 101 Switching Protocols\r\n\
+        Connection: Upgrade\r\nUpgrade: h2c\r\n\r\n");
+    // Handler sees the request as if it was received over HTTP/2
+    head.version = Version::Http20;
+    head.headers.remove_raw("Connection");
+    head.headers.remove_raw("Upgrade");
+    head.headers.remove_raw("HTTP2-Settings");
+    fill_head(&mut head, transport.socket(), conn.proxy,
+              scope.trusted_proxies());
+    Some(engine.start_upgraded(head, transport, scope))
+}
+
 // Parses headers
 //
 // On error returns bool, which is true if keep-alive connection can be
 // carried on.
 fn parse_headers<S, M>(transport: &mut Transport<S>, end: usize,
-    scope: &mut Scope<M::Context>) -> Result<ReadBody<M>, bool>
-    where M: Server, S: StreamSocket,
+    scope: &mut Scope<M::Context>, conn: &Connection)
+    -> Result<ReadBody<M>, bool>
+    where M: Server, S: StreamSocket + SocketInfo,
+{
+    let parsed = Head::parse(&transport.input()[..end+4],
+                             scope.max_headers_num());
+    start_request(transport, end, parsed, scope, conn)
+}
+
+// Passes already parsed headers to the handler, or sends an error page
+//
+// Consumes the headers from the input buffer. Return value is the same
+// as for `parse_headers`.
+fn start_request<S, M>(transport: &mut Transport<S>, end: usize,
+    parsed: Result<Head, StatusCode>, scope: &mut Scope<M::Context>,
+    conn: &Connection)
+    -> Result<ReadBody<M>, bool>
+    where M: Server, S: StreamSocket + SocketInfo,
 {
     // Determines if we can keep-alive after error response.
     // We may not be able to keep keep-alive for multiple reasons:
@@ -154,8 +477,10 @@ fn parse_headers<S, M>(transport: &mut Transport<S>, end: usize,
     // Determines if we can safely send the response body
     let mut is_head = false;
 
-    let status = match Head::parse(&transport.input()[..end+4]) {
-        Ok(head) => {
+    let status = match parsed {
+        Ok(mut head) => {
+            fill_head(&mut head, transport.socket(), conn.proxy,
+                      scope.trusted_proxies());
             is_head = head.method == Head;
             match M::headers_received(&head, scope) {
                 Ok((_, RecvMode::Buffered(x), _)) if x >= MAX_BUF_SIZE
@@ -168,7 +493,10 @@ fn parse_headers<S, M>(transport: &mut Transport<S>, end: usize,
                             // Probably can handle small
                             // request bodies that are already
                             // in the buffer
-                            if body == BodyKind::Fixed(0) {
+                            if (body == BodyKind::Fixed(0) ||
+                                body == BodyKind::Upgrade) &&
+                               !head.wants_close()
+                            {
                                 can_keep_alive = true;
                             }
                             match (body, mode) {
@@ -198,12 +526,15 @@ fn parse_headers<S, M>(transport: &mut Transport<S>, end: usize,
                     format!("{} 100 Continue\r\n\r\n", head.version)
                     .as_bytes());
             }
+            let pipelining = body != BodyKind::Upgrade && !head.wants_close();
             let mut resp = Response::new(transport.output(), &head);
             Ok(ReadBody {
                 machine: m.request_start(head, &mut resp, scope),
                 deadline: dline,
                 progress: start_body(mode, body),
                 response: state(resp),
+                trailers: Headers::new(),
+                pipelining: pipelining,
             })
         }
         Err(status) => {
@@ -219,7 +550,7 @@ impl<M: Server> ParserImpl<M>
 {
     fn wrap<S: StreamSocket>(self) -> Parser<M, S>
     {
-        Parser(self, PhantomData)
+        Parser(self, Connection::default(), PhantomData)
     }
     fn request<C, S>(self, scope: &mut Scope<C>) -> Request<Parser<M, S>>
         where C: Context, S: StreamSocket
@@ -228,26 +559,41 @@ impl<M: Server> ParserImpl<M>
         use self::ParserImpl::*;
         use self::BodyProgress::*;
         let (exp, dline) = match self {
+            Preamble(x) => (Bytes(x), None),
+            // No HTTP/1.x method starts with the same bytes as the preface
+            Start => (Bytes(PREFACE_PREFIX), None),
             Idle => (Bytes(0), None),
-            ReadHeaders => (Delimiter(0, b"\r\n\r\n", MAX_HEADERS_SIZE), None),
+            ReadHeaders => {
+                (Delimiter(0, b"\r\n\r\n", scope.max_headers_size()), None)
+            }
             ReadingBody(ref b) => {
                 let exp = match *&b.progress {
                     BufferFixed(x) => Bytes(x),
                     BufferEOF(x) => Bytes(x),
-                    BufferChunked(_, off, 0)
-                    => Delimiter(off, b"\r\n", off+MAX_CHUNK_HEAD),
-                    BufferChunked(_, off, y) => Bytes(off + y),
+                    BufferChunked(_, off, ChunkState::Head)
+                    | ProgressiveChunked(_, off, ChunkState::Head)
+                    => Delimiter(off, b"\r\n", off+scope.max_chunk_head()),
+                    BufferChunked(_, off, ChunkState::Data(y))
+                    => Bytes(off + y as usize),
+                    BufferChunked(_, off, ChunkState::DataEnd)
+                    | ProgressiveChunked(_, off, ChunkState::DataEnd)
+                    => Bytes(off + 2),
+                    BufferChunked(_, off, ChunkState::Trailers)
+                    | ProgressiveChunked(_, off, ChunkState::Trailers)
+                    => Delimiter(off, b"\r\n",
+                                 off+scope.max_headers_size()),
                     ProgressiveFixed(hint, left)
                     => Bytes(min(hint as u64, left) as usize),
                     ProgressiveEOF(hint) => Bytes(hint),
-                    ProgressiveChunked(_, off, 0)
-                    => Delimiter(off, b"\r\n", off+MAX_CHUNK_HEAD),
-                    ProgressiveChunked(hint, off, left)
+                    ProgressiveChunked(hint, off, ChunkState::Data(left))
                     => Bytes(min(hint as u64, off as u64 +left) as usize)
                 };
                 (exp, Some(b.deadline))
             }
             Processing(..) => unreachable!(),
+            Upgraded(..) => unreachable!(),
+            Http2(..) => unreachable!(),
+            Upgrading(_) => (Flush(0), None),
             /// TODO(tailhook) fix output timeout
             DoneResponse => (Flush(0), None),
         };
@@ -260,29 +606,54 @@ impl<M: Server> ParserImpl<M>
     }
 }
 
-impl<S: StreamSocket, M: Server> Protocol for Parser<M, S> {
-    type Context = M::Context;
-    type Socket = S;
-    type Seed = ();
-    fn create(_seed: (), _sock: &mut Self::Socket,
+impl<M: Server> ParserImpl<M> {
+    fn bytes_read<S>(self, conn: &mut Connection,
+        transport: &mut Transport<S>, end: usize,
         scope: &mut Scope<M::Context>)
-        -> Request<Self>
-    {
-        Some((ParserImpl::Idle.wrap(), E::Bytes(1),
-            Deadline::now() + scope.byte_timeout()))
-    }
-    fn bytes_read(self, transport: &mut Transport<S>,
-                  end: usize, scope: &mut Scope<M::Context>)
-        -> Request<Self>
+        -> Request<Parser<M, S>>
+        where S: StreamSocket + SocketInfo
     {
         use self::ParserImpl::*;
         use self::BodyProgress::*;
-        match self.0 {
-            Idle => {
+        use chunked::State::{Data, DataEnd, Trailers};
+        match self {
+            Preamble(_) => {
+                let res = proxy::parse(&transport.input()[..]);
+                match res {
+                    Ok(proxy::Status::Complete(addrs, len)) => {
+                        transport.input().consume(len);
+                        conn.proxy = addrs;
+                        Start.request(scope)
+                    }
+                    Ok(proxy::Status::Incomplete(x)) => {
+                        Preamble(x).request(scope)
+                    }
+                    Ok(proxy::Status::Absent)
+                    if scope.proxy_protocol() == ProxyProtocol::Optional
+                    => Start.request(scope),
+                    // Don't even try to respond, as we don't know if peer
+                    // speaks HTTP at all
+                    Ok(proxy::Status::Absent) | Err(_) => None,
+                }
+            }
+            Start => {
+                let h2 = transport.socket().negotiated_protocol() ==
+                         Some(&b"h2"[..]) ||
+                         scope.http2_cleartext() &&
+                         transport.input()[..]
+                            .starts_with(&PREFACE[..PREFACE_PREFIX]);
+                if h2 {
+                    let engine = Engine::new(conn.proxy);
+                    return http2(engine.bytes_read(transport, end, scope));
+                }
                 start_headers(scope)
             }
+            Idle => start_headers(scope),
             ReadHeaders => {
-                match parse_headers(transport, end, scope) {
+                if let Some(req) = h2c_upgrade(transport, end, scope, conn) {
+                    return http2(req);
+                }
+                match parse_headers(transport, end, scope, conn) {
                     Ok(body) => {
                         ReadingBody(body).request(scope)
                     }
@@ -295,7 +666,7 @@ impl<S: StreamSocket, M: Server> Protocol for Parser<M, S> {
                     }
                 }
             }
-            ReadingBody(rb) => {
+            ReadingBody(mut rb) => {
                 let (inp, out) = transport.buffers();
                 let mut resp = rb.response.with(out);
                 let (m, progress) = match rb.progress {
@@ -307,46 +678,71 @@ impl<S: StreamSocket, M: Server> Protocol for Parser<M, S> {
                         (m, None)
                     }
                     BufferEOF(_) => unreachable!(),
-                    BufferChunked(limit, off, 0) => {
-                        let clen_end = inp[off..end].iter()
-                            .position(|&x| x == b';')
-                            .map(|x| x + off).unwrap_or(end);
-                        let val_opt = from_utf8(&inp[off..clen_end]).ok()
-                            .and_then(|x| u64::from_str_radix(x, 16).ok());
-                        match val_opt {
-                            Some(0) => {
-                                inp.remove_range(off..end+2);
-                                let m = rb.machine.and_then(
-                                    |m| m.request_received(
-                                        &inp[..off], &mut resp, scope));
-                                inp.consume(off);
-                                (m, None)
-                            }
-                            Some(chunk_len) => {
-                                if off as u64 + chunk_len > limit as u64 {
-                                    inp.consume(end+2);
-                                    rb.machine.map(
-                                        |m| m.bad_request(&mut resp, scope));
-                                    return Parser::error(scope, resp,
-                                                         BadRequest);
-                                }
-                                inp.remove_range(off..end+2);
-                                (rb.machine,
-                                    Some(BufferChunked(limit, off,
-                                                  chunk_len as usize)))
-                            }
-                            None => {
+                    BufferChunked(limit, off, ChunkState::Head) => {
+                        let (size, ext) = match chunk_head(&inp[off..end]) {
+                            Ok(pair) => pair,
+                            Err(_) => {
                                 inp.consume(end+2);
                                 rb.machine.map(
                                     |m| m.bad_request(&mut resp, scope));
                                 return Parser::error(scope, resp,
                                                      BadRequest);
                             }
+                        };
+                        if off as u64 + size > limit as u64 {
+                            inp.consume(end+2);
+                            rb.machine.map(
+                                |m| m.bad_request(&mut resp, scope));
+                            return Parser::error(scope, resp, BadRequest);
+                        }
+                        inp.remove_range(off..end+2);
+                        let m = call_extensions(rb.machine, ext,
+                                                &mut resp, scope);
+                        let next = if size == 0 { Trailers }
+                                   else { Data(size) };
+                        (m, Some(BufferChunked(limit, off, next)))
+                    }
+                    BufferChunked(limit, off, Data(bytes)) => {
+                        (rb.machine, Some(BufferChunked(limit,
+                            off + bytes as usize, DataEnd)))
+                    }
+                    BufferChunked(limit, off, DataEnd) => {
+                        if chunked::check_data_end(&inp[off..]).is_err() {
+                            inp.consume(off+2);
+                            rb.machine.map(
+                                |m| m.bad_request(&mut resp, scope));
+                            return Parser::error(scope, resp, BadRequest);
                         }
+                        inp.remove_range(off..off+2);
+                        (rb.machine,
+                            Some(BufferChunked(limit, off, ChunkState::Head)))
                     }
-                    BufferChunked(limit, off, bytes) => {
-                        debug_assert!(bytes == end);
-                        (rb.machine, Some(BufferChunked(limit, off+bytes, 0)))
+                    BufferChunked(limit, off, Trailers) => {
+                        if end > off {
+                            let res = add_trailer(&mut rb.trailers,
+                                                  &inp[off..end],
+                                                  scope.max_headers_num());
+                            if res.is_err() {
+                                inp.consume(end+2);
+                                rb.machine.map(
+                                    |m| m.bad_request(&mut resp, scope));
+                                return Parser::error(scope, resp,
+                                                     BadRequest);
+                            }
+                            inp.remove_range(off..end+2);
+                            (rb.machine,
+                                Some(BufferChunked(limit, off, Trailers)))
+                        } else {
+                            let trailers = replace(&mut rb.trailers,
+                                                   Headers::new());
+                            let m = call_trailers(rb.machine, trailers,
+                                                  &mut resp, scope);
+                            let m = m.and_then(
+                                |m| m.request_received(
+                                    &inp[..off], &mut resp, scope));
+                            inp.consume(off+2);
+                            (m, None)
+                        }
                     }
                     ProgressiveFixed(hint, mut left) => {
                         let real_bytes = min(inp.len() as u64, left) as usize;
@@ -369,47 +765,81 @@ impl<S: StreamSocket, M: Server> Protocol for Parser<M, S> {
                             |m| m.request_chunk(&inp[..ln], &mut resp, scope));
                         (m, Some(ProgressiveEOF(hint)))
                     }
-                    ProgressiveChunked(hint, off, 0) => {
-                        let clen_end = inp[off..end].iter()
-                            .position(|&x| x == b';')
-                            .map(|x| x + off).unwrap_or(end);
-                        let val_opt = from_utf8(&inp[off..clen_end]).ok()
-                            .and_then(|x| u64::from_str_radix(x, 16).ok());
-                        match val_opt {
-                            Some(0) => {
-                                inp.remove_range(off..end+2);
-                                let m = rb.machine.and_then(
-                                    |m| m.request_received(
-                                        &inp[..off], &mut resp, scope));
-                                inp.consume(off);
-                                (m, None)
-                            }
-                            Some(chunk_len) => {
-                                inp.remove_range(off..end+2);
-                                (rb.machine,
-                                    Some(ProgressiveChunked(hint, off,
-                                                  chunk_len)))
-                            }
-                            None => {
+                    ProgressiveChunked(hint, off, ChunkState::Head) => {
+                        let (size, ext) = match chunk_head(&inp[off..end]) {
+                            Ok(pair) => pair,
+                            Err(_) => {
                                 inp.consume(end+2);
                                 rb.machine.map(
                                     |m| m.bad_request(&mut resp, scope));
                                 return Parser::error(scope, resp, BadRequest);
                             }
-                        }
+                        };
+                        inp.remove_range(off..end+2);
+                        let m = call_extensions(rb.machine, ext,
+                                                &mut resp, scope);
+                        let next = if size == 0 { Trailers }
+                                   else { Data(size) };
+                        (m, Some(ProgressiveChunked(hint, off, next)))
                     }
-                    ProgressiveChunked(hint, off, mut left) => {
-                        let ln = min(off as u64 + left, inp.len() as u64) as usize;
+                    ProgressiveChunked(hint, off, Data(mut left)) => {
+                        let ln = min(off as u64 + left,
+                                     inp.len() as u64) as usize;
                         left -= (ln - off) as u64;
+                        let next = if left == 0 { DataEnd }
+                                   else { Data(left) };
                         if ln < hint {
                             (rb.machine,
-                                Some(ProgressiveChunked(hint, ln, left)))
+                                Some(ProgressiveChunked(hint, ln, next)))
                         } else {
                             let m = rb.machine.and_then(
                                 |m| m.request_chunk(&inp[..ln],
                                     &mut resp, scope));
                             inp.consume(ln);
-                            (m, Some(ProgressiveChunked(hint, 0, left)))
+                            (m, Some(ProgressiveChunked(hint, 0, next)))
+                        }
+                    }
+                    ProgressiveChunked(hint, off, DataEnd) => {
+                        if chunked::check_data_end(&inp[off..]).is_err() {
+                            inp.consume(off+2);
+                            rb.machine.map(
+                                |m| m.bad_request(&mut resp, scope));
+                            return Parser::error(scope, resp, BadRequest);
+                        }
+                        inp.remove_range(off..off+2);
+                        (rb.machine,
+                            Some(ProgressiveChunked(hint, off,
+                                                    ChunkState::Head)))
+                    }
+                    ProgressiveChunked(hint, off, Trailers) => {
+                        if end > off {
+                            let res = add_trailer(&mut rb.trailers,
+                                                  &inp[off..end],
+                                                  scope.max_headers_num());
+                            if res.is_err() {
+                                inp.consume(end+2);
+                                rb.machine.map(
+                                    |m| m.bad_request(&mut resp, scope));
+                                return Parser::error(scope, resp, BadRequest);
+                            }
+                            inp.remove_range(off..end+2);
+                            (rb.machine,
+                                Some(ProgressiveChunked(hint, off, Trailers)))
+                        } else {
+                            let mut m = rb.machine;
+                            if off > 0 {
+                                m = m.and_then(
+                                    |m| m.request_chunk(&inp[..off],
+                                        &mut resp, scope));
+                            }
+                            inp.consume(off+2);
+                            let trailers = replace(&mut rb.trailers,
+                                                   Headers::new());
+                            let m = call_trailers(m, trailers,
+                                                  &mut resp, scope);
+                            let m = m.and_then(
+                                |m| m.request_end(&mut resp, scope));
+                            (m, None)
                         }
                     }
                 };
@@ -420,44 +850,90 @@ impl<S: StreamSocket, M: Server> Protocol for Parser<M, S> {
                             deadline: rb.deadline,
                             progress: p,
                             response: state(resp),
+                            trailers: rb.trailers,
+                            pipelining: rb.pipelining,
                         }).request(scope)
                     }
-                    None => Parser::complete(scope, m, resp, rb.deadline)
+                    None => Parser::complete(scope, m, resp, rb.deadline,
+                                             new_pipeline(rb.pipelining))
                 }
             }
             // Spurious event?
             me @ DoneResponse => me.request(scope),
-            Processing(m, r, dline) => Some((Processing(m, r, dline).wrap(),
-                                             E::Sleep, dline)),
+            Processing(m, r, dline, mut pipeline) => {
+                if pipeline.can_read(scope.pipeline_depth(),
+                                     scope.max_pipeline_buffer())
+                {
+                    read_ahead(transport, end, scope, conn, &mut pipeline);
+                }
+                Parser::processing(scope, m, r, dline, pipeline)
+            }
+            // Spurious event, still flushing response
+            me @ Upgrading(_) => me.request(scope),
+            Upgraded(proto) => {
+                upgraded(proto.bytes_read(transport, end, scope))
+            }
+            Http2(engine) => {
+                http2(engine.bytes_read(transport, end, scope))
+            }
         }
     }
-    fn bytes_flushed(self, _transport: &mut Transport<S>,
-                     scope: &mut Scope<Self::Context>)
-        -> Request<Self>
+    fn bytes_flushed<S>(self, transport: &mut Transport<S>,
+        scope: &mut Scope<M::Context>)
+        -> Request<Parser<M, S>>
+        where S: StreamSocket
     {
-        match self.0 {
+        match self {
             ParserImpl::DoneResponse => None,
+            ParserImpl::Upgrading(proto) => {
+                upgraded(proto.start(transport, scope))
+            }
+            ParserImpl::Upgraded(proto) => {
+                upgraded(proto.bytes_flushed(transport, scope))
+            }
+            ParserImpl::Http2(engine) => {
+                http2(engine.bytes_flushed(transport, scope))
+            }
             me => me.request(scope),
         }
     }
-    fn exception(self, transport: &mut Transport<S>, exc: Exception,
-        scope: &mut Scope<Self::Context>)
-        -> Request<Self>
+    fn exception<S>(self, transport: &mut Transport<S>, exc: Exception,
+        scope: &mut Scope<M::Context>)
+        -> Request<Parser<M, S>>
+        where S: StreamSocket + SocketInfo
     {
         use self::ParserImpl::*;
         use self::BodyProgress::*;
         use rotor_stream::Exception::*;
+        let me = match self {
+            Upgraded(proto) => {
+                return upgraded(proto.exception(transport, exc, scope));
+            }
+            Http2(engine) => {
+                return http2(engine.exception(transport, exc, scope));
+            }
+            // Protocol is never started, so we just close connection
+            Upgrading(_) => return None,
+            me => me,
+        };
         match exc {
             LimitReached => {
-                match self.0 {
+                match me {
                     ReadHeaders => {
                         Parser::raw_error(scope, transport,
                             RequestHeaderFieldsTooLarge)
                     }
+                    Processing(m, r, dline, mut pipeline) => {
+                        // Next request will get the error when it's current
+                        pipeline.block();
+                        Parser::processing(scope, m, r, dline, pipeline)
+                    }
                     ReadingBody(rb) => {
                         assert!(matches!(rb.progress,
-                            ProgressiveChunked(_, _, 0) |
-                            BufferChunked(_, _, 0)));
+                            ProgressiveChunked(_, _, ChunkState::Head) |
+                            ProgressiveChunked(_, _, ChunkState::Trailers) |
+                            BufferChunked(_, _, ChunkState::Head) |
+                            BufferChunked(_, _, ChunkState::Trailers)));
                         let mut resp = rb.response.with(transport.output());
                         rb.machine.map(|m| m.bad_request(&mut resp, scope));
                         Parser::error(scope, resp, BadRequest)
@@ -466,7 +942,7 @@ impl<S: StreamSocket, M: Server> Protocol for Parser<M, S> {
                 }
             }
             EndOfStream => {
-                match self.0 {
+                match me {
                     ReadingBody(rb) => {
                         match rb.progress {
                             BufferEOF(_) | ProgressiveEOF(_) => {
@@ -480,7 +956,8 @@ impl<S: StreamSocket, M: Server> Protocol for Parser<M, S> {
                                 }
                                 m = m.and_then(
                                     |m| m.request_end(&mut resp, scope));
-                                Parser::complete(scope, m, resp, rb.deadline)
+                                Parser::complete(scope, m, resp, rb.deadline,
+                                                 Pipeline::blocked())
                             }
                             _ => {
                                 // Incomplete request
@@ -492,21 +969,30 @@ impl<S: StreamSocket, M: Server> Protocol for Parser<M, S> {
                             }
                         }
                     }
-                    Processing(..) => unreachable!(),
-                    Idle | ReadHeaders | DoneResponse => None,
+                    Processing(m, r, dline, mut pipeline) => {
+                        // Client has sent all pipelined requests, finish
+                        // processing them
+                        pipeline.block();
+                        Parser::processing(scope, m, r, dline, pipeline)
+                    }
+                    Upgraded(..) | Upgrading(..) | Http2(..)
+                    => unreachable!(),
+                    Preamble(_) | Start | Idle | ReadHeaders | DoneResponse
+                    => None,
                 }
             }
             ReadError(_) => None,
             WriteError(_) => None,
         }
     }
-    fn timeout(self, transport: &mut Transport<S>,
-        scope: &mut Scope<Self::Context>)
-        -> Request<Self>
+    fn timeout<S>(self, transport: &mut Transport<S>,
+        scope: &mut Scope<M::Context>)
+        -> Request<Parser<M, S>>
+        where S: StreamSocket + SocketInfo
     {
         use self::ParserImpl::*;
-        match self.0 {
-            Idle | DoneResponse => None,
+        match self {
+            Preamble(_) | Start | Idle | DoneResponse => None,
             ReadHeaders => {
                 Parser::raw_error(scope, transport, RequestTimeout)
             }
@@ -520,29 +1006,56 @@ impl<S: StreamSocket, M: Server> Protocol for Parser<M, S> {
                             deadline: deadline,
                             progress: rb.progress,
                             response: state(resp),
+                            trailers: rb.trailers,
+                            pipelining: rb.pipelining,
                         }).request(scope)
                     }
                     None => Parser::error(scope, resp, RequestTimeout),
                 }
             }
-            Processing(m, respimp, _) => {
+            Processing(m, respimp, dline, mut pipeline) => {
+                let now = Deadline::now();
+                pipeline.map(|q| {
+                    if q.machine.is_none() || q.deadline > now {
+                        return q;
+                    }
+                    queued_event(q, transport, |m, resp, _| {
+                        let res = m.timeout(resp, scope);
+                        if res.is_none() && !resp.is_started() {
+                            scope.emit_error_page(RequestTimeout, resp);
+                        }
+                        res
+                    })
+                });
+                if dline > now {
+                    // Only some of the pipelined requests timed out
+                    return Parser::processing(scope, m, respimp, dline,
+                                              pipeline);
+                }
                 let mut resp = respimp.with(transport.output());
                 match m.timeout(&mut resp, scope) {
                     Some((m, dline)) => {
-                        Parser::complete(scope, Some(m), resp, dline)
+                        Parser::complete(scope, Some(m), resp, dline,
+                                         pipeline)
                     }
                     None => Parser::error(scope, resp, RequestTimeout),
                 }
             }
+            Upgrading(_) => None,
+            Upgraded(proto) => upgraded(proto.timeout(transport, scope)),
+            Http2(engine) => http2(engine.timeout(transport, scope)),
         }
     }
-    fn wakeup(self, transport: &mut Transport<S>,
-        scope: &mut Scope<Self::Context>)
-        -> Request<Self>
+    fn wakeup<S>(self, transport: &mut Transport<S>,
+        scope: &mut Scope<M::Context>)
+        -> Request<Parser<M, S>>
+        where S: StreamSocket + SocketInfo
     {
         use self::ParserImpl::*;
-        match self.0 {
-            me@Idle | me@ReadHeaders | me@DoneResponse => me.request(scope),
+        match self {
+            me@Preamble(_) | me@Start | me@Idle | me@ReadHeaders
+            | me@DoneResponse
+            => me.request(scope),
             ReadingBody(rb) => {
                 let mut resp = rb.response.with(transport.output());
                 let m = rb.machine.and_then(|m| m.wakeup(&mut resp, scope));
@@ -551,13 +1064,363 @@ impl<S: StreamSocket, M: Server> Protocol for Parser<M, S> {
                     deadline: rb.deadline,
                     progress: rb.progress,
                     response: state(resp),
+                    trailers: rb.trailers,
+                    pipelining: rb.pipelining,
                 }).request(scope)
             }
-            Processing(m, respimp, dline) => {
+            Processing(m, respimp, dline, mut pipeline) => {
+                // Notifier is shared by all requests of the connection, so
+                // every handler is woken up, unless buffers are full
+                let full = pipeline.buffered() >= scope.max_pipeline_buffer();
+                pipeline.map(|mut q| {
+                    if q.machine.is_none() {
+                        return q;
+                    }
+                    if full {
+                        q.woken = true;
+                        return q;
+                    }
+                    q.woken = false;
+                    queued_event(q, transport, |m, resp, dline| {
+                        m.wakeup(resp, scope).map(|m| (m, dline))
+                    })
+                });
                 let mut resp = respimp.with(transport.output());
                 let mres = m.wakeup(&mut resp, scope);
-                Parser::complete(scope, mres, resp, dline)
+                Parser::complete(scope, mres, resp, dline, pipeline)
             }
+            me @ Upgrading(_) => me.request(scope),
+            Upgraded(proto) => upgraded(proto.wakeup(transport, scope)),
+            Http2(engine) => http2(engine.wakeup(transport, scope)),
         }
     }
 }
+
+impl<S: StreamSocket + SocketInfo, M: Server> Protocol for Parser<M, S> {
+    type Context = M::Context;
+    type Socket = S;
+    type Seed = ();
+    fn create(_seed: (), _sock: &mut Self::Socket,
+        scope: &mut Scope<M::Context>)
+        -> Request<Self>
+    {
+        let state = match scope.proxy_protocol() {
+            ProxyProtocol::Off => ParserImpl::Start,
+            ProxyProtocol::Optional | ProxyProtocol::Required => {
+                ParserImpl::Preamble(1)
+            }
+        };
+        state.request(scope)
+    }
+    fn bytes_read(self, transport: &mut Transport<S>,
+                  end: usize, scope: &mut Scope<M::Context>)
+        -> Request<Self>
+    {
+        let Parser(me, mut conn, _) = self;
+        let req = me.bytes_read(&mut conn, transport, end, scope);
+        keep(req, conn)
+    }
+    fn bytes_flushed(self, transport: &mut Transport<S>,
+                     scope: &mut Scope<Self::Context>)
+        -> Request<Self>
+    {
+        keep(self.0.bytes_flushed(transport, scope), self.1)
+    }
+    fn exception(self, transport: &mut Transport<S>, exc: Exception,
+        scope: &mut Scope<Self::Context>)
+        -> Request<Self>
+    {
+        keep(self.0.exception(transport, exc, scope), self.1)
+    }
+    fn timeout(self, transport: &mut Transport<S>,
+        scope: &mut Scope<Self::Context>)
+        -> Request<Self>
+    {
+        keep(self.0.timeout(transport, scope), self.1)
+    }
+    fn wakeup(self, transport: &mut Transport<S>,
+        scope: &mut Scope<Self::Context>)
+        -> Request<Self>
+    {
+        keep(self.0.wakeup(transport, scope), self.1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::net::TcpStream as StdStream;
+
+    use rotor::{self, Scope, Notifier};
+    use rotor::mio::tcp::TcpListener;
+    use rotor_stream::{Accept, Stream, Deadline};
+    use hyper::status::StatusCode;
+    use hyper::header::ContentLength;
+    use time::Duration;
+
+    use server::{self, Server, Head, Response, RecvMode, NoUpgrade};
+    use http2::PREFACE;
+    use test_util::{read_some, find};
+    use super::Parser;
+
+    struct Context {
+        log: Rc<RefCell<Vec<String>>>,
+        waker: Rc<RefCell<Option<Notifier>>>,
+        http2: bool,
+    }
+
+    impl server::Context for Context {
+        fn pipeline_depth(&self) -> usize {
+            4
+        }
+        fn max_pipeline_buffer(&self) -> usize {
+            // A single response fits, two don't
+            64
+        }
+        fn http2_cleartext(&self) -> bool {
+            self.http2
+        }
+    }
+
+    /// Responds with the path, requests to `/slow` wait for a wakeup
+    struct Path(String);
+
+    impl Path {
+        fn respond(self, res: &mut Response) {
+            let body = format!("response {}\n", self.0);
+            res.status(StatusCode::Ok);
+            res.add_header(ContentLength(body.len() as u64)).unwrap();
+            res.done_headers().unwrap();
+            res.write_body(body.as_bytes());
+            res.done();
+        }
+    }
+
+    impl Server for Path {
+        type Context = Context;
+        type Upgrade = NoUpgrade<Context>;
+        fn headers_received(head: &Head, _scope: &mut Scope<Context>)
+            -> Result<(Self, RecvMode, Deadline), StatusCode>
+        {
+            Ok((Path(format!("{}", head.uri)), RecvMode::Buffered(1024),
+                Deadline::now() + Duration::seconds(10)))
+        }
+        fn request_start(self, _head: Head, _res: &mut Response,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            Some(self)
+        }
+        fn request_received(self, _data: &[u8], res: &mut Response,
+            scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            scope.log.borrow_mut().push(format!("received {}", self.0));
+            if self.0 == "/slow" {
+                let notifier = scope.notifier();
+                *scope.waker.borrow_mut() = Some(notifier);
+                Some(self)
+            } else {
+                self.respond(res);
+                None
+            }
+        }
+        fn request_chunk(self, _chunk: &[u8], _res: &mut Response,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            unreachable!();
+        }
+        fn request_end(self, _res: &mut Response,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            unreachable!();
+        }
+        fn timeout(self, _res: &mut Response, _scope: &mut Scope<Context>)
+            -> Option<(Self, Deadline)>
+        {
+            unreachable!();
+        }
+        fn wakeup(self, res: &mut Response, scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            scope.log.borrow_mut().push(format!("wakeup {}", self.0));
+            self.respond(res);
+            None
+        }
+    }
+
+    /// Starts the server and connects to it
+    macro_rules! serve {
+        ($event_loop:ident, $handler:ident, $peer:ident,
+         $log:ident, $notifier:ident, $http2:expr) => {
+            let $log = Rc::new(RefCell::new(Vec::<String>::new()));
+            let $notifier = Rc::new(RefCell::new(None));
+            let mut $event_loop = rotor::EventLoop::new().unwrap();
+            let mut $handler = rotor::Handler::new(Context {
+                log: $log.clone(),
+                waker: $notifier.clone(),
+                http2: $http2,
+            }, &mut $event_loop);
+            let lst = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())
+                .unwrap();
+            let addr = lst.local_addr().unwrap();
+            assert!($handler.add_machine_with(&mut $event_loop, |scope| {
+                Accept::<Stream<Parser<Path, _>>, _>::new(lst, scope)
+            }).is_ok());
+            let mut $peer = StdStream::connect(addr).unwrap();
+        }
+    }
+
+    /// Starts the server and sends all the requests in a single write
+    macro_rules! pipeline {
+        ($event_loop:ident, $handler:ident, $peer:ident,
+         $log:ident, $notifier:ident, $requests:expr) => {
+            serve!($event_loop, $handler, $peer, $log, $notifier, false);
+            let mut data = Vec::new();
+            for path in $requests.iter() {
+                write!(&mut data, "GET {} HTTP/1.1\r\nHost: x\r\n\r\n",
+                       path).unwrap();
+            }
+            $peer.write_all(&data).unwrap();
+        }
+    }
+
+    fn position(data: &[u8], path: &str) -> usize {
+        find(data, format!("response {}\n", path).as_bytes())
+            .expect("response is received")
+    }
+
+    #[test]
+    fn pipelined() {
+        pipeline!(event_loop, handler, peer, log, notifier,
+                  ["/a", "/b", "/c"]);
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            find(&data, b"response /c\n").is_some()
+        });
+        assert!(position(&data, "/a") < position(&data, "/b"));
+        assert!(position(&data, "/b") < position(&data, "/c"));
+        assert_eq!(position(&data, "/c"), data.len());
+        assert_eq!(log.borrow().len(), 3);
+        assert!(notifier.borrow().is_none());
+    }
+
+    #[test]
+    fn pipelined_after_wakeup() {
+        pipeline!(event_loop, handler, peer, log, notifier,
+                  ["/slow", "/a", "/b"]);
+        // Subsequent requests are processed while first one waits
+        run_until!(event_loop, handler, log.borrow().len() == 3);
+        assert_eq!(&log.borrow()[..], &[
+            "received /slow".to_string(),
+            "received /a".to_string(),
+            "received /b".to_string(),
+        ][..]);
+        // But their responses are held until first one is complete
+        let mut data = Vec::new();
+        for _ in 0..10 {
+            event_loop.run_once(&mut handler, Some(10)).unwrap();
+            read_some(&mut peer, &mut data);
+        }
+        assert_eq!(data.len(), 0);
+
+        notifier.borrow().as_ref().unwrap().wakeup().unwrap();
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            find(&data, b"response /b\n").is_some()
+        });
+        assert!(data.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(position(&data, "/slow") < position(&data, "/a"));
+        assert!(position(&data, "/a") < position(&data, "/b"));
+        assert_eq!(position(&data, "/b"), data.len());
+        assert_eq!(log.borrow().last().unwrap(), "wakeup /slow");
+    }
+
+    #[test]
+    fn pipeline_buffer_full() {
+        pipeline!(event_loop, handler, peer, log, notifier,
+                  ["/slow", "/a", "/b", "/c"]);
+        run_until!(event_loop, handler, log.borrow().len() == 3);
+        // Responses to `/a` and `/b` fill the buffer
+        for _ in 0..10 {
+            event_loop.run_once(&mut handler, Some(10)).unwrap();
+        }
+        assert_eq!(log.borrow().len(), 3);
+
+        notifier.borrow().as_ref().unwrap().wakeup().unwrap();
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            find(&data, b"response /c\n").is_some()
+        });
+        assert!(position(&data, "/slow") < position(&data, "/a"));
+        assert!(position(&data, "/a") < position(&data, "/b"));
+        assert!(position(&data, "/b") < position(&data, "/c"));
+        assert_eq!(log.borrow().last().unwrap(), "received /c");
+    }
+
+    const H2C: &'static [u8] = b"GET /h2c HTTP/1.1\r\nHost: x\r\n\
+        Connection: Upgrade, HTTP2-Settings\r\n\
+        Upgrade: h2c\r\n\
+        HTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\n\r\n";
+
+    #[test]
+    fn prior_knowledge_disabled() {
+        serve!(event_loop, handler, peer, log, notifier, false);
+        peer.write_all(PREFACE).unwrap();
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, !read_some(&mut peer, &mut data));
+        assert!(data.starts_with(b"HTTP/1."));
+        assert!(log.borrow().is_empty());
+        assert!(notifier.borrow().is_none());
+    }
+
+    #[test]
+    fn prior_knowledge() {
+        serve!(event_loop, handler, peer, log, notifier, true);
+        peer.write_all(PREFACE).unwrap();
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            data.len() >= 9
+        });
+        // The first frame is SETTINGS
+        assert_eq!(data[3], 0x4);
+        assert!(log.borrow().is_empty());
+        assert!(notifier.borrow().is_none());
+    }
+
+    #[test]
+    fn h2c_disabled() {
+        serve!(event_loop, handler, peer, log, notifier, false);
+        peer.write_all(H2C).unwrap();
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            find(&data, b"response /h2c\n").is_some()
+        });
+        assert!(data.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert_eq!(&log.borrow()[..], &["received /h2c".to_string()]);
+        assert!(notifier.borrow().is_none());
+    }
+
+    #[test]
+    fn h2c() {
+        serve!(event_loop, handler, peer, log, notifier, true);
+        peer.write_all(H2C).unwrap();
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            find(&data, b"\r\n\r\n").is_some()
+        });
+        assert!(data.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        // Request is served as the stream 1 of HTTP/2 connection
+        assert_eq!(&log.borrow()[..], &["received /h2c".to_string()]);
+        assert!(notifier.borrow().is_none());
+    }
+}
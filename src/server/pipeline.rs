@@ -0,0 +1,154 @@
+//! Bookkeeping of pipelined requests
+//!
+//! While the handler of the current request is busy (the `Processing` state
+//! of the parser), subsequent requests without a body are read and passed
+//! to their own handlers. Their responses are written into separate buffers
+//! which are appended to the output in the order of requests, when all
+//! previous responses are complete. The size of these buffers is limited by
+//! `Context::max_pipeline_buffer()`.
+use std::mem::replace;
+use std::collections::VecDeque;
+
+use rotor_stream::{Buf, Deadline};
+
+use message::MessageState;
+
+
+/// A request that is read ahead of the current one
+pub struct Queued<M> {
+    /// Handler, `None` when it has finished
+    pub machine: Option<M>,
+    pub response: MessageState,
+    /// Output buffer, which is moved to the real one when request becomes
+    /// the current one
+    pub buf: Buf,
+    pub deadline: Deadline,
+    /// Wakeup is postponed because buffers are full
+    pub woken: bool,
+}
+
+pub struct Pipeline<M> {
+    queue: VecDeque<Queued<M>>,
+    /// Don't read more requests until current one is finished
+    ///
+    /// This is set when next request has a body, is an upgrade, wants
+    /// connection to be closed or is malformed. Such requests are processed
+    /// in the ordinary way, when every queued request is finished.
+    blocked: bool,
+}
+
+impl<M> Pipeline<M> {
+    pub fn new() -> Pipeline<M> {
+        Pipeline {
+            queue: VecDeque::new(),
+            blocked: false,
+        }
+    }
+    /// Pipeline that never accepts requests
+    pub fn blocked() -> Pipeline<M> {
+        Pipeline {
+            queue: VecDeque::new(),
+            blocked: true,
+        }
+    }
+    /// Returns true if one more request can be read ahead
+    ///
+    /// The `depth` is the maximum number of requests processed at once,
+    /// including the current one.
+    pub fn can_read(&self, depth: usize, max_buffer: usize) -> bool {
+        !self.blocked && self.queue.len() + 1 < depth &&
+            self.buffered() < max_buffer
+    }
+    /// Total size of the responses in the queue
+    pub fn buffered(&self) -> usize {
+        self.queue.iter().fold(0, |total, q| total + q.buf.len())
+    }
+    pub fn block(&mut self) {
+        self.blocked = true;
+    }
+    pub fn push(&mut self, request: Queued<M>) {
+        self.queue.push_back(request);
+    }
+    /// Removes the oldest request from the queue
+    pub fn pop(&mut self) -> Option<Queued<M>> {
+        self.queue.pop_front()
+    }
+    /// Returns earliest deadline of queued requests which are not finished
+    pub fn deadline(&self) -> Option<Deadline> {
+        self.queue.iter()
+            .filter(|q| q.machine.is_some())
+            .map(|q| q.deadline)
+            .min()
+    }
+    /// Applies the function to every queued request (in order)
+    pub fn map<F>(&mut self, f: F)
+        where F: FnMut(Queued<M>) -> Queued<M>
+    {
+        let queue = replace(&mut self.queue, VecDeque::new());
+        self.queue = queue.into_iter().map(f).collect();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use rotor_stream::{Buf, Deadline};
+    use message::MessageState;
+    use super::{Pipeline, Queued};
+
+    fn queued(data: &[u8]) -> Queued<()> {
+        let mut buf = Buf::new();
+        buf.write(data).unwrap();
+        Queued {
+            machine: Some(()),
+            response: MessageState::RequestStart,
+            buf: buf,
+            deadline: Deadline::now(),
+            woken: false,
+        }
+    }
+
+    #[test]
+    fn depth() {
+        let mut pipe = Pipeline::new();
+        assert!(!pipe.can_read(1, 100));
+        assert!(pipe.can_read(3, 100));
+        pipe.push(queued(b"1"));
+        assert!(pipe.can_read(3, 100));
+        pipe.push(queued(b"2"));
+        assert!(!pipe.can_read(3, 100));
+        pipe.block();
+        assert!(!pipe.can_read(10, 100));
+        assert!(!Pipeline::<()>::blocked().can_read(10, 100));
+    }
+
+    #[test]
+    fn buffer_limit() {
+        let mut pipe = Pipeline::new();
+        pipe.push(queued(b"123"));
+        assert_eq!(pipe.buffered(), 3);
+        assert!(pipe.can_read(10, 4));
+        pipe.push(queued(b"4"));
+        assert_eq!(pipe.buffered(), 4);
+        assert!(!pipe.can_read(10, 4));
+    }
+
+    #[test]
+    fn order() {
+        let mut pipe = Pipeline::new();
+        pipe.push(queued(b"1"));
+        pipe.push(queued(b"2"));
+        pipe.push(queued(b"3"));
+        pipe.map(|mut q| {
+            if &q.buf[..] == b"2" {
+                q.machine = None;
+            }
+            q
+        });
+        let mut out = Buf::new();
+        while let Some(q) = pipe.pop() {
+            out.extend(&q.buf[..]);
+        }
+        assert_eq!(&out[..], b"123");
+    }
+}
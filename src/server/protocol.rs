@@ -1,10 +1,12 @@
 use hyper::status::StatusCode;
+use hyper::header::Headers;
 use rotor::Scope;
 use rotor_stream::{Deadline};
 
 use super::context::Context;
 use super::request::Head;
 use super::Response;
+use super::upgrade::Upgrade;
 
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -37,6 +39,11 @@ pub enum RecvMode {
 /// Used for all versions of HTTP
 pub trait Server: Sized {
     type Context: Context;
+    /// The protocol connection is switched to after `101 Switching Protocols`
+    ///
+    /// Use `websocket::WebSocket<_>` for websockets, or `NoUpgrade<_>` if
+    /// you never switch protocols.
+    type Upgrade: Upgrade<Context=Self::Context>;
     /// Encountered when headers received
     ///
     /// Returns self, mode and timeout for reading whole request.
@@ -111,6 +118,35 @@ pub trait Server: Sized {
         scope: &mut Scope<Self::Context>)
         -> Option<Self>;
 
+    /// Trailer fields of the chunked request are received
+    ///
+    /// Called only if there is at least one trailer field, right before
+    /// `request_received()` (in buffered mode) or `request_end()` (in
+    /// progressive mode). So it's fine to store trailers and check them
+    /// against the whole request body.
+    ///
+    /// Fields that determine message framing (`Content-Length` and
+    /// `Transfer-Encoding`) are rejected with `400 Bad Request`.
+    fn request_trailers(self, _trailers: Headers, _response: &mut Response,
+        _scope: &mut Scope<Self::Context>)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+
+    /// Chunk extensions of the chunked request are received
+    ///
+    /// Called for every chunk that has extensions (including the last
+    /// zero-length chunk) when chunk size line is parsed, i.e. before the
+    /// data of the chunk is passed to the handler. Quoted values are
+    /// already unescaped.
+    fn chunk_extensions(self, _extensions: &[(String, Option<String>)],
+        _response: &mut Response, _scope: &mut Scope<Self::Context>)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+
     /// Request timeout occured
     ///
     /// This is only called if headers are already received but state machine
@@ -129,4 +165,18 @@ pub trait Server: Sized {
         -> Option<(Self, Deadline)>;
     fn wakeup(self, response: &mut Response, scope: &mut Scope<Self::Context>)
         -> Option<Self>;
+
+    /// Called when `101 Switching Protocols` response is complete
+    ///
+    /// To request an upgrade write `101` response (i.e. with
+    /// `websocket::accept()`), finish it and return `Some(self)` from the
+    /// handler. The returned protocol takes the connection over after the
+    /// response is flushed, with any bytes client sent after the request
+    /// still in the input buffer. When `None` is returned (the default)
+    /// connection is closed.
+    fn upgrade(self, _scope: &mut Scope<Self::Context>)
+        -> Option<Self::Upgrade>
+    {
+        None
+    }
 }
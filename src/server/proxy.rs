@@ -0,0 +1,277 @@
+//! PROXY protocol (v1 and v2) preamble parser
+//!
+//! The preamble is sent by load balancers (e.g. HAProxy) as the first bytes
+//! of the connection and contains the original addresses of the client
+//! connection. See http://www.haproxy.org/download/1.5/doc/proxy-protocol.txt
+use std::cmp::min;
+use std::str::from_utf8;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+
+
+/// Signature of the binary (v2) header
+const V2_SIGNATURE: &'static [u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+/// Length of the fixed part of the v2 header
+const V2_HEADER: usize = 16;
+/// Maximum length of the v1 line including CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+quick_error! {
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum ProxyError {
+        InvalidLine {
+            description("Malformed PROXY protocol v1 line")
+        }
+        LineTooLong {
+            description("PROXY protocol v1 line is too long")
+        }
+        UnsupportedVersion(version: u8) {
+            description("Unsupported PROXY protocol version")
+            display("Unsupported PROXY protocol version {}", version)
+        }
+        UnsupportedCommand(cmd: u8) {
+            description("Unsupported PROXY protocol v2 command")
+            display("Unsupported PROXY protocol v2 command {}", cmd)
+        }
+        InvalidAddress {
+            description("Address block of PROXY protocol v2 is invalid")
+        }
+    }
+}
+
+/// Whether listener expects PROXY protocol preamble
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// No preamble is expected (default)
+    Off,
+    /// Preamble is parsed if present
+    ///
+    /// This is useful for migration only, as it allows any client to
+    /// spoof it's address.
+    Optional,
+    /// Connections without valid preamble are closed
+    Required,
+}
+
+/// Addresses of the original connection received in the preamble
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyAddrs {
+    /// Address of the client
+    pub source: SocketAddr,
+    /// Address the client has connected to (i.e. of the load balancer)
+    pub destination: SocketAddr,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Status {
+    /// Preamble is parsed, with addresses and number of bytes consumed
+    ///
+    /// Addresses are `None` for `UNKNOWN` (v1), `LOCAL` and non-IP (v2)
+    /// connections
+    Complete(Option<ProxyAddrs>, usize),
+    /// At least this number of bytes is needed
+    Incomplete(usize),
+    /// Data doesn't start with the preamble
+    Absent,
+}
+
+fn starts_like(data: &[u8], prefix: &[u8]) -> bool {
+    let n = min(data.len(), prefix.len());
+    &data[..n] == &prefix[..n]
+}
+
+/// Parses the preamble at the start of the data
+pub fn parse(data: &[u8]) -> Result<Status, ProxyError> {
+    if data.len() == 0 {
+        Ok(Status::Incomplete(1))
+    } else if starts_like(data, V2_SIGNATURE) {
+        parse_v2(data)
+    } else if starts_like(data, b"PROXY ") {
+        parse_v1(data)
+    } else {
+        Ok(Status::Absent)
+    }
+}
+
+fn parse_v1(data: &[u8]) -> Result<Status, ProxyError> {
+    use self::ProxyError::*;
+    let limit = min(data.len(), V1_MAX_LENGTH);
+    let end = match data[..limit].windows(2).position(|x| x == b"\r\n") {
+        Some(end) => end,
+        None if data.len() >= V1_MAX_LENGTH => return Err(LineTooLong),
+        None => return Ok(Status::Incomplete(data.len() + 1)),
+    };
+    let line = try!(from_utf8(&data[..end]).map_err(|_| InvalidLine));
+    let words = line.split(' ').collect::<Vec<_>>();
+    if words.len() < 2 || words[0] != "PROXY" {
+        return Err(InvalidLine);
+    }
+    if words[1] == "UNKNOWN" {
+        // The rest of the line must be ignored
+        return Ok(Status::Complete(None, end+2));
+    }
+    if words.len() != 6 {
+        return Err(InvalidLine);
+    }
+    let sport = try!(words[4].parse::<u16>().map_err(|_| InvalidLine));
+    let dport = try!(words[5].parse::<u16>().map_err(|_| InvalidLine));
+    let addrs = match words[1] {
+        "TCP4" => {
+            let src = try!(words[2].parse().map_err(|_| InvalidLine));
+            let dst = try!(words[3].parse().map_err(|_| InvalidLine));
+            ProxyAddrs {
+                source: SocketAddr::V4(SocketAddrV4::new(src, sport)),
+                destination: SocketAddr::V4(SocketAddrV4::new(dst, dport)),
+            }
+        }
+        "TCP6" => {
+            let src = try!(words[2].parse().map_err(|_| InvalidLine));
+            let dst = try!(words[3].parse().map_err(|_| InvalidLine));
+            ProxyAddrs {
+                source: SocketAddr::V6(SocketAddrV6::new(src, sport, 0, 0)),
+                destination: SocketAddr::V6(
+                    SocketAddrV6::new(dst, dport, 0, 0)),
+            }
+        }
+        _ => return Err(InvalidLine),
+    };
+    Ok(Status::Complete(Some(addrs), end+2))
+}
+
+fn port(data: &[u8]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+fn ipv6(data: &[u8]) -> Ipv6Addr {
+    Ipv6Addr::new(port(&data[0..]), port(&data[2..]), port(&data[4..]),
+                  port(&data[6..]), port(&data[8..]), port(&data[10..]),
+                  port(&data[12..]), port(&data[14..]))
+}
+
+fn parse_v2(data: &[u8]) -> Result<Status, ProxyError> {
+    use self::ProxyError::*;
+    if data.len() < V2_HEADER {
+        return Ok(Status::Incomplete(V2_HEADER));
+    }
+    let version = data[12] >> 4;
+    let command = data[12] & 0x0F;
+    if version != 2 {
+        return Err(UnsupportedVersion(version));
+    }
+    let total = V2_HEADER + port(&data[14..16]) as usize;
+    if data.len() < total {
+        return Ok(Status::Incomplete(total));
+    }
+    let block = &data[V2_HEADER..total];
+    match command {
+        // LOCAL: connection is established by the proxy itself
+        0x0 => return Ok(Status::Complete(None, total)),
+        0x1 => {}
+        _ => return Err(UnsupportedCommand(command)),
+    }
+    // Upper half of the byte is address family, lower half is transport
+    // protocol. We don't distinguish stream and datagram here.
+    let addrs = match data[13] >> 4 {
+        // AF_INET
+        0x1 => {
+            if block.len() < 12 {
+                return Err(InvalidAddress);
+            }
+            let src = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            let dst = Ipv4Addr::new(block[4], block[5], block[6], block[7]);
+            Some(ProxyAddrs {
+                source: SocketAddr::V4(
+                    SocketAddrV4::new(src, port(&block[8..]))),
+                destination: SocketAddr::V4(
+                    SocketAddrV4::new(dst, port(&block[10..]))),
+            })
+        }
+        // AF_INET6
+        0x2 => {
+            if block.len() < 36 {
+                return Err(InvalidAddress);
+            }
+            Some(ProxyAddrs {
+                source: SocketAddr::V6(SocketAddrV6::new(
+                    ipv6(&block[0..16]), port(&block[32..]), 0, 0)),
+                destination: SocketAddr::V6(SocketAddrV6::new(
+                    ipv6(&block[16..32]), port(&block[34..]), 0, 0)),
+            })
+        }
+        // AF_UNSPEC, AF_UNIX
+        0x0 | 0x3 => None,
+        _ => return Err(InvalidAddress),
+    };
+    // Type-length-value records after the addresses are skipped
+    Ok(Status::Complete(addrs, total))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use super::{parse, Status, ProxyAddrs, ProxyError};
+
+    fn addrs(src: &str, dst: &str) -> Option<ProxyAddrs> {
+        Some(ProxyAddrs {
+            source: src.parse::<SocketAddr>().unwrap(),
+            destination: dst.parse::<SocketAddr>().unwrap(),
+        })
+    }
+
+    #[test]
+    fn v1() {
+        assert_eq!(parse(b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 443\r\nGET"),
+            Ok(Status::Complete(
+                addrs("192.168.0.1:56324", "10.0.0.1:443"), 43)));
+        assert_eq!(parse(b"PROXY TCP6 ::1 2001:db8::1 1000 80\r\n"),
+            Ok(Status::Complete(
+                addrs("[::1]:1000", "[2001:db8::1]:80"), 36)));
+        assert_eq!(parse(b"PROXY UNKNOWN whatever\r\n"),
+            Ok(Status::Complete(None, 24)));
+        assert_eq!(parse(b"PROXY TCP4 1.2.3.4"), Ok(Status::Incomplete(19)));
+        assert_eq!(parse(b"PRO"), Ok(Status::Incomplete(4)));
+        assert_eq!(parse(b"PROXY TCP4 1.2.3.4 5.6.7.8 1\r\n"),
+            Err(ProxyError::InvalidLine));
+        assert_eq!(parse(b"PROXY TCP4 ::1 ::1 1 2\r\n"),
+            Err(ProxyError::InvalidLine));
+        assert_eq!(parse(&[b'P'; 120][..]), Ok(Status::Absent));
+        let mut long = b"PROXY ".to_vec();
+        long.extend([b'x'; 120].iter().cloned());
+        assert_eq!(parse(&long), Err(ProxyError::LineTooLong));
+    }
+
+    #[test]
+    fn absent() {
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n"), Ok(Status::Absent));
+        assert_eq!(parse(b""), Ok(Status::Incomplete(1)));
+    }
+
+    #[test]
+    fn v2() {
+        let mut data = b"\r\n\r\n\x00\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        assert_eq!(parse(&data), Ok(Status::Incomplete(28)));
+        data.extend(&[192, 168, 0, 1, 10, 0, 0, 1, 0xDC, 0x04, 0x01, 0xBB]);
+        assert_eq!(parse(&data), Ok(Status::Complete(
+            addrs("192.168.0.1:56324", "10.0.0.1:443"), 28)));
+        // LOCAL command
+        data[12] = 0x20;
+        assert_eq!(parse(&data), Ok(Status::Complete(None, 28)));
+        data[12] = 0x11;
+        assert_eq!(parse(&data), Err(ProxyError::UnsupportedVersion(1)));
+        assert_eq!(parse(b"\r\n\r\n"), Ok(Status::Incomplete(16)));
+    }
+
+    #[test]
+    fn v2_ipv6() {
+        let mut data = b"\r\n\r\n\x00\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+        let mut src = [0u8; 16];
+        src[15] = 1;
+        let mut dst = [0u8; 16];
+        dst[0] = 0x20; dst[1] = 0x01; dst[2] = 0x0d; dst[3] = 0xb8;
+        dst[15] = 1;
+        data.extend(&src);
+        data.extend(&dst);
+        data.extend(&[0x03, 0xE8, 0x00, 0x50]);
+        assert_eq!(parse(&data), Ok(Status::Complete(
+            addrs("[::1]:1000", "[2001:db8::1]:80"), 52)));
+    }
+}
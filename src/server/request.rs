@@ -1,11 +1,19 @@
+use std::net::SocketAddr;
+
+use ip::IpAddr;
+
 use hyper::version::HttpVersion as Version;
 use hyper::status::StatusCode::{self, BadRequest};
+use hyper::status::StatusCode::RequestHeaderFieldsTooLarge;
 use hyper::method::Method;
 use hyper::uri::RequestUri;
 use hyper::header::Headers;
 use httparse;
 
+use headers::has_token;
+
 use super::MAX_HEADERS_NUM;
+use super::{ProxyAddrs, PeerCertificate};
 
 
 #[derive(Debug)]
@@ -16,23 +24,71 @@ use super::MAX_HEADERS_NUM;
 ///
 /// Note: we do our base to keep Head object same for HTTP 1-2 and HTTPS
 pub struct Head {
-    // TODO(tailhook) add source IP address
+    /// Address of the client (`None` for unix sockets)
+    pub peer_addr: Option<SocketAddr>,
+    /// Address the connection is accepted on (`None` for unix sockets)
+    pub local_addr: Option<SocketAddr>,
+    /// Original addresses of the connection from the PROXY protocol
+    /// preamble (see `Context::proxy_protocol`)
+    pub proxy: Option<ProxyAddrs>,
+    /// Effective address of the client
+    ///
+    /// This is the address of the peer (or the one from the PROXY protocol),
+    /// unless peer is a trusted proxy (see `Context::trusted_proxies`),
+    /// in which case it's taken from the forwarding headers.
+    pub client_ip: Option<IpAddr>,
     pub version: Version,
+    /// True if request is received over TLS, or trusted proxy has received
+    /// it over TLS
     pub https: bool,
+    /// Server name requested by the TLS client (SNI)
+    ///
+    /// Note that the client may send a different `Host` header, so it's up
+    /// to the handler to check that they match if that matters.
+    pub server_name: Option<String>,
+    /// Certificate of the client, if it has sent one and the listener
+    /// verifies client certificates (see `TlsAcceptor::verify_clients`)
+    ///
+    /// Return an error code (e.g. `403 Forbidden`) from
+    /// `Server::headers_received` to reject the request.
+    pub peer_certificate: Option<PeerCertificate>,
     pub method: Method,
     pub uri: RequestUri,
     pub headers: Headers,
 }
 
 impl Head {
-    pub fn parse(data: &[u8]) -> Result<Head, StatusCode> {
-        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS_NUM];
-        let mut raw = httparse::Request::new(&mut headers);
+    /// Parses request headers
+    ///
+    /// No more than `max_headers` are allowed. Up to `MAX_HEADERS_NUM`
+    /// headers are parsed without memory allocation.
+    pub fn parse(data: &[u8], max_headers: usize)
+        -> Result<Head, StatusCode>
+    {
+        if max_headers <= MAX_HEADERS_NUM {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS_NUM];
+            Head::parse_with(data, &mut headers[..max_headers])
+        } else {
+            let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+            Head::parse_with(data, &mut headers[..])
+        }
+    }
+    fn parse_with(data: &[u8], headers: &mut [httparse::Header])
+        -> Result<Head, StatusCode>
+    {
+        let mut raw = httparse::Request::new(headers);
         match raw.parse(data) {
             Ok(httparse::Status::Complete(x)) => {
                 assert!(x == data.len());
                 Ok(Head {
+                    // Filled in by the parser, as it knows the socket
+                    peer_addr: None,
+                    local_addr: None,
+                    proxy: None,
+                    client_ip: None,
                     https: false,
+                    server_name: None,
+                    peer_certificate: None,
                     version: if raw.version.unwrap() == 1 { Version::Http11 }
                              else { Version::Http10 },
                     method: try!(raw.method.unwrap().parse()
@@ -44,6 +100,9 @@ impl Head {
                 })
             }
             Ok(_) => unreachable!(),
+            Err(httparse::Error::TooManyHeaders) => {
+                Err(RequestHeaderFieldsTooLarge)
+            }
             Err(_) => {
                 // Anything to do with error?
                 // Should more precice errors be here?
@@ -51,4 +110,15 @@ impl Head {
             }
         }
     }
+    /// Returns true if client doesn't want to keep connection alive
+    ///
+    /// That is when there is `Connection: close` or when it's HTTP/1.0
+    /// request without `Connection: keep-alive`
+    pub fn wants_close(&self) -> bool {
+        if has_token(&self.headers, "Connection", "close") {
+            return true;
+        }
+        self.version == Version::Http10 &&
+            !has_token(&self.headers, "Connection", "keep-alive")
+    }
 }
@@ -1,9 +1,11 @@
 use rotor_stream::Buf;
 use hyper::status::StatusCode;
-use hyper::header::{Header, HeaderFormat};
+use hyper::version::HttpVersion::Http10;
+use hyper::header::{Header, HeaderFormat, Headers};
 
 use super::{Head};
-use message::{MessageState, Message, HeaderError};
+use message::{MessageState, Message, HeaderError, Close};
+use headers::has_token;
 
 
 /// This response is returned when Response is dropping without writing
@@ -37,11 +39,17 @@ impl<'a> Response<'a> {
     pub fn new<'x>(out_buf: &'x mut Buf, head: &Head) -> Response<'x>
     {
         use message::Body::*;
-        // TODO(tailhook) implement Connection: Close,
-        // (including explicit one in HTTP/1.0) and maybe others
         MessageState::ResponseStart {
             body: if head.method == Head { Ignored } else { Normal },
             version: head.version,
+            trailers: has_token(&head.headers, "TE", "trailers"),
+            close: if head.wants_close() {
+                Close::Pending
+            } else if head.version == Http10 {
+                Close::KeepAlive10
+            } else {
+                Close::No
+            },
         }.with(out_buf)
     }
     /// Returns true if it's okay too proceed with keep-alive connection
@@ -49,7 +57,7 @@ impl<'a> Response<'a> {
         use message::MessageState::*;
         use message::Body::*;
         if self.is_complete() {
-            return true;
+            return !self.is_close();
         }
         let (buf, me) = self.0.decompose();
         match me {
@@ -157,6 +165,20 @@ impl<'a> Response<'a> {
     pub fn is_complete(&self) -> bool {
         self.0.is_complete()
     }
+    /// Returns true if connection will be closed after the response
+    ///
+    /// This happens when client sends `Connection: close`, uses HTTP/1.0
+    /// without `Connection: keep-alive` or when you add `Connection: close`
+    /// header to the response.
+    pub fn is_close(&self) -> bool {
+        self.0.is_close()
+    }
+    /// Returns true if `101 Switching Protocols` response is complete
+    ///
+    /// After this point the connection is no more HTTP
+    pub fn is_upgraded(&self) -> bool {
+        self.0.is_upgraded()
+    }
     /// Writes needed final finalization data into the buffer and asserts
     /// that response is in the appropriate state for that.
     ///
@@ -169,6 +191,21 @@ impl<'a> Response<'a> {
     pub fn done(&mut self) {
         self.0.done()
     }
+    /// Finishes chunked response writing trailer fields after last chunk
+    ///
+    /// Trailers are allowed only if request contains `TE: trailers`, and
+    /// only for the fields that don't influence message framing or
+    /// processing. On error nothing is written, so you may either retry
+    /// with a different set of trailers or just call `done()`.
+    ///
+    /// # Panics
+    ///
+    /// When the response is not chunked or is in the wrong state
+    pub fn done_with_trailers(&mut self, trailers: &Headers)
+        -> Result<(), HeaderError>
+    {
+        self.0.done_with_trailers(trailers)
+    }
     /// This is used for error pages, where it's impossible to parse input
     /// headers (i.e. get Head object needed for `Message::new`)
     pub fn simple<'x>(out_buf: &'x mut Buf, is_head: bool) -> Response<'x>
@@ -180,3 +217,7 @@ impl<'a> Response<'a> {
 pub fn state(resp: Response) -> MessageState {
     resp.0.state()
 }
+
+pub fn buffer<'x>(resp: Response<'x>) -> &'x mut Buf {
+    resp.0.decompose().0
+}
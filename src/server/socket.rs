@@ -0,0 +1,86 @@
+use std::net::SocketAddr;
+
+use ip::IpAddr;
+use rotor::mio::tcp::TcpStream;
+use rotor::mio::unix::UnixStream;
+
+use super::request::Head;
+use super::proxy::ProxyAddrs;
+use super::forwarded::{self, Cidr};
+
+
+/// Verified certificate of the TLS client
+///
+/// Only the fields useful for authorization are extracted. The certificate
+/// is checked against the CA bundle during the handshake, so handlers can
+/// trust these values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCertificate {
+    /// Common name (CN) of the subject
+    pub common_name: Option<String>,
+    /// DNS names from the subject alternative names extension
+    pub dns_names: Vec<String>,
+    /// IP addresses from the subject alternative names extension
+    pub ip_addrs: Vec<IpAddr>,
+}
+
+/// A socket that knows properties of the connection
+///
+/// The information is put into the `Head` of every request. Unix sockets
+/// have no (IP) addresses, so they are always `None` for them.
+pub trait SocketInfo {
+    fn peer_addr(&self) -> Option<SocketAddr>;
+    fn local_addr(&self) -> Option<SocketAddr>;
+    /// Returns true if connection is encrypted with TLS
+    fn is_tls(&self) -> bool {
+        false
+    }
+    /// Returns server name requested by TLS client (SNI)
+    fn server_name(&self) -> Option<String> {
+        None
+    }
+    /// Returns verified certificate of the client (mutual TLS)
+    fn peer_certificate(&self) -> Option<PeerCertificate> {
+        None
+    }
+    /// Returns application protocol selected with TLS ALPN extension
+    ///
+    /// When it's `h2`, the `Parser` switches to HTTP/2 right away.
+    fn negotiated_protocol(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+impl SocketInfo for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+}
+
+impl SocketInfo for UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Fills the connection properties of the request head
+///
+/// The client address is resolved from forwarding headers if the peer is
+/// one of the `trusted` proxies, so headers must be already parsed.
+pub fn fill_head<S: SocketInfo>(head: &mut Head, sock: &S,
+    proxy: Option<ProxyAddrs>, trusted: &[Cidr])
+{
+    head.peer_addr = sock.peer_addr();
+    head.local_addr = sock.local_addr();
+    head.proxy = proxy;
+    head.https = sock.is_tls();
+    head.server_name = sock.server_name();
+    head.peer_certificate = sock.peer_certificate();
+    forwarded::resolve(head, trusted);
+}
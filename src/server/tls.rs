@@ -0,0 +1,744 @@
+//! TLS termination (requires `tls` feature)
+//!
+//! Wrap the listener into `TlsListener` to get encrypted connections:
+//!
+//! ```ignore
+//! let acceptor = TlsAcceptor::from_pem_files("cert.pem", "key.pem")
+//!     .unwrap();
+//! let lst = TlsListener::new(TcpListener::bind(&addr).unwrap(), acceptor);
+//! Accept::<Stream<Parser<MyServer, _>>, _>::new(lst, scope)
+//! ```
+//!
+//! The handshake is made lazily by the first reads and writes on the
+//! stream, so accepting connection never blocks.
+//!
+//! To serve multiple domains on the same address, create the acceptor with
+//! `TlsAcceptor::with_resolver()`. The certificate is chosen by the server
+//! name sent by the client (SNI), and the `CertResolver` may be updated at
+//! any time, for example by keeping it in your `Context`.
+//!
+//! Client certificates are verified when enabled by
+//! `TlsAcceptor::verify_clients()`, the certificate is in
+//! `Head::peer_certificate` then.
+//!
+//! HTTP/2 is negotiated with ALPN when enabled by
+//! `TlsAcceptor::enable_http2()`.
+use std::io;
+use std::mem;
+use std::io::{Read, Write};
+use std::ascii::AsciiExt;
+use std::sync::{Arc, RwLock};
+use std::path::Path;
+use std::net::{SocketAddr, Ipv4Addr, Ipv6Addr};
+use std::collections::HashMap;
+
+use ip::IpAddr;
+
+use rotor::mio::{Evented, Selector, Token, EventSet, PollOpt, TryAccept};
+use rotor_stream::SocketError;
+use openssl::ssl::{Ssl, SslContext, SslMethod, NonblockingSslStream};
+use openssl::ssl::{SSL_VERIFY_PEER, SSL_VERIFY_FAIL_IF_NO_PEER_CERT};
+use openssl::ssl::error::{SslError, NonblockingSslError};
+use openssl::x509::{X509, X509FileType};
+use openssl::nid::Nid;
+
+use super::socket::{SocketInfo, PeerCertificate};
+
+
+/// Value of `SSL_TLSEXT_ERR_OK` for the servername callback
+const SSL_TLSEXT_ERR_OK: i32 = 0;
+
+/// Server-side TLS configuration shared by all connections
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    context: Arc<SslContext>,
+}
+
+/// Whether clients must present a certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAuth {
+    /// Certificate is verified if sent, but connections without
+    /// certificate are accepted too
+    Optional,
+    /// Handshake fails if client doesn't send a valid certificate
+    Required,
+}
+
+/// Certificates for TLS virtual hosts keyed by server name
+///
+/// Names are either exact (`www.example.com`) or wildcards
+/// (`*.example.com`), the latter match a single label only, like
+/// certificates do. Exact names take precedence.
+///
+/// All methods take `&self`, so the resolver may be shared between the
+/// acceptor and the `Context` (in an `Arc`) to replace certificates while
+/// the server is running. Established connections are not affected.
+pub struct CertResolver {
+    names: RwLock<HashMap<String, Arc<SslContext>>>,
+}
+
+/// A TLS-encrypted stream
+///
+/// Implements everything needed to be used in `rotor_stream::Stream` and
+/// `SocketInfo::is_tls()` returns true, so `Head::https` is set for every
+/// request received over it.
+pub struct TlsStream<S> {
+    stream: NonblockingSslStream<S>,
+}
+
+/// A listener which wraps every accepted connection into `TlsStream`
+pub struct TlsListener<L> {
+    listener: L,
+    acceptor: TlsAcceptor,
+}
+
+impl TlsAcceptor {
+    /// Loads certificate chain and private key from PEM files
+    ///
+    /// The certificate file may contain intermediate certificates after the
+    /// certificate of the server itself.
+    pub fn from_pem_files<C, K>(cert: C, key: K)
+        -> Result<TlsAcceptor, SslError>
+        where C: AsRef<Path>, K: AsRef<Path>
+    {
+        TlsAcceptor::context_from_pem_files(cert, key)
+            .map(TlsAcceptor::from_context)
+    }
+    /// Creates OpenSSL context with certificate chain and private key
+    /// loaded from PEM files
+    pub fn context_from_pem_files<C, K>(cert: C, key: K)
+        -> Result<SslContext, SslError>
+        where C: AsRef<Path>, K: AsRef<Path>
+    {
+        let mut ctx = try!(SslContext::new(SslMethod::Sslv23));
+        try!(ctx.set_certificate_chain_file(cert.as_ref(),
+                                            X509FileType::PEM));
+        try!(ctx.set_private_key_file(key.as_ref(), X509FileType::PEM));
+        try!(ctx.check_private_key());
+        Ok(ctx)
+    }
+    /// Uses fully configured OpenSSL context
+    pub fn from_context(context: SslContext) -> TlsAcceptor {
+        TlsAcceptor {
+            context: Arc::new(context),
+        }
+    }
+    /// Enables verification of client certificates against CA bundle
+    ///
+    /// The `ca_file` contains one or more PEM-encoded certificates. Should
+    /// be called on the context before passing it to `from_context()` or
+    /// `with_resolver()`.
+    ///
+    /// When using `CertResolver`, the verification mode and callback always
+    /// come from the default context, because OpenSSL doesn't copy them
+    /// when the context selected by SNI is set (`SSL_set_SSL_CTX`). But the
+    /// trusted certificates are those of the selected context, so call it
+    /// with the same `ca_file` on every context added to the resolver too,
+    /// otherwise no client certificate is accepted for these names.
+    pub fn verify_clients<P: AsRef<Path>>(context: &mut SslContext,
+        ca_file: P, mode: ClientAuth)
+        -> Result<(), SslError>
+    {
+        try!(context.set_CA_file(ca_file.as_ref()));
+        let flags = match mode {
+            ClientAuth::Optional => SSL_VERIFY_PEER,
+            ClientAuth::Required => {
+                SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT
+            }
+        };
+        context.set_verify(flags, None);
+        Ok(())
+    }
+    /// Advertises HTTP/2 (with HTTP/1.1 fallback) using ALPN
+    ///
+    /// Clients which select `h2` are served by HTTP/2 engine of the
+    /// `Parser`, the same `Server` handles requests of both versions.
+    /// Protocol is chosen by the context selected by SNI, so this should be
+    /// called on every context added to `CertResolver` too.
+    pub fn enable_http2(context: &mut SslContext) {
+        context.set_alpn_protocols(&[&b"h2"[..], &b"http/1.1"[..]]);
+    }
+    /// Selects certificate by the server name (SNI) using the resolver
+    ///
+    /// The `default` context is used when client sends no server name or
+    /// there is no certificate for the name.
+    pub fn with_resolver(mut default: SslContext,
+        resolver: Arc<CertResolver>)
+        -> TlsAcceptor
+    {
+        default.set_servername_callback_with_data(
+            Some(select_certificate), resolver);
+        TlsAcceptor::from_context(default)
+    }
+    /// Starts server-side handshake on the socket
+    pub fn accept<S>(&self, sock: S) -> Result<TlsStream<S>, SslError>
+        where S: Read + Write
+    {
+        let ctx = &*self.context;
+        let stream = try!(NonblockingSslStream::accept(ctx, sock));
+        Ok(TlsStream { stream: stream })
+    }
+}
+
+fn select_certificate(ssl: &mut Ssl, _alert: &mut i32,
+    resolver: &Arc<CertResolver>)
+    -> i32
+{
+    if let Some(ctx) = ssl.get_servername().and_then(|n| resolver.get(&n)) {
+        // The returned value refers to the same context but doesn't own
+        // a reference, so it must not be dropped
+        mem::forget(ssl.set_ssl_context(&ctx));
+    }
+    SSL_TLSEXT_ERR_OK
+}
+
+/// Returns wildcard name matching the host, i.e. `*.example.com` for
+/// `www.example.com`
+fn wildcard(name: &str) -> Option<String> {
+    name.find('.').map(|dot| format!("*{}", &name[dot..]))
+}
+
+fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::V4(
+            Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))),
+        16 => {
+            let mut seg = [0u16; 8];
+            for (i, pair) in bytes.chunks(2).enumerate() {
+                seg[i] = (pair[0] as u16) << 8 | pair[1] as u16;
+            }
+            Some(IpAddr::V6(Ipv6Addr::new(seg[0], seg[1], seg[2], seg[3],
+                                          seg[4], seg[5], seg[6], seg[7])))
+        }
+        _ => None,
+    }
+}
+
+fn peer_certificate(cert: &X509) -> PeerCertificate {
+    let mut dns_names = Vec::new();
+    let mut ip_addrs = Vec::new();
+    if let Some(names) = cert.subject_alt_names() {
+        for name in names.iter() {
+            if let Some(dns) = name.dnsname() {
+                dns_names.push(dns.to_string());
+            } else if let Some(ip) = name.ipaddress().and_then(ip_from_bytes)
+            {
+                ip_addrs.push(ip);
+            }
+        }
+    }
+    PeerCertificate {
+        common_name: cert.subject_name().text_by_nid(Nid::CN)
+            .map(|x| x.to_string()),
+        dns_names: dns_names,
+        ip_addrs: ip_addrs,
+    }
+}
+
+impl CertResolver {
+    pub fn new() -> CertResolver {
+        CertResolver {
+            names: RwLock::new(HashMap::new()),
+        }
+    }
+    /// Adds or replaces certificate for the name (may be a wildcard)
+    pub fn insert(&self, name: &str, acceptor: &TlsAcceptor) {
+        self.names.write().unwrap()
+            .insert(name.to_ascii_lowercase(), acceptor.context.clone());
+    }
+    /// Removes certificate for the name, returns false if there was none
+    pub fn remove(&self, name: &str) -> bool {
+        self.names.write().unwrap()
+            .remove(&name.to_ascii_lowercase()).is_some()
+    }
+    /// Finds the certificate for the server name requested by client
+    pub fn get(&self, server_name: &str) -> Option<Arc<SslContext>> {
+        let name = server_name.trim_right_matches('.').to_ascii_lowercase();
+        let names = self.names.read().unwrap();
+        names.get(&name).or_else(|| {
+            wildcard(&name).and_then(|w| names.get(&w))
+        }).cloned()
+    }
+}
+
+impl<S> TlsStream<S> {
+    pub fn get_ref(&self) -> &S {
+        self.stream.get_ref()
+    }
+    pub fn get_mut(&mut self) -> &mut S {
+        self.stream.get_mut()
+    }
+}
+
+fn convert_error(e: NonblockingSslError) -> io::Error {
+    match e {
+        NonblockingSslError::WantRead | NonblockingSslError::WantWrite => {
+            io::Error::new(io::ErrorKind::WouldBlock, "TLS wants more data")
+        }
+        NonblockingSslError::SslError(SslError::StreamError(e)) => e,
+        NonblockingSslError::SslError(SslError::SslSessionClosed) => {
+            io::Error::new(io::ErrorKind::ConnectionAborted,
+                           "TLS session closed")
+        }
+        NonblockingSslError::SslError(e) => {
+            io::Error::new(io::ErrorKind::Other, e)
+        }
+    }
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream.read(buf) {
+            // Peer has sent `close_notify`, it's a clean end of stream
+            Err(NonblockingSslError::SslError(SslError::SslSessionClosed))
+            => Ok(0),
+            res => res.map_err(convert_error),
+        }
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf).map_err(convert_error)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.get_mut().flush()
+    }
+}
+
+impl<S: Evented> Evented for TlsStream<S> {
+    fn register(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.get_ref().register(selector, token, interest, opts)
+    }
+    fn reregister(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.get_ref().reregister(selector, token, interest, opts)
+    }
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.get_ref().deregister(selector)
+    }
+}
+
+impl<S: SocketError> SocketError for TlsStream<S> {
+    fn take_socket_error(&self) -> io::Result<()> {
+        self.get_ref().take_socket_error()
+    }
+}
+
+impl<S: SocketInfo> SocketInfo for TlsStream<S> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().local_addr()
+    }
+    fn is_tls(&self) -> bool {
+        true
+    }
+    fn server_name(&self) -> Option<String> {
+        self.stream.ssl().get_servername()
+    }
+    fn peer_certificate(&self) -> Option<PeerCertificate> {
+        // Certificate is only there when verification has succeeded,
+        // otherwise handshake fails
+        self.stream.ssl().peer_certificate().map(|c| peer_certificate(&c))
+    }
+    fn negotiated_protocol(&self) -> Option<&[u8]> {
+        self.stream.ssl().selected_alpn_protocol()
+    }
+}
+
+impl<L> TlsListener<L> {
+    pub fn new(listener: L, acceptor: TlsAcceptor) -> TlsListener<L> {
+        TlsListener {
+            listener: listener,
+            acceptor: acceptor,
+        }
+    }
+}
+
+impl<L> TryAccept for TlsListener<L>
+    where L: TryAccept, L::Output: Read + Write
+{
+    type Output = TlsStream<L::Output>;
+    fn accept(&self) -> io::Result<Option<TlsStream<L::Output>>> {
+        loop {
+            match try!(self.listener.accept()) {
+                Some(sock) => match self.acceptor.accept(sock) {
+                    Ok(stream) => return Ok(Some(stream)),
+                    // Connection is dropped, don't fail the listener
+                    Err(_) => continue,
+                },
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl<L: Evented> Evented for TlsListener<L> {
+    fn register(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.listener.register(selector, token, interest, opts)
+    }
+    fn reregister(&self, selector: &mut Selector, token: Token,
+        interest: EventSet, opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.listener.reregister(selector, token, interest, opts)
+    }
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.listener.deregister(selector)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::File;
+    use std::thread;
+    use std::sync::Arc;
+    use std::path::PathBuf;
+    use std::io::{Read, Write};
+    use std::sync::mpsc::channel;
+    use std::net::{TcpListener, TcpStream, Ipv4Addr, Ipv6Addr};
+
+    use ip::IpAddr;
+    use rotor::{self, Scope};
+    use rotor::mio::tcp::TcpListener as MioListener;
+    use rotor_stream::{Accept, Stream, Deadline};
+    use hyper::status::StatusCode;
+    use hyper::header::ContentLength;
+    use openssl::ssl::{Ssl, SslContext, SslMethod, SslStream};
+    use openssl::x509::{X509Generator, X509FileType};
+    use openssl::crypto::hash::Type;
+    use time::Duration;
+
+    use server::{self, Server, Head, Response, RecvMode, NoUpgrade};
+    use server::Parser;
+    use test_util::find;
+    use super::{TlsAcceptor, TlsStream, TlsListener, CertResolver};
+    use super::{ClientAuth, wildcard, ip_from_bytes, peer_certificate};
+
+    /// Generates self-signed certificate, returns paths to the PEM files
+    fn generate(name: &str) -> (PathBuf, PathBuf) {
+        let (cert, key) = X509Generator::new()
+            .set_bitlength(2048)
+            .set_valid_period(1)
+            .add_name("CN".to_string(), name.to_string())
+            .set_sign_hash(Type::SHA256)
+            .generate().unwrap();
+        let dir = env::temp_dir();
+        let cert_path = dir.join(format!("rotor-http-{}-cert.pem", name));
+        let key_path = dir.join(format!("rotor-http-{}-key.pem", name));
+        cert.write_pem(&mut File::create(&cert_path).unwrap()).unwrap();
+        key.write_pem(&mut File::create(&key_path).unwrap()).unwrap();
+        (cert_path, key_path)
+    }
+
+    /// Connects to the acceptor with the client context, exchanges
+    /// a message and returns the server side of the stream
+    fn connect(acceptor: TlsAcceptor, ctx: SslContext)
+        -> Option<TlsStream<TcpStream>>
+    {
+        connect_to(acceptor, ctx, "localhost").0
+    }
+
+    /// Same as `connect()` but with specified server name, also returns
+    /// the common name of the certificate which client has received
+    fn connect_to(acceptor: TlsAcceptor, ctx: SslContext, name: &str)
+        -> (Option<TlsStream<TcpStream>>, Option<String>)
+    {
+        let lst = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = lst.local_addr().unwrap();
+        let name = name.to_string();
+        let client = thread::spawn(move || {
+            let sock = TcpStream::connect(addr).unwrap();
+            let ssl = Ssl::new(&ctx).unwrap();
+            ssl.set_hostname(&name).unwrap();
+            let mut stream = match SslStream::connect(ssl, sock) {
+                Ok(stream) => stream,
+                Err(_) => return None,
+            };
+            let server_name = stream.ssl().peer_certificate()
+                .and_then(|c| peer_certificate(&c).common_name);
+            // Server may reject the client after the handshake
+            stream.write_all(b"ping").ok();
+            let mut buf = [0u8; 4];
+            if stream.read_exact(&mut buf).is_ok() {
+                assert_eq!(&buf, b"pong");
+            }
+            server_name
+        });
+        let (sock, _) = lst.accept().unwrap();
+        let mut stream = acceptor.accept(sock).unwrap();
+        let mut buf = [0u8; 4];
+        if stream.read_exact(&mut buf).is_err() {
+            return (None, client.join().unwrap());
+        }
+        assert_eq!(&buf, b"ping");
+        stream.write_all(b"pong").unwrap();
+        (Some(stream), client.join().unwrap())
+    }
+
+    #[test]
+    fn handshake() {
+        let (cert, key) = generate("localhost");
+        let acceptor = TlsAcceptor::from_pem_files(&cert, &key).unwrap();
+        let ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+        let stream = connect(acceptor, ctx).unwrap();
+        assert_eq!(stream.stream.ssl().get_servername(),
+                   Some("localhost".to_string()));
+        assert!(stream.stream.ssl().peer_certificate().is_none());
+    }
+
+    struct Context;
+
+    impl server::Context for Context {}
+
+    /// Replies with the request body and whether request is secure
+    struct Echo(bool, Vec<u8>);
+
+    impl Server for Echo {
+        type Context = Context;
+        type Upgrade = NoUpgrade<Context>;
+        fn headers_received(head: &Head, _scope: &mut Scope<Context>)
+            -> Result<(Self, RecvMode, Deadline), StatusCode>
+        {
+            Ok((Echo(head.https, Vec::new()), RecvMode::Progressive(1),
+                Deadline::now() + Duration::seconds(10)))
+        }
+        fn request_start(self, _head: Head, _res: &mut Response,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            Some(self)
+        }
+        fn request_received(self, _data: &[u8], _res: &mut Response,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            unreachable!();
+        }
+        fn request_chunk(mut self, chunk: &[u8], _res: &mut Response,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            self.1.extend(chunk.iter().cloned());
+            Some(self)
+        }
+        fn request_end(self, res: &mut Response, _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            let body = format!("https={} body={}", self.0,
+                               String::from_utf8_lossy(&self.1));
+            res.status(StatusCode::Ok);
+            res.add_header(ContentLength(body.len() as u64)).unwrap();
+            res.done_headers().unwrap();
+            res.write_body(body.as_bytes());
+            res.done();
+            None
+        }
+        fn timeout(self, _res: &mut Response, _scope: &mut Scope<Context>)
+            -> Option<(Self, Deadline)>
+        {
+            unreachable!();
+        }
+        fn wakeup(self, _res: &mut Response, _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn parser() {
+        let (cert, key) = generate("localhost");
+        let acceptor = TlsAcceptor::from_pem_files(&cert, &key).unwrap();
+        let mut event_loop = rotor::EventLoop::new().unwrap();
+        let mut handler = rotor::Handler::new(Context, &mut event_loop);
+        let lst = MioListener::bind(&"127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let addr = lst.local_addr().unwrap();
+        let lst = TlsListener::new(lst, acceptor);
+        assert!(handler.add_machine_with(&mut event_loop, |scope| {
+            Accept::<Stream<Parser<Echo, _>>, _>::new(lst, scope)
+        }).is_ok());
+
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let sock = TcpStream::connect(addr).unwrap();
+            let ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+            let mut stream = SslStream::connect(&ctx, sock).unwrap();
+            // Body is delimited by the end of stream, which is
+            // `close_notify` for TLS
+            stream.write_all(b"POST / HTTP/1.0\r\n\r\nhello").unwrap();
+            stream.shutdown().unwrap();
+            let mut data = Vec::new();
+            let mut buf = [0u8; 1024];
+            loop {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => data.extend(buf[..n].iter().cloned()),
+                }
+            }
+            tx.send(data).unwrap();
+        });
+        let mut response = None;
+        run_until!(event_loop, handler, {
+            response = response.or_else(|| rx.try_recv().ok());
+            response.is_some()
+        });
+        let data = response.unwrap();
+        assert!(find(&data, b" 200 OK\r\n").is_some());
+        assert!(find(&data, b"https=true body=hello").is_some());
+    }
+
+    #[test]
+    fn bad_key() {
+        let (cert, _) = generate("bad-cert");
+        let (_, key) = generate("bad-key");
+        assert!(TlsAcceptor::from_pem_files(&cert, &key).is_err());
+    }
+
+    #[test]
+    fn client_certificate() {
+        let (cert, key) = generate("localhost");
+        // Self-signed client certificate is it's own CA
+        let (client_cert, client_key) = generate("client");
+        let mut server = TlsAcceptor::context_from_pem_files(&cert, &key)
+            .unwrap();
+        TlsAcceptor::verify_clients(&mut server, &client_cert,
+            ClientAuth::Required).unwrap();
+        let acceptor = TlsAcceptor::from_context(server);
+
+        let mut ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+        ctx.set_certificate_file(&client_cert, X509FileType::PEM).unwrap();
+        ctx.set_private_key_file(&client_key, X509FileType::PEM).unwrap();
+        let stream = connect(acceptor.clone(), ctx).unwrap();
+        let peer = stream.stream.ssl().peer_certificate().unwrap();
+        assert_eq!(peer_certificate(&peer).common_name,
+                   Some("client".to_string()));
+
+        // No certificate
+        let ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+        assert!(connect(acceptor, ctx).is_none());
+    }
+
+    #[test]
+    fn sni_client_certificate() {
+        let (cert, key) = generate("localhost");
+        let (sni_cert, sni_key) = generate("sni.example.com");
+        let (client_cert, client_key) = generate("client");
+        let mut default = TlsAcceptor::context_from_pem_files(&cert, &key)
+            .unwrap();
+        TlsAcceptor::verify_clients(&mut default, &client_cert,
+            ClientAuth::Required).unwrap();
+        let mut sni = TlsAcceptor::context_from_pem_files(&sni_cert,
+                                                          &sni_key)
+            .unwrap();
+        TlsAcceptor::verify_clients(&mut sni, &client_cert,
+            ClientAuth::Required).unwrap();
+        let resolver = Arc::new(CertResolver::new());
+        resolver.insert("sni.example.com", &TlsAcceptor::from_context(sni));
+        let acceptor = TlsAcceptor::with_resolver(default, resolver);
+
+        let mut ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+        ctx.set_certificate_file(&client_cert, X509FileType::PEM).unwrap();
+        ctx.set_private_key_file(&client_key, X509FileType::PEM).unwrap();
+        let (stream, server_cert) = connect_to(acceptor.clone(), ctx,
+                                               "sni.example.com");
+        assert_eq!(server_cert, Some("sni.example.com".to_string()));
+        let stream = stream.unwrap();
+        assert_eq!(stream.stream.ssl().get_servername(),
+                   Some("sni.example.com".to_string()));
+        let peer = stream.stream.ssl().peer_certificate().unwrap();
+        assert_eq!(peer_certificate(&peer).common_name,
+                   Some("client".to_string()));
+
+        // No certificate
+        let ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+        let (stream, _) = connect_to(acceptor.clone(), ctx,
+                                     "sni.example.com");
+        assert!(stream.is_none());
+
+        // Certificate signed by the CA which is not trusted
+        let (other_cert, other_key) = generate("other-client");
+        let mut ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+        ctx.set_certificate_file(&other_cert, X509FileType::PEM).unwrap();
+        ctx.set_private_key_file(&other_key, X509FileType::PEM).unwrap();
+        let (stream, _) = connect_to(acceptor, ctx, "sni.example.com");
+        assert!(stream.is_none());
+    }
+
+    #[test]
+    fn sni_without_verify_clients() {
+        let (cert, key) = generate("localhost");
+        let (sni_cert, sni_key) = generate("plain.example.com");
+        let (client_cert, client_key) = generate("client");
+        let mut default = TlsAcceptor::context_from_pem_files(&cert, &key)
+            .unwrap();
+        TlsAcceptor::verify_clients(&mut default, &client_cert,
+            ClientAuth::Required).unwrap();
+        let sni = TlsAcceptor::context_from_pem_files(&sni_cert, &sni_key)
+            .unwrap();
+        let resolver = Arc::new(CertResolver::new());
+        resolver.insert("plain.example.com",
+                        &TlsAcceptor::from_context(sni));
+        let acceptor = TlsAcceptor::with_resolver(default, resolver);
+
+        // Verification mode of the default context is still in effect
+        let ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+        let (stream, _) = connect_to(acceptor.clone(), ctx,
+                                     "plain.example.com");
+        assert!(stream.is_none());
+
+        // But the selected context trusts no certificates
+        let mut ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+        ctx.set_certificate_file(&client_cert, X509FileType::PEM).unwrap();
+        ctx.set_private_key_file(&client_key, X509FileType::PEM).unwrap();
+        let (stream, _) = connect_to(acceptor, ctx, "plain.example.com");
+        assert!(stream.is_none());
+    }
+
+    #[test]
+    fn ip_addrs() {
+        assert_eq!(ip_from_bytes(&[127, 0, 0, 1]),
+                   Some(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))));
+        let mut v6 = [0u8; 16];
+        v6[15] = 1;
+        assert_eq!(ip_from_bytes(&v6),
+                   Some(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))));
+        assert_eq!(ip_from_bytes(&[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn wildcards() {
+        assert_eq!(wildcard("www.example.com"),
+                   Some("*.example.com".to_string()));
+        assert_eq!(wildcard("localhost"), None);
+    }
+
+    #[test]
+    fn resolver() {
+        let ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+        let acceptor = TlsAcceptor::from_context(ctx);
+        let resolver = CertResolver::new();
+        resolver.insert("*.example.com", &acceptor);
+        resolver.insert("Example.org", &acceptor);
+        assert!(resolver.get("www.example.com").is_some());
+        assert!(resolver.get("WWW.EXAMPLE.COM.").is_some());
+        assert!(resolver.get("example.com").is_none());
+        assert!(resolver.get("a.b.example.com").is_none());
+        assert!(resolver.get("example.org").is_some());
+        assert!(resolver.remove("example.org"));
+        assert!(!resolver.remove("example.org"));
+        assert!(resolver.get("example.org").is_none());
+    }
+}
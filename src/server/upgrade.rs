@@ -0,0 +1,93 @@
+use std::marker::PhantomData;
+
+use rotor::Scope;
+use rotor_stream::{StreamSocket, Request, Transport, Exception};
+
+use super::context::Context;
+
+
+/// A protocol the connection is switched to after `101 Switching Protocols`
+///
+/// The methods mirror `rotor_stream::Protocol`, but are generic over the
+/// socket type, so the same `Server` implementation works for any
+/// `StreamSocket`. Returning `None` from any method closes the connection.
+pub trait Upgrade: Sized {
+    type Context: Context;
+
+    /// Called when the `101 Switching Protocols` response is flushed
+    ///
+    /// Any bytes received after the request headers (and request body if
+    /// there was any) are already in `transport.input()`, so this is the
+    /// first chance to process them.
+    fn start<S: StreamSocket>(self, transport: &mut Transport<S>,
+        scope: &mut Scope<Self::Context>)
+        -> Request<Self>;
+
+    fn bytes_read<S: StreamSocket>(self, transport: &mut Transport<S>,
+        end: usize, scope: &mut Scope<Self::Context>)
+        -> Request<Self>;
+
+    fn bytes_flushed<S: StreamSocket>(self, transport: &mut Transport<S>,
+        scope: &mut Scope<Self::Context>)
+        -> Request<Self>;
+
+    fn exception<S: StreamSocket>(self, transport: &mut Transport<S>,
+        exc: Exception, scope: &mut Scope<Self::Context>)
+        -> Request<Self>;
+
+    fn timeout<S: StreamSocket>(self, transport: &mut Transport<S>,
+        scope: &mut Scope<Self::Context>)
+        -> Request<Self>;
+
+    fn wakeup<S: StreamSocket>(self, transport: &mut Transport<S>,
+        scope: &mut Scope<Self::Context>)
+        -> Request<Self>;
+}
+
+enum Void {}
+
+/// An upgrade protocol for servers that never switch protocols
+///
+/// Use it as `type Upgrade = NoUpgrade<Context>` in the `Server`. The
+/// value of this type can't be constructed.
+pub struct NoUpgrade<C>(Void, PhantomData<*const C>);
+
+impl<C: Context> Upgrade for NoUpgrade<C> {
+    type Context = C;
+    fn start<S: StreamSocket>(self, _transport: &mut Transport<S>,
+        _scope: &mut Scope<C>)
+        -> Request<Self>
+    {
+        match self.0 {}
+    }
+    fn bytes_read<S: StreamSocket>(self, _transport: &mut Transport<S>,
+        _end: usize, _scope: &mut Scope<C>)
+        -> Request<Self>
+    {
+        match self.0 {}
+    }
+    fn bytes_flushed<S: StreamSocket>(self, _transport: &mut Transport<S>,
+        _scope: &mut Scope<C>)
+        -> Request<Self>
+    {
+        match self.0 {}
+    }
+    fn exception<S: StreamSocket>(self, _transport: &mut Transport<S>,
+        _exc: Exception, _scope: &mut Scope<C>)
+        -> Request<Self>
+    {
+        match self.0 {}
+    }
+    fn timeout<S: StreamSocket>(self, _transport: &mut Transport<S>,
+        _scope: &mut Scope<C>)
+        -> Request<Self>
+    {
+        match self.0 {}
+    }
+    fn wakeup<S: StreamSocket>(self, _transport: &mut Transport<S>,
+        _scope: &mut Scope<C>)
+        -> Request<Self>
+    {
+        match self.0 {}
+    }
+}
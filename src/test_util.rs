@@ -0,0 +1,66 @@
+//! Helpers for the tests which drive state machines by the real event loop
+//! against a stand-in peer on the loopback interface
+//!
+//! The peer is a plain blocking socket used from the same thread, so every
+//! read on it has a short timeout and the loop is turned between reads.
+use std::rc::Rc;
+use std::cell::Cell;
+use std::io::{Read, ErrorKind};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use client::Wake;
+
+
+/// Turns the event loop until the condition is true
+///
+/// Panics if condition is not reached in about five seconds.
+macro_rules! run_until {
+    ($event_loop:expr, $handler:expr, $cond:expr) => {{
+        let mut iterations = 0;
+        while !$cond {
+            iterations += 1;
+            assert!(iterations < 500, "condition is not reached in time");
+            $event_loop.run_once(&mut $handler, Some(10)).unwrap();
+        }
+    }}
+}
+
+/// Reads whatever peer has received so far into the `data`
+///
+/// Returns `false` when connection is closed by the other side
+pub fn read_some(peer: &mut TcpStream, data: &mut Vec<u8>) -> bool {
+    peer.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+    let mut buf = [0u8; 4096];
+    match peer.read(&mut buf) {
+        Ok(0) => false,
+        Ok(n) => {
+            data.extend(buf[..n].iter().cloned());
+            true
+        }
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                      e.kind() == ErrorKind::TimedOut => true,
+        // Connection reset is also a kind of close
+        Err(_) => false,
+    }
+}
+
+/// Returns position right after the first occurence of `needle`
+pub fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|w| w == needle)
+        .map(|x| x + needle.len())
+}
+
+/// The waker which counts how many times it was woken up
+#[derive(Clone)]
+pub struct Waker(pub Rc<Cell<usize>>);
+
+impl Wake for Waker {
+    fn wake(&self) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+pub fn waker() -> Waker {
+    Waker(Rc::new(Cell::new(0)))
+}
@@ -0,0 +1,322 @@
+use std::io::Write;
+use std::str::from_utf8;
+
+use rotor_stream::Buf;
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum FrameError {
+        ReservedBits {
+            description("Reserved bits are set in the frame header")
+        }
+        UnknownOpcode(code: u8) {
+            description("Unknown frame opcode")
+            display("Unknown frame opcode 0x{:x}", code)
+        }
+        FragmentedControl {
+            description("Control frame is fragmented")
+        }
+        ControlTooLong {
+            description("Control frame payload is longer than 125 bytes")
+        }
+        InvalidClosePayload {
+            description("Close frame payload is malformed")
+        }
+        InvalidCloseCode(code: u16) {
+            description("Close code is not allowed on the wire")
+            display("Close code {} is not allowed on the wire", code)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+/// Parsed header of a single frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub mask: Option<[u8; 4]>,
+    /// Length of the payload (i.e. without the header itself)
+    pub length: u64,
+    /// Number of bytes occupied by the header
+    pub header_size: usize,
+}
+
+impl Opcode {
+    pub fn from_u8(code: u8) -> Option<Opcode> {
+        use self::Opcode::*;
+        match code {
+            0x0 => Some(Continuation),
+            0x1 => Some(Text),
+            0x2 => Some(Binary),
+            0x8 => Some(Close),
+            0x9 => Some(Ping),
+            0xA => Some(Pong),
+            _ => None,
+        }
+    }
+    pub fn as_u8(self) -> u8 {
+        use self::Opcode::*;
+        match self {
+            Continuation => 0x0,
+            Text => 0x1,
+            Binary => 0x2,
+            Close => 0x8,
+            Ping => 0x9,
+            Pong => 0xA,
+        }
+    }
+    pub fn is_control(self) -> bool {
+        self.as_u8() & 0x8 != 0
+    }
+}
+
+/// Returns the size of the frame header
+///
+/// Only first two bytes of the frame are needed to find that out
+///
+/// # Panics
+///
+/// When `data` is shorter than two bytes
+pub fn header_size(data: &[u8]) -> usize {
+    let mask = if data[1] & 0x80 != 0 { 4 } else { 0 };
+    match data[1] & 0x7F {
+        126 => 4 + mask,
+        127 => 10 + mask,
+        _ => 2 + mask,
+    }
+}
+
+impl Frame {
+    /// Parses frame header
+    ///
+    /// Returns `Ok(None)` when there are not enough bytes in the buffer yet.
+    /// Only the header itself is validated, mask requirements depend on
+    /// the side of the connection, so they are checked by the caller.
+    pub fn parse(data: &[u8]) -> Result<Option<Frame>, FrameError> {
+        use self::FrameError::*;
+        if data.len() < 2 {
+            return Ok(None);
+        }
+        let size = header_size(data);
+        if data.len() < size {
+            return Ok(None);
+        }
+        if data[0] & 0x70 != 0 {
+            return Err(ReservedBits);
+        }
+        let fin = data[0] & 0x80 != 0;
+        let opcode = try!(Opcode::from_u8(data[0] & 0x0F)
+            .ok_or(UnknownOpcode(data[0] & 0x0F)));
+        let (length, mask_off) = match data[1] & 0x7F {
+            126 => (((data[2] as u64) << 8) | data[3] as u64, 4),
+            127 => {
+                let mut x = 0u64;
+                for &b in &data[2..10] {
+                    x = (x << 8) | b as u64;
+                }
+                (x, 10)
+            }
+            x => (x as u64, 2),
+        };
+        let mask = if data[1] & 0x80 != 0 {
+            Some([data[mask_off], data[mask_off+1],
+                  data[mask_off+2], data[mask_off+3]])
+        } else {
+            None
+        };
+        if opcode.is_control() {
+            if !fin {
+                return Err(FragmentedControl);
+            }
+            if length > 125 {
+                return Err(ControlTooLong);
+            }
+        }
+        Ok(Some(Frame {
+            fin: fin,
+            opcode: opcode,
+            mask: mask,
+            length: length,
+            header_size: size,
+        }))
+    }
+}
+
+/// Masks or unmasks data in-place
+///
+/// The `offset` is a position of the `data` in the frame payload, so that
+/// payload may be unmasked by parts.
+pub fn apply_mask(data: &mut [u8], mask: [u8; 4], offset: usize) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= mask[(offset + i) & 3];
+    }
+}
+
+/// Writes a whole frame into the buffer
+///
+/// Server must not mask frames, while client must.
+pub fn write_frame(buf: &mut Buf, fin: bool, opcode: Opcode,
+    mask: Option<[u8; 4]>, data: &[u8])
+{
+    let first = if fin { 0x80 } else { 0 } | opcode.as_u8();
+    let mbit = if mask.is_some() { 0x80 } else { 0 };
+    let len = data.len() as u64;
+    if len < 126 {
+        buf.write(&[first, mbit | len as u8]).unwrap();
+    } else if len <= 0xFFFF {
+        buf.write(&[first, mbit | 126, (len >> 8) as u8, len as u8]).unwrap();
+    } else {
+        buf.write(&[first, mbit | 127,
+            (len >> 56) as u8, (len >> 48) as u8,
+            (len >> 40) as u8, (len >> 32) as u8,
+            (len >> 24) as u8, (len >> 16) as u8,
+            (len >> 8) as u8, len as u8]).unwrap();
+    }
+    match mask {
+        Some(mask) => {
+            buf.write(&mask).unwrap();
+            let mut data = data.to_vec();
+            apply_mask(&mut data, mask, 0);
+            buf.write(&data).unwrap();
+        }
+        None => {
+            buf.write(data).unwrap();
+        }
+    }
+}
+
+/// Checks if close code is allowed to be sent over the wire
+pub fn is_valid_close_code(code: u16) -> bool {
+    match code {
+        1000...1003 | 1007...1011 | 3000...4999 => true,
+        _ => false,
+    }
+}
+
+/// Parses payload of the Close frame into a code and a reason
+///
+/// Empty payload is valid and means there is no status code
+pub fn parse_close(data: &[u8]) -> Result<(Option<u16>, &str), FrameError> {
+    use self::FrameError::*;
+    match data.len() {
+        0 => Ok((None, "")),
+        1 => Err(InvalidClosePayload),
+        _ => {
+            let code = ((data[0] as u16) << 8) | data[1] as u16;
+            if !is_valid_close_code(code) {
+                return Err(InvalidCloseCode(code));
+            }
+            let reason = try!(from_utf8(&data[2..])
+                .map_err(|_| InvalidClosePayload));
+            Ok((Some(code), reason))
+        }
+    }
+}
+
+/// Writes the Close frame with specified code and reason
+pub fn write_close(buf: &mut Buf, mask: Option<[u8; 4]>,
+    code: u16, reason: &str)
+{
+    let mut data = Vec::with_capacity(2 + reason.len());
+    data.push((code >> 8) as u8);
+    data.push(code as u8);
+    data.extend(reason.as_bytes().iter().cloned());
+    write_frame(buf, true, Opcode::Close, mask, &data);
+}
+
+#[cfg(test)]
+mod test {
+    use rotor_stream::Buf;
+    use super::{Frame, Opcode, write_frame, apply_mask, parse_close};
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    #[test]
+    fn unmasked_text() {
+        // Example from RFC 6455 section 5.7
+        let data = b"\x81\x05\x48\x65\x6c\x6c\x6f";
+        let frame = Frame::parse(data).unwrap().unwrap();
+        assert_eq!(frame, Frame {
+            fin: true,
+            opcode: Opcode::Text,
+            mask: None,
+            length: 5,
+            header_size: 2,
+        });
+        let mut buf = Buf::new();
+        write_frame(&mut buf, true, Opcode::Text, None, b"Hello");
+        assert_eq!(&buf[..], &data[..]);
+    }
+
+    #[test]
+    fn masked_text() {
+        let data = b"\x81\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58";
+        let frame = Frame::parse(data).unwrap().unwrap();
+        assert_eq!(frame.mask, Some(MASK));
+        assert_eq!(frame.header_size, 6);
+        let mut payload = data[6..].to_vec();
+        apply_mask(&mut payload, MASK, 0);
+        assert_eq!(&payload[..], b"Hello");
+        let mut buf = Buf::new();
+        write_frame(&mut buf, true, Opcode::Text, Some(MASK), b"Hello");
+        assert_eq!(&buf[..], &data[..]);
+    }
+
+    #[test]
+    fn partial_mask() {
+        let mut payload = b"\x7f\x9f\x4d\x51\x58".to_vec();
+        apply_mask(&mut payload[..3], MASK, 0);
+        apply_mask(&mut payload[3..], MASK, 3);
+        assert_eq!(&payload[..], b"Hello");
+    }
+
+    #[test]
+    fn lengths() {
+        let mut buf = Buf::new();
+        write_frame(&mut buf, true, Opcode::Binary, None, &[0; 256]);
+        assert_eq!(&buf[..4], b"\x82\x7E\x01\x00");
+        assert_eq!(Frame::parse(&buf[..]).unwrap().unwrap().length, 256);
+        let mut buf = Buf::new();
+        write_frame(&mut buf, false, Opcode::Binary, None, &[0; 65536]);
+        assert_eq!(&buf[..10], b"\x02\x7F\x00\x00\x00\x00\x00\x01\x00\x00");
+        let frame = Frame::parse(&buf[..]).unwrap().unwrap();
+        assert_eq!(frame.length, 65536);
+        assert_eq!(frame.header_size, 10);
+        assert!(!frame.fin);
+    }
+
+    #[test]
+    fn incomplete() {
+        assert!(Frame::parse(b"\x81").unwrap().is_none());
+        assert!(Frame::parse(b"\x81\xFE\x01").unwrap().is_none());
+        assert!(Frame::parse(b"\x81\x85\x37\xfa").unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Frame::parse(b"\xC1\x00").is_err());  // RSV1
+        assert!(Frame::parse(b"\x83\x00").is_err());  // reserved opcode
+        assert!(Frame::parse(b"\x09\x00").is_err());  // fragmented ping
+        assert!(Frame::parse(b"\x89\x7E\x00\x7E").is_err());  // long ping
+    }
+
+    #[test]
+    fn close() {
+        assert_eq!(parse_close(b"").unwrap(), (None, ""));
+        assert_eq!(parse_close(b"\x03\xe8bye").unwrap(), (Some(1000), "bye"));
+        assert!(parse_close(b"\x03").is_err());
+        assert!(parse_close(b"\x03\xed").is_err());  // 1005
+        assert!(parse_close(b"\x03\xe8\xff").is_err());
+    }
+}
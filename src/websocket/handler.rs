@@ -0,0 +1,73 @@
+use rotor::Scope;
+use rotor_stream::Deadline;
+
+use server::Context;
+use super::Sender;
+
+
+/// A complete (defragmented) websocket message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message<'a> {
+    Text(&'a str),
+    Binary(&'a [u8]),
+}
+
+/// A handler of the websocket connection
+///
+/// The handler is wrapped into `WebSocket` and returned from
+/// `Server::upgrade()` after successful handshake. Returning `None` from any
+/// method initiates the closing handshake with the `1000 Normal Closure`
+/// code (unless you've already called `Sender::close()`).
+pub trait WebSocketHandler: Sized {
+    type Context: Context;
+
+    /// Maximum size of the message (after defragmentation)
+    ///
+    /// When message is larger connection is closed with the
+    /// `1009 Message Too Big` code. Note that message is buffered in memory
+    /// entirely before being passed to the `message()`.
+    fn max_message_size(&self) -> usize {
+        65536
+    }
+
+    /// A complete message received
+    fn message(self, message: Message, sender: &mut Sender,
+        scope: &mut Scope<Self::Context>)
+        -> Option<Self>;
+
+    /// Ping received
+    ///
+    /// Pong is sent automatically, so you don't need to do that.
+    fn ping(self, _data: &[u8], _sender: &mut Sender,
+        _scope: &mut Scope<Self::Context>)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+
+    /// Pong received
+    fn pong(self, _data: &[u8], _sender: &mut Sender,
+        _scope: &mut Scope<Self::Context>)
+        -> Option<Self>
+    {
+        Some(self)
+    }
+
+    /// Connection is closed
+    ///
+    /// The code is `None` when peer sent no status code, or when connection
+    /// was closed abnormally (i.e. without closing handshake). Reply to the
+    /// close frame is sent automatically.
+    fn closed(self, _code: Option<u16>, _reason: &str,
+        _scope: &mut Scope<Self::Context>)
+    {}
+
+    /// Timeout occured
+    ///
+    /// Return new deadline to continue processing
+    fn timeout(self, sender: &mut Sender, scope: &mut Scope<Self::Context>)
+        -> Option<(Self, Deadline)>;
+
+    fn wakeup(self, sender: &mut Sender, scope: &mut Scope<Self::Context>)
+        -> Option<Self>;
+}
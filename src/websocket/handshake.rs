@@ -0,0 +1,235 @@
+use std::fmt;
+
+use sha1::Sha1;
+use rustc_serialize::base64::{ToBase64, FromBase64, STANDARD};
+use hyper;
+use hyper::method::Method::Get;
+use hyper::version::HttpVersion::Http11;
+use hyper::status::StatusCode::{self, BadRequest, UpgradeRequired};
+use hyper::status::StatusCode::SwitchingProtocols;
+use hyper::header::{Header, HeaderFormat};
+use hyper::header::parsing::from_one_raw_str;
+
+use server::{Head, Response};
+use headers::has_token;
+
+
+/// The magic string which is concatenated to the key (RFC 6455 section 1.3)
+pub const GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// The only protocol version we support
+pub const VERSION: &'static str = "13";
+
+/// The `Sec-WebSocket-Accept` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecWebSocketAccept(pub String);
+
+/// The `Sec-WebSocket-Version` header
+///
+/// Should be sent with the `426 Upgrade Required` response, when client
+/// requested the version we don't support.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecWebSocketVersion(pub String);
+
+/// `Upgrade: websocket` header for the handshake response
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct UpgradeWebSocket;
+
+/// `Connection: Upgrade` header for the handshake response
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ConnectionUpgrade;
+
+impl Header for SecWebSocketAccept {
+    fn header_name() -> &'static str {
+        "Sec-WebSocket-Accept"
+    }
+    fn parse_header(raw: &[Vec<u8>]) -> hyper::Result<SecWebSocketAccept> {
+        from_one_raw_str(raw).map(SecWebSocketAccept)
+    }
+}
+
+impl HeaderFormat for SecWebSocketAccept {
+    fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Header for SecWebSocketVersion {
+    fn header_name() -> &'static str {
+        "Sec-WebSocket-Version"
+    }
+    fn parse_header(raw: &[Vec<u8>]) -> hyper::Result<SecWebSocketVersion> {
+        from_one_raw_str(raw).map(SecWebSocketVersion)
+    }
+}
+
+impl HeaderFormat for SecWebSocketVersion {
+    fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Header for UpgradeWebSocket {
+    fn header_name() -> &'static str {
+        "Upgrade"
+    }
+    fn parse_header(_raw: &[Vec<u8>]) -> hyper::Result<UpgradeWebSocket> {
+        // Only used for writing
+        Err(hyper::Error::Header)
+    }
+}
+
+impl HeaderFormat for UpgradeWebSocket {
+    fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("websocket")
+    }
+}
+
+impl Header for ConnectionUpgrade {
+    fn header_name() -> &'static str {
+        "Connection"
+    }
+    fn parse_header(_raw: &[Vec<u8>]) -> hyper::Result<ConnectionUpgrade> {
+        // Only used for writing
+        Err(hyper::Error::Header)
+    }
+}
+
+impl HeaderFormat for ConnectionUpgrade {
+    fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Upgrade")
+    }
+}
+
+/// Computes the value of `Sec-WebSocket-Accept` for the specified key
+pub fn accept_key(key: &[u8]) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key);
+    sha1.update(GUID.as_bytes());
+    sha1.digest().bytes().to_base64(STANDARD)
+}
+
+/// Validates the websocket handshake request and returns the key
+///
+/// Returns `UpgradeRequired` when the protocol version is not supported,
+/// you should add `SecWebSocketVersion` header to the error response in
+/// this case.
+pub fn validate(head: &Head) -> Result<&[u8], StatusCode> {
+    if head.method != Get || head.version != Http11 {
+        return Err(BadRequest);
+    }
+    if !has_token(&head.headers, "Upgrade", "websocket") ||
+       !has_token(&head.headers, "Connection", "upgrade")
+    {
+        return Err(BadRequest);
+    }
+    match head.headers.get_raw("Sec-WebSocket-Version") {
+        Some(values) if values.len() == 1 &&
+                           &values[0][..] == VERSION.as_bytes() => {}
+        _ => return Err(UpgradeRequired),
+    }
+    match head.headers.get_raw("Sec-WebSocket-Key") {
+        Some(values) if values.len() == 1 => {
+            let key = &values[0][..];
+            match key.from_base64() {
+                Ok(ref nonce) if nonce.len() == 16 => Ok(key),
+                _ => Err(BadRequest),
+            }
+        }
+        _ => Err(BadRequest),
+    }
+}
+
+/// Validates the handshake and writes `101 Switching Protocols` response
+///
+/// The response is left before `done_headers()` so you can add more headers
+/// (i.e. `Sec-WebSocket-Protocol`). Then finish response with
+/// `done_headers()` and `done()` and return the `WebSocket` from
+/// `Server::upgrade()`.
+///
+/// On error response is untouched, so you can return an error page.
+pub fn accept(head: &Head, response: &mut Response) -> Result<(), StatusCode>
+{
+    let accept = accept_key(try!(validate(head)));
+    response.status(SwitchingProtocols);
+    response.add_header(UpgradeWebSocket).unwrap();
+    response.add_header(ConnectionUpgrade).unwrap();
+    response.add_header(SecWebSocketAccept(accept)).unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::status::StatusCode::{BadRequest, UpgradeRequired};
+    use server::{Head, MAX_HEADERS_NUM};
+    use super::{accept_key, validate};
+
+    fn head(request_line: &str, headers: &[&str]) -> Head {
+        let mut data = format!("{}\r\nHost: example.com\r\n",
+                               request_line);
+        for line in headers {
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push_str("\r\n");
+        Head::parse(data.as_bytes(), MAX_HEADERS_NUM).unwrap()
+    }
+
+    const GOOD: &'static [&'static str] = &[
+        "Upgrade: websocket",
+        "Connection: keep-alive, Upgrade",
+        "Sec-WebSocket-Version: 13",
+        "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==",
+    ];
+
+    #[test]
+    fn rfc_key() {
+        assert_eq!(accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+                   "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn valid() {
+        let head = head("GET /chat HTTP/1.1", GOOD);
+        assert_eq!(validate(&head), Ok(&b"dGhlIHNhbXBsZSBub25jZQ=="[..]));
+    }
+
+    #[test]
+    fn bad_request_line() {
+        assert_eq!(validate(&head("POST /chat HTTP/1.1", GOOD)),
+                   Err(BadRequest));
+        assert_eq!(validate(&head("GET /chat HTTP/1.0", GOOD)),
+                   Err(BadRequest));
+    }
+
+    #[test]
+    fn missing_upgrade() {
+        assert_eq!(validate(&head("GET /chat HTTP/1.1", &GOOD[1..])),
+                   Err(BadRequest));
+        assert_eq!(validate(&head("GET /chat HTTP/1.1", &[
+            GOOD[0], "Connection: keep-alive", GOOD[2], GOOD[3],
+        ])), Err(BadRequest));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        assert_eq!(validate(&head("GET /chat HTTP/1.1", &[
+            GOOD[0], GOOD[1], "Sec-WebSocket-Version: 8", GOOD[3],
+        ])), Err(UpgradeRequired));
+        assert_eq!(validate(&head("GET /chat HTTP/1.1", &[
+            GOOD[0], GOOD[1], GOOD[3],
+        ])), Err(UpgradeRequired));
+    }
+
+    #[test]
+    fn bad_key() {
+        // Valid base64, but not 16 bytes
+        assert_eq!(validate(&head("GET /chat HTTP/1.1", &[
+            GOOD[0], GOOD[1], GOOD[2], "Sec-WebSocket-Key: c2hvcnQ=",
+        ])), Err(BadRequest));
+        assert_eq!(validate(&head("GET /chat HTTP/1.1", &[
+            GOOD[0], GOOD[1], GOOD[2], "Sec-WebSocket-Key: !!!",
+        ])), Err(BadRequest));
+        assert_eq!(validate(&head("GET /chat HTTP/1.1", &GOOD[..3])),
+                   Err(BadRequest));
+    }
+}
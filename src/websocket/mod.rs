@@ -0,0 +1,23 @@
+//! WebSocket (RFC 6455) implementation
+//!
+//! Websocket connection starts as an ordinary HTTP/1.1 request handled by
+//! `server::Server`. To accept it, call `websocket::accept()` which validates
+//! the handshake and writes `101 Switching Protocols` response, finish the
+//! response and return `WebSocket` from `Server::upgrade()`. After the
+//! response is flushed, the connection is driven by `WebSocketHandler`.
+//!
+//! Messages are defragmented before passing to the handler, pings are
+//! replied automatically and closing handshake is done by the library.
+//! No extensions are supported yet.
+mod frame;
+mod handshake;
+mod handler;
+mod protocol;
+
+pub use self::frame::{Frame, Opcode, FrameError};
+pub use self::handshake::{SecWebSocketAccept, SecWebSocketVersion};
+pub use self::handshake::{accept_key, validate, accept};
+pub use self::handler::{WebSocketHandler, Message};
+pub use self::protocol::{WebSocket, Sender};
+pub use self::protocol::{NORMAL_CLOSURE, GOING_AWAY, PROTOCOL_ERROR};
+pub use self::protocol::{INVALID_PAYLOAD, MESSAGE_TOO_BIG};
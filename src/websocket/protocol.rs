@@ -0,0 +1,627 @@
+use std::str::from_utf8;
+
+use rotor::Scope;
+use rotor_stream::{Buf, MAX_BUF_SIZE};
+use rotor_stream::{StreamSocket, Deadline, Expectation as E};
+use rotor_stream::{Request, Transport, Exception};
+
+use server::{Context, Upgrade};
+use super::frame::{Frame, Opcode, FrameError, header_size, apply_mask};
+use super::frame::{write_frame, write_close, parse_close};
+use super::handler::{WebSocketHandler, Message};
+
+
+pub const NORMAL_CLOSURE: u16 = 1000;
+pub const GOING_AWAY: u16 = 1001;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const INVALID_PAYLOAD: u16 = 1007;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Writes messages to the websocket
+///
+/// Messages are put into the output buffer immediately, and are sent on
+/// the next loop iteration. Server frames are never fragmented nor masked.
+pub struct Sender<'a> {
+    buf: &'a mut Buf,
+    closed: &'a mut bool,
+}
+
+enum State {
+    /// Waiting for the frame header (bytes needed)
+    Header(usize),
+    /// Waiting for the payload of the frame
+    Payload(Frame),
+    /// Closing handshake is done, flushing the output
+    Closing,
+}
+
+/// The state machine of the websocket connection
+///
+/// Return it from `Server::upgrade()` to drive the connection after
+/// the `101 Switching Protocols` response is sent.
+pub struct WebSocket<H: WebSocketHandler> {
+    /// Handler is `None` when we wait the reply to our close frame
+    handler: Option<H>,
+    state: State,
+    deadline: Deadline,
+    /// Opcode and payload of the fragmented message received so far
+    message: Option<(Opcode, Vec<u8>)>,
+    close_sent: bool,
+}
+
+impl<'a> Sender<'a> {
+    fn frame(&mut self, opcode: Opcode, data: &[u8]) {
+        if *self.closed {
+            panic!("Called {:?} method on websocket after close", opcode);
+        }
+        write_frame(self.buf, true, opcode, None, data);
+    }
+    /// Send a text message
+    ///
+    /// # Panics
+    ///
+    /// When `close()` is already called
+    pub fn text(&mut self, data: &str) {
+        self.frame(Opcode::Text, data.as_bytes())
+    }
+    /// Send a binary message
+    ///
+    /// # Panics
+    ///
+    /// When `close()` is already called
+    pub fn binary(&mut self, data: &[u8]) {
+        self.frame(Opcode::Binary, data)
+    }
+    /// Send a ping
+    ///
+    /// # Panics
+    ///
+    /// When data is longer than 125 bytes or `close()` is already called
+    pub fn ping(&mut self, data: &[u8]) {
+        assert!(data.len() <= 125, "Ping payload is too long");
+        self.frame(Opcode::Ping, data)
+    }
+    /// Send an unsolicited pong (i.e. used as heartbeat)
+    ///
+    /// # Panics
+    ///
+    /// When data is longer than 125 bytes or `close()` is already called
+    pub fn pong(&mut self, data: &[u8]) {
+        assert!(data.len() <= 125, "Pong payload is too long");
+        self.frame(Opcode::Pong, data)
+    }
+    /// Start closing handshake
+    ///
+    /// Subsequent calls are ignored. The `WebSocketHandler::closed()` is
+    /// called when peer replies with the close frame.
+    pub fn close(&mut self, code: u16, reason: &str) {
+        if !*self.closed {
+            write_close(self.buf, None, code, reason);
+            *self.closed = true;
+        }
+    }
+    /// Returns true if close frame is already sent
+    pub fn is_closed(&self) -> bool {
+        *self.closed
+    }
+}
+
+impl<H: WebSocketHandler> WebSocket<H> {
+    pub fn new(handler: H, deadline: Deadline) -> WebSocket<H> {
+        WebSocket {
+            handler: Some(handler),
+            state: State::Header(2),
+            deadline: deadline,
+            message: None,
+            close_sent: false,
+        }
+    }
+    fn expect(self, scope: &mut Scope<H::Context>) -> Request<Self> {
+        let exp = match self.state {
+            State::Header(x) => E::Bytes(x),
+            State::Payload(ref frame) => E::Bytes(frame.length as usize),
+            State::Closing => E::Flush(0),
+        };
+        let deadline = if self.handler.is_some() && !self.close_sent {
+            self.deadline
+        } else {
+            Deadline::now() + scope.byte_timeout()
+        };
+        Some((self, exp, deadline))
+    }
+    fn call<F>(mut self, out: &mut Buf, scope: &mut Scope<H::Context>, f: F)
+        -> Request<Self>
+        where F: FnOnce(H, &mut Sender, &mut Scope<H::Context>) -> Option<H>
+    {
+        self.handler = match self.handler.take() {
+            Some(h) => f(h, &mut Sender {
+                buf: out,
+                closed: &mut self.close_sent,
+            }, scope),
+            // Waiting for close reply, everything else is ignored
+            None => None,
+        };
+        if self.handler.is_none() && !self.close_sent {
+            write_close(out, None, NORMAL_CLOSURE, "");
+            self.close_sent = true;
+        }
+        self.expect(scope)
+    }
+    fn fail(mut self, code: u16, out: &mut Buf,
+        scope: &mut Scope<H::Context>)
+        -> Request<Self>
+    {
+        if !self.close_sent {
+            write_close(out, None, code, "");
+            self.close_sent = true;
+        }
+        self.handler.take().map(|h| h.closed(Some(code), "", scope));
+        self.state = State::Closing;
+        self.expect(scope)
+    }
+    fn deliver(self, opcode: Opcode, data: &[u8], out: &mut Buf,
+        scope: &mut Scope<H::Context>)
+        -> Request<Self>
+    {
+        match opcode {
+            Opcode::Text => match from_utf8(data) {
+                Ok(text) => self.call(out, scope,
+                    |h, sender, scope| h.message(Message::Text(text),
+                                                 sender, scope)),
+                Err(_) => self.fail(INVALID_PAYLOAD, out, scope),
+            },
+            Opcode::Binary => self.call(out, scope,
+                |h, sender, scope| h.message(Message::Binary(data),
+                                             sender, scope)),
+            _ => unreachable!(),
+        }
+    }
+    fn frame(mut self, frame: Frame, data: Vec<u8>, out: &mut Buf,
+        scope: &mut Scope<H::Context>)
+        -> Request<Self>
+    {
+        use super::frame::Opcode::*;
+        match frame.opcode {
+            Ping => {
+                if !self.close_sent {
+                    write_frame(out, true, Pong, None, &data);
+                }
+                self.call(out, scope, |h, sender, scope| {
+                    h.ping(&data, sender, scope)
+                })
+            }
+            Pong => {
+                self.call(out, scope, |h, sender, scope| {
+                    h.pong(&data, sender, scope)
+                })
+            }
+            Close => {
+                let (code, reason) = match parse_close(&data) {
+                    Ok(pair) => pair,
+                    Err(FrameError::InvalidCloseCode(_)) => {
+                        return self.fail(PROTOCOL_ERROR, out, scope);
+                    }
+                    Err(_) => return self.fail(INVALID_PAYLOAD, out, scope),
+                };
+                if !self.close_sent {
+                    match code {
+                        Some(code) => write_close(out, None, code, ""),
+                        None => write_frame(out, true, Close, None, b""),
+                    }
+                    self.close_sent = true;
+                }
+                self.handler.take().map(|h| h.closed(code, reason, scope));
+                self.state = State::Closing;
+                self.expect(scope)
+            }
+            Text | Binary => {
+                if self.message.is_some() {
+                    // New message started before previous one is finished
+                    return self.fail(PROTOCOL_ERROR, out, scope);
+                }
+                if frame.fin {
+                    self.deliver(frame.opcode, &data, out, scope)
+                } else {
+                    self.message = Some((frame.opcode, data));
+                    self.expect(scope)
+                }
+            }
+            Continuation => {
+                match self.message.take() {
+                    Some((opcode, mut buf)) => {
+                        buf.extend(data.iter().cloned());
+                        if frame.fin {
+                            self.deliver(opcode, &buf, out, scope)
+                        } else {
+                            self.message = Some((opcode, buf));
+                            self.expect(scope)
+                        }
+                    }
+                    None => self.fail(PROTOCOL_ERROR, out, scope),
+                }
+            }
+        }
+    }
+}
+
+impl<H: WebSocketHandler> Upgrade for WebSocket<H> {
+    type Context = H::Context;
+    fn start<S: StreamSocket>(self, _transport: &mut Transport<S>,
+        scope: &mut Scope<H::Context>)
+        -> Request<Self>
+    {
+        // Bytes that are already in the input buffer are treated as frames
+        self.expect(scope)
+    }
+    fn bytes_read<S: StreamSocket>(mut self, transport: &mut Transport<S>,
+        _end: usize, scope: &mut Scope<H::Context>)
+        -> Request<Self>
+    {
+        let (inp, out) = transport.buffers();
+        match self.state {
+            State::Header(_) => {
+                let frame = match Frame::parse(&inp[..]) {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => {
+                        self.state = State::Header(header_size(&inp[..]));
+                        return self.expect(scope);
+                    }
+                    Err(_) => return self.fail(PROTOCOL_ERROR, out, scope),
+                };
+                if frame.mask.is_none() {
+                    // Client must mask all frames
+                    return self.fail(PROTOCOL_ERROR, out, scope);
+                }
+                if frame.length >= MAX_BUF_SIZE as u64 {
+                    return self.fail(MESSAGE_TOO_BIG, out, scope);
+                }
+                if !frame.opcode.is_control() {
+                    let limit = self.handler.as_ref()
+                        .map(|h| h.max_message_size())
+                        .unwrap_or(MAX_BUF_SIZE);
+                    let buffered = self.message.as_ref()
+                        .map(|&(_, ref buf)| buf.len()).unwrap_or(0);
+                    if buffered as u64 + frame.length > limit as u64 {
+                        return self.fail(MESSAGE_TOO_BIG, out, scope);
+                    }
+                }
+                inp.consume(frame.header_size);
+                self.state = State::Payload(frame);
+                self.expect(scope)
+            }
+            State::Payload(frame) => {
+                let len = frame.length as usize;
+                let mut data = inp[..len].to_vec();
+                inp.consume(len);
+                if let Some(mask) = frame.mask {
+                    apply_mask(&mut data, mask, 0);
+                }
+                self.state = State::Header(2);
+                self.frame(frame, data, out, scope)
+            }
+            // Spurious event, just keep flushing
+            State::Closing => self.expect(scope),
+        }
+    }
+    fn bytes_flushed<S: StreamSocket>(self,
+        _transport: &mut Transport<S>, scope: &mut Scope<H::Context>)
+        -> Request<Self>
+    {
+        match self.state {
+            State::Closing => None,
+            _ => self.expect(scope),
+        }
+    }
+    fn exception<S: StreamSocket>(self, _transport: &mut Transport<S>,
+        _exc: Exception, scope: &mut Scope<H::Context>)
+        -> Request<Self>
+    {
+        self.handler.map(|h| h.closed(None, "", scope));
+        None
+    }
+    fn timeout<S: StreamSocket>(mut self, transport: &mut Transport<S>,
+        scope: &mut Scope<H::Context>)
+        -> Request<Self>
+    {
+        if matches!(self.state, State::Closing) || self.close_sent {
+            // Peer has not replied to close or not read the data in time
+            self.handler.map(|h| h.closed(None, "", scope));
+            return None;
+        }
+        let out = transport.output();
+        match self.handler.take() {
+            Some(h) => {
+                let res = h.timeout(&mut Sender {
+                    buf: out,
+                    closed: &mut self.close_sent,
+                }, scope);
+                match res {
+                    Some((h, deadline)) => {
+                        self.handler = Some(h);
+                        self.deadline = deadline;
+                        self.expect(scope)
+                    }
+                    None => {
+                        if !self.close_sent {
+                            write_close(out, None, GOING_AWAY, "");
+                            self.close_sent = true;
+                        }
+                        self.expect(scope)
+                    }
+                }
+            }
+            None => unreachable!(),
+        }
+    }
+    fn wakeup<S: StreamSocket>(self, transport: &mut Transport<S>,
+        scope: &mut Scope<H::Context>)
+        -> Request<Self>
+    {
+        if matches!(self.state, State::Closing) {
+            return self.expect(scope);
+        }
+        self.call(transport.output(), scope,
+            |h, sender, scope| h.wakeup(sender, scope))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::net::TcpStream as StdStream;
+
+    use rotor::{self, Scope};
+    use rotor::mio::tcp::TcpListener;
+    use rotor_stream::{Accept, Stream, Deadline, Buf};
+    use time::Duration;
+
+    use server::{self, Server, Head, Response, RecvMode, Parser};
+    use hyper::status::StatusCode;
+    use test_util::{read_some, find};
+    use websocket::{accept, WebSocketHandler, Message, Sender};
+    use super::WebSocket;
+    use super::super::frame::{Opcode, write_frame, write_close};
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+    const HANDSHAKE: &'static [u8] = b"GET /chat HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+    struct Context(Rc<RefCell<Vec<String>>>);
+
+    impl server::Context for Context {}
+
+    struct Handshake;
+    struct Echo;
+
+    impl Server for Handshake {
+        type Context = Context;
+        type Upgrade = WebSocket<Echo>;
+        fn headers_received(_head: &Head, _scope: &mut Scope<Context>)
+            -> Result<(Self, RecvMode, Deadline), StatusCode>
+        {
+            Ok((Handshake, RecvMode::Buffered(1024),
+                Deadline::now() + Duration::seconds(10)))
+        }
+        fn request_start(self, head: Head, res: &mut Response,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            accept(&head, res).unwrap();
+            res.done_headers().unwrap();
+            res.done();
+            Some(self)
+        }
+        fn request_received(self, _data: &[u8], _res: &mut Response,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            Some(self)
+        }
+        fn request_chunk(self, _chunk: &[u8], _res: &mut Response,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            unreachable!();
+        }
+        fn request_end(self, _res: &mut Response,
+            _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            unreachable!();
+        }
+        fn timeout(self, _res: &mut Response, _scope: &mut Scope<Context>)
+            -> Option<(Self, Deadline)>
+        {
+            unreachable!();
+        }
+        fn wakeup(self, _res: &mut Response, _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            unreachable!();
+        }
+        fn upgrade(self, _scope: &mut Scope<Context>)
+            -> Option<WebSocket<Echo>>
+        {
+            Some(WebSocket::new(Echo,
+                Deadline::now() + Duration::seconds(10)))
+        }
+    }
+
+    impl WebSocketHandler for Echo {
+        type Context = Context;
+        fn message(self, message: Message, sender: &mut Sender,
+            scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            match message {
+                Message::Text("quit") => return None,
+                Message::Text(text) => {
+                    scope.0.borrow_mut().push(format!("text {}", text));
+                    sender.text(text);
+                }
+                Message::Binary(data) => {
+                    scope.0.borrow_mut().push(format!("binary {:?}", data));
+                    sender.binary(data);
+                }
+            }
+            Some(self)
+        }
+        fn ping(self, data: &[u8], _sender: &mut Sender,
+            scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            scope.0.borrow_mut().push(format!("ping {:?}", data));
+            Some(self)
+        }
+        fn pong(self, data: &[u8], _sender: &mut Sender,
+            scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            scope.0.borrow_mut().push(format!("pong {:?}", data));
+            Some(self)
+        }
+        fn closed(self, code: Option<u16>, reason: &str,
+            scope: &mut Scope<Context>)
+        {
+            scope.0.borrow_mut().push(format!("closed {:?} {}",
+                                              code, reason));
+        }
+        fn timeout(self, _sender: &mut Sender, _scope: &mut Scope<Context>)
+            -> Option<(Self, Deadline)>
+        {
+            unreachable!();
+        }
+        fn wakeup(self, _sender: &mut Sender, _scope: &mut Scope<Context>)
+            -> Option<Self>
+        {
+            unreachable!();
+        }
+    }
+
+    /// Starts the server and makes the websocket handshake to it
+    macro_rules! connect {
+        ($event_loop:ident, $handler:ident, $peer:ident, $log:ident) => {
+            let $log = Rc::new(RefCell::new(Vec::<String>::new()));
+            let mut $event_loop = rotor::EventLoop::new().unwrap();
+            let mut $handler = rotor::Handler::new(
+                Context($log.clone()), &mut $event_loop);
+            let lst = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())
+                .unwrap();
+            let addr = lst.local_addr().unwrap();
+            assert!($handler.add_machine_with(&mut $event_loop, |scope| {
+                Accept::<Stream<Parser<Handshake, _>>, _>::new(lst, scope)
+            }).is_ok());
+            let mut $peer = StdStream::connect(addr).unwrap();
+            $peer.write_all(HANDSHAKE).unwrap();
+            let mut data = Vec::new();
+            run_until!($event_loop, $handler, {
+                read_some(&mut $peer, &mut data);
+                find(&data, b"\r\n\r\n").is_some()
+            });
+            assert!(data.starts_with(b"HTTP/1.1 101"));
+            assert!(find(&data, b"s3pPLMBiTxaQ9kYGzzhZRbK+xOo=").is_some());
+            assert_eq!(find(&data, b"\r\n\r\n"), Some(data.len()));
+        }
+    }
+
+    fn send(peer: &mut StdStream, fin: bool, opcode: Opcode,
+        mask: Option<[u8; 4]>, data: &[u8])
+    {
+        let mut buf = Buf::new();
+        write_frame(&mut buf, fin, opcode, mask, data);
+        peer.write_all(&buf[..]).unwrap();
+    }
+
+    #[test]
+    fn control_frames_between_fragments() {
+        connect!(event_loop, handler, peer, log);
+        send(&mut peer, false, Opcode::Text, Some(MASK), b"hel");
+        send(&mut peer, true, Opcode::Ping, Some(MASK), b"p");
+        send(&mut peer, false, Opcode::Continuation, Some(MASK), b"l");
+        send(&mut peer, true, Opcode::Pong, Some(MASK), b"q");
+        send(&mut peer, true, Opcode::Continuation, Some(MASK), b"o");
+        send(&mut peer, true, Opcode::Binary, Some(MASK), b"\x00\xff");
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            data.len() >= 14
+        });
+        assert_eq!(&data[..],
+                   &b"\x8a\x01p\x81\x05hello\x82\x02\x00\xff"[..]);
+        assert_eq!(&log.borrow()[..], &[
+            "ping [112]".to_string(),
+            "pong [113]".to_string(),
+            "text hello".to_string(),
+            "binary [0, 255]".to_string(),
+        ][..]);
+    }
+
+    #[test]
+    fn client_close() {
+        connect!(event_loop, handler, peer, log);
+        let mut buf = Buf::new();
+        write_close(&mut buf, Some(MASK), 1000, "bye");
+        peer.write_all(&buf[..]).unwrap();
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, !read_some(&mut peer, &mut data));
+        assert_eq!(&data[..], &b"\x88\x02\x03\xe8"[..]);
+        assert_eq!(&log.borrow()[..], &["closed Some(1000) bye".to_string()]);
+    }
+
+    #[test]
+    fn server_close() {
+        connect!(event_loop, handler, peer, log);
+        send(&mut peer, true, Opcode::Text, Some(MASK), b"quit");
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, {
+            read_some(&mut peer, &mut data);
+            data.len() >= 4
+        });
+        assert_eq!(&data[..], &b"\x88\x02\x03\xe8"[..]);
+        // Messages are ignored until peer replies to the close frame
+        send(&mut peer, true, Opcode::Text, Some(MASK), b"late");
+        let mut buf = Buf::new();
+        write_close(&mut buf, Some(MASK), 1000, "");
+        peer.write_all(&buf[..]).unwrap();
+        run_until!(event_loop, handler, !read_some(&mut peer, &mut data));
+        assert_eq!(data.len(), 4);
+        assert!(log.borrow().is_empty());
+    }
+
+    #[test]
+    fn unmasked_frame() {
+        connect!(event_loop, handler, peer, log);
+        send(&mut peer, true, Opcode::Text, None, b"hello");
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, !read_some(&mut peer, &mut data));
+        assert_eq!(&data[..], &b"\x88\x02\x03\xea"[..]);
+        assert_eq!(&log.borrow()[..], &["closed Some(1002) ".to_string()]);
+    }
+
+    #[test]
+    fn interleaved_messages() {
+        connect!(event_loop, handler, peer, log);
+        send(&mut peer, false, Opcode::Text, Some(MASK), b"hel");
+        send(&mut peer, true, Opcode::Text, Some(MASK), b"lo");
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, !read_some(&mut peer, &mut data));
+        assert_eq!(&data[..], &b"\x88\x02\x03\xea"[..]);
+        assert_eq!(&log.borrow()[..], &["closed Some(1002) ".to_string()]);
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        connect!(event_loop, handler, peer, log);
+        send(&mut peer, true, Opcode::Text, Some(MASK), b"\xff\xfe");
+        let mut data = Vec::new();
+        run_until!(event_loop, handler, !read_some(&mut peer, &mut data));
+        assert_eq!(&data[..], &b"\x88\x02\x03\xef"[..]);
+        assert_eq!(&log.borrow()[..], &["closed Some(1007) ".to_string()]);
+    }
+}